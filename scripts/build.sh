@@ -103,7 +103,8 @@ build_wasm() {
   # Build WASM module with Rust
   cargo build --target wasm32-wasip1 --release
 
-  local wasm_file="target/wasm32-wasip1/release/${module_name//-/_}.wasm"
+  # Crates share the wasm/ workspace target directory
+  local wasm_file="../target/wasm32-wasip1/release/${module_name//-/_}.wasm"
 
   if [ ! -f "$wasm_file" ]; then
     echo -e "${RED}Error: Failed to build ${wasm_file}${NC}"
@@ -141,6 +142,11 @@ echo ""
 echo -e "${GREEN}=== Building Rust WASM Modules ===${NC}"
 build_wasm "client-filter-rust" "wasm/client-filter-rust"
 build_wasm "server-filter-rust" "wasm/server-filter-rust"
+build_wasm "header-transform-rust" "wasm/header-transform-rust"
+build_wasm "response-cache-rust" "wasm/response-cache-rust"
+build_wasm "canary-rust" "wasm/canary-rust"
+build_wasm "cors-rust" "wasm/cors-rust"
+build_wasm "tcp-authz-rust" "wasm/tcp-authz-rust"
 
 # Build Go services
 echo -e "${GREEN}=== Building Services ===${NC}"
@@ -152,8 +158,13 @@ build_service "service-b" "services/service-b"
 # Summary
 echo -e "${GREEN}=== Build Summary ===${NC}"
 echo -e "Rust WASM modules:"
-echo -e "  • client-filter-rust.wasm - $(ls -lh wasm/client-filter-rust/target/wasm32-wasip1/release/client_filter_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
-echo -e "  • server-filter-rust.wasm - $(ls -lh wasm/server-filter-rust/target/wasm32-wasip1/release/server_filter_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
+echo -e "  • client-filter-rust.wasm - $(ls -lh wasm/target/wasm32-wasip1/release/client_filter_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
+echo -e "  • server-filter-rust.wasm - $(ls -lh wasm/target/wasm32-wasip1/release/server_filter_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
+echo -e "  • header-transform-rust.wasm - $(ls -lh wasm/target/wasm32-wasip1/release/header_transform_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
+echo -e "  • response-cache-rust.wasm - $(ls -lh wasm/target/wasm32-wasip1/release/response_cache_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
+echo -e "  • canary-rust.wasm - $(ls -lh wasm/target/wasm32-wasip1/release/canary_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
+echo -e "  • cors-rust.wasm - $(ls -lh wasm/target/wasm32-wasip1/release/cors_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
+echo -e "  • tcp-authz-rust.wasm - $(ls -lh wasm/target/wasm32-wasip1/release/tcp_authz_rust.wasm 2>/dev/null | awk '{print $5}' || echo 'not found')"
 echo ""
 echo -e "Docker images:"
 docker images | grep -E "(jwt-vending-service|sgnl-pdp-service|service-a|service-b)" | head -4
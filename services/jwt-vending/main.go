@@ -4,9 +4,11 @@ import (
 	"crypto/rand"
 	"crypto/rsa"
 	"crypto/x509"
+	"encoding/base64"
 	"encoding/json"
 	"encoding/pem"
 	"log"
+	"math/big"
 	"net/http"
 	"time"
 
@@ -20,9 +22,15 @@ var (
 	validPublicKey    *rsa.PublicKey
 )
 
+// validKeyID identifies the valid signing key in token headers and the JWKS
+const validKeyID = "jwt-vending-valid"
+
 // TokenRequest represents the request body for token generation
 type TokenRequest struct {
-	ServiceID string `json:"service_id"` // e.g., "service-a"
+	ServiceID string `json:"service_id"`         // e.g., "service-a"
+	Audience  string `json:"audience,omitempty"` // defaults to "service-mesh"
+	Scope     string `json:"scope,omitempty"`
+	DPoPJkt   string `json:"dpop_jkt,omitempty"` // binds the token to a DPoP key (RFC 9449)
 }
 
 // TokenResponse represents the response containing the JWT
@@ -34,6 +42,13 @@ type TokenResponse struct {
 // JWTClaims represents the JWT claims structure
 type JWTClaims struct {
 	jwt.RegisteredClaims
+	Scope string        `json:"scope,omitempty"`
+	Cnf   *Confirmation `json:"cnf,omitempty"`
+}
+
+// Confirmation binds a token to a proof-of-possession key
+type Confirmation struct {
+	Jkt string `json:"jkt"`
 }
 
 func init() {
@@ -54,23 +69,33 @@ func init() {
 	log.Println("JWT signing keys generated successfully")
 }
 
-// generateToken creates a JWT token signed with the specified key
-func generateToken(serviceID string, privateKey *rsa.PrivateKey) (string, error) {
+// generateToken creates a JWT token for the request signed with the specified key
+func generateToken(req TokenRequest, privateKey *rsa.PrivateKey) (string, error) {
 	now := time.Now()
 	expiresAt := now.Add(5 * time.Minute)
 
+	audience := req.Audience
+	if audience == "" {
+		audience = "service-mesh"
+	}
+
 	claims := JWTClaims{
 		RegisteredClaims: jwt.RegisteredClaims{
-			Subject:   serviceID,
+			Subject:   req.ServiceID,
 			Issuer:    "jwt-vending-service",
-			Audience:  jwt.ClaimStrings{"service-mesh"},
+			Audience:  jwt.ClaimStrings{audience},
 			ExpiresAt: jwt.NewNumericDate(expiresAt),
 			IssuedAt:  jwt.NewNumericDate(now),
 			NotBefore: jwt.NewNumericDate(now),
 		},
+		Scope: req.Scope,
+	}
+	if req.DPoPJkt != "" {
+		claims.Cnf = &Confirmation{Jkt: req.DPoPJkt}
 	}
 
 	token := jwt.NewWithClaims(jwt.SigningMethodRS256, claims)
+	token.Header["kid"] = validKeyID
 	tokenString, err := token.SignedString(privateKey)
 	if err != nil {
 		return "", err
@@ -97,7 +122,7 @@ func handleValidToken(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
-	tokenString, err := generateToken(req.ServiceID, validPrivateKey)
+	tokenString, err := generateToken(req, validPrivateKey)
 	if err != nil {
 		log.Printf("Error generating valid token: %v", err)
 		http.Error(w, "Failed to generate token", http.StatusInternalServerError)
@@ -134,7 +159,7 @@ func handleInvalidToken(w http.ResponseWriter, r *http.Request) {
 	}
 
 	// Sign with the invalid private key
-	tokenString, err := generateToken(req.ServiceID, invalidPrivateKey)
+	tokenString, err := generateToken(req, invalidPrivateKey)
 	if err != nil {
 		log.Printf("Error generating invalid token: %v", err)
 		http.Error(w, "Failed to generate token", http.StatusInternalServerError)
@@ -170,6 +195,29 @@ func handlePublicKey(w http.ResponseWriter, r *http.Request) {
 	w.Write(pubKeyPEM)
 }
 
+// handleJWKS returns the public key as a JWKS document for filters that verify tokens locally
+func handleJWKS(w http.ResponseWriter, r *http.Request) {
+	if r.Method != http.MethodGet {
+		http.Error(w, "Method not allowed", http.StatusMethodNotAllowed)
+		return
+	}
+
+	encode := func(b []byte) string { return base64.RawURLEncoding.EncodeToString(b) }
+	jwks := map[string]interface{}{
+		"keys": []map[string]string{{
+			"kty": "RSA",
+			"use": "sig",
+			"alg": "RS256",
+			"kid": validKeyID,
+			"n":   encode(validPublicKey.N.Bytes()),
+			"e":   encode(big.NewInt(int64(validPublicKey.E)).Bytes()),
+		}},
+	}
+
+	w.Header().Set("Content-Type", "application/json")
+	json.NewEncoder(w).Encode(jwks)
+}
+
 // handleHealth returns health status
 func handleHealth(w http.ResponseWriter, r *http.Request) {
 	w.Header().Set("Content-Type", "application/json")
@@ -180,6 +228,7 @@ func main() {
 	http.HandleFunc("/token/valid", handleValidToken)
 	http.HandleFunc("/token/invalid", handleInvalidToken)
 	http.HandleFunc("/public-key", handlePublicKey)
+	http.HandleFunc("/.well-known/jwks.json", handleJWKS)
 	http.HandleFunc("/health", handleHealth)
 
 	port := ":8081"
@@ -188,6 +237,7 @@ func main() {
 	log.Printf("  POST /token/valid - Generate valid JWT")
 	log.Printf("  POST /token/invalid - Generate invalid JWT")
 	log.Printf("  GET /public-key - Get public key for validation")
+	log.Printf("  GET /.well-known/jwks.json - Get public key as JWKS")
 	log.Printf("  GET /health - Health check")
 
 	if err := http.ListenAndServe(port, nil); err != nil {
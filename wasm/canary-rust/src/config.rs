@@ -0,0 +1,71 @@
+use serde::Deserialize;
+use wasm_common::logging::LoggingConfig;
+
+/// Plugin configuration for the canary filter, supplied as JSON through the
+/// Envoy `configuration` field. Unconfigured, every request is stable.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Requests put in the canary whatever the percentage, e.g. testers
+    /// sending `x-canary: always` or users in a `beta` group.
+    pub rules: Vec<CohortRule>,
+    /// Share (0-100) of the remaining requests put in the canary.
+    pub percentage: f64,
+    /// Request header set to the cohort's name, for routes to match on to
+    /// pick the canary's cluster. Envoy re-selects the route after the
+    /// filter changes it.
+    pub routing_header: String,
+    pub canary_cohort: String,
+    pub stable_cohort: String,
+    /// Keep clients in their cohort with a cookie. Without it each request
+    /// is assigned anew.
+    pub sticky_cookie: Option<StickyCookie>,
+    pub logging: LoggingConfig,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            rules: Vec::new(),
+            percentage: 0.0,
+            routing_header: "x-canary-cohort".to_string(),
+            canary_cohort: "canary".to_string(),
+            stable_cohort: "stable".to_string(),
+            sticky_cookie: None,
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+/// Matches requests by a header or a claim of their bearer token, e.g.
+/// `{"claim": "groups", "value": "beta"}`. The token isn't verified; the
+/// cohort only decides where an authorized request goes.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum CohortRule {
+    Header {
+        header: String,
+        value: String,
+    },
+    /// A top-level claim equal to `value`, or an array claim containing it.
+    Claim {
+        claim: String,
+        value: String,
+    },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StickyCookie {
+    pub name: String,
+    pub max_age_secs: u64,
+}
+
+impl Default for StickyCookie {
+    fn default() -> Self {
+        StickyCookie {
+            name: "canary-cohort".to_string(),
+            max_age_secs: 86_400,
+        }
+    }
+}
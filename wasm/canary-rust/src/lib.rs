@@ -0,0 +1,188 @@
+mod config;
+#[cfg(test)]
+mod tests;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use proxy_wasm::traits::*;
+use proxy_wasm::types::*;
+use serde_json::Value;
+use std::rc::Rc;
+use wasm_common::logging::LogFields;
+use wasm_common::{log_debug, log_info, log_warn, time, token};
+
+use crate::config::{CohortRule, FilterConfig};
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Info);
+    wasm_common::logging::init("canary");
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(CanaryRoot::default())
+    });
+}}
+
+/// Filter state key holding the request's cohort, for later filters and
+/// access logs (`%FILTER_STATE(wasm.canary.cohort:PLAIN)%`).
+const COHORT_PROPERTY: &str = "canary.cohort";
+
+#[derive(Default)]
+struct CanaryRoot {
+    config: Rc<FilterConfig>,
+}
+
+impl Context for CanaryRoot {}
+
+impl RootContext for CanaryRoot {
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        let raw = self.get_vm_configuration().unwrap_or_default();
+        match wasm_common::vm::configure(&raw) {
+            Ok(_) => true,
+            Err(e) => {
+                log_warn!("Invalid VM configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let raw = self.get_plugin_configuration().unwrap_or_default();
+        match wasm_common::config::parse::<FilterConfig>(&raw) {
+            Ok(config) => {
+                log_info!(
+                    "Configured: {}% canary, {} rule(s), sticky: {}",
+                    config.percentage,
+                    config.rules.len(),
+                    config.sticky_cookie.is_some()
+                );
+                wasm_common::logging::configure(&config.logging);
+                self.config = Rc::new(config);
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid plugin configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(CanaryHttp {
+            context_id,
+            config: self.config.clone(),
+            set_cookie: None,
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+struct CanaryHttp {
+    context_id: u32,
+    config: Rc<FilterConfig>,
+    /// Cookie sticking a fresh assignment, sent with the response.
+    set_cookie: Option<String>,
+}
+
+impl Context for CanaryHttp {}
+
+impl HttpContext for CanaryHttp {
+    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        let (cohort, how) = match self.sticky_cohort() {
+            Some(cohort) => (cohort, "cookie"),
+            None => {
+                let (canary, how) = match self.config.rules.iter().any(|rule| self.matches(rule)) {
+                    true => (true, "rule"),
+                    false => (self.in_percentage(), "percentage"),
+                };
+                let cohort = match canary {
+                    true => self.config.canary_cohort.clone(),
+                    false => self.config.stable_cohort.clone(),
+                };
+                self.set_cookie = self.config.sticky_cookie.as_ref().map(|cookie| {
+                    format!(
+                        "{}={}; Path=/; Max-Age={}; HttpOnly",
+                        cookie.name, cohort, cookie.max_age_secs
+                    )
+                });
+                (cohort, how)
+            }
+        };
+        log_debug!(fields: self.log_fields(); "Assigned to {} by {}", cohort, how);
+
+        // Overwritten so clients can't pick their cohort by sending the header
+        self.set_http_request_header(&self.config.routing_header, Some(&cohort));
+        self.set_property(vec![COHORT_PROPERTY], Some(cohort.as_bytes()));
+        Action::Continue
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        if let Some(cookie) = &self.set_cookie {
+            self.add_http_response_header("set-cookie", cookie);
+        }
+        Action::Continue
+    }
+}
+
+impl CanaryHttp {
+    fn log_fields(&self) -> LogFields<'static> {
+        LogFields {
+            context_id: Some(self.context_id),
+            ..Default::default()
+        }
+    }
+
+    /// The cohort named by the sticky cookie, if it names a known one.
+    fn sticky_cohort(&self) -> Option<String> {
+        let name = &self.config.sticky_cookie.as_ref()?.name;
+        let cookies = self.get_http_request_header("cookie")?;
+        let cohort = cookies
+            .split(';')
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(cookie, _)| cookie == name)?
+            .1;
+        [&self.config.canary_cohort, &self.config.stable_cohort]
+            .into_iter()
+            .find(|known| *known == cohort)
+            .cloned()
+    }
+
+    fn matches(&self, rule: &CohortRule) -> bool {
+        match rule {
+            CohortRule::Header { header, value } => self
+                .get_http_request_header(header)
+                .is_some_and(|v| v == *value),
+            CohortRule::Claim { claim, value } => {
+                let claims = self
+                    .get_http_request_header("authorization")
+                    .and_then(|header| token::from_header(&header, "Bearer").and_then(claims));
+                match claims.as_ref().and_then(|claims| claims.get(claim)) {
+                    Some(Value::String(s)) => s == value,
+                    Some(Value::Array(items)) => {
+                        items.iter().any(|item| item.as_str() == Some(value))
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// Whether the request falls in the canary's share, by a hash of its
+    /// request id so retries of a request land in the same cohort.
+    fn in_percentage(&self) -> bool {
+        let seed = self
+            .get_http_request_header("x-request-id")
+            .unwrap_or_else(|| format!("{}:{}", time::now_nanos(self), self.context_id));
+        let hash = seed.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        ((hash % 10_000) as f64) < self.config.percentage * 100.0
+    }
+}
+
+/// The claims of a JWT, unverified.
+fn claims(token: &str) -> Option<serde_json::Map<String, Value>> {
+    let payload = URL_SAFE_NO_PAD.decode(token.split('.').nth(1)?).ok()?;
+    serde_json::from_slice(&payload).ok()
+}
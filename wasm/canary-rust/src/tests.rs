@@ -0,0 +1,67 @@
+//! Cohort assignment against the mock host.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use proxy_wasm::traits::HttpContext;
+use std::rc::Rc;
+use wasm_common::mock_host;
+
+use crate::config::FilterConfig;
+use crate::CanaryHttp;
+
+fn filter(config: serde_json::Value) -> CanaryHttp {
+    mock_host::reset();
+    CanaryHttp {
+        context_id: 2,
+        config: Rc::new(serde_json::from_value::<FilterConfig>(config).unwrap()),
+        set_cookie: None,
+    }
+}
+
+fn cohort() -> Option<String> {
+    mock_host::request_header("x-canary-cohort")
+}
+
+#[test]
+fn percentage_assignment_is_stuck_with_a_cookie() {
+    let mut filter = filter(serde_json::json!({ "percentage": 100.0, "sticky_cookie": {} }));
+    mock_host::set_request_headers(&[(":path", "/"), ("x-canary-cohort", "stable")]);
+    filter.on_http_request_headers(2, true);
+    mock_host::set_response_headers(&[(":status", "200")]);
+    filter.on_http_response_headers(1, true);
+
+    assert_eq!(cohort().as_deref(), Some("canary"));
+    assert_eq!(
+        mock_host::response_header("set-cookie").as_deref(),
+        Some("canary-cohort=canary; Path=/; Max-Age=86400; HttpOnly")
+    );
+}
+
+#[test]
+fn sticky_cookie_overrides_percentage() {
+    let mut filter = filter(serde_json::json!({ "percentage": 100.0, "sticky_cookie": {} }));
+    mock_host::set_request_headers(&[
+        (":path", "/"),
+        ("cookie", "theme=dark; canary-cohort=stable"),
+    ]);
+    filter.on_http_request_headers(2, true);
+    mock_host::set_response_headers(&[(":status", "200")]);
+    filter.on_http_response_headers(1, true);
+
+    assert_eq!(cohort().as_deref(), Some("stable"));
+    assert_eq!(mock_host::response_header("set-cookie"), None);
+}
+
+#[test]
+fn claim_rule_puts_request_in_canary() {
+    let mut filter = filter(serde_json::json!({ "rules": [{"claim": "groups", "value": "beta"}] }));
+    let claims = URL_SAFE_NO_PAD.encode(br#"{"sub":"alice","groups":["staff","beta"]}"#);
+    let authorization = format!("Bearer e30.{}.sig", claims);
+    mock_host::set_request_headers(&[(":path", "/"), ("authorization", &authorization)]);
+    filter.on_http_request_headers(2, true);
+    assert_eq!(cohort().as_deref(), Some("canary"));
+
+    mock_host::set_request_headers(&[(":path", "/")]);
+    filter.on_http_request_headers(1, true);
+    assert_eq!(cohort().as_deref(), Some("stable"));
+}
@@ -0,0 +1,237 @@
+use serde::Deserialize;
+use std::time::Duration;
+use wasm_common::glob;
+use wasm_common::logging::LoggingConfig;
+
+use crate::dpop::DpopConfig;
+use crate::identity_chain::IdentityChainConfig;
+use crate::oauth2::OAuth2Config;
+use crate::replay::ReplayConfig;
+use crate::retry::RetryPolicy;
+
+/// Plugin configuration for the client filter, supplied as JSON through the
+/// Envoy `configuration` field. Every field is optional and falls back to the
+/// values the filter used before it became configurable.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Authorities that get a JWT injected. Entries may contain `*` wildcards,
+    /// e.g. `service-b*` or `*.internal:8080`.
+    pub target_authorities: Vec<String>,
+    /// Paths of target authorities that get a JWT. The first entry whose
+    /// `authority` matches restricts its requests to `path_prefixes`; targets
+    /// without an entry get one on every path.
+    pub target_paths: Vec<TargetPaths>,
+    /// Audience and scope to request for tokens sent to particular targets,
+    /// so each destination gets a narrowly scoped JWT. The first entry whose
+    /// `authority` and `path_prefix` match wins; other targets get the
+    /// generic token.
+    pub audiences: Vec<TargetAudience>,
+    pub vending_cluster: String,
+    pub vending_path: String,
+    pub vending_authority: String,
+    pub service_id: String,
+    /// Fetch tokens from an OAuth2 token endpoint with the client-credentials
+    /// grant instead of the vending service. Disabled when absent.
+    pub oauth2: Option<OAuth2Config>,
+    /// Where the token is put on outbound requests.
+    pub token_header: TokenHeader,
+    /// Requests already carrying any of these headers, e.g. `Authorization`
+    /// for end-user credentials or a marker header set by the application,
+    /// are forwarded untouched without fetching a token.
+    pub passthrough_headers: Vec<String>,
+    /// Attach DPoP proofs to outbound requests. Disabled when absent.
+    pub dpop: Option<DpopConfig>,
+    /// Record the service id and token audience of each request given a
+    /// token in the stream's filter state, for later filters in the chain.
+    pub annotate_requests: bool,
+    /// Append a signed hop to the identity chain of requests to targets.
+    /// Disabled when absent.
+    pub identity_chain: Option<IdentityChainConfig>,
+    /// Credential injected when no token can be obtained. Disabled when
+    /// absent, leaving such requests without a token.
+    pub fallback_token: Option<FallbackToken>,
+    /// What happens to a request no token (nor fallback) could be got for.
+    pub failure_mode: FailureMode,
+    pub timeout_ms: u64,
+    /// Retry transient token fetch failures before falling back. Disabled
+    /// when absent.
+    pub retry: Option<RetryPolicy>,
+    /// Replay requests the upstream rejects with 401 with a fresh token.
+    /// Disabled when absent.
+    pub replay_unauthorized: Option<ReplayConfig>,
+    /// Cached tokens are refreshed once they are within this margin of
+    /// expiry, so a token never expires while a request is in flight.
+    pub token_refresh_margin_ms: u64,
+    /// How often the root checks whether a token fetched by another VM has
+    /// arrived for requests parked on this one.
+    pub token_wait_poll_ms: u64,
+    pub stat_prefix: String,
+    pub logging: LoggingConfig,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            target_authorities: vec![
+                "service-b:8083".to_string(),
+                "service-b".to_string(),
+                "envoy-service-b:10001".to_string(),
+            ],
+            target_paths: Vec::new(),
+            audiences: Vec::new(),
+            vending_cluster: "jwt-vending-service".to_string(),
+            vending_path: "/token/valid".to_string(),
+            vending_authority: "jwt-vending-service:8081".to_string(),
+            service_id: "service-a".to_string(),
+            oauth2: None,
+            token_header: TokenHeader::default(),
+            passthrough_headers: Vec::new(),
+            dpop: None,
+            annotate_requests: false,
+            identity_chain: None,
+            fallback_token: None,
+            failure_mode: FailureMode::Open,
+            timeout_ms: 5000,
+            retry: None,
+            replay_unauthorized: None,
+            token_refresh_margin_ms: 30_000,
+            token_wait_poll_ms: 100,
+            stat_prefix: "client_filter".to_string(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+/// The outbound credential header, e.g. `X-Id-Token` with an empty scheme
+/// or `Proxy-Authorization` with `Bearer`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TokenHeader {
+    pub name: String,
+    /// Prefixed to the token as `<scheme> <token>`; empty for the bare token.
+    pub scheme: String,
+    /// Header used instead when the request already carries `name`, so an
+    /// end-user credential is forwarded untouched alongside the service's.
+    /// Without it, an existing header is overwritten.
+    pub secondary: Option<String>,
+}
+
+impl TokenHeader {
+    /// The header a request's token goes in, given the value of `name` it
+    /// came with.
+    pub fn for_request(&self, existing: Option<&str>) -> &str {
+        match &self.secondary {
+            Some(secondary) if existing.is_some_and(|value| !value.is_empty()) => secondary,
+            _ => &self.name,
+        }
+    }
+}
+
+impl Default for TokenHeader {
+    fn default() -> Self {
+        TokenHeader {
+            name: "Authorization".to_string(),
+            scheme: "Bearer".to_string(),
+            secondary: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Send the request on without a token, for the upstream to reject.
+    #[default]
+    #[serde(rename = "fail_open")]
+    Open,
+    /// Reject the request locally with 503.
+    #[serde(rename = "fail_closed")]
+    Closed,
+}
+
+/// A long-lived credential for riding out vending outages. Files are out of
+/// reach of the VM, so a file-backed secret is passed in through an
+/// environment variable, which Envoy sets from `vm_config.environment_variables`.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct FallbackToken {
+    pub token: Option<String>,
+    /// Environment variable holding the token. Takes precedence over `token`.
+    pub token_env: Option<String>,
+}
+
+impl FallbackToken {
+    pub fn resolve(&self) -> Option<String> {
+        self.token_env
+            .as_ref()
+            .and_then(|name| std::env::var(name).ok())
+            .or_else(|| self.token.clone())
+            .filter(|token| !token.is_empty())
+    }
+}
+
+/// Path prefixes, e.g. `/api/`, that requests to authorities matching
+/// `authority` must have to get a JWT.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TargetPaths {
+    pub authority: String,
+    pub path_prefixes: Vec<String>,
+}
+
+/// Token parameters for requests to authorities matching `authority`, which
+/// may contain `*` wildcards like `target_authorities`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TargetAudience {
+    pub authority: String,
+    /// Limits the entry to paths with this prefix. Listed before an entry
+    /// for the whole authority, it gives those paths their own scope.
+    pub path_prefix: Option<String>,
+    pub audience: Option<String>,
+    pub scope: Option<String>,
+}
+
+impl TargetAudience {
+    /// Identity of the tokens minted for this audience, distinguishing their
+    /// cache entries and fetches from other audiences'.
+    pub fn token_id(&self, service_id: &str) -> String {
+        let parts = (service_id, &self.audience, &self.scope);
+        serde_json::to_string(&parts).unwrap_or_default()
+    }
+}
+
+impl FilterConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// How long other VMs honor a fetch lock before assuming its holder is
+    /// gone.
+    pub fn fetch_lease_ms(&self) -> u64 {
+        self.timeout_ms + 1000
+    }
+
+    pub fn is_target(&self, authority: &str, path: &str) -> bool {
+        let paths = self
+            .target_paths
+            .iter()
+            .find(|paths| glob::matches(&paths.authority, authority));
+        self.target_authorities
+            .iter()
+            .any(|pattern| glob::matches(pattern, authority))
+            && paths.is_none_or(|paths| {
+                paths
+                    .path_prefixes
+                    .iter()
+                    .any(|prefix| path.starts_with(prefix.as_str()))
+            })
+    }
+
+    pub fn audience_for(&self, authority: &str, path: &str) -> Option<&TargetAudience> {
+        self.audiences.iter().find(|a| {
+            glob::matches(&a.authority, authority)
+                && a.path_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| path.starts_with(prefix.as_str()))
+        })
+    }
+}
@@ -0,0 +1,133 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Sender-constrains tokens with DPoP (RFC 9449): each VM holds a P-256 key
+/// and every outbound request carries a `DPoP` proof signed with it, bound
+/// to the request's method and URI. Tokens are requested bound to the key's
+/// thumbprint, so set `token_header.scheme` to `DPoP` for resource servers
+/// that expect that scheme.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DpopConfig {
+    /// Bind proofs to the token they accompany with the `ath` claim.
+    pub include_ath: bool,
+}
+
+impl Default for DpopConfig {
+    fn default() -> Self {
+        DpopConfig { include_ath: true }
+    }
+}
+
+/// Public half of the key, as embedded in proofs.
+#[derive(Serialize)]
+struct Jwk {
+    kty: &'static str,
+    crv: &'static str,
+    x: String,
+    y: String,
+}
+
+#[derive(Serialize)]
+struct ProofHeader<'a> {
+    typ: &'static str,
+    alg: &'static str,
+    jwk: &'a Jwk,
+}
+
+#[derive(Serialize)]
+struct ProofClaims<'a> {
+    jti: String,
+    htm: &'a str,
+    htu: &'a str,
+    iat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ath: Option<String>,
+}
+
+pub struct DpopKey {
+    signing_key: SigningKey,
+    jwk: Jwk,
+    /// JWK SHA-256 thumbprint (RFC 7638), the `jkt` tokens are bound to.
+    pub thumbprint: String,
+}
+
+impl DpopKey {
+    /// Generates a key from the host's random source.
+    pub fn generate() -> Result<Self, String> {
+        let mut seed = [0u8; 32];
+        getrandom::getrandom(&mut seed).map_err(|e| e.to_string())?;
+        let signing_key = SigningKey::from_slice(&seed).map_err(|e| e.to_string())?;
+
+        let point = signing_key.verifying_key().to_encoded_point(false);
+        let (Some(x), Some(y)) = (point.x(), point.y()) else {
+            return Err("public key has no affine coordinates".to_string());
+        };
+        let jwk = Jwk {
+            kty: "EC",
+            crv: "P-256",
+            x: URL_SAFE_NO_PAD.encode(x),
+            y: URL_SAFE_NO_PAD.encode(y),
+        };
+        // Required members in lexicographic order, without whitespace
+        let canonical = format!(
+            r#"{{"crv":"{}","kty":"{}","x":"{}","y":"{}"}}"#,
+            jwk.crv, jwk.kty, jwk.x, jwk.y
+        );
+        let thumbprint = URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()));
+        Ok(DpopKey {
+            signing_key,
+            jwk,
+            thumbprint,
+        })
+    }
+
+    /// Signs a proof for a request to `uri` (without query or fragment).
+    /// `token` is the access token the proof accompanies, if `ath` is wanted.
+    pub fn proof(
+        &self,
+        method: &str,
+        uri: &str,
+        now_secs: u64,
+        token: Option<&str>,
+    ) -> Result<String, String> {
+        let mut jti = [0u8; 16];
+        getrandom::getrandom(&mut jti).map_err(|e| e.to_string())?;
+        let header = ProofHeader {
+            typ: "dpop+jwt",
+            alg: "ES256",
+            jwk: &self.jwk,
+        };
+        let claims = ProofClaims {
+            jti: URL_SAFE_NO_PAD.encode(jti),
+            htm: method,
+            htu: uri,
+            iat: now_secs,
+            ath: token.map(|t| URL_SAFE_NO_PAD.encode(Sha256::digest(t.as_bytes()))),
+        };
+
+        let signing_input = format!("{}.{}", encode_part(&header)?, encode_part(&claims)?);
+        let signature: Signature = self.signing_key.sign(signing_input.as_bytes());
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+}
+
+/// The `htu` of a request: its URI without query or fragment.
+pub fn target_uri(scheme: &str, authority: &str, path: &str) -> String {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    format!("{}://{}{}", scheme, authority, path)
+}
+
+fn encode_part<T: Serialize>(value: &T) -> Result<String, String> {
+    serde_json::to_vec(value)
+        .map(|json| URL_SAFE_NO_PAD.encode(json))
+        .map_err(|e| e.to_string())
+}
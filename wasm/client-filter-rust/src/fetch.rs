@@ -0,0 +1,218 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use wasm_common::callout::{self, HttpCallout};
+use wasm_common::time;
+
+use crate::config::{FilterConfig, TargetAudience};
+use crate::dpop::{self, DpopKey};
+use crate::oauth2::{self, OAuth2Config};
+use crate::token_cache;
+
+/// A token to fetch, kept by the flight so the root can retry the fetch
+/// after the request that started it has handed it over.
+#[derive(Clone, Debug, Default)]
+pub struct TokenFetch {
+    /// Identity of the token, keying its cache entry and flight.
+    pub token_id: String,
+    /// Audience configured for the request's authority, if any.
+    pub audience: Option<TargetAudience>,
+    /// Trace context of the request that started the fetch.
+    pub trace_headers: Vec<(&'static str, String)>,
+}
+
+/// Why no token came out of a fetch.
+#[derive(Debug)]
+pub enum FetchError {
+    /// The callout could not be dispatched, e.g. for an unknown cluster.
+    Dispatch(String),
+    /// Timeouts, resets and 5xx responses, which may succeed if retried.
+    Transient(String),
+    /// Any other non-2xx response.
+    Rejected(String),
+    /// A 2xx response without a usable token.
+    Invalid(String),
+}
+
+impl FetchError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, FetchError::Transient(_))
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Dispatch(message)
+            | FetchError::Transient(message)
+            | FetchError::Rejected(message)
+            | FetchError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: String,
+    expires_in: i64,
+}
+
+#[derive(Serialize)]
+struct TokenRequest<'a> {
+    service_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audience: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+    /// Thumbprint of the DPoP key the token is to be bound to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dpop_jkt: Option<&'a str>,
+}
+
+/// Dispatches the token request from `ctx`, whose `on_http_call_response`
+/// receives the result.
+pub fn dispatch<C: Context + ?Sized>(
+    ctx: &C,
+    config: &FilterConfig,
+    dpop_key: Option<&DpopKey>,
+    fetch: &TokenFetch,
+    timeout: Duration,
+) -> Result<u32, FetchError> {
+    let dispatched = match &config.oauth2 {
+        Some(oauth2) => dispatch_oauth2(ctx, oauth2, dpop_key, fetch, timeout),
+        None => dispatch_vending(ctx, config, dpop_key, fetch, timeout),
+    };
+    dispatched.map_err(FetchError::Dispatch)
+}
+
+/// Requests a token from the JWT vending service.
+fn dispatch_vending<C: Context + ?Sized>(
+    ctx: &C,
+    config: &FilterConfig,
+    dpop_key: Option<&DpopKey>,
+    fetch: &TokenFetch,
+    timeout: Duration,
+) -> Result<u32, String> {
+    let request_body = serde_json::to_vec(&TokenRequest {
+        service_id: &config.service_id,
+        audience: fetch.audience.as_ref().and_then(|a| a.audience.as_deref()),
+        scope: fetch.audience.as_ref().and_then(|a| a.scope.as_deref()),
+        dpop_jkt: dpop_key.map(|key| key.thumbprint.as_str()),
+    })
+    .map_err(|e| format!("failed to serialize request: {}", e))?;
+
+    HttpCallout::post(
+        &config.vending_cluster,
+        &config.vending_authority,
+        &config.vending_path,
+    )
+    .headers(&fetch.trace_headers)
+    .json(&request_body)
+    .timeout(timeout)
+    .dispatch(ctx)
+    .map_err(|e| format!("{:?}", e))
+}
+
+/// Requests a token from the OAuth2 token endpoint with the
+/// client-credentials grant.
+fn dispatch_oauth2<C: Context + ?Sized>(
+    ctx: &C,
+    oauth2: &OAuth2Config,
+    dpop_key: Option<&DpopKey>,
+    fetch: &TokenFetch,
+    timeout: Duration,
+) -> Result<u32, String> {
+    let secret = oauth2.client_secret();
+    let body = oauth2.form_body(fetch.audience.as_ref(), secret.as_deref());
+    let authorization = oauth2.basic_authorization(secret.as_deref());
+
+    let mut callout = HttpCallout::post(&oauth2.cluster, &oauth2.authority, &oauth2.token_path)
+        .headers(&fetch.trace_headers)
+        .header("accept", "application/json")
+        .body("application/x-www-form-urlencoded", body.as_bytes())
+        .timeout(timeout);
+    if let Some(authorization) = &authorization {
+        callout = callout.header("authorization", authorization);
+    }
+    // Proof of possession for a token bound to this VM's key (RFC 9449 section 5)
+    let proof = match dpop_key {
+        Some(key) => {
+            let uri = dpop::target_uri(&oauth2.scheme, &oauth2.authority, &oauth2.token_path);
+            Some(key.proof("POST", &uri, time::now_secs(ctx), None)?)
+        }
+        None => None,
+    };
+    if let Some(proof) = &proof {
+        callout = callout.header("dpop", proof);
+    }
+    callout.dispatch(ctx).map_err(|e| format!("{:?}", e))
+}
+
+/// Parses a token response from the vending service or OAuth2 endpoint.
+fn parse_response(config: &FilterConfig, body: &[u8]) -> Result<TokenResponse, String> {
+    if config.oauth2.is_none() {
+        return serde_json::from_slice(body).map_err(|e| e.to_string());
+    }
+    if let Ok(error) = serde_json::from_slice::<oauth2::ErrorResponse>(body) {
+        return Err(format!("{} {}", error.error, error.error_description)
+            .trim_end()
+            .to_string());
+    }
+    let response: oauth2::TokenResponse =
+        serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    Ok(TokenResponse {
+        token: response.access_token,
+        expires_in: response.expires_in,
+    })
+}
+
+/// Reads and validates the token callout's response, caching a usable
+/// token under `token_id`.
+pub fn read_response<C: Context + ?Sized>(
+    ctx: &C,
+    config: &FilterConfig,
+    token_id: &str,
+    body_size: usize,
+) -> Result<String, FetchError> {
+    // Timeouts and resets surface as a missing status
+    let status = callout::response_status(ctx);
+    if status.is_empty() || status.starts_with('5') {
+        return Err(FetchError::Transient(format!(
+            "token request failed with status {:?}",
+            status
+        )));
+    }
+
+    // Other failures come back as a non-2xx status; OAuth2 adds a JSON error body
+    let response_body = ctx
+        .get_http_call_response_body(0, body_size)
+        .unwrap_or_default();
+    let parsed = parse_response(config, &response_body);
+    if !callout::is_success(&status) {
+        let detail = parsed.err().map(|e| format!(": {}", e)).unwrap_or_default();
+        return Err(FetchError::Rejected(format!(
+            "token request failed with status {:?}{}",
+            status, detail
+        )));
+    }
+    let token_resp = parsed
+        .map_err(|e| FetchError::Invalid(format!("failed to parse token response: {}", e)))?;
+
+    if token_resp.token.is_empty() {
+        return Err(FetchError::Invalid(
+            "empty token received from token service".to_string(),
+        ));
+    }
+
+    // Cache the token until it expires
+    if token_resp.expires_in > 0 {
+        let expires_at_ms = time::now_ms(ctx) + token_resp.expires_in as u64 * 1000;
+        token_cache::store(
+            ctx,
+            &token_cache::token_key(token_id),
+            &token_resp.token,
+            expires_at_ms,
+        );
+    }
+    Ok(token_resp.token)
+}
@@ -0,0 +1,44 @@
+use serde::Deserialize;
+use wasm_common::identity;
+
+/// Appends this service's hop to the `X-Forwarded-Identity` chain of
+/// outbound requests, so a service several calls away can see every caller
+/// in between. Applications pass the header of the request they are serving
+/// on to the calls they make for it, like trace headers.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct IdentityChainConfig {
+    pub header: String,
+    /// Header naming the party the application is serving, such as the
+    /// `X-Principal-ID` the server filter sets, which becomes the original
+    /// caller of a new chain. The service itself is when it's absent.
+    pub caller_header: String,
+    /// Identifies the secret to the server filters verifying the chain.
+    pub key_id: String,
+    pub secret: Option<String>,
+    /// Environment variable holding the secret. Takes precedence over
+    /// `secret`.
+    pub secret_env: Option<String>,
+}
+
+impl Default for IdentityChainConfig {
+    fn default() -> Self {
+        IdentityChainConfig {
+            header: identity::HEADER.to_string(),
+            caller_header: "X-Principal-ID".to_string(),
+            key_id: String::new(),
+            secret: None,
+            secret_env: None,
+        }
+    }
+}
+
+impl IdentityChainConfig {
+    pub fn resolve_secret(&self) -> Option<String> {
+        self.secret_env
+            .as_ref()
+            .and_then(|name| std::env::var(name).ok())
+            .or_else(|| self.secret.clone())
+            .filter(|secret| !secret.is_empty())
+    }
+}
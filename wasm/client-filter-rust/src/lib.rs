@@ -1,31 +1,213 @@
+mod config;
+mod dpop;
+mod fetch;
+mod identity_chain;
+mod metrics;
+mod oauth2;
+mod replay;
+mod retry;
+mod single_flight;
+#[cfg(test)]
+mod tests;
+mod token_cache;
+
+use proxy_wasm::hostcalls;
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
-use log::info;
-use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+use wasm_common::annotation::RequestAnnotation;
+use wasm_common::logging::LogFields;
+use wasm_common::response::{self, Problem};
+use wasm_common::{callout, identity, log_info, log_warn, time, token, trace};
 
-const JWT_VENDING_SERVICE_CLUSTER: &str = "jwt-vending-service";
-const JWT_VENDING_SERVICE_PATH: &str = "/token/valid";
+use crate::config::{FailureMode, FilterConfig, TokenHeader};
+use crate::dpop::{DpopConfig, DpopKey};
+use crate::fetch::TokenFetch;
+use crate::metrics::Metrics;
+use crate::replay::{Replay, ReplayStep};
+use crate::retry::Retry;
+use crate::single_flight::{SharedFlight, Waiter};
 
 proxy_wasm::main! {{
     proxy_wasm::set_log_level(LogLevel::Info);
+    wasm_common::logging::init("client_filter");
     proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
-        Box::new(ClientFilterRoot)
+        Box::new(ClientFilterRoot::default())
     });
 }}
 
-struct ClientFilterRoot;
+#[derive(Default)]
+struct ClientFilterRoot {
+    config: Rc<FilterConfig>,
+    flight: SharedFlight,
+    metrics: Metrics,
+    /// This VM's DPoP key, generated once `dpop` is configured.
+    dpop_key: Option<Rc<DpopKey>>,
+}
+
+impl Context for ClientFilterRoot {
+    fn on_http_call_response(
+        &mut self,
+        token_id: u32,
+        _num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        let retrying =
+            self.flight
+                .borrow()
+                .iter()
+                .find_map(|(fetch_id, flight)| match &flight.retry {
+                    Some(retry) if retry.call == Some(token_id) => {
+                        Some((fetch_id.clone(), retry.dispatched_ms))
+                    }
+                    _ => None,
+                });
+        let Some((fetch_id, dispatched_ms)) = retrying else {
+            return;
+        };
 
-impl Context for ClientFilterRoot {}
+        let result = fetch::read_response(self, &self.config, &fetch_id, body_size);
+        let latency_ms = time::now_ms(self).saturating_sub(dispatched_ms);
+        self.metrics.fetch_completed(&result, latency_ms);
+        let token = match result {
+            Ok(token) => {
+                log_info!(
+                    "Successfully obtained JWT token on retry (length: {})",
+                    token.len()
+                );
+                Some(token)
+            }
+            Err(e) => {
+                log_warn!("Token fetch retry failed: {}", e);
+                if e.is_transient() && self.schedule_retry(&fetch_id) {
+                    return;
+                }
+                None
+            }
+        };
+        self.finish_retry(&fetch_id, token);
+    }
+}
 
 impl RootContext for ClientFilterRoot {
     fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
-        info!("[Client WASM Rust] VM started");
-        true
+        let raw = self.get_vm_configuration().unwrap_or_default();
+        match wasm_common::vm::configure(&raw) {
+            Ok(vm_config) => {
+                log_info!(
+                    "VM started (log_level: {:?}, {} constant(s))",
+                    vm_config.log_level,
+                    vm_config.constants.len()
+                );
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid VM configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let raw = self.get_plugin_configuration().unwrap_or_default();
+        match wasm_common::config::parse::<FilterConfig>(&raw) {
+            Ok(config) => {
+                log_info!(
+                    "Configured: targets={:?}, vending_cluster={}, service_id={}",
+                    config.target_authorities,
+                    config.vending_cluster,
+                    config.service_id
+                );
+                if config
+                    .replay_unauthorized
+                    .as_ref()
+                    .is_some_and(|replay| replay.clusters.is_empty())
+                {
+                    log_warn!("No replay clusters configured, 401s won't be replayed");
+                }
+                if config.dpop.is_some() && self.dpop_key.is_none() {
+                    match DpopKey::generate() {
+                        Ok(key) => {
+                            log_info!("Generated DPoP key (jkt: {})", key.thumbprint);
+                            self.dpop_key = Some(Rc::new(key));
+                        }
+                        Err(e) => {
+                            log_warn!("Failed to generate DPoP key: {}", e);
+                            return false;
+                        }
+                    }
+                }
+                // Poll for tokens fetched by other VMs while requests are parked
+                self.set_tick_period(Duration::from_millis(config.token_wait_poll_ms));
+                wasm_common::logging::configure(&config.logging);
+                self.metrics = Metrics::define(&config.stat_prefix);
+                self.config = Rc::new(config);
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid plugin configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_tick(&mut self) {
+        let now_ms = time::now_ms(self);
+        let abandoned = self.dispatch_due_retries(now_ms);
+        let mut ready = Vec::new();
+        {
+            let mut flights = self.flight.borrow_mut();
+            for (token_id, flight) in flights.iter_mut() {
+                if flight.waiters.is_empty() || flight.fetching {
+                    continue;
+                }
+
+                // No fetch is running in this VM: either another VM holds the
+                // lock or the local leader gave up. Pick up the token once
+                // cached, or stop waiting if the lock was released without
+                // producing one.
+                let key = token_cache::token_key(token_id);
+                let margin_ms = self.config.token_refresh_margin_ms;
+                let token = token_cache::lookup(self, &key, now_ms, margin_ms).map(|c| c.token);
+                if token.is_none()
+                    && single_flight::is_locked(self, &single_flight::lock_key(token_id), now_ms)
+                {
+                    continue;
+                }
+                ready.push((std::mem::take(&mut flight.waiters), token));
+            }
+            flights.retain(|_, flight| flight.fetching || !flight.waiters.is_empty());
+            self.metrics.report_waiting(&flights);
+        }
+
+        // Resuming switches the effective context, so it comes last
+        for fetch_id in abandoned {
+            self.finish_retry(&fetch_id, None);
+        }
+        for (waiters, token) in ready {
+            let token = token.or_else(|| fallback_token(&self.config, &self.metrics));
+            log_info!("Resuming {} parked request(s) from tick", waiters.len());
+            injector(self, &self.config, self.dpop_key.as_deref())
+                .resume_waiters(waiters, token.as_deref());
+        }
     }
 
     fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
         Some(Box::new(ClientFilterHttp {
             context_id,
+            config: self.config.clone(),
+            flight: self.flight.clone(),
+            metrics: self.metrics,
+            dpop_key: self.dpop_key.clone(),
+            fetch: TokenFetch::default(),
+            fetch_started_ms: 0,
+            fetch_leader: false,
+            started_ms: 0,
+            released_ms: Rc::default(),
+            replay: None,
         }))
     }
 
@@ -34,125 +216,749 @@ impl RootContext for ClientFilterRoot {
     }
 }
 
-struct ClientFilterHttp {
-    context_id: u32,
+impl ClientFilterRoot {
+    /// Dispatches the retries whose backoff has elapsed. Returns the fetches
+    /// given up on, to be finished once nothing else needs the root context.
+    fn dispatch_due_retries(&self, now_ms: u64) -> Vec<String> {
+        let Some(policy) = &self.config.retry else {
+            return Vec::new();
+        };
+        let mut abandoned = Vec::new();
+        let mut flights = self.flight.borrow_mut();
+        for (fetch_id, flight) in flights.iter_mut() {
+            let Some(retry) = flight.retry.as_mut() else {
+                continue;
+            };
+            if retry.call.is_some() || retry.next_attempt_ms > now_ms {
+                continue;
+            }
+            let remaining_ms = policy.deadline_at(retry.started_ms).saturating_sub(now_ms);
+            if remaining_ms == 0 {
+                abandoned.push(fetch_id.clone());
+                continue;
+            }
+
+            retry.attempts += 1;
+            metrics::increment(self.metrics.fetch_attempts);
+            let timeout = Duration::from_millis(self.config.timeout_ms.min(remaining_ms));
+            match fetch::dispatch(
+                self,
+                &self.config,
+                self.dpop_key.as_deref(),
+                &retry.fetch,
+                timeout,
+            ) {
+                Ok(call_id) => {
+                    log_info!(
+                        "Retrying token fetch, attempt {} (call_id: {})",
+                        retry.attempts,
+                        call_id
+                    );
+                    retry.call = Some(call_id);
+                    retry.dispatched_ms = now_ms;
+                    let lock_key = single_flight::lock_key(fetch_id);
+                    single_flight::renew(self, &lock_key, now_ms + self.config.fetch_lease_ms());
+                }
+                Err(e) => {
+                    log_warn!("Failed to dispatch token request: {}", e);
+                    self.metrics.fetch_failed(&e);
+                    abandoned.push(fetch_id.clone());
+                }
+            }
+        }
+        abandoned
+    }
+
+    /// Schedules the next attempt of a failed retry. Returns false if the
+    /// retry policy allows no further attempt.
+    fn schedule_retry(&self, fetch_id: &str) -> bool {
+        let Some(policy) = &self.config.retry else {
+            return false;
+        };
+        let now_ms = time::now_ms(self);
+        let mut flights = self.flight.borrow_mut();
+        let Some(retry) = flights
+            .get_mut(fetch_id)
+            .and_then(|flight| flight.retry.as_mut())
+        else {
+            return false;
+        };
+        let Some(next_attempt_ms) =
+            policy.next_attempt_at(retry.attempts, retry.started_ms, now_ms)
+        else {
+            return false;
+        };
+        retry.next_attempt_ms = next_attempt_ms;
+        retry.call = None;
+        let lock_key = single_flight::lock_key(fetch_id);
+        single_flight::renew(
+            self,
+            &lock_key,
+            next_attempt_ms + self.config.fetch_lease_ms(),
+        );
+        true
+    }
+
+    /// Ends a retried fetch, resuming every request waiting for it with the
+    /// token or, failing that, the fallback credential.
+    fn finish_retry(&self, fetch_id: &str, token: Option<String>) {
+        single_flight::release(self, &single_flight::lock_key(fetch_id));
+        let waiters = self
+            .flight
+            .borrow_mut()
+            .remove(fetch_id)
+            .map(|flight| flight.waiters)
+            .unwrap_or_default();
+        self.metrics.report_waiting(&self.flight.borrow());
+        let token = token.or_else(|| fallback_token(&self.config, &self.metrics));
+        log_info!("Resuming {} parked request(s) after retries", waiters.len());
+        injector(self, &self.config, self.dpop_key.as_deref())
+            .resume_waiters(waiters, token.as_deref());
+    }
 }
 
-#[derive(Deserialize)]
-struct TokenResponse {
-    token: String,
-    #[allow(dead_code)]
-    expires_in: i64,
+/// Logs from an HTTP context, tagged with its context id.
+macro_rules! ctx_info {
+    ($ctx:expr, $($arg:tt)+) => {
+        log_info!(fields: $ctx.log_fields(); $($arg)+)
+    };
 }
 
-#[derive(Serialize)]
-struct TokenRequest {
-    service_id: String,
+/// Like `ctx_info!`, for failures, which are logged even when sampling.
+macro_rules! ctx_warn {
+    ($ctx:expr, $($arg:tt)+) => {
+        log_warn!(fields: $ctx.log_fields(); $($arg)+)
+    };
 }
 
-impl Context for ClientFilterHttp {
-    fn on_http_call_response(&mut self, _token_id: u32, num_headers: usize, body_size: usize, _num_trailers: usize) {
-        info!("[Client WASM Rust] Received JWT response (headers: {}, body: {})", num_headers, body_size);
+#[derive(Default)]
+struct ClientFilterHttp {
+    context_id: u32,
+    config: Rc<FilterConfig>,
+    flight: SharedFlight,
+    metrics: Metrics,
+    dpop_key: Option<Rc<DpopKey>>,
+    /// The token the request needs.
+    fetch: TokenFetch,
+    /// When this context dispatched its token callout.
+    fetch_started_ms: u64,
+    /// Set while this context owns the VM's outstanding vending callout.
+    fetch_leader: bool,
+    /// When the request's headers reached the filter.
+    started_ms: u64,
+    /// When the request was let through or answered, set by whichever
+    /// context resumes it. Never set for requests the filter passes over,
+    /// such as non-target and passthrough ones.
+    released_ms: Rc<Cell<Option<u64>>>,
+    /// The request kept for replaying it on a 401, with `replay_unauthorized`.
+    replay: Option<Replay>,
+}
 
-        // Get response body
-        let response_body = match self.get_http_call_response_body(0, body_size) {
-            Some(body) => body,
-            None => {
-                info!("[Client WASM Rust] Failed to get response body");
-                self.resume_http_request();
-                return;
+impl Context for ClientFilterHttp {
+    fn on_http_call_response(
+        &mut self,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        match self.replay.as_ref().map(|replay| replay.step) {
+            Some(ReplayStep::Fetching(call)) if call == token_id => {
+                return self.replay_with_token(body_size)
             }
-        };
+            Some(ReplayStep::Replaying(call)) if call == token_id => {
+                return self.finish_replay(body_size)
+            }
+            _ => {}
+        }
+        ctx_info!(
+            self,
+            "Received JWT response (headers: {}, body: {})",
+            num_headers,
+            body_size
+        );
 
-        // Parse token response
-        let token_resp: TokenResponse = match serde_json::from_slice(&response_body) {
-            Ok(resp) => resp,
+        let result = fetch::read_response(self, &self.config, &self.fetch.token_id, body_size);
+        let latency_ms = time::now_ms(self).saturating_sub(self.fetch_started_ms);
+        self.metrics.fetch_completed(&result, latency_ms);
+        match result {
+            Ok(token) => {
+                ctx_info!(
+                    self,
+                    "Successfully obtained JWT token (length: {})",
+                    token.len()
+                );
+                self.finish_fetch(Some(&token));
+            }
             Err(e) => {
-                info!("[Client WASM Rust] Failed to parse token response: {}", e);
-                self.resume_http_request();
-                return;
+                ctx_warn!(self, "Token fetch failed: {}", e);
+                if e.is_transient() && self.hand_off_retry() {
+                    return;
+                }
+                self.finish_fetch(None);
             }
-        };
-
-        if token_resp.token.is_empty() {
-            info!("[Client WASM Rust] Empty token received from JWT vending service");
-            self.resume_http_request();
-            return;
         }
+    }
 
-        info!("[Client WASM Rust] Successfully obtained JWT token (length: {})", token_resp.token.len());
-
-        // Inject JWT token into the Authorization header
-        let auth_header = format!("Bearer {}", token_resp.token);
-        self.set_http_request_header("Authorization", Some(&auth_header));
-        info!("[Client WASM Rust] Injected JWT token into Authorization header");
-
-        // Resume the request
-        self.resume_http_request();
+    fn on_done(&mut self) -> bool {
+        // The request went away with the callout still outstanding; hand the
+        // parked requests back rather than leaving them waiting forever.
+        if self.fetch_leader {
+            ctx_info!(self, "Token fetch abandoned");
+            self.release_flight();
+        }
+        if let Some(released_ms) = self.released_ms.get() {
+            metrics::record(
+                self.metrics.added_latency_ms,
+                released_ms.saturating_sub(self.started_ms),
+            );
+        }
+        true
     }
 }
 
 impl HttpContext for ClientFilterHttp {
-    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+    fn on_http_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        self.started_ms = time::now_ms(self);
         // Get the target service from the authority header
         let authority = match self.get_http_request_header(":authority") {
             Some(auth) => auth,
             None => {
-                info!("[Client WASM Rust] No authority header found");
+                ctx_info!(self, "No authority header found");
                 return Action::Continue;
             }
         };
 
-        // Only process requests to service-b
-        if authority != "service-b:8083" && authority != "service-b" && authority != "envoy-service-b:10001" {
-            info!("[Client WASM Rust] Skipping JWT injection for non-service-b request: {}", authority);
+        // Only process requests to configured target services and paths
+        let path = self.get_http_request_header(":path").unwrap_or_default();
+        if !self.config.is_target(&authority, &path) {
+            ctx_info!(
+                self,
+                "Skipping JWT injection for non-target request: {}{}",
+                authority,
+                path
+            );
             return Action::Continue;
         }
 
-        info!("[Client WASM Rust] Intercepted request to {}, fetching JWT token", authority);
-
-        // Prepare request body
-        let request_body = match serde_json::to_vec(&TokenRequest {
-            service_id: "service-a".to_string(),
-        }) {
-            Ok(body) => body,
-            Err(e) => {
-                info!("[Client WASM Rust] Failed to serialize request: {}", e);
-                return Action::Continue;
+        // Leave requests that bring their own credentials alone
+        let passthrough = self.config.passthrough_headers.iter().find(|name| {
+            self.get_http_request_header(name)
+                .is_some_and(|value| !value.is_empty())
+        });
+        if let Some(name) = passthrough {
+            ctx_info!(self, "Request carries {}, passing through", name);
+            metrics::increment(self.metrics.passthrough);
+            return Action::Continue;
+        }
+        if let Some(replay_config) = &self.config.replay_unauthorized {
+            match replay_config.cluster_for(&authority) {
+                Some(cluster) => {
+                    let header = &self.config.token_header;
+                    let existing = self.get_http_request_header(&header.name);
+                    self.replay = Some(Replay {
+                        cluster: cluster.to_string(),
+                        token_header: header.for_request(existing.as_deref()).to_string(),
+                        complete: end_of_stream,
+                        ..Default::default()
+                    });
+                }
+                None => ctx_info!(
+                    self,
+                    "No replay cluster serves {}, its 401s won't be replayed",
+                    authority
+                ),
             }
+        }
+
+        // Tokens for a configured audience are cached and fetched separately
+        let audience = self.config.audience_for(&authority, &path).cloned();
+        let mut token_id = match &audience {
+            Some(audience) => audience.token_id(&self.config.service_id),
+            None => self.config.service_id.clone(),
         };
+        // DPoP-bound tokens are only usable with this VM's key
+        if let Some(key) = &self.dpop_key {
+            token_id = format!("{}#{}", token_id, key.thumbprint);
+        }
+        self.fetch.token_id = token_id;
+        self.fetch.audience = audience;
+        if self.config.annotate_requests {
+            let audience = self.fetch.audience.as_ref();
+            RequestAnnotation {
+                service_id: self.config.service_id.clone(),
+                audience: audience.and_then(|a| a.audience.clone()),
+                scope: audience.and_then(|a| a.scope.clone()),
+            }
+            .publish(self);
+        }
+        self.extend_identity_chain();
 
-        // Make HTTP callout to JWT vending service
-        let headers = vec![
-            (":method", "POST"),
-            (":path", JWT_VENDING_SERVICE_PATH),
-            (":authority", "jwt-vending-service:8081"),
-            ("content-type", "application/json"),
-        ];
+        // Reuse a cached token while it is comfortably within its lifetime
+        let key = token_cache::token_key(&self.fetch.token_id);
+        if let Some(cached) = token_cache::lookup(
+            self,
+            &key,
+            time::now_ms(self),
+            self.config.token_refresh_margin_ms,
+        ) {
+            ctx_info!(self, "Using cached JWT token for {}", authority);
+            metrics::increment(self.metrics.token_cache_hits);
+            self.injector().inject(&cached.token);
+            self.released_ms.set(Some(time::now_ms(self)));
+            return Action::Continue;
+        }
+        metrics::increment(self.metrics.token_cache_misses);
 
-        match self.dispatch_http_call(
-            JWT_VENDING_SERVICE_CLUSTER,
-            headers,
-            Some(&request_body),
-            vec![],
-            std::time::Duration::from_secs(5),
+        // Join an in-progress fetch instead of issuing a duplicate callout
+        let now_ms = time::now_ms(self);
+        let lock_key = single_flight::lock_key(&self.fetch.token_id);
+        let lease_ms = self.config.fetch_lease_ms();
+        {
+            let mut flights = self.flight.borrow_mut();
+            let flight = flights.entry(self.fetch.token_id.clone()).or_default();
+            if flight.fetching || !single_flight::try_acquire(self, &lock_key, now_ms, lease_ms) {
+                ctx_info!(self, "Token fetch in progress, parking request");
+                flight.waiters.push(self.waiter());
+                self.metrics.report_waiting(&flights);
+                return Action::Pause;
+            }
+            flight.fetching = true;
+        }
+        self.fetch_leader = true;
+
+        ctx_info!(
+            self,
+            "Intercepted request to {}, fetching JWT token",
+            authority
+        );
+
+        // Fetch in the request's trace
+        self.fetch.trace_headers =
+            trace::propagation_headers(|name| self.get_http_request_header(name));
+        let timeout = self.config.timeout();
+        metrics::increment(self.metrics.fetch_attempts);
+        match fetch::dispatch(
+            self,
+            &self.config,
+            self.dpop_key.as_deref(),
+            &self.fetch,
+            timeout,
         ) {
             Ok(call_id) => {
-                info!("[Client WASM Rust] Dispatched HTTP call to JWT vending service (call_id: {})", call_id);
+                ctx_info!(self, "Dispatched token request (call_id: {})", call_id);
+                self.fetch_started_ms = now_ms;
+                self.metrics.report_waiting(&self.flight.borrow());
                 Action::Pause
             }
             Err(e) => {
-                info!("[Client WASM Rust] Failed to dispatch HTTP call: {:?}", e);
+                ctx_warn!(self, "Failed to dispatch token request: {}", e);
+                self.metrics.fetch_failed(&e);
+                self.release_flight();
+                let fallback = fallback_token(&self.config, &self.metrics);
+                self.released_ms.set(Some(time::now_ms(self)));
+                if !self.injector().apply(fallback.as_deref()) {
+                    return Action::Pause;
+                }
                 Action::Continue
             }
         }
     }
 
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        // Keep a copy of the body as it goes upstream, in case it is replayed
+        let Some(replay_config) = &self.config.replay_unauthorized else {
+            return Action::Continue;
+        };
+        let chunk = self.get_http_request_body(0, body_size).unwrap_or_default();
+        if let Some(replay) = self.replay.as_mut() {
+            replay.append(&chunk, replay_config.max_body_bytes);
+            replay.complete = end_of_stream;
+        }
+        Action::Continue
+    }
+
     fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
         // Log response status for debugging
-        if let Some(status) = self.get_http_response_header(":status") {
-            info!("[Client WASM Rust] Response status: {}", status);
+        let status = self.get_http_response_header(":status");
+        if let Some(status) = &status {
+            ctx_info!(self, "Response status: {}", status);
+        }
+
+        // Hold a 401 back while the request is replayed with a fresh token
+        let replayable = self.replay.as_ref().is_some_and(Replay::is_replayable);
+        if status.as_deref() == Some("401") && replayable && self.start_replay() {
+            return Action::Pause;
         }
         Action::Continue
     }
-}
\ No newline at end of file
+}
+
+impl ClientFilterHttp {
+    fn log_fields(&self) -> LogFields<'static> {
+        LogFields {
+            context_id: Some(self.context_id),
+            ..Default::default()
+        }
+    }
+
+    /// Appends this service's hop to the request's identity chain, when
+    /// `identity_chain` is configured.
+    fn extend_identity_chain(&self) {
+        let Some(chain_config) = &self.config.identity_chain else {
+            return;
+        };
+        let Some(secret) = chain_config.resolve_secret() else {
+            ctx_warn!(
+                self,
+                "No identity chain secret configured, not extending the chain"
+            );
+            return;
+        };
+        let chain = self
+            .get_http_request_header(&chain_config.header)
+            .filter(|chain| !chain.is_empty());
+        let service_id = &self.config.service_id;
+        let caller = chain
+            .as_deref()
+            .and_then(identity::original_caller)
+            .or_else(|| self.get_http_request_header(&chain_config.caller_header))
+            .unwrap_or_else(|| service_id.clone());
+        let now_secs = time::now_secs(self);
+        let chain = identity::append(
+            chain.as_deref(),
+            &caller,
+            service_id,
+            now_secs,
+            &chain_config.key_id,
+            &secret,
+        );
+        self.set_http_request_header(&chain_config.header, Some(&chain));
+    }
+
+    /// Drops the cached token the upstream rejected and fetches a fresh one
+    /// to replay the request with. Returns false if the fetch couldn't be
+    /// dispatched.
+    fn start_replay(&mut self) -> bool {
+        ctx_info!(
+            self,
+            "Upstream rejected the token, replaying with a fresh one"
+        );
+        metrics::increment(self.metrics.replays);
+        token_cache::remove(self, &token_cache::token_key(&self.fetch.token_id));
+        let timeout = self.config.timeout();
+        metrics::increment(self.metrics.fetch_attempts);
+        match fetch::dispatch(
+            self,
+            &self.config,
+            self.dpop_key.as_deref(),
+            &self.fetch,
+            timeout,
+        ) {
+            Ok(call_id) => {
+                self.fetch_started_ms = time::now_ms(self);
+                self.set_replay_step(ReplayStep::Fetching(call_id));
+                true
+            }
+            Err(e) => {
+                ctx_warn!(self, "Failed to dispatch token request for replay: {}", e);
+                self.metrics.fetch_failed(&e);
+                metrics::increment(self.metrics.replay_failures);
+                self.set_replay_step(ReplayStep::Done);
+                false
+            }
+        }
+    }
+
+    /// Replays the request with the fresh token just fetched, or lets the
+    /// 401 through without one.
+    fn replay_with_token(&mut self, body_size: usize) {
+        let result = fetch::read_response(self, &self.config, &self.fetch.token_id, body_size);
+        let latency_ms = time::now_ms(self).saturating_sub(self.fetch_started_ms);
+        self.metrics.fetch_completed(&result, latency_ms);
+        let token = match result {
+            Ok(token) => token,
+            Err(e) => {
+                ctx_warn!(self, "Token fetch for replay failed: {}", e);
+                return self.abandon_replay();
+            }
+        };
+
+        let (Some(replay_config), Some(replay)) =
+            (&self.config.replay_unauthorized, self.replay.as_ref())
+        else {
+            return self.abandon_replay();
+        };
+        let credentials = self
+            .injector()
+            .credential_headers(&replay.token_header, &token);
+        let headers = replay::request_headers(self.get_http_request_headers(), credentials);
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let body = (!replay.body.is_empty()).then_some(replay.body.as_slice());
+        match self.dispatch_http_call(
+            &replay.cluster,
+            headers,
+            body,
+            vec![],
+            replay_config.timeout(),
+        ) {
+            Ok(call_id) => {
+                ctx_info!(self, "Replaying request (call_id: {})", call_id);
+                self.set_replay_step(ReplayStep::Replaying(call_id));
+            }
+            Err(e) => {
+                ctx_warn!(self, "Failed to dispatch replay: {:?}", e);
+                self.abandon_replay();
+            }
+        }
+    }
+
+    /// Sends the replayed request's response downstream in place of the 401.
+    fn finish_replay(&mut self, body_size: usize) {
+        self.set_replay_step(ReplayStep::Done);
+        // Timeouts and resets surface as a missing status
+        let Ok(status) = callout::response_status(self).parse::<u32>() else {
+            ctx_warn!(self, "Replay failed, passing the 401 through");
+            return self.abandon_replay();
+        };
+        ctx_info!(self, "Replay answered with status {}", status);
+        let headers = replay::response_headers(self.get_http_call_response_headers());
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let body = self.get_http_call_response_body(0, body_size);
+        self.send_http_response(status, headers, body.as_deref());
+    }
+
+    /// Gives up on replaying, resuming the held-back 401.
+    fn abandon_replay(&mut self) {
+        metrics::increment(self.metrics.replay_failures);
+        self.set_replay_step(ReplayStep::Done);
+        self.resume_http_response();
+    }
+
+    fn set_replay_step(&mut self, step: ReplayStep) {
+        if let Some(replay) = self.replay.as_mut() {
+            replay.step = step;
+            if step == ReplayStep::Done {
+                replay.body = Vec::new();
+            }
+        }
+    }
+
+    /// Hands a transiently failed fetch to the root to retry, parking this
+    /// request with the others waiting for it. Returns false if the retry
+    /// policy allows no further attempt.
+    fn hand_off_retry(&mut self) -> bool {
+        let Some(policy) = &self.config.retry else {
+            return false;
+        };
+        let now_ms = time::now_ms(self);
+        let Some(next_attempt_ms) = policy.next_attempt_at(1, self.fetch_started_ms, now_ms) else {
+            return false;
+        };
+
+        let backoff_ms = next_attempt_ms - now_ms;
+        ctx_info!(self, "Retrying token fetch in {} ms", backoff_ms);
+        let lock_key = single_flight::lock_key(&self.fetch.token_id);
+        single_flight::renew(
+            self,
+            &lock_key,
+            next_attempt_ms + self.config.fetch_lease_ms(),
+        );
+        self.fetch_leader = false;
+        let mut flights = self.flight.borrow_mut();
+        let flight = flights.entry(self.fetch.token_id.clone()).or_default();
+        flight.waiters.push(self.waiter());
+        flight.retry = Some(Retry {
+            fetch: self.fetch.clone(),
+            attempts: 1,
+            started_ms: self.fetch_started_ms,
+            next_attempt_ms,
+            call: None,
+            dispatched_ms: 0,
+        });
+        true
+    }
+
+    /// Completes this context's fetch: applies the token (or the fallback
+    /// credential, if none was obtained) to the request and every request
+    /// parked behind the fetch, and resumes them.
+    fn finish_fetch(&mut self, token: Option<&str>) {
+        self.release_flight();
+        let fallback = token
+            .is_none()
+            .then(|| fallback_token(&self.config, &self.metrics))
+            .flatten();
+        let token = token.or(fallback.as_deref());
+        let injector = self.injector();
+        let waiters = self
+            .flight
+            .borrow_mut()
+            .remove(&self.fetch.token_id)
+            .map(|flight| flight.waiters)
+            .unwrap_or_default();
+        self.metrics.report_waiting(&self.flight.borrow());
+
+        self.released_ms.set(Some(time::now_ms(self)));
+        if injector.apply(token) {
+            self.resume_http_request();
+        }
+
+        if !waiters.is_empty() {
+            ctx_info!(self, "Resuming {} parked request(s)", waiters.len());
+            injector.resume_waiters(waiters, token);
+        }
+    }
+
+    fn injector(&self) -> TokenInjector<'_> {
+        injector(self, &self.config, self.dpop_key.as_deref())
+    }
+
+    fn waiter(&self) -> Waiter {
+        Waiter {
+            context_id: self.context_id,
+            released_ms: self.released_ms.clone(),
+        }
+    }
+
+    /// Gives up fetch leadership. Requests still parked are resumed by the
+    /// root tick once it sees the lock released.
+    fn release_flight(&mut self) {
+        self.fetch_leader = false;
+        single_flight::release(self, &single_flight::lock_key(&self.fetch.token_id));
+        if let Some(flight) = self.flight.borrow_mut().get_mut(&self.fetch.token_id) {
+            flight.fetching = false;
+        }
+        self.metrics.report_waiting(&self.flight.borrow());
+    }
+}
+
+/// The fallback credential standing in for a token that couldn't be
+/// obtained, if one is configured.
+fn fallback_token(config: &FilterConfig, metrics: &Metrics) -> Option<String> {
+    let token = config.fallback_token.as_ref()?.resolve()?;
+    log_warn!(
+        "No token available for {}, using fallback credential",
+        config.service_id
+    );
+    metrics::increment(metrics.fallback_token_used);
+    Some(token)
+}
+
+/// Puts tokens on outbound requests, addressing whichever HTTP context is
+/// currently effective.
+struct TokenInjector<'a> {
+    header: &'a TokenHeader,
+    dpop: Option<(&'a DpopConfig, &'a DpopKey)>,
+    failure_mode: FailureMode,
+    now_ms: u64,
+    now_secs: u64,
+}
+
+fn injector<'a, C>(
+    ctx: &C,
+    config: &'a FilterConfig,
+    dpop_key: Option<&'a DpopKey>,
+) -> TokenInjector<'a>
+where
+    C: Context + ?Sized,
+{
+    TokenInjector {
+        header: &config.token_header,
+        dpop: config.dpop.as_ref().zip(dpop_key),
+        failure_mode: config.failure_mode,
+        now_ms: time::now_ms(ctx),
+        now_secs: time::now_secs(ctx),
+    }
+}
+
+impl TokenInjector<'_> {
+    /// Injects the JWT into the configured header, with a DPoP proof for the
+    /// request if enabled.
+    fn inject(&self, token: &str) {
+        let name = self
+            .header
+            .for_request(request_header(&self.header.name).as_deref());
+        for (name, value) in self.credential_headers(name, token) {
+            let _ = hostcalls::set_map_value(MapType::HttpRequestHeaders, &name, Some(&value));
+        }
+        log_info!("Injected JWT token into {} header", name);
+    }
+
+    /// The headers presenting `token` on the request: `name` holding it, and
+    /// a DPoP proof for the request if enabled.
+    fn credential_headers(&self, name: &str, token: &str) -> Vec<(String, String)> {
+        let mut headers = vec![(
+            name.to_string(),
+            token::header_value(&self.header.scheme, token),
+        )];
+        if let Some((config, key)) = self.dpop {
+            let method = request_header(":method").unwrap_or_else(|| "GET".to_string());
+            let scheme = request_header(":scheme").unwrap_or_else(|| "http".to_string());
+            let authority = request_header(":authority").unwrap_or_default();
+            let uri = dpop::target_uri(
+                &scheme,
+                &authority,
+                &request_header(":path").unwrap_or_default(),
+            );
+            match key.proof(
+                &method,
+                &uri,
+                self.now_secs,
+                config.include_ath.then_some(token),
+            ) {
+                Ok(proof) => headers.push(("DPoP".to_string(), proof)),
+                Err(e) => log_warn!("Failed to create DPoP proof: {}", e),
+            }
+        }
+        headers
+    }
+
+    /// Injects `token` if one was obtained. Without one the request goes on
+    /// unauthenticated, unless the filter fails closed: then it is rejected
+    /// with 503 and this returns false.
+    fn apply(&self, token: Option<&str>) -> bool {
+        match token {
+            Some(token) => self.inject(token),
+            None if self.failure_mode == FailureMode::Closed => {
+                log_info!("No token available, rejecting request");
+                let request_id = request_header("x-request-id").unwrap_or_default();
+                let body = Problem::new(503, "Unable to obtain service token")
+                    .instance(&request_id)
+                    .to_json();
+                let headers = vec![("content-type", response::PROBLEM_CONTENT_TYPE)];
+                let _ = hostcalls::send_http_response(503, headers, Some(body.as_bytes()));
+                return false;
+            }
+            None => {}
+        }
+        true
+    }
+
+    /// Resumes parked requests, applying `token`. Each waiter is addressed by
+    /// switching the effective context, so this must be the last thing the
+    /// calling callback does.
+    fn resume_waiters(&self, waiters: Vec<Waiter>, token: Option<&str>) {
+        for waiter in waiters {
+            // The request may have been reset while it was parked
+            if hostcalls::set_effective_context(waiter.context_id).is_err() {
+                continue;
+            }
+            waiter.released_ms.set(Some(self.now_ms));
+            if self.apply(token) {
+                let _ = hostcalls::resume_http_request();
+            }
+        }
+    }
+}
+
+fn request_header(name: &str) -> Option<String> {
+    hostcalls::get_map_value(MapType::HttpRequestHeaders, name)
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,130 @@
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MetricType;
+use std::cell::Cell;
+use std::collections::HashMap;
+use wasm_common::log_warn;
+
+use crate::fetch::FetchError;
+use crate::single_flight::{self, TokenFlight};
+
+/// Envoy stats exported by the client filter. Metric ids are per VM, so each
+/// root context defines them once and hands them to its HTTP contexts.
+///
+/// Names are `<stat_prefix>.<name>`; Envoy exposes them on `/stats` under
+/// `wasmcustom.`.
+#[derive(Default, Clone, Copy)]
+pub struct Metrics {
+    /// Requests forwarded untouched because of a passthrough header.
+    pub passthrough: Option<u32>,
+    pub token_cache_hits: Option<u32>,
+    pub token_cache_misses: Option<u32>,
+    /// Token callouts dispatched or attempted, retries included.
+    pub fetch_attempts: Option<u32>,
+    pub fetch_successes: Option<u32>,
+    pub fetch_dispatch_errors: Option<u32>,
+    /// Timeouts, resets and 5xx responses.
+    pub fetch_transient_errors: Option<u32>,
+    /// Other non-2xx responses, such as rejected client credentials.
+    pub fetch_rejected: Option<u32>,
+    /// 2xx responses without a usable token.
+    pub fetch_invalid_responses: Option<u32>,
+    /// Time from dispatching a token callout to receiving its response.
+    pub fetch_latency_ms: Option<u32>,
+    /// Failed token fetches covered by the fallback credential.
+    pub fallback_token_used: Option<u32>,
+    /// Requests rejected upstream with 401 and replayed with a fresh token.
+    pub replays: Option<u32>,
+    /// Replays abandoned for want of a token or a response, letting the 401
+    /// through.
+    pub replay_failures: Option<u32>,
+    /// Requests paused waiting for a token.
+    pub requests_waiting: Option<u32>,
+    /// Time from a request's headers reaching the filter to its being let
+    /// through or answered, token fetches included.
+    pub added_latency_ms: Option<u32>,
+}
+
+thread_local! {
+    // This VM's contribution to the `requests_waiting` gauge, which all VMs
+    // share
+    static REPORTED_WAITING: Cell<i64> = const { Cell::new(0) };
+}
+
+impl Metrics {
+    pub fn define(prefix: &str) -> Self {
+        let counter = |name: &str| define(MetricType::Counter, &format!("{}.{}", prefix, name));
+        Metrics {
+            passthrough: counter("passthrough"),
+            token_cache_hits: counter("token.cache.hits"),
+            token_cache_misses: counter("token.cache.misses"),
+            fetch_attempts: counter("token.fetch.attempts"),
+            fetch_successes: counter("token.fetch.successes"),
+            fetch_dispatch_errors: counter("token.fetch.failures.dispatch"),
+            fetch_transient_errors: counter("token.fetch.failures.transient"),
+            fetch_rejected: counter("token.fetch.failures.rejected"),
+            fetch_invalid_responses: counter("token.fetch.failures.invalid"),
+            fetch_latency_ms: define(
+                MetricType::Histogram,
+                &format!("{}.token.fetch.latency_ms", prefix),
+            ),
+            fallback_token_used: counter("token.fallback_used"),
+            replays: counter("replay.attempts"),
+            replay_failures: counter("replay.failures"),
+            requests_waiting: define(MetricType::Gauge, &format!("{}.requests_waiting", prefix)),
+            added_latency_ms: define(
+                MetricType::Histogram,
+                &format!("{}.added_latency_ms", prefix),
+            ),
+        }
+    }
+
+    /// Brings the `requests_waiting` gauge in line with this VM's fetches.
+    pub fn report_waiting(&self, flights: &HashMap<String, TokenFlight>) {
+        let waiting = single_flight::waiting(flights) as i64;
+        let delta = REPORTED_WAITING.with(|reported| waiting - reported.replace(waiting));
+        if let Some(id) = self.requests_waiting.filter(|_| delta != 0) {
+            let _ = hostcalls::increment_metric(id, delta);
+        }
+    }
+
+    /// Records the outcome and latency of a token callout's response.
+    pub fn fetch_completed<T>(&self, result: &Result<T, FetchError>, latency_ms: u64) {
+        record(self.fetch_latency_ms, latency_ms);
+        match result {
+            Ok(_) => increment(self.fetch_successes),
+            Err(e) => self.fetch_failed(e),
+        }
+    }
+
+    /// Counts a failed fetch under its reason.
+    pub fn fetch_failed(&self, error: &FetchError) {
+        increment(match error {
+            FetchError::Dispatch(_) => self.fetch_dispatch_errors,
+            FetchError::Transient(_) => self.fetch_transient_errors,
+            FetchError::Rejected(_) => self.fetch_rejected,
+            FetchError::Invalid(_) => self.fetch_invalid_responses,
+        });
+    }
+}
+
+fn define(metric_type: MetricType, name: &str) -> Option<u32> {
+    match hostcalls::define_metric(metric_type, name) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            log_warn!("Failed to define metric {}: {:?}", name, e);
+            None
+        }
+    }
+}
+
+pub fn increment(metric: Option<u32>) {
+    if let Some(id) = metric {
+        let _ = hostcalls::increment_metric(id, 1);
+    }
+}
+
+pub fn record(metric: Option<u32>, value: u64) {
+    if let Some(id) = metric {
+        let _ = hostcalls::record_metric(id, value);
+    }
+}
@@ -0,0 +1,128 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use wasm_common::query;
+
+use crate::config::TargetAudience;
+
+/// Fetches tokens from a standard OAuth2 token endpoint with the
+/// client-credentials grant (RFC 6749 section 4.4):
+///
+/// ```json
+/// "oauth2": {
+///   "cluster": "idp",
+///   "authority": "idp.example.com",
+///   "token_path": "/oauth2/token",
+///   "client_id": "service-a",
+///   "client_secret_env": "OAUTH2_CLIENT_SECRET"
+/// }
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct OAuth2Config {
+    pub cluster: String,
+    pub authority: String,
+    pub token_path: String,
+    /// Scheme of the token endpoint's URL, as signed into DPoP proofs.
+    pub scheme: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Environment variable holding the client secret, so it needn't appear
+    /// in the plugin configuration. Envoy sets the VM's environment from
+    /// `vm_config.environment_variables`, e.g. forwarding a variable that a
+    /// Kubernetes Secret was mounted into. Takes precedence over
+    /// `client_secret`.
+    pub client_secret_env: Option<String>,
+    pub client_auth: ClientAuth,
+    /// Scope requested unless the target's audience sets its own.
+    pub scope: Option<String>,
+}
+
+impl Default for OAuth2Config {
+    fn default() -> Self {
+        OAuth2Config {
+            cluster: "oauth2-token-endpoint".to_string(),
+            authority: "oauth2-token-endpoint".to_string(),
+            token_path: "/oauth2/token".to_string(),
+            scheme: "https".to_string(),
+            client_id: String::new(),
+            client_secret: None,
+            client_secret_env: None,
+            client_auth: ClientAuth::Basic,
+            scope: None,
+        }
+    }
+}
+
+/// How the client authenticates to the token endpoint.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// HTTP Basic `Authorization` header (`client_secret_basic`).
+    #[default]
+    Basic,
+    /// `client_id` and `client_secret` form fields (`client_secret_post`).
+    Post,
+}
+
+/// A successful token endpoint response. `expires_in` is optional in the
+/// spec; a token without it is used once and not cached.
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    #[serde(default)]
+    pub expires_in: i64,
+}
+
+/// An error response from the token endpoint (RFC 6749 section 5.2).
+#[derive(Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    #[serde(default)]
+    pub error_description: String,
+}
+
+impl OAuth2Config {
+    pub fn client_secret(&self) -> Option<String> {
+        self.client_secret_env
+            .as_ref()
+            .and_then(|name| std::env::var(name).ok())
+            .or_else(|| self.client_secret.clone())
+    }
+
+    /// Form body of the token request, asking for the target's audience and
+    /// scope when it has them.
+    pub fn form_body(&self, target: Option<&TargetAudience>, secret: Option<&str>) -> String {
+        let mut fields = vec![("grant_type", "client_credentials")];
+        let scope = target
+            .and_then(|t| t.scope.as_deref())
+            .or(self.scope.as_deref());
+        if let Some(scope) = scope {
+            fields.push(("scope", scope));
+        }
+        if let Some(audience) = target.and_then(|t| t.audience.as_deref()) {
+            fields.push(("audience", audience));
+        }
+        if self.client_auth == ClientAuth::Post {
+            fields.push(("client_id", &self.client_id));
+            if let Some(secret) = secret {
+                fields.push(("client_secret", secret));
+            }
+        }
+        query::encode(&fields)
+    }
+
+    /// `Authorization` header value for `client_secret_basic`, whose
+    /// credentials are form-encoded before base64 (section 2.3.1).
+    pub fn basic_authorization(&self, secret: Option<&str>) -> Option<String> {
+        if self.client_auth != ClientAuth::Basic {
+            return None;
+        }
+        let credentials = format!(
+            "{}:{}",
+            query::encode_component(&self.client_id),
+            query::encode_component(secret.unwrap_or_default())
+        );
+        Some(format!("Basic {}", STANDARD.encode(credentials)))
+    }
+}
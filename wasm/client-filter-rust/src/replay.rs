@@ -0,0 +1,130 @@
+use serde::Deserialize;
+use std::time::Duration;
+use wasm_common::glob;
+
+/// Replays of requests the upstream rejects with 401, with a freshly fetched
+/// token, so a token revoked early or refused over clock skew doesn't fail
+/// the caller. Envoy sends a request upstream only once, so the replay is a
+/// callout to the cluster serving the request's authority, whose response is
+/// sent in place of the 401. A request is replayed at most once.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReplayConfig {
+    /// Clusters to replay requests on. The first entry whose `authority`
+    /// matches names the cluster; requests to authorities without an entry
+    /// are not replayed.
+    pub clusters: Vec<ReplayCluster>,
+    /// Largest request body kept for a replay. Requests with larger bodies
+    /// get the 401.
+    pub max_body_bytes: usize,
+    pub timeout_ms: u64,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            clusters: Vec::new(),
+            max_body_bytes: 64 * 1024,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// The cluster serving authorities matching `authority`, which may contain
+/// `*` wildcards like `target_authorities`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ReplayCluster {
+    pub authority: String,
+    pub cluster: String,
+}
+
+impl ReplayConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn cluster_for(&self, authority: &str) -> Option<&str> {
+        self.clusters
+            .iter()
+            .find(|entry| glob::matches(&entry.authority, authority))
+            .map(|entry| entry.cluster.as_str())
+    }
+}
+
+/// A request's body, copied as it streams upstream, and how far its replay
+/// has got.
+#[derive(Debug, Default)]
+pub struct Replay {
+    /// Cluster serving the request's authority.
+    pub cluster: String,
+    /// Header the request's token went in.
+    pub token_header: String,
+    pub body: Vec<u8>,
+    /// Set once the whole body has been seen.
+    pub complete: bool,
+    /// Set when the body outgrew `max_body_bytes` and was dropped.
+    pub overflowed: bool,
+    pub step: ReplayStep,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayStep {
+    #[default]
+    Pending,
+    /// Waiting for the fresh token from this callout.
+    Fetching(u32),
+    /// Waiting for the replayed request's response from this callout.
+    Replaying(u32),
+    Done,
+}
+
+impl Replay {
+    pub fn append(&mut self, chunk: &[u8], max_body_bytes: usize) {
+        if self.overflowed {
+            return;
+        }
+        if self.body.len() + chunk.len() > max_body_bytes {
+            self.overflowed = true;
+            self.body = Vec::new();
+            return;
+        }
+        self.body.extend_from_slice(chunk);
+    }
+
+    pub fn is_replayable(&self) -> bool {
+        self.step == ReplayStep::Pending && self.complete && !self.overflowed
+    }
+}
+
+/// Headers of the replayed request: the original ones, with the rejected
+/// credentials swapped for `credentials`.
+pub fn request_headers(
+    original: Vec<(String, String)>,
+    credentials: Vec<(String, String)>,
+) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = original
+        .into_iter()
+        .filter(|(name, _)| {
+            !name.eq_ignore_ascii_case("content-length")
+                && !name.eq_ignore_ascii_case("transfer-encoding")
+                && !credentials
+                    .iter()
+                    .any(|(replaced, _)| name.eq_ignore_ascii_case(replaced))
+        })
+        .collect();
+    headers.extend(credentials);
+    headers
+}
+
+/// Headers of the replayed request's response to send downstream, without
+/// pseudo-headers and framing, which Envoy sets itself.
+pub fn response_headers(headers: Vec<(String, String)>) -> Vec<(String, String)> {
+    headers
+        .into_iter()
+        .filter(|(name, _)| {
+            !name.starts_with(':')
+                && !name.eq_ignore_ascii_case("content-length")
+                && !name.eq_ignore_ascii_case("transfer-encoding")
+        })
+        .collect()
+}
@@ -0,0 +1,65 @@
+use serde::Deserialize;
+
+use crate::fetch::TokenFetch;
+
+/// Retries of token fetches that failed transiently (timeouts, resets and
+/// 5xx responses), with exponential backoff. The request that started the
+/// fetch waits with the others while the root retries it from its tick, so
+/// backoff is only as fine as `token_wait_poll_ms`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first.
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Time from the first attempt after which no further attempt is made.
+    /// Callouts are given at most what remains of it.
+    pub deadline_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            deadline_ms: 3000,
+        }
+    }
+}
+
+/// A fetch the root is retrying.
+#[derive(Debug)]
+pub struct Retry {
+    pub fetch: TokenFetch,
+    /// Attempts made so far.
+    pub attempts: u32,
+    pub started_ms: u64,
+    pub next_attempt_ms: u64,
+    /// The root's outstanding callout, between dispatch and response.
+    pub call: Option<u32>,
+    /// When `call` was dispatched.
+    pub dispatched_ms: u64,
+}
+
+impl RetryPolicy {
+    /// When to make the attempt following `attempts` failed ones, or `None`
+    /// once the attempts or the deadline are used up.
+    pub fn next_attempt_at(&self, attempts: u32, started_ms: u64, now_ms: u64) -> Option<u64> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let exponent = attempts.saturating_sub(1).min(16);
+        let backoff_ms = self
+            .initial_backoff_ms
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff_ms);
+        let at_ms = now_ms + backoff_ms;
+        (at_ms < self.deadline_at(started_ms)).then_some(at_ms)
+    }
+
+    pub fn deadline_at(&self, started_ms: u64) -> u64 {
+        started_ms.saturating_add(self.deadline_ms)
+    }
+}
@@ -0,0 +1,78 @@
+use proxy_wasm::traits::Context;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::retry::Retry;
+
+const LOCK_KEY_PREFIX: &str = "client_filter.token_fetch:";
+
+/// Per-VM bookkeeping for an in-progress token fetch. Only one HTTP context
+/// per VM dispatches the vending callout for a token; the others park their
+/// context id here and are resumed once a token is available.
+#[derive(Default)]
+pub struct TokenFlight {
+    /// True while a context in this VM has the vending callout outstanding,
+    /// or the root is retrying it.
+    pub fetching: bool,
+    pub waiters: Vec<Waiter>,
+    /// Set once the fetch has been handed to the root for retrying.
+    pub retry: Option<Retry>,
+}
+
+/// A request parked for a token.
+#[derive(Clone, Debug)]
+pub struct Waiter {
+    pub context_id: u32,
+    /// When the request was let through, for the latency the filter added.
+    pub released_ms: Rc<Cell<Option<u64>>>,
+}
+
+/// Fetches keyed by the identity of the token being fetched.
+pub type SharedFlight = Rc<RefCell<HashMap<String, TokenFlight>>>;
+
+/// Requests of this VM paused for a token: the contexts with a vending
+/// callout outstanding and the requests parked behind them.
+pub fn waiting(flights: &HashMap<String, TokenFlight>) -> usize {
+    flights
+        .values()
+        .map(|flight| flight.waiters.len() + usize::from(flight.fetching && flight.retry.is_none()))
+        .sum()
+}
+
+/// Shared-data key of the cross-VM fetch lock for `token_id`.
+pub fn lock_key(token_id: &str) -> String {
+    format!("{}{}", LOCK_KEY_PREFIX, token_id)
+}
+
+/// Tries to take the fetch lock for `lease_ms`. The lease bounds how long
+/// other VMs wait if the holder disappears without releasing it.
+pub fn try_acquire<C: Context + ?Sized>(ctx: &C, key: &str, now_ms: u64, lease_ms: u64) -> bool {
+    let (data, cas) = ctx.get_shared_data(key);
+    if lease_expiry(data.as_deref()) > now_ms {
+        return false;
+    }
+    let lease = (now_ms + lease_ms).to_string();
+    ctx.set_shared_data(key, Some(lease.as_bytes()), cas)
+        .is_ok()
+}
+
+pub fn is_locked<C: Context + ?Sized>(ctx: &C, key: &str, now_ms: u64) -> bool {
+    let (data, _) = ctx.get_shared_data(key);
+    lease_expiry(data.as_deref()) > now_ms
+}
+
+/// Extends the lease of a lock this VM holds to `until_ms`.
+pub fn renew<C: Context + ?Sized>(ctx: &C, key: &str, until_ms: u64) {
+    let _ = ctx.set_shared_data(key, Some(until_ms.to_string().as_bytes()), None);
+}
+
+pub fn release<C: Context + ?Sized>(ctx: &C, key: &str) {
+    let _ = ctx.set_shared_data(key, None, None);
+}
+
+fn lease_expiry(data: Option<&[u8]>) -> u64 {
+    data.and_then(|d| std::str::from_utf8(d).ok())
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
@@ -0,0 +1,365 @@
+//! Request flows through `ClientFilterHttp` against the mock host.
+
+use proxy_wasm::traits::{Context, HttpContext, RootContext};
+use proxy_wasm::types::{Action, BufferType};
+use std::rc::Rc;
+use wasm_common::mock_host;
+
+use crate::config::{FailureMode, FilterConfig};
+use crate::metrics::Metrics;
+use crate::replay::{ReplayCluster, ReplayConfig};
+use crate::retry::RetryPolicy;
+use crate::token_cache;
+use crate::{ClientFilterHttp, ClientFilterRoot};
+
+const TOKEN_RESPONSE: &str = r#"{"token":"fresh","expires_in":300}"#;
+
+fn filter(config: FilterConfig) -> ClientFilterHttp {
+    mock_host::reset();
+    ClientFilterHttp {
+        context_id: 2,
+        config: Rc::new(config),
+        ..Default::default()
+    }
+}
+
+/// Another request of the same VM as `filter`.
+fn sibling(filter: &ClientFilterHttp, context_id: u32) -> ClientFilterHttp {
+    ClientFilterHttp {
+        context_id,
+        config: filter.config.clone(),
+        flight: filter.flight.clone(),
+        metrics: filter.metrics,
+        ..Default::default()
+    }
+}
+
+/// Runs the request headers of a `GET http://service-b/api`, with `extra`
+/// headers.
+fn request(filter: &mut ClientFilterHttp, extra: &[(&str, &str)]) -> Action {
+    let mut headers = vec![
+        (":authority", "service-b"),
+        (":method", "GET"),
+        (":path", "/api"),
+        ("x-request-id", "req-1"),
+    ];
+    headers.extend_from_slice(extra);
+    mock_host::set_request_headers(&headers);
+    filter.on_http_request_headers(4, true)
+}
+
+fn call_response(filter: &mut ClientFilterHttp, call: u32, status: &str, body: &str) {
+    mock_host::set_http_call_response(status, body.as_bytes());
+    filter.on_http_call_response(call, 1, body.len(), 0);
+}
+
+/// Replays 401s from `service-b*` on the `service-b` cluster, listed after
+/// another authority's.
+fn replaying() -> FilterConfig {
+    FilterConfig {
+        replay_unauthorized: Some(ReplayConfig {
+            clusters: vec![
+                ReplayCluster {
+                    authority: "service-c".to_string(),
+                    cluster: "service-c".to_string(),
+                },
+                ReplayCluster {
+                    authority: "service-b*".to_string(),
+                    cluster: "service-b".to_string(),
+                },
+            ],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Runs a `POST` with `body` whose token the upstream rejects with 401.
+fn rejected_post(filter: &mut ClientFilterHttp, body: &[u8]) -> Action {
+    cache_token(filter, "rejected");
+    mock_host::set_request_headers(&[
+        (":authority", "service-b:8083"),
+        (":method", "POST"),
+        (":path", "/api"),
+        ("content-length", &body.len().to_string()),
+    ]);
+    assert_eq!(filter.on_http_request_headers(4, false), Action::Continue);
+    mock_host::set_buffer(BufferType::HttpRequestBody, body);
+    assert_eq!(
+        filter.on_http_request_body(body.len(), true),
+        Action::Continue
+    );
+    mock_host::set_response_headers(&[(":status", "401")]);
+    filter.on_http_response_headers(1, true)
+}
+
+fn cache_token(filter: &ClientFilterHttp, token: &str) {
+    let expires_at_ms = mock_host::DEFAULT_TIME_NANOS / 1_000_000 + 300_000;
+    let key = token_cache::token_key(&filter.config.service_id);
+    token_cache::store(filter, &key, token, expires_at_ms);
+}
+
+#[test]
+fn non_target_requests_pass_through() {
+    let mut filter = filter(FilterConfig::default());
+    mock_host::set_request_headers(&[(":authority", "service-c"), (":path", "/api")]);
+
+    assert_eq!(filter.on_http_request_headers(2, true), Action::Continue);
+
+    assert_eq!(mock_host::request_header("authorization"), None);
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn cached_tokens_are_injected_without_a_fetch() {
+    let mut filter = filter(FilterConfig::default());
+    cache_token(&filter, "cached");
+
+    assert_eq!(request(&mut filter, &[]), Action::Continue);
+
+    assert_eq!(
+        mock_host::request_header("authorization").as_deref(),
+        Some("Bearer cached")
+    );
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn fetched_tokens_are_injected_and_cached() {
+    let mut filter = filter(FilterConfig::default());
+
+    assert_eq!(request(&mut filter, &[]), Action::Pause);
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].upstream, "jwt-vending-service");
+    assert_eq!(calls[0].header(":path"), Some("/token/valid"));
+
+    call_response(&mut filter, 1, "200", TOKEN_RESPONSE);
+
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+    assert_eq!(
+        mock_host::request_header("authorization").as_deref(),
+        Some("Bearer fresh")
+    );
+    let key = token_cache::token_key("service-a");
+    let now_ms = mock_host::DEFAULT_TIME_NANOS / 1_000_000;
+    let cached = token_cache::lookup(&filter, &key, now_ms, 0).expect("cached token");
+    assert_eq!(cached.token, "fresh");
+}
+
+#[test]
+fn parked_requests_resume_with_the_leaders_token() {
+    let mut leader = filter(FilterConfig::default());
+    let mut waiter = sibling(&leader, 3);
+
+    assert_eq!(request(&mut leader, &[]), Action::Pause);
+    assert_eq!(request(&mut waiter, &[]), Action::Pause);
+    assert_eq!(mock_host::http_calls().len(), 1);
+
+    call_response(&mut leader, 1, "200", TOKEN_RESPONSE);
+
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 2);
+    assert_eq!(mock_host::with(|host| host.effective_context), Some(3));
+    assert_eq!(
+        mock_host::request_header("authorization").as_deref(),
+        Some("Bearer fresh")
+    );
+    assert!(leader.flight.borrow().is_empty());
+}
+
+#[test]
+fn added_latency_covers_the_token_wait() {
+    let mut filter = filter(FilterConfig::default());
+    filter.metrics = Metrics::define("client_filter");
+
+    assert_eq!(request(&mut filter, &[]), Action::Pause);
+    mock_host::with(|host| host.time_nanos += 25_000_000);
+    call_response(&mut filter, 1, "200", TOKEN_RESPONSE);
+    mock_host::with(|host| host.time_nanos += 50_000_000);
+    filter.on_done();
+    assert_eq!(
+        mock_host::metric("client_filter.added_latency_ms"),
+        Some(25)
+    );
+
+    // Requests the filter passes over aren't measured
+    let mut other = sibling(&filter, 3);
+    mock_host::set_request_headers(&[(":authority", "service-c"), (":path", "/api")]);
+    assert_eq!(other.on_http_request_headers(2, true), Action::Continue);
+    other.on_done();
+    assert_eq!(
+        mock_host::metric("client_filter.added_latency_ms"),
+        Some(25)
+    );
+
+    // Cached tokens are measured up to their injection
+    let mut cached = sibling(&filter, 4);
+    assert_eq!(request(&mut cached, &[]), Action::Continue);
+    mock_host::with(|host| host.time_nanos += 10_000_000);
+    cached.on_done();
+    assert_eq!(mock_host::metric("client_filter.added_latency_ms"), Some(0));
+}
+
+#[test]
+fn exhausted_retries_fail_closed() {
+    let mut filter = filter(FilterConfig {
+        failure_mode: FailureMode::Closed,
+        retry: Some(RetryPolicy {
+            max_attempts: 2,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let mut root = ClientFilterRoot {
+        config: filter.config.clone(),
+        flight: filter.flight.clone(),
+        ..Default::default()
+    };
+
+    assert_eq!(request(&mut filter, &[]), Action::Pause);
+    call_response(&mut filter, 1, "503", "");
+    assert!(mock_host::local_response().is_none());
+
+    mock_host::with(|host| host.time_nanos += 100_000_000);
+    root.on_tick();
+    assert_eq!(mock_host::http_calls().len(), 2);
+
+    mock_host::set_http_call_response("503", b"");
+    root.on_http_call_response(2, 1, 0, 0);
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 503);
+    assert!(response
+        .body_str()
+        .contains("Unable to obtain service token"));
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 0);
+    assert!(root.flight.borrow().is_empty());
+}
+
+#[test]
+fn tokens_go_in_the_secondary_header_next_to_end_user_credentials() {
+    let mut config = FilterConfig::default();
+    config.token_header.secondary = Some("x-service-token".to_string());
+    let mut filter = filter(config);
+    cache_token(&filter, "cached");
+
+    assert_eq!(
+        request(&mut filter, &[("authorization", "Bearer end-user")]),
+        Action::Continue
+    );
+
+    assert_eq!(
+        mock_host::request_header("authorization").as_deref(),
+        Some("Bearer end-user")
+    );
+    assert_eq!(
+        mock_host::request_header("x-service-token").as_deref(),
+        Some("Bearer cached")
+    );
+}
+
+#[test]
+fn rejected_requests_are_replayed_with_a_fresh_token() {
+    let mut filter = filter(replaying());
+
+    assert_eq!(rejected_post(&mut filter, b"payload"), Action::Pause);
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].upstream, "jwt-vending-service");
+
+    call_response(&mut filter, 1, "200", TOKEN_RESPONSE);
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].upstream, "service-b");
+    assert_eq!(calls[1].header(":authority"), Some("service-b:8083"));
+    assert_eq!(calls[1].header("authorization"), Some("Bearer fresh"));
+    assert_eq!(calls[1].header("content-length"), None);
+    assert_eq!(calls[1].body, b"payload");
+
+    call_response(&mut filter, 2, "200", "ok");
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header(":status"), None);
+    assert_eq!(response.body_str(), "ok");
+    assert_eq!(mock_host::with(|host| host.resumed_responses), 0);
+}
+
+#[test]
+fn authorities_without_a_replay_cluster_are_not_replayed() {
+    let mut filter = filter(replaying());
+    cache_token(&filter, "rejected");
+
+    mock_host::set_request_headers(&[(":authority", "envoy-service-b:10001"), (":path", "/")]);
+    assert_eq!(filter.on_http_request_headers(4, true), Action::Continue);
+    mock_host::set_response_headers(&[(":status", "401")]);
+
+    assert_eq!(filter.on_http_response_headers(1, true), Action::Continue);
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn oversized_bodies_are_not_replayed() {
+    let mut config = replaying();
+    if let Some(replay) = config.replay_unauthorized.as_mut() {
+        replay.max_body_bytes = 4;
+    }
+    let mut filter = filter(config);
+
+    assert_eq!(rejected_post(&mut filter, b"payload"), Action::Continue);
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn failed_replay_fetches_let_the_401_through() {
+    let mut filter = filter(replaying());
+
+    assert_eq!(rejected_post(&mut filter, b"payload"), Action::Pause);
+    call_response(&mut filter, 1, "503", "");
+
+    assert_eq!(mock_host::http_calls().len(), 1);
+    assert_eq!(mock_host::with(|host| host.resumed_responses), 1);
+    assert!(mock_host::local_response().is_none());
+}
+
+#[test]
+fn replays_keep_the_token_in_the_secondary_header() {
+    let mut config = replaying();
+    config.token_header.secondary = Some("x-service-token".to_string());
+    let mut filter = filter(config);
+    cache_token(&filter, "rejected");
+
+    assert_eq!(
+        request(&mut filter, &[("authorization", "Bearer end-user")]),
+        Action::Continue
+    );
+    mock_host::set_response_headers(&[(":status", "401")]);
+    assert_eq!(filter.on_http_response_headers(1, true), Action::Pause);
+    call_response(&mut filter, 1, "200", TOKEN_RESPONSE);
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].header("authorization"), Some("Bearer end-user"));
+    assert_eq!(calls[1].header("x-service-token"), Some("Bearer fresh"));
+}
+
+#[test]
+fn replays_keep_the_token_in_the_header_it_was_injected_in() {
+    let mut config = replaying();
+    config.token_header.secondary = Some("x-service-token".to_string());
+    let mut filter = filter(config);
+    cache_token(&filter, "rejected");
+
+    assert_eq!(
+        request(&mut filter, &[("x-service-token", "Bearer other")]),
+        Action::Continue
+    );
+    mock_host::set_response_headers(&[(":status", "401")]);
+    assert_eq!(filter.on_http_response_headers(1, true), Action::Pause);
+    call_response(&mut filter, 1, "200", TOKEN_RESPONSE);
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].header("authorization"), Some("Bearer fresh"));
+    assert_eq!(calls[1].header("x-service-token"), Some("Bearer other"));
+}
@@ -0,0 +1,45 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+
+const TOKEN_KEY_PREFIX: &str = "client_filter.token:";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedToken {
+    pub token: String,
+    pub expires_at_ms: u64,
+}
+
+/// Shared-data key for the token identified by `token_id`.
+pub fn token_key(token_id: &str) -> String {
+    format!("{}{}", TOKEN_KEY_PREFIX, token_id)
+}
+
+/// Returns the cached token if it remains valid for at least `margin_ms`.
+pub fn lookup<C: Context + ?Sized>(
+    ctx: &C,
+    key: &str,
+    now_ms: u64,
+    margin_ms: u64,
+) -> Option<CachedToken> {
+    let (data, _) = ctx.get_shared_data(key);
+    let cached: CachedToken = serde_json::from_slice(&data?).ok()?;
+    if cached.expires_at_ms <= now_ms.saturating_add(margin_ms) {
+        return None;
+    }
+    Some(cached)
+}
+
+pub fn store<C: Context + ?Sized>(ctx: &C, key: &str, token: &str, expires_at_ms: u64) {
+    let cached = CachedToken {
+        token: token.to_string(),
+        expires_at_ms,
+    };
+    if let Ok(value) = serde_json::to_vec(&cached) {
+        let _ = ctx.set_shared_data(key, Some(&value), None);
+    }
+}
+
+/// Drops a cached token, e.g. one the upstream has rejected.
+pub fn remove<C: Context + ?Sized>(ctx: &C, key: &str) {
+    let _ = ctx.set_shared_data(key, None, None);
+}
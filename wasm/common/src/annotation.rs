@@ -0,0 +1,37 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+
+/// Filter state key of the annotation. Envoy stores it as
+/// `wasm.envoy_wasm_poc.annotation`, which access logs can print with
+/// `%FILTER_STATE(wasm.envoy_wasm_poc.annotation:PLAIN)%`.
+const ANNOTATION_KEY: &str = "envoy_wasm_poc.annotation";
+
+/// What the client filter knows about an outbound request, left in the
+/// stream's filter state for filters later in the same chain, such as the
+/// server filter when both run in one listener. Unlike a header it can't be
+/// set by the application or reach the upstream.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestAnnotation {
+    /// Service the token was obtained for.
+    pub service_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+impl RequestAnnotation {
+    /// Records the annotation on the current request.
+    pub fn publish<C: Context + ?Sized>(&self, ctx: &C) {
+        if let Ok(value) = serde_json::to_vec(self) {
+            ctx.set_property(vec![ANNOTATION_KEY], Some(&value));
+        }
+    }
+
+    /// The annotation a filter earlier in the chain left on the current
+    /// request, if any.
+    pub fn read<C: Context + ?Sized>(ctx: &C) -> Option<RequestAnnotation> {
+        serde_json::from_slice(&ctx.get_property(vec![ANNOTATION_KEY])?).ok()
+    }
+}
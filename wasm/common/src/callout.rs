@@ -0,0 +1,84 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use std::time::Duration;
+
+/// An HTTP callout to an Envoy cluster:
+///
+/// ```ignore
+/// HttpCallout::post("pdp", "pdp:8082", "/evaluate")
+///     .headers(&trace_headers)
+///     .json(&body)
+///     .timeout(timeout)
+///     .dispatch(self)
+/// ```
+pub struct HttpCallout<'a> {
+    cluster: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+    body: Option<&'a [u8]>,
+    timeout: Duration,
+}
+
+impl<'a> HttpCallout<'a> {
+    pub fn new(method: &'a str, cluster: &'a str, authority: &'a str, path: &'a str) -> Self {
+        HttpCallout {
+            cluster,
+            headers: vec![
+                (":method", method),
+                (":path", path),
+                (":authority", authority),
+            ],
+            body: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    pub fn get(cluster: &'a str, authority: &'a str, path: &'a str) -> Self {
+        Self::new("GET", cluster, authority, path)
+    }
+
+    pub fn post(cluster: &'a str, authority: &'a str, path: &'a str) -> Self {
+        Self::new("POST", cluster, authority, path)
+    }
+
+    pub fn header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Adds owned header values, such as `trace::propagation_headers`.
+    pub fn headers(mut self, headers: &'a [(&'static str, String)]) -> Self {
+        self.headers
+            .extend(headers.iter().map(|(name, value)| (*name, value.as_str())));
+        self
+    }
+
+    pub fn body(self, content_type: &'a str, body: &'a [u8]) -> Self {
+        let mut callout = self.header("content-type", content_type);
+        callout.body = Some(body);
+        callout
+    }
+
+    pub fn json(self, body: &'a [u8]) -> Self {
+        self.body("application/json", body)
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn dispatch<C: Context + ?Sized>(self, ctx: &C) -> Result<u32, Status> {
+        ctx.dispatch_http_call(self.cluster, self.headers, self.body, vec![], self.timeout)
+    }
+}
+
+/// Status of the callout response being handled, empty when there is none
+/// (timeouts and resets).
+pub fn response_status<C: Context + ?Sized>(ctx: &C) -> String {
+    ctx.get_http_call_response_header(":status")
+        .unwrap_or_default()
+}
+
+pub fn is_success(status: &str) -> bool {
+    status.starts_with('2')
+}
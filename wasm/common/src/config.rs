@@ -0,0 +1,29 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::vm;
+
+/// Parses a filter's JSON plugin configuration. An empty or blank buffer
+/// yields the defaults, so filters run unconfigured with their legacy
+/// behavior. `${name}` placeholders in string values are replaced with the
+/// VM's constants.
+pub fn parse<T: DeserializeOwned + Default>(raw: &[u8]) -> Result<T, serde_json::Error> {
+    if raw.iter().all(|b| b.is_ascii_whitespace()) {
+        return Ok(T::default());
+    }
+    if !vm::has_constants() {
+        return serde_json::from_slice(raw);
+    }
+    let mut value: Value = serde_json::from_slice(raw)?;
+    substitute_constants(&mut value);
+    serde_json::from_value(value)
+}
+
+fn substitute_constants(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = vm::substitute(s),
+        Value::Array(items) => items.iter_mut().for_each(substitute_constants),
+        Value::Object(fields) => fields.values_mut().for_each(substitute_constants),
+        _ => {}
+    }
+}
@@ -0,0 +1,66 @@
+use proxy_wasm::traits::Context;
+use serde::Serialize;
+
+/// Network-level attributes of the downstream connection, sent to the PDP
+/// with `connection_attributes` so policies can weigh where a request comes
+/// from and how it is protected. Attributes the host doesn't know, such as
+/// TLS details on a plaintext connection, are left out.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionAttributes {
+    /// Client IP, without the ephemeral port.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_address: Option<String>,
+    /// Address and port the client connected to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+    /// e.g. `TLSv1.3`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_version: Option<String>,
+    /// Identity from the mTLS client certificate, see `peer_identity`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_identity: Option<String>,
+}
+
+impl ConnectionAttributes {
+    pub fn read<C: Context + ?Sized>(ctx: &C) -> Self {
+        ConnectionAttributes {
+            source_address: property(ctx, &["source", "address"])
+                .map(|address| strip_port(&address).to_string()),
+            destination_address: property(ctx, &["destination", "address"]),
+            sni: property(ctx, &["connection", "requested_server_name"]),
+            tls_version: property(ctx, &["connection", "tls_version"]),
+            peer_identity: peer_identity(ctx),
+        }
+    }
+}
+
+/// Identity from the downstream client certificate: the first URI SAN
+/// (typically a SPIFFE ID), else the first DNS SAN. `None` without mTLS.
+pub fn peer_identity<C: Context + ?Sized>(ctx: &C) -> Option<String> {
+    ["uri_san_peer_certificate", "dns_san_peer_certificate"]
+        .iter()
+        .find_map(|attr| property(ctx, &["connection", attr]))
+}
+
+/// A non-empty string property.
+fn property<C: Context + ?Sized>(ctx: &C, path: &[&str]) -> Option<String> {
+    let value = ctx.get_property(path.to_vec())?;
+    String::from_utf8(value)
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// The host of `host:port` or `[v6]:port`.
+fn strip_port(address: &str) -> &str {
+    if let Some(bracketed) = address.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or(bracketed);
+    }
+    match address.rsplit_once(':') {
+        // A bare IPv6 address has several colons and no port
+        Some((host, _)) if !host.contains(':') => host,
+        _ => address,
+    }
+}
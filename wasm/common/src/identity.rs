@@ -0,0 +1,162 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Header carrying the identity chain of a multi-hop call.
+pub const HEADER: &str = "X-Forwarded-Identity";
+
+/// One hop of an identity chain: `service` calling on behalf of `caller`,
+/// the party that started the chain. The header holds one entry per hop,
+/// oldest first and comma-separated, each `<key id>.<payload>.<HMAC>` with
+/// the payload and HMAC-SHA256 base64url-encoded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Hop {
+    pub caller: String,
+    pub service: String,
+    pub iat: u64,
+    /// Digest of the entries before this one, so hops can't be dropped or
+    /// reordered.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prev: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    Malformed,
+    UnknownKey(String),
+    InvalidSignature,
+    /// A hop's `prev` doesn't match the entries before it.
+    Broken,
+    Expired,
+    TooLong,
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Malformed => write!(f, "Malformed identity chain"),
+            ChainError::UnknownKey(kid) => write!(f, "Unknown identity chain key: {}", kid),
+            ChainError::InvalidSignature => write!(f, "Invalid identity chain signature"),
+            ChainError::Broken => write!(f, "Identity chain has been altered"),
+            ChainError::Expired => write!(f, "Identity chain hop too old"),
+            ChainError::TooLong => write!(f, "Identity chain too long"),
+        }
+    }
+}
+
+/// The caller that started `chain`, read without verifying it.
+pub fn original_caller(chain: &str) -> Option<String> {
+    let (_, payload, _) = split_entry(entries(chain).next()?)?;
+    Some(decode_payload(payload).ok()?.caller)
+}
+
+/// Returns `chain`, the header's current value if any, with a hop for
+/// `service` appended, signed with `secret` under `key_id`.
+pub fn append(
+    chain: Option<&str>,
+    caller: &str,
+    service: &str,
+    now_secs: u64,
+    key_id: &str,
+    secret: &str,
+) -> String {
+    let previous: Vec<&str> = chain
+        .map(|chain| entries(chain).collect())
+        .unwrap_or_default();
+    let hop = Hop {
+        caller: caller.to_string(),
+        service: service.to_string(),
+        iat: now_secs,
+        prev: digest(&previous),
+    };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&hop).unwrap_or_default());
+    let signing_input = format!("{}.{}", key_id, payload);
+    let signature = URL_SAFE_NO_PAD.encode(mac(secret, &signing_input));
+    let entry = format!("{}.{}", signing_input, signature);
+    previous
+        .into_iter()
+        .chain(std::iter::once(entry.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Verifies every hop of `chain`, looking signing secrets up by key id with
+/// `secret`. Hops older than `max_age_secs` are rejected.
+pub fn verify(
+    chain: &str,
+    secret: impl Fn(&str) -> Option<String>,
+    now_secs: u64,
+    max_age_secs: u64,
+    max_hops: usize,
+) -> Result<Vec<Hop>, ChainError> {
+    let entries: Vec<&str> = entries(chain).collect();
+    if entries.len() > max_hops {
+        return Err(ChainError::TooLong);
+    }
+    let mut hops = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        let (key_id, payload, signature) = split_entry(entry).ok_or(ChainError::Malformed)?;
+        let secret = secret(key_id).ok_or_else(|| ChainError::UnknownKey(key_id.to_string()))?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| ChainError::Malformed)?;
+        let mut verifier = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .map_err(|_| ChainError::InvalidSignature)?;
+        verifier.update(format!("{}.{}", key_id, payload).as_bytes());
+        verifier
+            .verify_slice(&signature)
+            .map_err(|_| ChainError::InvalidSignature)?;
+
+        let hop = decode_payload(payload)?;
+        if hop.prev != digest(&entries[..index]) {
+            return Err(ChainError::Broken);
+        }
+        if hop.iat + max_age_secs < now_secs {
+            return Err(ChainError::Expired);
+        }
+        hops.push(hop);
+    }
+    Ok(hops)
+}
+
+fn entries(chain: &str) -> impl Iterator<Item = &str> {
+    chain
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+fn split_entry(entry: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = entry.split('.');
+    let parts = (parts.next()?, parts.next()?, parts.next()?, parts.next());
+    match parts {
+        (key_id, payload, signature, None) => Some((key_id, payload, signature)),
+        _ => None,
+    }
+}
+
+fn decode_payload(payload: &str) -> Result<Hop, ChainError> {
+    let json = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| ChainError::Malformed)?;
+    serde_json::from_slice(&json).map_err(|_| ChainError::Malformed)
+}
+
+/// Digest of the entries before a hop, empty for the first.
+fn digest(entries: &[&str]) -> String {
+    if entries.is_empty() {
+        return String::new();
+    }
+    URL_SAFE_NO_PAD.encode(Sha256::digest(entries.join(", ").as_bytes()))
+}
+
+fn mac(secret: &str, input: &str) -> Vec<u8> {
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return Vec::new();
+    };
+    mac.update(input.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
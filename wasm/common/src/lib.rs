@@ -0,0 +1,22 @@
+//! Utilities shared by the Rust WASM filters.
+
+pub mod annotation;
+pub mod callout;
+pub mod config;
+pub mod connection;
+pub mod glob;
+pub mod identity;
+pub mod logging;
+pub mod paths;
+pub mod pdp;
+pub mod query;
+pub mod response;
+#[cfg(test)]
+mod tests;
+pub mod time;
+pub mod token;
+pub mod trace;
+pub mod vm;
+
+#[cfg(feature = "mock-host")]
+pub mod mock_host;
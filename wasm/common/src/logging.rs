@@ -0,0 +1,366 @@
+//! Structured logging shared by the filters. Every line carries the
+//! filter's component tag and whichever request fields are known, either as
+//! one JSON object or as readable text:
+//!
+//! ```text
+//! {"component":"server_filter","level":"info","message":"Calling PDP","request_id":"r-1","principal":"alice"}
+//! [server_filter] Calling PDP request_id=r-1 principal=alice
+//! ```
+//!
+//! Log with [`log_info!`](crate::log_info), [`log_warn!`](crate::log_warn)
+//! or [`log_debug!`](crate::log_debug), passing request fields as
+//! `fields: <expr>;` before the format string.
+//!
+//! Credentials never reach the log: anything shaped like a JWT or following
+//! an auth scheme such as `Bearer` is masked in every line, and headers and
+//! bodies are meant to be logged through [`headers`] and [`body`], which
+//! mask sensitive headers and truncate bodies.
+
+use proxy_wasm::hostcalls;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::time::UNIX_EPOCH;
+
+use crate::vm::LogLevelName;
+
+pub use log::Level;
+
+/// Stands in for masked credentials.
+const REDACTED: &str = "[REDACTED]";
+
+/// Auth schemes whose credentials are masked wherever they appear.
+const AUTH_SCHEMES: [&str; 3] = ["bearer ", "basic ", "dpop "];
+
+/// Shortest run after an auth scheme taken for a credential, so prose such
+/// as "DPoP key" is left alone.
+const MIN_CREDENTIAL_LEN: usize = 16;
+
+/// Plugin-level logging settings. Plugins sharing a VM share its logger, so
+/// the settings of the plugin configured last apply to all of them.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Overrides the VM's `log_level` when set.
+    pub level: Option<LogLevelName>,
+    pub format: LogFormat,
+    /// Thins out request lines at high traffic. Warnings and errors, and
+    /// lines not about a request, are always logged. Every request line is
+    /// logged when absent.
+    pub sampling: Option<LogSampling>,
+    /// Headers whose values are masked when headers are logged, matched
+    /// case-insensitively.
+    pub sensitive_headers: Vec<String>,
+    /// Bytes of a body logged before the rest is cut off.
+    pub max_body_bytes: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: None,
+            format: LogFormat::default(),
+            sampling: None,
+            sensitive_headers: [
+                "authorization",
+                "proxy-authorization",
+                "cookie",
+                "set-cookie",
+                "x-api-key",
+                "dpop",
+            ]
+            .iter()
+            .map(|h| h.to_string())
+            .collect(),
+            max_body_bytes: 256,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LogSampling {
+    /// Fraction (0.0-1.0) of requests whose lines are logged. A request's
+    /// lines are kept or dropped together.
+    pub rate: f64,
+    /// Most request lines logged per second by each VM, after sampling.
+    /// Unlimited when absent.
+    pub max_per_second: Option<u32>,
+}
+
+impl Default for LogSampling {
+    fn default() -> Self {
+        LogSampling {
+            rate: 1.0,
+            max_per_second: None,
+        }
+    }
+}
+
+impl LogSampling {
+    /// Whether the lines of the request in `context_id` are sampled.
+    fn samples(&self, context_id: u32) -> bool {
+        // Spread consecutive context ids uniformly over [0, 1)
+        let hash = (context_id as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 11;
+        (hash as f64 / (1u64 << 53) as f64) < self.rate
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// One JSON object per line, for log pipelines.
+    #[default]
+    Json,
+    /// The message followed by `key=value` fields, for reading.
+    Text,
+}
+
+/// Request attributes attached to a log line. Unset fields are left out.
+#[derive(Serialize, Default, Clone, Copy, Debug)]
+pub struct LogFields<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub principal: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decision: Option<&'a str>,
+}
+
+/// `Some(value)` unless `value` is empty, for fields not yet known.
+pub fn known(value: &str) -> Option<&str> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+thread_local! {
+    static COMPONENT: Cell<&'static str> = const { Cell::new("wasm_filter") };
+    static FORMAT: Cell<LogFormat> = const { Cell::new(LogFormat::Json) };
+    static SAMPLING: RefCell<Option<LogSampling>> = const { RefCell::new(None) };
+    // Second of the current rate limit window and the lines logged in it
+    static WINDOW: Cell<(u64, u32)> = const { Cell::new((0, 0)) };
+    static SENSITIVE_HEADERS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    static MAX_BODY_BYTES: Cell<usize> = const { Cell::new(256) };
+}
+
+/// Sets the component tag of the filter's lines, once at VM creation.
+pub fn init(component: &'static str) {
+    COMPONENT.with(|c| c.set(component));
+}
+
+/// Applies a plugin's logging settings.
+pub fn configure(config: &LoggingConfig) {
+    FORMAT.with(|format| format.set(config.format));
+    SAMPLING.with(|sampling| *sampling.borrow_mut() = config.sampling.clone());
+    SENSITIVE_HEADERS.with(|headers| *headers.borrow_mut() = config.sensitive_headers.clone());
+    MAX_BODY_BYTES.with(|max| max.set(config.max_body_bytes));
+    if let Some(level) = config.level {
+        proxy_wasm::set_log_level(level.into());
+    }
+}
+
+/// Whether lines at `level` are logged, to skip gathering costly detail.
+pub fn enabled(level: Level) -> bool {
+    level <= log::max_level()
+}
+
+/// Writes one line. Use the macros instead of calling this directly.
+pub fn emit(level: Level, fields: &LogFields, message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    if level > Level::Warn
+        && fields
+            .context_id
+            .is_some_and(|context_id| !sampled(context_id))
+    {
+        return;
+    }
+    let component = COMPONENT.with(Cell::get);
+    let message = message.to_string();
+    let message = redact_credentials(&message);
+    let line = match FORMAT.with(Cell::get) {
+        LogFormat::Json => json_line(component, level, fields, &message),
+        LogFormat::Text => text_line(component, fields, &message),
+    };
+    log::log!(level, "{}", line);
+}
+
+/// Whether a request line passes sampling and the rate limit.
+fn sampled(context_id: u32) -> bool {
+    SAMPLING.with(|sampling| {
+        let sampling = sampling.borrow();
+        let Some(sampling) = sampling.as_ref() else {
+            return true;
+        };
+        if !sampling.samples(context_id) {
+            return false;
+        }
+        let Some(max_per_second) = sampling.max_per_second else {
+            return true;
+        };
+        let now_secs = hostcalls::get_current_time()
+            .ok()
+            .and_then(|now| now.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        WINDOW.with(|window| {
+            let (second, count) = window.get();
+            let count = if second == now_secs { count } else { 0 };
+            window.set((now_secs, count.saturating_add(1)));
+            count < max_per_second
+        })
+    })
+}
+
+fn json_line(component: &str, level: Level, fields: &LogFields, message: &str) -> String {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        component: &'a str,
+        level: &'a str,
+        message: &'a str,
+        #[serde(flatten)]
+        fields: &'a LogFields<'a>,
+    }
+    let level = level.as_str().to_ascii_lowercase();
+    let line = Line {
+        component,
+        level: &level,
+        message,
+        fields,
+    };
+    serde_json::to_string(&line).unwrap_or_default()
+}
+
+fn text_line(component: &str, fields: &LogFields, message: &str) -> String {
+    let mut line = format!("[{}] {}", component, message);
+    if let Some(context_id) = fields.context_id {
+        line.push_str(&format!(" context_id={}", context_id));
+    }
+    let named = [
+        ("request_id", fields.request_id),
+        ("principal", fields.principal),
+        ("asset", fields.asset),
+        ("decision", fields.decision),
+    ];
+    for (name, value) in named {
+        if let Some(value) = value {
+            line.push_str(&format!(" {}={}", name, value));
+        }
+    }
+    line
+}
+
+/// Headers as `name: value` pairs for logging, with the values of
+/// `sensitive_headers` masked.
+pub fn headers(pairs: &[(String, String)]) -> String {
+    SENSITIVE_HEADERS.with(|sensitive| {
+        let sensitive = sensitive.borrow();
+        pairs
+            .iter()
+            .map(
+                |(name, value)| match sensitive.iter().any(|s| s.eq_ignore_ascii_case(name)) {
+                    true => format!("{}: {}", name, REDACTED),
+                    false => format!("{}: {}", name, value),
+                },
+            )
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+/// A body for logging, cut off after `max_body_bytes`.
+pub fn body(body: &[u8]) -> String {
+    let max = MAX_BODY_BYTES.with(Cell::get);
+    if body.len() <= max {
+        return String::from_utf8_lossy(body).into_owned();
+    }
+    format!(
+        "{}... ({} bytes)",
+        String::from_utf8_lossy(&body[..max]),
+        body.len()
+    )
+}
+
+/// Masks credentials in a line: JWTs, and whatever follows an auth scheme.
+fn redact_credentials(line: &str) -> Cow<'_, str> {
+    let lower = line.to_ascii_lowercase();
+    if !lower.contains("eyj") && !AUTH_SCHEMES.iter().any(|scheme| lower.contains(scheme)) {
+        return Cow::Borrowed(line);
+    }
+    let bytes = line.as_bytes();
+    let mut redacted = String::with_capacity(line.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // Credentials after a scheme, e.g. `Bearer abc...`, are masked whatever their shape
+        let word_start = i == 0 || !bytes[i - 1].is_ascii_alphanumeric();
+        if let Some(scheme) = AUTH_SCHEMES
+            .iter()
+            .find(|scheme| word_start && lower[i..].starts_with(*scheme))
+        {
+            let start = i + scheme.len();
+            let end = start + credential_len(&bytes[start..]);
+            if end - start >= MIN_CREDENTIAL_LEN {
+                redacted.push_str(&line[i..start]);
+                redacted.push_str(REDACTED);
+                i = end;
+                continue;
+            }
+        }
+        // Bare JWTs: three dot-separated base64url parts starting with `{"`
+        if line[i..].starts_with("eyJ") && (i == 0 || !is_base64url_byte(bytes[i - 1])) {
+            let end = i + credential_len(&bytes[i..]);
+            if line[i..end].matches('.').count() >= 2 {
+                redacted.push_str(REDACTED);
+                i = end;
+                continue;
+            }
+        }
+        let next = line[i..].chars().next().map_or(1, char::len_utf8);
+        redacted.push_str(&line[i..i + next]);
+        i += next;
+    }
+    Cow::Owned(redacted)
+}
+
+fn credential_len(bytes: &[u8]) -> usize {
+    bytes.iter().take_while(|b| is_credential_byte(**b)).count()
+}
+
+/// Bytes of base64, base64url and token68 credentials.
+fn is_credential_byte(b: u8) -> bool {
+    is_base64url_byte(b) || matches!(b, b'.' | b'~' | b'+' | b'/' | b'=')
+}
+
+fn is_base64url_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_')
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, fields: $fields:expr; $($arg:tt)+) => {
+        $crate::logging::emit($level, &$fields, format_args!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::emit($level, &$crate::logging::LogFields::default(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::log_at!($crate::logging::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::log_at!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::log_at!($crate::logging::Level::Debug, $($arg)+) };
+}
@@ -0,0 +1,807 @@
+//! In-process stand-in for the Envoy side of the proxy-wasm ABI, so filter
+//! contexts can be unit tested natively without a proxy.
+//!
+//! The SDK's hostcalls are `extern "C"` imports that Envoy provides at
+//! instantiation. This module defines them with `#[no_mangle]`, backed by a
+//! thread-local [`Host`] that tests populate and inspect:
+//!
+//! ```ignore
+//! mock_host::reset();
+//! mock_host::set_request_headers(&[(":path", "/"), ("authorization", "Bearer ...")]);
+//! let action = ctx.on_http_request_headers(0, true);
+//! assert_eq!(mock_host::with(|host| host.http_calls.len()), 1);
+//! ```
+//!
+//! Only enabled by the `mock-host` feature, which filters turn on for their
+//! tests alone; a WASM build containing these symbols would shadow the real
+//! host.
+#![allow(clippy::missing_safety_doc)]
+
+use proxy_wasm::types::{BufferType, MapType, Status, StreamType};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+/// Time reported by a freshly reset host: 2024-01-01T00:00:00Z.
+pub const DEFAULT_TIME_NANOS: u64 = 1_704_067_200 * 1_000_000_000;
+
+/// An HTTP callout dispatched by the filter.
+#[derive(Clone, Debug)]
+pub struct HttpCall {
+    pub token: u32,
+    pub upstream: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub timeout_ms: u32,
+}
+
+impl HttpCall {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find(&self.headers, name)
+    }
+}
+
+/// A gRPC callout dispatched by the filter.
+#[derive(Clone, Debug)]
+pub struct GrpcCall {
+    pub token: u32,
+    pub upstream: String,
+    pub service: String,
+    pub method: String,
+    pub message: Vec<u8>,
+}
+
+/// A local reply sent by the filter.
+#[derive(Clone, Debug)]
+pub struct LocalResponse {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Set for gRPC replies, whose body is the `grpc-message`.
+    pub grpc_status: Option<i32>,
+}
+
+impl LocalResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find(&self.headers, name)
+    }
+
+    pub fn body_str(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap_or_default()
+    }
+}
+
+/// Everything the filter can observe of, or do to, the proxy.
+#[derive(Default)]
+pub struct Host {
+    pub time_nanos: u64,
+    /// Header maps keyed by `MapType`. Names are matched case-insensitively.
+    pub maps: HashMap<u32, Vec<(String, String)>>,
+    /// Buffers keyed by `BufferType`.
+    pub buffers: HashMap<u32, Vec<u8>>,
+    /// Properties keyed by their path segments joined with `.`.
+    pub properties: HashMap<String, Vec<u8>>,
+    pub shared_data: HashMap<String, (Vec<u8>, u32)>,
+    pub queues: Vec<(String, VecDeque<Vec<u8>>)>,
+    pub metrics: Vec<(String, u64)>,
+    pub http_calls: Vec<HttpCall>,
+    pub grpc_calls: Vec<GrpcCall>,
+    /// gRPC status reported by `get_grpc_status`.
+    pub grpc_status: (u32, String),
+    pub local_response: Option<LocalResponse>,
+    pub resumed_requests: usize,
+    pub resumed_responses: usize,
+    /// Times a paused downstream connection was resumed.
+    pub resumed_downstream: usize,
+    /// Whether the filter closed the downstream connection.
+    pub closed_downstream: bool,
+    pub effective_context: Option<u32>,
+    pub tick_period_ms: u32,
+    pub logs: Vec<String>,
+    next_token: u32,
+}
+
+thread_local! {
+    static HOST: RefCell<Host> = RefCell::new(Host::default());
+}
+
+/// Replaces the host with an empty one at [`DEFAULT_TIME_NANOS`].
+pub fn reset() {
+    HOST.with(|host| {
+        *host.borrow_mut() = Host {
+            time_nanos: DEFAULT_TIME_NANOS,
+            next_token: 1,
+            ..Default::default()
+        }
+    });
+}
+
+pub fn with<R>(f: impl FnOnce(&mut Host) -> R) -> R {
+    HOST.with(|host| f(&mut host.borrow_mut()))
+}
+
+pub fn set_request_headers(headers: &[(&str, &str)]) {
+    set_map(MapType::HttpRequestHeaders, headers);
+}
+
+pub fn set_response_headers(headers: &[(&str, &str)]) {
+    set_map(MapType::HttpResponseHeaders, headers);
+}
+
+pub fn request_header(name: &str) -> Option<String> {
+    map_value(MapType::HttpRequestHeaders, name)
+}
+
+pub fn response_header(name: &str) -> Option<String> {
+    map_value(MapType::HttpResponseHeaders, name)
+}
+
+pub fn set_request_trailers(trailers: &[(&str, &str)]) {
+    set_map(MapType::HttpRequestTrailers, trailers);
+}
+
+pub fn set_response_trailers(trailers: &[(&str, &str)]) {
+    set_map(MapType::HttpResponseTrailers, trailers);
+}
+
+pub fn response_trailer(name: &str) -> Option<String> {
+    map_value(MapType::HttpResponseTrailers, name)
+}
+
+/// Sets the response the next `on_http_call_response` reads.
+pub fn set_http_call_response(status: &str, body: &[u8]) {
+    set_map(MapType::HttpCallResponseHeaders, &[(":status", status)]);
+    set_buffer(BufferType::HttpCallResponseBody, body);
+}
+
+pub fn set_buffer(buffer_type: BufferType, data: &[u8]) {
+    with(|host| host.buffers.insert(buffer_type as u32, data.to_vec()));
+}
+
+pub fn buffer(buffer_type: BufferType) -> Option<Vec<u8>> {
+    with(|host| host.buffers.get(&(buffer_type as u32)).cloned())
+}
+
+pub fn set_property(path: &[&str], value: &[u8]) {
+    with(|host| host.properties.insert(path.join("."), value.to_vec()));
+}
+
+pub fn property(path: &[&str]) -> Option<Vec<u8>> {
+    with(|host| host.properties.get(&path.join(".")).cloned())
+}
+
+pub fn local_response() -> Option<LocalResponse> {
+    with(|host| host.local_response.clone())
+}
+
+pub fn http_calls() -> Vec<HttpCall> {
+    with(|host| host.http_calls.clone())
+}
+
+pub fn logs() -> Vec<String> {
+    with(|host| host.logs.clone())
+}
+
+/// Current value of the metric named `name`, if defined.
+pub fn metric(name: &str) -> Option<u64> {
+    with(|host| {
+        host.metrics
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| *v)
+    })
+}
+
+fn set_map(map_type: MapType, headers: &[(&str, &str)]) {
+    let pairs = headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    with(|host| host.maps.insert(map_type as u32, pairs));
+}
+
+fn map_value(map_type: MapType, name: &str) -> Option<String> {
+    with(|host| {
+        let map = host.maps.get(&(map_type as u32))?;
+        find(map, name).map(str::to_string)
+    })
+}
+
+fn find<'a>(pairs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    pairs
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+unsafe fn bytes<'a>(data: *const u8, size: usize) -> &'a [u8] {
+    if data.is_null() || size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, size)
+    }
+}
+
+unsafe fn string(data: *const u8, size: usize) -> String {
+    String::from_utf8_lossy(bytes(data, size)).into_owned()
+}
+
+/// Hands `value` to the SDK, which takes ownership with
+/// `Vec::from_raw_parts(data, size, size)`.
+unsafe fn give(value: &[u8], return_data: *mut *mut u8, return_size: *mut usize) {
+    let boxed: Box<[u8]> = value.into();
+    *return_size = boxed.len();
+    *return_data = Box::into_raw(boxed) as *mut u8;
+}
+
+/// Property paths arrive as segments separated by NUL bytes.
+unsafe fn property_path(data: *const u8, size: usize) -> String {
+    string(data, size).replace('\0', ".")
+}
+
+/// Decodes the ABI's serialized header map: a count, the key and value
+/// lengths, then each NUL-terminated key and value.
+fn deserialize_map(data: &[u8]) -> Vec<(String, String)> {
+    let read_u32 = |at: usize| -> usize {
+        data.get(at..at + 4)
+            .map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    let count = read_u32(0);
+    let mut offset = 4 + count * 8;
+    let mut pairs = Vec::with_capacity(count);
+    for n in 0..count {
+        let key_len = read_u32(4 + n * 8);
+        let value_len = read_u32(8 + n * 8);
+        let key = data.get(offset..offset + key_len).unwrap_or_default();
+        offset += key_len + 1;
+        let value = data.get(offset..offset + value_len).unwrap_or_default();
+        offset += value_len + 1;
+        pairs.push((
+            String::from_utf8_lossy(key).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        ));
+    }
+    pairs
+}
+
+fn serialize_map(pairs: &[(String, String)]) -> Vec<u8> {
+    let mut data = (pairs.len() as u32).to_le_bytes().to_vec();
+    for (key, value) in pairs {
+        data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    }
+    for (key, value) in pairs {
+        data.extend_from_slice(key.as_bytes());
+        data.push(0);
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+    }
+    data
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_log(
+    _level: u32,
+    message_data: *const u8,
+    message_size: usize,
+) -> Status {
+    let message = string(message_data, message_size);
+    with(|host| host.logs.push(message));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_log_level(return_level: *mut u32) -> Status {
+    *return_level = 0;
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+    *return_time = with(|host| host.time_nanos);
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_set_tick_period_milliseconds(period: u32) -> Status {
+    with(|host| host.tick_period_ms = period);
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_buffer_bytes(
+    buffer_type: u32,
+    start: usize,
+    max_size: usize,
+    return_buffer_data: *mut *mut u8,
+    return_buffer_size: *mut usize,
+) -> Status {
+    let Some(buffer) = with(|host| host.buffers.get(&buffer_type).cloned()) else {
+        return Status::NotFound;
+    };
+    let start = start.min(buffer.len());
+    let end = start.saturating_add(max_size).min(buffer.len());
+    give(&buffer[start..end], return_buffer_data, return_buffer_size);
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_buffer_bytes(
+    buffer_type: u32,
+    start: usize,
+    size: usize,
+    buffer_data: *const u8,
+    buffer_size: usize,
+) -> Status {
+    let value = bytes(buffer_data, buffer_size).to_vec();
+    with(|host| {
+        let buffer = host.buffers.entry(buffer_type).or_default();
+        let start = start.min(buffer.len());
+        let end = start.saturating_add(size).min(buffer.len());
+        buffer.splice(start..end, value);
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_header_map_pairs(
+    map_type: u32,
+    return_map_data: *mut *mut u8,
+    return_map_size: *mut usize,
+) -> Status {
+    let pairs = with(|host| host.maps.get(&map_type).cloned().unwrap_or_default());
+    give(&serialize_map(&pairs), return_map_data, return_map_size);
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_header_map_pairs(
+    map_type: u32,
+    map_data: *const u8,
+    map_size: usize,
+) -> Status {
+    let pairs = deserialize_map(bytes(map_data, map_size));
+    with(|host| host.maps.insert(map_type, pairs));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_header_map_value(
+    map_type: u32,
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = with(|host| {
+        let map = host.maps.get(&map_type)?;
+        find(map, &key).map(str::to_string)
+    });
+    match value {
+        Some(value) => {
+            give(value.as_bytes(), return_value_data, return_value_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_replace_header_map_value(
+    map_type: u32,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = string(value_data, value_size);
+    with(|host| {
+        let map = host.maps.entry(map_type).or_default();
+        map.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+        map.push((key, value));
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_remove_header_map_value(
+    map_type: u32,
+    key_data: *const u8,
+    key_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    with(|host| {
+        if let Some(map) = host.maps.get_mut(&map_type) {
+            map.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+        }
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_add_header_map_value(
+    map_type: u32,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = string(value_data, value_size);
+    with(|host| host.maps.entry(map_type).or_default().push((key, value)));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_property(
+    path_data: *const u8,
+    path_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let path = property_path(path_data, path_size);
+    match with(|host| host.properties.get(&path).cloned()) {
+        Some(value) => {
+            give(&value, return_value_data, return_value_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_property(
+    path_data: *const u8,
+    path_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let path = property_path(path_data, path_size);
+    let value = bytes(value_data, value_size).to_vec();
+    with(|host| host.properties.insert(path, value));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+    return_cas: *mut u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    match with(|host| host.shared_data.get(&key).cloned()) {
+        Some((value, cas)) => {
+            give(&value, return_value_data, return_value_size);
+            *return_cas = cas;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+    cas: u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = bytes(value_data, value_size).to_vec();
+    with(|host| {
+        let current = host.shared_data.get(&key).map_or(0, |(_, cas)| *cas);
+        if cas != 0 && cas != current {
+            return Status::CasMismatch;
+        }
+        host.shared_data.insert(key, (value, current + 1));
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_register_shared_queue(
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id = with(
+        |host| match host.queues.iter().position(|(n, _)| *n == name) {
+            Some(index) => index as u32 + 1,
+            None => {
+                host.queues.push((name, VecDeque::new()));
+                host.queues.len() as u32
+            }
+        },
+    );
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_resolve_shared_queue(
+    _vm_id_data: *const u8,
+    _vm_id_size: usize,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    match with(|host| host.queues.iter().position(|(n, _)| *n == name)) {
+        Some(index) => {
+            *return_id = index as u32 + 1;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_dequeue_shared_queue(
+    queue_id: u32,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let item = with(|host| {
+        let (_, queue) = host.queues.get_mut((queue_id as usize).checked_sub(1)?)?;
+        Some(queue.pop_front())
+    });
+    match item {
+        None => Status::NotFound,
+        Some(None) => Status::Empty,
+        Some(Some(value)) => {
+            give(&value, return_value_data, return_value_size);
+            Status::Ok
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_enqueue_shared_queue(
+    queue_id: u32,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let value = bytes(value_data, value_size).to_vec();
+    with(
+        |host| match host.queues.get_mut((queue_id as usize).wrapping_sub(1)) {
+            Some((_, queue)) => {
+                queue.push_back(value);
+                Status::Ok
+            }
+            None => Status::NotFound,
+        },
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_continue_stream(stream_type: u32) -> Status {
+    with(|host| {
+        if stream_type == StreamType::HttpRequest as u32 {
+            host.resumed_requests += 1;
+        } else if stream_type == StreamType::HttpResponse as u32 {
+            host.resumed_responses += 1;
+        } else if stream_type == StreamType::Downstream as u32 {
+            host.resumed_downstream += 1;
+        }
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_close_stream(stream_type: u32) -> Status {
+    if stream_type == StreamType::Downstream as u32 {
+        with(|host| host.closed_downstream = true);
+    }
+    Status::Ok
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn proxy_send_local_response(
+    status_code: u32,
+    _status_code_details_data: *const u8,
+    _status_code_details_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    grpc_status: i32,
+) -> Status {
+    let response = LocalResponse {
+        status: status_code,
+        headers: deserialize_map(bytes(headers_data, headers_size)),
+        body: bytes(body_data, body_size).to_vec(),
+        grpc_status: (grpc_status >= 0).then_some(grpc_status),
+    };
+    with(|host| host.local_response = Some(response));
+    Status::Ok
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn proxy_http_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    _trailers_data: *const u8,
+    _trailers_size: usize,
+    timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let mut call = HttpCall {
+        token: 0,
+        upstream: string(upstream_data, upstream_size),
+        headers: deserialize_map(bytes(headers_data, headers_size)),
+        body: bytes(body_data, body_size).to_vec(),
+        timeout_ms: timeout,
+    };
+    *return_token = with(|host| {
+        call.token = host.next_token;
+        host.next_token += 1;
+        host.http_calls.push(call.clone());
+        call.token
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn proxy_grpc_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    service_name_data: *const u8,
+    service_name_size: usize,
+    method_name_data: *const u8,
+    method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    message_data: *const u8,
+    message_size: usize,
+    _timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let mut call = GrpcCall {
+        token: 0,
+        upstream: string(upstream_data, upstream_size),
+        service: string(service_name_data, service_name_size),
+        method: string(method_name_data, method_name_size),
+        message: bytes(message_data, message_size).to_vec(),
+    };
+    *return_token = with(|host| {
+        call.token = host.next_token;
+        host.next_token += 1;
+        host.grpc_calls.push(call.clone());
+        call.token
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_stream(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _return_stream_id: *mut u32,
+) -> Status {
+    Status::Unimplemented
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_send(
+    _token: u32,
+    _message_data: *const u8,
+    _message_size: usize,
+    _end_stream: u32,
+) -> Status {
+    Status::Unimplemented
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_cancel(_token: u32) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_close(_token: u32) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_status(
+    return_code: *mut u32,
+    return_message_data: *mut *mut u8,
+    return_message_size: *mut usize,
+) -> Status {
+    let (code, message) = with(|host| host.grpc_status.clone());
+    *return_code = code;
+    give(message.as_bytes(), return_message_data, return_message_size);
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_set_effective_context(context_id: u32) -> Status {
+    with(|host| host.effective_context = Some(context_id));
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_call_foreign_function(
+    _function_name_data: *const u8,
+    _function_name_size: usize,
+    _arguments_data: *const u8,
+    _arguments_size: usize,
+    _results_data: *mut *mut u8,
+    _results_size: *mut usize,
+) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_done() -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_define_metric(
+    _metric_type: u32,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id = with(
+        |host| match host.metrics.iter().position(|(n, _)| *n == name) {
+            Some(index) => index as u32,
+            None => {
+                host.metrics.push((name, 0));
+                host.metrics.len() as u32 - 1
+            }
+        },
+    );
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status {
+    match with(|host| host.metrics.get(metric_id as usize).map(|(_, v)| *v)) {
+        Some(value) => {
+            *return_value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+    with(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some((_, current)) => {
+            *current = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status {
+    with(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some((_, current)) => {
+            *current = current.saturating_add_signed(offset);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
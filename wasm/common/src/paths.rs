@@ -0,0 +1,54 @@
+use regex::Regex;
+use serde::Deserialize;
+
+/// Matches a request path, ignoring any query string or fragment:
+///
+/// ```json
+/// [{"exact": "/healthz"}, {"prefix": "/.well-known/"}, {"regex": "^/admin(/|$)"}]
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "RawPathMatch")]
+pub enum PathMatch {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPathMatch {
+    exact: Option<String>,
+    prefix: Option<String>,
+    regex: Option<String>,
+}
+
+impl TryFrom<RawPathMatch> for PathMatch {
+    type Error = String;
+
+    fn try_from(raw: RawPathMatch) -> Result<Self, Self::Error> {
+        match (raw.exact, raw.prefix, raw.regex) {
+            (Some(path), None, None) => Ok(PathMatch::Exact(path)),
+            (None, Some(prefix), None) => Ok(PathMatch::Prefix(prefix)),
+            (None, None, Some(pattern)) => Regex::new(&pattern)
+                .map(PathMatch::Regex)
+                .map_err(|e| format!("invalid path regex: {}", e)),
+            _ => Err("path match needs exactly one of exact, prefix or regex".to_string()),
+        }
+    }
+}
+
+impl PathMatch {
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+        match self {
+            PathMatch::Exact(exact) => path == exact,
+            PathMatch::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathMatch::Regex(regex) => regex.is_match(path),
+        }
+    }
+}
+
+/// Whether any entry of `list` matches `path`.
+pub fn any_match(list: &[PathMatch], path: &str) -> bool {
+    list.iter().any(|m| m.matches(path))
+}
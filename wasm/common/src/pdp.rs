@@ -0,0 +1,380 @@
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::connection::ConnectionAttributes;
+
+/// How a filter reaches the PDP.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PdpTransport {
+    /// JSON, or protobuf with `pdp_encoding`, over an HTTP callout to
+    /// `pdp_path`.
+    #[default]
+    Http,
+    /// Protobuf over a gRPC callout to `pdp_grpc_service`/`pdp_grpc_method`.
+    /// The messages are defined in `server-filter-rust/proto/evaluation.proto`.
+    Grpc,
+}
+
+#[derive(Serialize)]
+pub struct Principal {
+    pub id: String,
+    /// The party making the request on behalf of `id`, see `delegation`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Query {
+    #[serde(rename = "assetId")]
+    pub asset_id: String,
+    pub action: String,
+}
+
+/// How the decisions for a batch of queries combine into one verdict.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CombineMode {
+    /// Allow only if every query is allowed.
+    #[default]
+    All,
+    /// Allow if any query is allowed.
+    Any,
+}
+
+#[derive(Serialize)]
+pub struct EvaluationRequest {
+    pub principal: Principal,
+    pub queries: Vec<Query>,
+    /// Request attributes for policies to consider, see `context_headers`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub context: BTreeMap<String, String>,
+    /// Sent with `connection_attributes`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection: Option<ConnectionAttributes>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Decision {
+    pub decision: String,
+    pub reason: String,
+    #[serde(default)]
+    pub obligations: Obligations,
+    #[serde(default)]
+    pub quota: Option<Quota>,
+    /// Seconds the decision may be cached for, in place of the filter's
+    /// configured TTL.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Unix time in seconds after which the decision must no longer be
+    /// served from cache.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// Conditions attached to an Allow that the filter enforces on the request
+/// it forwards and on the upstream response.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Obligations {
+    /// Headers set on the response, e.g. `cache-control: no-store` for
+    /// sensitive assets.
+    pub response_headers: BTreeMap<String, String>,
+    /// Trailers set on responses that end with trailers, such as gRPC
+    /// responses. A response without trailers doesn't get them.
+    pub response_trailers: BTreeMap<String, String>,
+    /// Response header values the principal may not receive, e.g.
+    /// `x-data-classification: ["restricted"]`. A successful response
+    /// carrying one is replaced with a denial.
+    pub deny_response_headers: BTreeMap<String, Vec<String>>,
+    /// JSON pointers of response body fields to remove or mask, see
+    /// `redact::redact`.
+    pub redact_fields: Vec<String>,
+    /// Headers set on the request forwarded upstream.
+    pub request_headers: BTreeMap<String, String>,
+    /// Headers removed from the request forwarded upstream.
+    pub request_headers_to_remove: Vec<String>,
+}
+
+impl Obligations {
+    /// Whether nothing is left to enforce on the response. The request
+    /// headers are applied when the request is allowed.
+    pub fn is_empty(&self) -> bool {
+        self.response_headers.is_empty()
+            && self.response_trailers.is_empty()
+            && self.deny_response_headers.is_empty()
+            && self.redact_fields.is_empty()
+    }
+
+    fn merge(&mut self, other: &Obligations) {
+        self.response_headers.extend(other.response_headers.clone());
+        self.response_trailers
+            .extend(other.response_trailers.clone());
+        for (name, values) in &other.deny_response_headers {
+            self.deny_response_headers
+                .entry(name.clone())
+                .or_default()
+                .extend(values.clone());
+        }
+        self.redact_fields
+            .extend(other.redact_fields.iter().cloned());
+        self.request_headers.extend(other.request_headers.clone());
+        self.request_headers_to_remove
+            .extend(other.request_headers_to_remove.iter().cloned());
+    }
+
+    /// Whether a response with the given headers must be withheld. Values
+    /// are compared case-insensitively.
+    pub fn forbids<F: Fn(&str) -> Option<String>>(&self, header: F) -> bool {
+        self.deny_response_headers.iter().any(|(name, denied)| {
+            header(name)
+                .is_some_and(|value| denied.iter().any(|d| d.eq_ignore_ascii_case(value.trim())))
+        })
+    }
+}
+
+/// Quota block of a PDP decision. The server filter keeps counting down from
+/// `remaining` locally, so requests answered from the decision cache are
+/// still charged and an exhausted principal is rejected without a callout
+/// until `reset`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u64,
+    /// Requests left after the one being evaluated.
+    pub remaining: u64,
+    /// Unix time in seconds at which the quota renews.
+    pub reset: u64,
+}
+
+impl Quota {
+    /// `X-RateLimit-*` headers telling the client its remaining budget.
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("X-RateLimit-Limit", self.limit.to_string()),
+            ("X-RateLimit-Remaining", self.remaining.to_string()),
+            ("X-RateLimit-Reset", self.reset.to_string()),
+        ]
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EvaluationResponse {
+    pub decisions: Vec<Decision>,
+}
+
+impl EvaluationRequest {
+    pub fn encode_proto(&self) -> Vec<u8> {
+        proto::EvaluationRequest {
+            principal: Some(proto::Principal {
+                id: self.principal.id.clone(),
+                actor: self.principal.actor.clone().unwrap_or_default(),
+            }),
+            queries: self
+                .queries
+                .iter()
+                .map(|q| proto::Query {
+                    asset_id: q.asset_id.clone(),
+                    action: q.action.clone(),
+                })
+                .collect(),
+            context: self.context.clone(),
+            connection: self.connection.as_ref().map(|c| proto::Connection {
+                source_address: c.source_address.clone().unwrap_or_default(),
+                destination_address: c.destination_address.clone().unwrap_or_default(),
+                sni: c.sni.clone().unwrap_or_default(),
+                tls_version: c.tls_version.clone().unwrap_or_default(),
+                peer_identity: c.peer_identity.clone().unwrap_or_default(),
+            }),
+        }
+        .encode_to_vec()
+    }
+}
+
+impl EvaluationResponse {
+    /// Reduces the decisions for `expected` queries to the one that decides
+    /// the request: the first denial under `All` (or the first decision if
+    /// all allow), the first allow under `Any`. Returns `None` if the PDP
+    /// answered fewer queries than were asked.
+    pub fn combine(&self, mode: CombineMode, expected: usize) -> Option<&Decision> {
+        if self.decisions.is_empty() || self.decisions.len() < expected {
+            return None;
+        }
+        let decisive = match mode {
+            CombineMode::All => self.decisions.iter().find(|d| d.decision != "Allow"),
+            CombineMode::Any => self.decisions.iter().find(|d| d.decision == "Allow"),
+        };
+        decisive.or(self.decisions.first())
+    }
+
+    /// Obligations of every allowing decision, which all apply to the
+    /// response when the request is let through.
+    pub fn obligations(&self) -> Obligations {
+        let mut merged = Obligations::default();
+        for decision in self.decisions.iter().filter(|d| d.decision == "Allow") {
+            merged.merge(&decision.obligations);
+        }
+        merged
+    }
+
+    /// The most restrictive quota reported for any query.
+    pub fn quota(&self) -> Option<Quota> {
+        self.decisions
+            .iter()
+            .filter_map(|d| d.quota)
+            .min_by_key(|q| q.remaining)
+    }
+
+    /// How long the decisions may be cached according to the PDP: the
+    /// shortest `ttl_secs` or time left until `expires_at` of any of them,
+    /// or `None` if none reports one.
+    pub fn validity_ms(&self, now_ms: u64) -> Option<u64> {
+        self.decisions
+            .iter()
+            .flat_map(|d| {
+                let ttl_ms = d.ttl_secs.map(|secs| secs.saturating_mul(1000));
+                let remaining_ms = d
+                    .expires_at
+                    .map(|at| at.saturating_mul(1000).saturating_sub(now_ms));
+                ttl_ms.into_iter().chain(remaining_ms)
+            })
+            .min()
+    }
+
+    pub fn decode_proto(buf: &[u8]) -> Result<Self, prost::DecodeError> {
+        let resp = proto::EvaluationResponse::decode(buf)?;
+        Ok(EvaluationResponse {
+            decisions: resp
+                .decisions
+                .into_iter()
+                .map(|d| {
+                    let obligations = d.obligations.unwrap_or_default();
+                    Decision {
+                        decision: d.decision,
+                        reason: d.reason,
+                        obligations: Obligations {
+                            response_headers: obligations.response_headers,
+                            response_trailers: obligations.response_trailers,
+                            deny_response_headers: obligations
+                                .deny_response_headers
+                                .into_iter()
+                                .map(|(name, list)| (name, list.values))
+                                .collect(),
+                            redact_fields: obligations.redact_fields,
+                            request_headers: obligations.request_headers,
+                            request_headers_to_remove: obligations.request_headers_to_remove,
+                        },
+                        quota: d.quota.map(|q| Quota {
+                            limit: q.limit,
+                            remaining: q.remaining,
+                            reset: q.reset,
+                        }),
+                        ttl_secs: d.ttl_secs,
+                        expires_at: d.expires_at,
+                    }
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Protobuf mirror of the JSON wire types, matching `server-filter-rust/proto/evaluation.proto`.
+mod proto {
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Principal {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub actor: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Query {
+        #[prost(string, tag = "1")]
+        pub asset_id: String,
+        #[prost(string, tag = "2")]
+        pub action: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct EvaluationRequest {
+        #[prost(message, optional, tag = "1")]
+        pub principal: Option<Principal>,
+        #[prost(message, repeated, tag = "2")]
+        pub queries: Vec<Query>,
+        #[prost(btree_map = "string, string", tag = "3")]
+        pub context: BTreeMap<String, String>,
+        #[prost(message, optional, tag = "4")]
+        pub connection: Option<Connection>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Connection {
+        #[prost(string, tag = "1")]
+        pub source_address: String,
+        #[prost(string, tag = "2")]
+        pub destination_address: String,
+        #[prost(string, tag = "3")]
+        pub sni: String,
+        #[prost(string, tag = "4")]
+        pub tls_version: String,
+        #[prost(string, tag = "5")]
+        pub peer_identity: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Decision {
+        #[prost(string, tag = "1")]
+        pub decision: String,
+        #[prost(string, tag = "2")]
+        pub reason: String,
+        #[prost(message, optional, tag = "3")]
+        pub obligations: Option<Obligations>,
+        #[prost(message, optional, tag = "4")]
+        pub quota: Option<Quota>,
+        #[prost(uint64, optional, tag = "5")]
+        pub ttl_secs: Option<u64>,
+        #[prost(uint64, optional, tag = "6")]
+        pub expires_at: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Quota {
+        #[prost(uint64, tag = "1")]
+        pub limit: u64,
+        #[prost(uint64, tag = "2")]
+        pub remaining: u64,
+        #[prost(uint64, tag = "3")]
+        pub reset: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Obligations {
+        #[prost(btree_map = "string, string", tag = "1")]
+        pub response_headers: BTreeMap<String, String>,
+        #[prost(btree_map = "string, message", tag = "2")]
+        pub deny_response_headers: BTreeMap<String, StringList>,
+        #[prost(string, repeated, tag = "3")]
+        pub redact_fields: Vec<String>,
+        #[prost(btree_map = "string, string", tag = "4")]
+        pub response_trailers: BTreeMap<String, String>,
+        #[prost(btree_map = "string, string", tag = "5")]
+        pub request_headers: BTreeMap<String, String>,
+        #[prost(string, repeated, tag = "6")]
+        pub request_headers_to_remove: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StringList {
+        #[prost(string, repeated, tag = "1")]
+        pub values: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct EvaluationResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub decisions: Vec<Decision>,
+    }
+}
@@ -0,0 +1,95 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Bytes left unescaped by `application/x-www-form-urlencoded`.
+const FORM: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'*')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_');
+
+/// Decoded query-string parameters, in request order. Repeated names are
+/// kept, so `get` returns the first value and `get_all` every value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryParams {
+    pairs: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    /// Parses the query component of a request path such as
+    /// `/assets?id=a%2Fb&tag=x#top`. Paths without a query yield no params.
+    pub fn from_path(path: &str) -> Self {
+        let path = path.split('#').next().unwrap_or(path);
+        match path.split_once('?') {
+            Some((_, query)) => QueryParams::parse(query),
+            None => QueryParams::default(),
+        }
+    }
+
+    /// Parses a raw `application/x-www-form-urlencoded` query string. `+`
+    /// decodes to a space and invalid UTF-8 is replaced rather than rejected.
+    /// A name without `=` gets an empty value.
+    pub fn parse(query: &str) -> Self {
+        let pairs = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode(name), decode(value))
+            })
+            .collect();
+        QueryParams { pairs }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.pairs.iter().any(|(n, _)| n == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+/// Encodes `pairs` as an `application/x-www-form-urlencoded` string, the
+/// inverse of `QueryParams::parse`.
+pub fn encode(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode_component(name), encode_component(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Escapes one form component. Spaces become `%20`, which decodes the same
+/// as `+`.
+pub fn encode_component(component: &str) -> String {
+    utf8_percent_encode(component, FORM).to_string()
+}
+
+fn decode(component: &str) -> String {
+    let component = component.replace('+', " ");
+    percent_decode_str(&component)
+        .decode_utf8_lossy()
+        .into_owned()
+}
@@ -0,0 +1,68 @@
+use proxy_wasm::traits::HttpContext;
+use serde::Serialize;
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// An RFC 7807 problem details object. `type` is left as `about:blank`, so
+/// `title` is the status's reason phrase.
+#[derive(Serialize, Debug)]
+pub struct Problem<'a> {
+    #[serde(rename = "type")]
+    pub problem_type: &'a str,
+    pub title: &'a str,
+    pub status: u32,
+    pub detail: &'a str,
+    /// The request id, identifying this occurrence of the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<&'a str>,
+}
+
+impl<'a> Problem<'a> {
+    pub fn new(status: u32, detail: &'a str) -> Self {
+        Problem {
+            problem_type: "about:blank",
+            title: title(status),
+            status,
+            detail,
+            instance: None,
+        }
+    }
+
+    pub fn instance(mut self, instance: &'a str) -> Self {
+        self.instance = Some(instance).filter(|id| !id.is_empty());
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Reason phrase of the statuses the filters reply with.
+pub fn title(status: u32) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+/// Sends a local reply with a problem details body.
+pub fn send_problem<C: HttpContext + ?Sized>(ctx: &C, problem: &Problem) {
+    let body = problem.to_json();
+    ctx.send_http_response(
+        problem.status,
+        vec![("content-type", PROBLEM_CONTENT_TYPE)],
+        Some(body.as_bytes()),
+    );
+}
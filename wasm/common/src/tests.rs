@@ -0,0 +1,146 @@
+//! Identity chain signing and query-string parsing.
+
+use crate::identity::{self, ChainError};
+use crate::query::{self, QueryParams};
+
+const NOW_SECS: u64 = 1_704_067_200;
+
+fn secret(key_id: &str) -> Option<String> {
+    (key_id == "k1").then(|| "chain-secret".to_string())
+}
+
+/// A chain of `services` calling on behalf of `user`, one hop each.
+fn chain(services: &[&str]) -> String {
+    services.iter().fold(String::new(), |chain, service| {
+        let chain = (!chain.is_empty()).then_some(chain.as_str());
+        identity::append(chain, "user", service, NOW_SECS, "k1", "chain-secret")
+    })
+}
+
+fn verify(chain: &str) -> Result<Vec<identity::Hop>, ChainError> {
+    identity::verify(chain, secret, NOW_SECS, 60, 8)
+}
+
+#[test]
+fn appended_hops_verify() {
+    let chain = chain(&["service-a", "service-b"]);
+
+    let hops = verify(&chain).unwrap();
+    assert_eq!(hops.len(), 2);
+    assert_eq!(hops[0].service, "service-a");
+    assert_eq!(hops[0].prev, "");
+    assert_eq!(hops[1].service, "service-b");
+    assert_ne!(hops[1].prev, "");
+    assert!(hops.iter().all(|hop| hop.caller == "user"));
+    assert_eq!(identity::original_caller(&chain).as_deref(), Some("user"));
+}
+
+#[test]
+fn dropped_hops_break_the_chain() {
+    let chain = chain(&["service-a", "service-b", "service-c"]);
+    let entries: Vec<&str> = chain.split(", ").collect();
+
+    let dropped = [entries[0], entries[2]].join(", ");
+    assert_eq!(verify(&dropped), Err(ChainError::Broken));
+    let reordered = [entries[1], entries[0], entries[2]].join(", ");
+    assert_eq!(verify(&reordered), Err(ChainError::Broken));
+}
+
+#[test]
+fn hops_spliced_from_another_chain_break_it() {
+    let chain = chain(&["service-a", "service-b"]);
+    let other = identity::append(None, "mallory", "service-x", NOW_SECS, "k1", "chain-secret");
+
+    // Each entry is validly signed, but the second's prev digest covers
+    // only the original first entry
+    let spliced = format!("{}, {}", other, chain.split(", ").nth(1).unwrap());
+    assert_eq!(verify(&spliced), Err(ChainError::Broken));
+}
+
+#[test]
+fn altered_hops_fail_their_signature() {
+    let chain = chain(&["service-a"]);
+    let (signing_input, signature) = chain.rsplit_once('.').unwrap();
+    let (key_id, _) = signing_input.split_once('.').unwrap();
+    let forged = identity::append(None, "admin", "service-a", NOW_SECS, "k1", "guess");
+    let forged_payload = forged.split('.').nth(1).unwrap();
+
+    let tampered = format!("{}.{}.{}", key_id, forged_payload, signature);
+    assert_eq!(verify(&tampered), Err(ChainError::InvalidSignature));
+    assert_eq!(verify(&forged), Err(ChainError::InvalidSignature));
+}
+
+#[test]
+fn chains_are_checked_for_keys_age_and_length() {
+    let unknown = identity::append(None, "user", "service-a", NOW_SECS, "k2", "chain-secret");
+    assert_eq!(
+        verify(&unknown),
+        Err(ChainError::UnknownKey("k2".to_string()))
+    );
+
+    let stale = identity::append(
+        None,
+        "user",
+        "service-a",
+        NOW_SECS - 61,
+        "k1",
+        "chain-secret",
+    );
+    assert_eq!(verify(&stale), Err(ChainError::Expired));
+
+    let long = chain(&["a", "b", "c", "d", "e", "f", "g", "h", "i"]);
+    assert_eq!(verify(&long), Err(ChainError::TooLong));
+
+    assert_eq!(verify("not-a-chain"), Err(ChainError::Malformed));
+}
+
+#[test]
+fn repeated_query_parameters_are_all_kept() {
+    let params = QueryParams::parse("tag=a&id=1&tag=b");
+
+    assert_eq!(params.len(), 3);
+    assert_eq!(params.get("tag"), Some("a"));
+    assert_eq!(params.get_all("tag").collect::<Vec<_>>(), ["a", "b"]);
+    assert_eq!(params.get("id"), Some("1"));
+}
+
+#[test]
+fn query_parameters_are_percent_decoded() {
+    let params = QueryParams::parse("id=a%2Fb&q=x+y%20z&na%6De=v&bad=%FF");
+
+    assert_eq!(params.get("id"), Some("a/b"));
+    assert_eq!(params.get("q"), Some("x y z"));
+    assert_eq!(params.get("name"), Some("v"));
+    assert_eq!(params.get("bad"), Some("\u{FFFD}"));
+}
+
+#[test]
+fn query_parameters_without_values_are_empty() {
+    let params = QueryParams::parse("flag&empty=&&id=1");
+
+    assert_eq!(params.len(), 3);
+    assert_eq!(params.get("flag"), Some(""));
+    assert_eq!(params.get("empty"), Some(""));
+    assert!(params.contains("flag"));
+    assert!(!params.contains("missing"));
+}
+
+#[test]
+fn query_is_read_from_the_path() {
+    let params = QueryParams::from_path("/assets?id=a%2Fb&tag=x#top");
+    assert_eq!(params.get("id"), Some("a/b"));
+    assert_eq!(params.get("tag"), Some("x"));
+
+    assert!(QueryParams::from_path("/assets").is_empty());
+    assert!(QueryParams::from_path("/assets#a?b=c").is_empty());
+}
+
+#[test]
+fn encoded_queries_parse_back() {
+    let encoded = query::encode(&[("a b", "c&d=e"), ("id", "x/y")]);
+
+    assert_eq!(encoded, "a%20b=c%26d%3De&id=x%2Fy");
+    let params = QueryParams::parse(&encoded);
+    assert_eq!(params.get("a b"), Some("c&d=e"));
+    assert_eq!(params.get("id"), Some("x/y"));
+}
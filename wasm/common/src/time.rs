@@ -0,0 +1,20 @@
+use proxy_wasm::traits::Context;
+use std::time::UNIX_EPOCH;
+
+/// Host time in milliseconds since the Unix epoch.
+pub fn now_ms<C: Context + ?Sized>(ctx: &C) -> u64 {
+    (now_nanos(ctx) / 1_000_000) as u64
+}
+
+/// Host time in nanoseconds since the Unix epoch.
+pub fn now_nanos<C: Context + ?Sized>(ctx: &C) -> u128 {
+    ctx.get_current_time()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+/// Host time in seconds since the Unix epoch.
+pub fn now_secs<C: Context + ?Sized>(ctx: &C) -> u64 {
+    now_ms(ctx) / 1000
+}
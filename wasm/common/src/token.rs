@@ -0,0 +1,25 @@
+/// Extracts the credential from an auth header value such as
+/// `Bearer <token>`. The scheme is matched case-insensitively; an empty
+/// `scheme` means the header holds the bare token.
+pub fn from_header<'a>(value: &'a str, scheme: &str) -> Option<&'a str> {
+    let token = if scheme.is_empty() {
+        value
+    } else {
+        let (found, token) = value.split_once(' ')?;
+        if !found.eq_ignore_ascii_case(scheme) {
+            return None;
+        }
+        token
+    };
+    Some(token.trim()).filter(|t| !t.is_empty())
+}
+
+/// Formats an auth header value carrying `token`, the inverse of
+/// `from_header`.
+pub fn header_value(scheme: &str, token: &str) -> String {
+    if scheme.is_empty() {
+        token.to_string()
+    } else {
+        format!("{} {}", scheme, token)
+    }
+}
@@ -0,0 +1,30 @@
+/// W3C trace context headers, preferred when present.
+const W3C_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+/// Zipkin B3 headers, used only when the request has no `traceparent`.
+const B3_HEADERS: [&str; 6] = [
+    "b3",
+    "x-b3-traceid",
+    "x-b3-spanid",
+    "x-b3-parentspanid",
+    "x-b3-sampled",
+    "x-b3-flags",
+];
+
+/// Returns the trace context headers of the current request, to be copied
+/// onto a callout so it joins the request's trace. `header` looks up a
+/// request header by name.
+pub fn propagation_headers<F>(header: F) -> Vec<(&'static str, String)>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let names: &[&'static str] = if header("traceparent").is_some() {
+        &W3C_HEADERS
+    } else {
+        &B3_HEADERS
+    };
+    names
+        .iter()
+        .filter_map(|name| header(name).map(|value| (*name, value)))
+        .collect()
+}
@@ -0,0 +1,81 @@
+use proxy_wasm::types::LogLevel;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// VM-wide settings from `vm_config.configuration`, read once in
+/// `on_vm_start`. They apply to every plugin instance the VM hosts, whose
+/// own settings come from their plugin configuration, so several plugins
+/// with different settings can share one VM.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct VmConfig {
+    /// Most verbose level logged by the VM. `info` when absent.
+    pub log_level: Option<LogLevelName>,
+    /// Values substituted for `${name}` placeholders in the string values of
+    /// every plugin configuration on the VM, e.g. a PDP cluster name shared
+    /// by all plugins.
+    pub constants: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevelName {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Critical,
+}
+
+impl From<LogLevelName> for LogLevel {
+    fn from(level: LogLevelName) -> Self {
+        match level {
+            LogLevelName::Trace => LogLevel::Trace,
+            LogLevelName::Debug => LogLevel::Debug,
+            LogLevelName::Info => LogLevel::Info,
+            LogLevelName::Warn => LogLevel::Warn,
+            LogLevelName::Error => LogLevel::Error,
+            LogLevelName::Critical => LogLevel::Critical,
+        }
+    }
+}
+
+thread_local! {
+    static CONSTANTS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+/// Parses and applies the VM configuration. An empty buffer applies the
+/// defaults.
+pub fn configure(raw: &[u8]) -> Result<VmConfig, serde_json::Error> {
+    let config: VmConfig = if raw.iter().all(|b| b.is_ascii_whitespace()) {
+        VmConfig::default()
+    } else {
+        serde_json::from_slice(raw)?
+    };
+    proxy_wasm::set_log_level(config.log_level.unwrap_or(LogLevelName::Info).into());
+    CONSTANTS.with(|constants| *constants.borrow_mut() = config.constants.clone());
+    Ok(config)
+}
+
+/// Replaces the `${name}` placeholders of the VM's constants in `value`.
+/// Unknown placeholders are left as they are.
+pub fn substitute(value: &str) -> String {
+    CONSTANTS.with(|constants| {
+        let constants = constants.borrow();
+        if !value.contains("${") {
+            return value.to_string();
+        }
+        constants
+            .iter()
+            .fold(value.to_string(), |value, (name, constant)| {
+                value.replace(&format!("${{{}}}", name), constant)
+            })
+    })
+}
+
+/// Whether the VM defines any constants.
+pub fn has_constants() -> bool {
+    CONSTANTS.with(|constants| !constants.borrow().is_empty())
+}
@@ -0,0 +1,83 @@
+use serde::Deserialize;
+use wasm_common::glob;
+use wasm_common::logging::LoggingConfig;
+
+/// Plugin configuration for the CORS filter, supplied as JSON through the
+/// Envoy `configuration` field. Unconfigured, no origin is allowed and
+/// browsers keep enforcing the same-origin policy.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Origins allowed to make cross-origin requests, e.g.
+    /// `https://app.example.com`. Entries may contain `*` wildcards, e.g.
+    /// `https://*.example.com`; a lone `*` allows any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers browsers may send, matched case-insensitively.
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts may read beyond the CORS-safelisted ones.
+    pub expose_headers: Vec<String>,
+    /// Let browsers send cookies and credentials. The allowed origin is then
+    /// always echoed, since browsers refuse `*` with credentials.
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight's result. Omitted when zero.
+    pub max_age_secs: u64,
+    pub logging: LoggingConfig,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "HEAD", "POST"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            allowed_headers: ["authorization", "content-type"]
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+            expose_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_secs: 600,
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|pattern| glob::matches(pattern, origin))
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    /// Whether every header of an `access-control-request-headers` list is
+    /// allowed.
+    pub fn allows_headers(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .all(|header| {
+                self.allowed_headers
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(header))
+            })
+    }
+
+    /// The `access-control-allow-origin` value for an allowed `origin`.
+    pub fn allow_origin_value<'a>(&self, origin: &'a str) -> &'a str {
+        let any = self.allowed_origins.iter().any(|pattern| pattern == "*");
+        match any && !self.allow_credentials {
+            true => "*",
+            false => origin,
+        }
+    }
+}
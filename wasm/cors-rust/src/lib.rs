@@ -0,0 +1,174 @@
+mod config;
+#[cfg(test)]
+mod tests;
+
+use proxy_wasm::traits::*;
+use proxy_wasm::types::*;
+use std::rc::Rc;
+use wasm_common::logging::LogFields;
+use wasm_common::{log_debug, log_info, log_warn};
+
+use crate::config::FilterConfig;
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Info);
+    wasm_common::logging::init("cors");
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(CorsRoot::default())
+    });
+}}
+
+#[derive(Default)]
+struct CorsRoot {
+    config: Rc<FilterConfig>,
+}
+
+impl Context for CorsRoot {}
+
+impl RootContext for CorsRoot {
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        let raw = self.get_vm_configuration().unwrap_or_default();
+        match wasm_common::vm::configure(&raw) {
+            Ok(_) => true,
+            Err(e) => {
+                log_warn!("Invalid VM configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let raw = self.get_plugin_configuration().unwrap_or_default();
+        match wasm_common::config::parse::<FilterConfig>(&raw) {
+            Ok(config) => {
+                log_info!(
+                    "Configured: {} allowed origin(s), credentials: {}",
+                    config.allowed_origins.len(),
+                    config.allow_credentials
+                );
+                wasm_common::logging::configure(&config.logging);
+                self.config = Rc::new(config);
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid plugin configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(CorsHttp {
+            context_id,
+            config: self.config.clone(),
+            origin: None,
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+struct CorsHttp {
+    context_id: u32,
+    config: Rc<FilterConfig>,
+    /// The allowed origin of a cross-origin request, answered on its response.
+    origin: Option<String>,
+}
+
+impl Context for CorsHttp {}
+
+impl HttpContext for CorsHttp {
+    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        // Same-origin and non-browser requests carry no origin
+        let Some(origin) = self.get_http_request_header("origin") else {
+            return Action::Continue;
+        };
+        if !self.config.allows_origin(&origin) {
+            log_debug!(fields: self.log_fields(); "Origin {} not allowed", origin);
+            return Action::Continue;
+        }
+
+        let method = self.get_http_request_header(":method").unwrap_or_default();
+        let requested_method = self.get_http_request_header("access-control-request-method");
+        match requested_method {
+            Some(requested_method) if method == "OPTIONS" => {
+                self.answer_preflight(&origin, &requested_method);
+                Action::Pause
+            }
+            _ => {
+                self.origin = Some(origin);
+                Action::Continue
+            }
+        }
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        let Some(origin) = &self.origin else {
+            return Action::Continue;
+        };
+        let config = &self.config;
+        self.set_http_response_header(
+            "access-control-allow-origin",
+            Some(config.allow_origin_value(origin)),
+        );
+        if config.allow_credentials {
+            self.set_http_response_header("access-control-allow-credentials", Some("true"));
+        }
+        if !config.expose_headers.is_empty() {
+            self.set_http_response_header(
+                "access-control-expose-headers",
+                Some(&config.expose_headers.join(", ")),
+            );
+        }
+        self.add_http_response_header("vary", "Origin");
+        Action::Continue
+    }
+}
+
+impl CorsHttp {
+    fn log_fields(&self) -> LogFields<'static> {
+        LogFields {
+            context_id: Some(self.context_id),
+            ..Default::default()
+        }
+    }
+
+    /// Answers a preflight locally with 204. A preflight asking for a method
+    /// or headers that aren't allowed gets no CORS headers, which browsers
+    /// take as a refusal.
+    fn answer_preflight(&self, origin: &str, requested_method: &str) {
+        let config = &self.config;
+        let requested_headers = self
+            .get_http_request_header("access-control-request-headers")
+            .unwrap_or_default();
+        if !config.allows_method(requested_method) || !config.allows_headers(&requested_headers) {
+            log_debug!(fields: self.log_fields(); "Preflight for {} {} refused", requested_method, requested_headers);
+            self.send_http_response(204, vec![("vary", "Origin")], None);
+            return;
+        }
+
+        let methods = config.allowed_methods.join(", ");
+        let headers = config.allowed_headers.join(", ");
+        let max_age = config.max_age_secs.to_string();
+        let mut reply = vec![
+            (
+                "access-control-allow-origin",
+                config.allow_origin_value(origin),
+            ),
+            ("access-control-allow-methods", methods.as_str()),
+            ("vary", "Origin"),
+        ];
+        if !headers.is_empty() {
+            reply.push(("access-control-allow-headers", &headers));
+        }
+        if config.allow_credentials {
+            reply.push(("access-control-allow-credentials", "true"));
+        }
+        if config.max_age_secs > 0 {
+            reply.push(("access-control-max-age", &max_age));
+        }
+        self.send_http_response(204, reply, None);
+    }
+}
@@ -0,0 +1,95 @@
+//! CORS handling against the mock host.
+
+use proxy_wasm::traits::HttpContext;
+use proxy_wasm::types::Action;
+use std::rc::Rc;
+use wasm_common::mock_host;
+
+use crate::config::FilterConfig;
+use crate::CorsHttp;
+
+fn filter(config: serde_json::Value) -> CorsHttp {
+    mock_host::reset();
+    CorsHttp {
+        context_id: 2,
+        config: Rc::new(serde_json::from_value::<FilterConfig>(config).unwrap()),
+        origin: None,
+    }
+}
+
+#[test]
+fn preflight_is_answered_locally() {
+    let mut filter = filter(serde_json::json!({
+        "allowed_origins": ["https://*.example.com"],
+        "allow_credentials": true
+    }));
+    mock_host::set_request_headers(&[
+        (":method", "OPTIONS"),
+        (":path", "/api"),
+        ("origin", "https://app.example.com"),
+        ("access-control-request-method", "POST"),
+        (
+            "access-control-request-headers",
+            "Content-Type, Authorization",
+        ),
+    ]);
+
+    assert_eq!(filter.on_http_request_headers(5, true), Action::Pause);
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 204);
+    assert_eq!(
+        response.header("access-control-allow-origin"),
+        Some("https://app.example.com")
+    );
+    assert_eq!(
+        response.header("access-control-allow-methods"),
+        Some("GET, HEAD, POST")
+    );
+    assert_eq!(
+        response.header("access-control-allow-credentials"),
+        Some("true")
+    );
+    assert_eq!(response.header("access-control-max-age"), Some("600"));
+}
+
+#[test]
+fn preflight_for_disallowed_method_is_refused() {
+    let mut filter = filter(serde_json::json!({ "allowed_origins": ["*"] }));
+    mock_host::set_request_headers(&[
+        (":method", "OPTIONS"),
+        ("origin", "https://app.example.com"),
+        ("access-control-request-method", "DELETE"),
+    ]);
+
+    filter.on_http_request_headers(3, true);
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 204);
+    assert_eq!(response.header("access-control-allow-origin"), None);
+}
+
+#[test]
+fn allowed_origin_is_answered_on_the_response() {
+    let mut filter = filter(serde_json::json!({
+        "allowed_origins": ["*"],
+        "expose_headers": ["x-request-id"]
+    }));
+    mock_host::set_request_headers(&[(":method", "GET"), ("origin", "https://app.example.com")]);
+    assert_eq!(filter.on_http_request_headers(2, true), Action::Continue);
+    mock_host::set_response_headers(&[(":status", "200")]);
+    filter.on_http_response_headers(1, true);
+
+    assert_eq!(
+        mock_host::response_header("access-control-allow-origin").as_deref(),
+        Some("*")
+    );
+    assert_eq!(
+        mock_host::response_header("access-control-expose-headers").as_deref(),
+        Some("x-request-id")
+    );
+    assert_eq!(
+        mock_host::response_header("vary").as_deref(),
+        Some("Origin")
+    );
+}
@@ -0,0 +1,124 @@
+use proxy_wasm::traits::Context;
+use serde::Deserialize;
+use wasm_common::log_info;
+use wasm_common::logging::LoggingConfig;
+
+/// Plugin configuration for the header transformation filter, supplied as
+/// JSON through the Envoy `configuration` field. Without any transforms the
+/// filter lets everything through untouched.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FilterConfig {
+    pub request: Transforms,
+    pub response: Transforms,
+    /// Filter metadata namespace holding per-route overrides.
+    pub route_metadata_namespace: String,
+    pub logging: LoggingConfig,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            request: Transforms::default(),
+            response: Transforms::default(),
+            route_metadata_namespace: "header_transform".to_string(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+/// Changes to one direction's headers, applied in field order: headers are
+/// removed, then renamed, then set. Names are case-insensitive.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Transforms {
+    pub remove: Vec<String>,
+    pub rename: Vec<Rename>,
+    pub set: Vec<SetHeader>,
+}
+
+impl Transforms {
+    /// Request headers read by `request_header` sources, which response
+    /// transforms can only see if captured while the request passes.
+    pub fn request_headers_read(&self) -> impl Iterator<Item = &str> {
+        self.set.iter().filter_map(|set| match &set.source {
+            ValueSource::RequestHeader(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+}
+
+/// Moves a header's value to another name, replacing any header there.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+/// Sets a header from a source, e.g. `{"name": "x-client-ip", "property":
+/// ["source", "address"]}`. Headers whose source has no value are left
+/// alone.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SetHeader {
+    pub name: String,
+    #[serde(flatten)]
+    pub source: ValueSource,
+    /// Add the value alongside existing ones instead of replacing them.
+    #[serde(default)]
+    pub append: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueSource {
+    /// A fixed value.
+    Value(String),
+    /// Another header of the same request or response.
+    Header(String),
+    /// A header of the request, for response transforms.
+    RequestHeader(String),
+    /// An Envoy attribute by path, e.g. `["connection", "tls_version"]`.
+    Property(Vec<String>),
+}
+
+/// Per-route overrides, read from the route's filter metadata:
+///
+/// ```yaml
+/// metadata:
+///   filter_metadata:
+///     header_transform:
+///       config: '{"request": {"remove": ["x-debug"]}}'
+/// ```
+///
+/// A direction given here replaces the plugin's transforms for that
+/// direction on the route; the other direction keeps the plugin's.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RouteConfig {
+    /// Leave the route's headers untouched.
+    pub skip: bool,
+    pub request: Option<Transforms>,
+    pub response: Option<Transforms>,
+}
+
+/// Reads the overrides for the current route. Routes without metadata, or
+/// with metadata that fails to parse, get no overrides.
+pub fn load_route<C: Context + ?Sized>(ctx: &C, namespace: &str) -> RouteConfig {
+    let path = vec![
+        "xds",
+        "route_metadata",
+        "filter_metadata",
+        namespace,
+        "config",
+    ];
+    let Some(raw) = ctx.get_property(path) else {
+        return RouteConfig::default();
+    };
+    match serde_json::from_slice(&raw) {
+        Ok(route) => route,
+        Err(e) => {
+            log_info!("Ignoring invalid route config: {}", e);
+            RouteConfig::default()
+        }
+    }
+}
@@ -0,0 +1,181 @@
+mod config;
+#[cfg(test)]
+mod tests;
+
+use proxy_wasm::hostcalls;
+use proxy_wasm::traits::*;
+use proxy_wasm::types::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_common::logging::LogFields;
+use wasm_common::{log_debug, log_info, log_warn};
+
+use crate::config::{FilterConfig, Transforms, ValueSource};
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Info);
+    wasm_common::logging::init("header_transform");
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(HeaderTransformRoot::default())
+    });
+}}
+
+#[derive(Default)]
+struct HeaderTransformRoot {
+    config: Rc<FilterConfig>,
+}
+
+impl Context for HeaderTransformRoot {}
+
+impl RootContext for HeaderTransformRoot {
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        let raw = self.get_vm_configuration().unwrap_or_default();
+        match wasm_common::vm::configure(&raw) {
+            Ok(_) => true,
+            Err(e) => {
+                log_warn!("Invalid VM configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let raw = self.get_plugin_configuration().unwrap_or_default();
+        match wasm_common::config::parse::<FilterConfig>(&raw) {
+            Ok(config) => {
+                log_info!(
+                    "Configured: {} request and {} response transform(s)",
+                    count(&config.request),
+                    count(&config.response)
+                );
+                wasm_common::logging::configure(&config.logging);
+                self.config = Rc::new(config);
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid plugin configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(HeaderTransformHttp {
+            context_id,
+            config: self.config.clone(),
+            response: None,
+            captured: HashMap::new(),
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+struct HeaderTransformHttp {
+    context_id: u32,
+    config: Rc<FilterConfig>,
+    /// Response transforms for the request's route, chosen when its headers
+    /// pass. None for skipped routes.
+    response: Option<Transforms>,
+    /// Request headers read by the response transforms, by lowercase name.
+    captured: HashMap<String, String>,
+}
+
+impl Context for HeaderTransformHttp {}
+
+impl HttpContext for HeaderTransformHttp {
+    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        let route = config::load_route(self, &self.config.route_metadata_namespace);
+        if route.skip {
+            log_debug!(fields: self.log_fields(); "Route skips header transforms");
+            return Action::Continue;
+        }
+        let request = route.request.as_ref().unwrap_or(&self.config.request);
+        let response = route
+            .response
+            .unwrap_or_else(|| self.config.response.clone());
+
+        // The request's headers may be gone or changed by the time the response arrives
+        self.captured = response
+            .request_headers_read()
+            .filter_map(|name| {
+                Some((
+                    name.to_ascii_lowercase(),
+                    self.get_http_request_header(name)?,
+                ))
+            })
+            .collect();
+        apply(request, MapType::HttpRequestHeaders, &self.captured);
+        log_debug!(fields: self.log_fields(); "Applied {} request transform(s)", count(request));
+        self.response = Some(response);
+        Action::Continue
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        if let Some(response) = &self.response {
+            apply(response, MapType::HttpResponseHeaders, &self.captured);
+            log_debug!(fields: self.log_fields(); "Applied {} response transform(s)", count(response));
+        }
+        Action::Continue
+    }
+}
+
+impl HeaderTransformHttp {
+    fn log_fields(&self) -> LogFields<'static> {
+        LogFields {
+            context_id: Some(self.context_id),
+            ..Default::default()
+        }
+    }
+}
+
+fn count(transforms: &Transforms) -> usize {
+    transforms.remove.len() + transforms.rename.len() + transforms.set.len()
+}
+
+/// Applies `transforms` to the headers in `map`. `captured` holds the request
+/// headers read by response transforms.
+fn apply(transforms: &Transforms, map: MapType, captured: &HashMap<String, String>) {
+    for name in &transforms.remove {
+        let _ = hostcalls::set_map_value(map, name, None);
+    }
+    for rename in &transforms.rename {
+        if let Some(value) = header(map, &rename.from) {
+            let _ = hostcalls::set_map_value(map, &rename.from, None);
+            let _ = hostcalls::set_map_value(map, &rename.to, Some(&value));
+        }
+    }
+    for set in &transforms.set {
+        let value = match &set.source {
+            ValueSource::Value(value) => Some(value.clone()),
+            ValueSource::Header(name) => header(map, name),
+            ValueSource::RequestHeader(name) => match map {
+                MapType::HttpRequestHeaders => header(map, name),
+                _ => captured.get(&name.to_ascii_lowercase()).cloned(),
+            },
+            ValueSource::Property(path) => property(path),
+        };
+        let Some(value) = value else {
+            continue;
+        };
+        let _ = match set.append {
+            true => hostcalls::add_map_value(map, &set.name, &value),
+            false => hostcalls::set_map_value(map, &set.name, Some(&value)),
+        };
+    }
+}
+
+fn header(map: MapType, name: &str) -> Option<String> {
+    hostcalls::get_map_value(map, name).ok().flatten()
+}
+
+/// An Envoy attribute as text. Addresses, names and the like are strings;
+/// other types come back in their binary encoding and aren't useful here.
+fn property(path: &[String]) -> Option<String> {
+    let value = hostcalls::get_property(path.iter().map(String::as_str).collect())
+        .ok()
+        .flatten()?;
+    String::from_utf8(value).ok()
+}
@@ -0,0 +1,108 @@
+//! Header transforms against the mock host.
+
+use proxy_wasm::traits::HttpContext;
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_common::mock_host;
+
+use crate::config::FilterConfig;
+use crate::HeaderTransformHttp;
+
+fn filter(config: serde_json::Value) -> HeaderTransformHttp {
+    mock_host::reset();
+    HeaderTransformHttp {
+        context_id: 2,
+        config: Rc::new(serde_json::from_value::<FilterConfig>(config).unwrap()),
+        response: None,
+        captured: HashMap::new(),
+    }
+}
+
+#[test]
+fn request_headers_are_removed_renamed_and_set() {
+    let mut filter = filter(serde_json::json!({
+        "request": {
+            "remove": ["x-debug"],
+            "rename": [{"from": "x-user", "to": "x-end-user"}],
+            "set": [
+                {"name": "x-env", "value": "prod"},
+                {"name": "x-caller", "header": "x-end-user"},
+                {"name": "x-client-ip", "property": ["source", "address"]},
+                {"name": "x-missing", "property": ["no", "such"]}
+            ]
+        }
+    }));
+    mock_host::set_property(&["source", "address"], b"10.0.0.7:51000");
+    mock_host::set_request_headers(&[(":path", "/"), ("x-debug", "1"), ("x-user", "alice")]);
+
+    filter.on_http_request_headers(3, true);
+
+    assert_eq!(mock_host::request_header("x-debug"), None);
+    assert_eq!(mock_host::request_header("x-user"), None);
+    assert_eq!(
+        mock_host::request_header("x-end-user").as_deref(),
+        Some("alice")
+    );
+    assert_eq!(mock_host::request_header("x-env").as_deref(), Some("prod"));
+    assert_eq!(
+        mock_host::request_header("x-caller").as_deref(),
+        Some("alice")
+    );
+    assert_eq!(
+        mock_host::request_header("x-client-ip").as_deref(),
+        Some("10.0.0.7:51000")
+    );
+    assert_eq!(mock_host::request_header("x-missing"), None);
+}
+
+#[test]
+fn response_headers_can_echo_request_headers() {
+    let mut filter = filter(serde_json::json!({
+        "response": {
+            "remove": ["server"],
+            "set": [{"name": "x-request-id", "request_header": "x-request-id"}]
+        }
+    }));
+    mock_host::set_request_headers(&[(":path", "/"), ("x-request-id", "req-1")]);
+    filter.on_http_request_headers(2, true);
+    mock_host::set_request_headers(&[]);
+    mock_host::set_response_headers(&[(":status", "200"), ("server", "envoy")]);
+
+    filter.on_http_response_headers(2, true);
+
+    assert_eq!(mock_host::response_header("server"), None);
+    assert_eq!(
+        mock_host::response_header("x-request-id").as_deref(),
+        Some("req-1")
+    );
+}
+
+#[test]
+fn route_overrides_replace_plugin_transforms() {
+    let mut filter = filter(serde_json::json!({
+        "request": {"set": [{"name": "x-env", "value": "prod"}]},
+        "response": {"set": [{"name": "x-served-by", "value": "edge"}]}
+    }));
+    let route = br#"{"request": {"remove": ["x-debug"]}}"#;
+    mock_host::set_property(
+        &[
+            "xds",
+            "route_metadata",
+            "filter_metadata",
+            "header_transform",
+            "config",
+        ],
+        route,
+    );
+    mock_host::set_request_headers(&[(":path", "/"), ("x-debug", "1")]);
+    filter.on_http_request_headers(2, true);
+    mock_host::set_response_headers(&[(":status", "200")]);
+    filter.on_http_response_headers(1, true);
+
+    assert_eq!(mock_host::request_header("x-debug"), None);
+    assert_eq!(mock_host::request_header("x-env"), None);
+    assert_eq!(
+        mock_host::response_header("x-served-by").as_deref(),
+        Some("edge")
+    );
+}
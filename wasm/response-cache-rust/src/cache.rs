@@ -0,0 +1,133 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+
+const RESPONSE_KEY_PREFIX: &str = "response_cache:";
+
+/// Headers describing the connection rather than the response, and the one
+/// this filter sets, which aren't stored.
+const NOT_STORED: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "upgrade",
+    "proxy-connection",
+    "x-cache",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedResponse {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    /// Base64, as shared data values are JSON.
+    body: String,
+    pub stored_at_ms: u64,
+    pub expires_at_ms: u64,
+}
+
+impl CachedResponse {
+    /// A bodiless response, given its body with `with_body` once complete.
+    pub fn new(status: u32, headers: &[(String, String)], now_ms: u64, ttl_ms: u64) -> Self {
+        CachedResponse {
+            status,
+            headers: headers
+                .iter()
+                .filter(|(name, _)| {
+                    !name.starts_with(':')
+                        && !NOT_STORED.contains(&name.to_ascii_lowercase().as_str())
+                })
+                .cloned()
+                .collect(),
+            body: String::new(),
+            stored_at_ms: now_ms,
+            expires_at_ms: now_ms + ttl_ms,
+        }
+    }
+
+    pub fn with_body(self, body: &[u8]) -> Self {
+        CachedResponse {
+            body: STANDARD.encode(body),
+            ..self
+        }
+    }
+
+    pub fn body(&self) -> Vec<u8> {
+        STANDARD.decode(&self.body).unwrap_or_default()
+    }
+}
+
+/// Shared-data key for the response to a GET of `path` on `authority`.
+pub fn response_key(authority: &str, path: &str) -> String {
+    format!("{}{}{}", RESPONSE_KEY_PREFIX, authority, path)
+}
+
+/// Returns the cached response for `key` if present and still fresh.
+/// Expired entries are cleared so shared data doesn't accumulate dead values.
+pub fn lookup<C: Context + ?Sized>(ctx: &C, key: &str, now_ms: u64) -> Option<CachedResponse> {
+    let (data, cas) = ctx.get_shared_data(key);
+    let cached: CachedResponse = serde_json::from_slice(&data?).ok()?;
+    if cached.expires_at_ms <= now_ms {
+        // A CAS mismatch means another worker refreshed the entry; leave it.
+        let _ = ctx.set_shared_data(key, None, cas);
+        return None;
+    }
+    Some(cached)
+}
+
+pub fn store<C: Context + ?Sized>(ctx: &C, key: &str, cached: &CachedResponse) {
+    if let Ok(value) = serde_json::to_vec(cached) {
+        let _ = ctx.set_shared_data(key, Some(&value), None);
+    }
+}
+
+/// The `Cache-Control` directives a shared cache acts on.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub max_age_secs: Option<u64>,
+    /// `s-maxage`, which takes precedence over `max-age` for shared caches.
+    pub s_maxage_secs: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(value: &str) -> Self {
+        let mut directives = CacheControl::default();
+        for directive in value.split(',') {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "max-age" => directives.max_age_secs = argument.and_then(|a| a.parse().ok()),
+                "s-maxage" => directives.s_maxage_secs = argument.and_then(|a| a.parse().ok()),
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    /// How long a response may be served from the cache, or None if it
+    /// mustn't be stored. Responses to requests with credentials are only
+    /// stored when explicitly marked shareable.
+    pub fn ttl_ms(&self, default_ttl_ms: u64, authenticated: bool) -> Option<u64> {
+        if self.no_store || self.no_cache || self.private {
+            return None;
+        }
+        if authenticated && !self.public && self.s_maxage_secs.is_none() {
+            return None;
+        }
+        let ttl_ms = self
+            .s_maxage_secs
+            .or(self.max_age_secs)
+            .map_or(default_ttl_ms, |secs| secs * 1000);
+        Some(ttl_ms).filter(|ttl_ms| *ttl_ms > 0)
+    }
+}
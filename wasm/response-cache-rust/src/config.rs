@@ -0,0 +1,29 @@
+use serde::Deserialize;
+use wasm_common::logging::LoggingConfig;
+use wasm_common::paths::PathMatch;
+
+/// Plugin configuration for the response cache, supplied as JSON through
+/// the Envoy `configuration` field. Nothing is cached until `routes` names
+/// some paths.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Paths whose GET responses are cached, e.g. `[{"prefix": "/static/"}]`.
+    pub routes: Vec<PathMatch>,
+    /// Lifetime of responses whose `Cache-Control` gives none.
+    pub default_ttl_ms: u64,
+    /// Responses with larger bodies are passed through uncached.
+    pub max_object_bytes: usize,
+    pub logging: LoggingConfig,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            routes: Vec::new(),
+            default_ttl_ms: 60_000,
+            max_object_bytes: 64 * 1024,
+            logging: LoggingConfig::default(),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+mod cache;
+mod config;
+#[cfg(test)]
+mod tests;
+
+use proxy_wasm::traits::*;
+use proxy_wasm::types::*;
+use std::rc::Rc;
+use wasm_common::logging::LogFields;
+use wasm_common::{log_debug, log_info, log_warn, paths, time};
+
+use crate::cache::{CacheControl, CachedResponse};
+use crate::config::FilterConfig;
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Info);
+    wasm_common::logging::init("response_cache");
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(ResponseCacheRoot::default())
+    });
+}}
+
+/// Marks responses served from the cache (`HIT`) or stored in it (`MISS`).
+const CACHE_HEADER: &str = "x-cache";
+
+#[derive(Default)]
+struct ResponseCacheRoot {
+    config: Rc<FilterConfig>,
+}
+
+impl Context for ResponseCacheRoot {}
+
+impl RootContext for ResponseCacheRoot {
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        let raw = self.get_vm_configuration().unwrap_or_default();
+        match wasm_common::vm::configure(&raw) {
+            Ok(_) => true,
+            Err(e) => {
+                log_warn!("Invalid VM configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let raw = self.get_plugin_configuration().unwrap_or_default();
+        match wasm_common::config::parse::<FilterConfig>(&raw) {
+            Ok(config) => {
+                log_info!(
+                    "Configured: {} route(s), default_ttl={}ms, max_object_bytes={}",
+                    config.routes.len(),
+                    config.default_ttl_ms,
+                    config.max_object_bytes
+                );
+                wasm_common::logging::configure(&config.logging);
+                self.config = Rc::new(config);
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid plugin configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(ResponseCacheHttp {
+            context_id,
+            config: self.config.clone(),
+            key: None,
+            authenticated: false,
+            pending: None,
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+struct ResponseCacheHttp {
+    context_id: u32,
+    config: Rc<FilterConfig>,
+    /// Key of the entry a cacheable request missed, to store its response.
+    key: Option<String>,
+    /// Whether the request carried credentials.
+    authenticated: bool,
+    /// A response being stored, waiting for its body.
+    pending: Option<CachedResponse>,
+}
+
+impl Context for ResponseCacheHttp {}
+
+impl HttpContext for ResponseCacheHttp {
+    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        let method = self.get_http_request_header(":method").unwrap_or_default();
+        let path = self.get_http_request_header(":path").unwrap_or_default();
+        if method != "GET" || !paths::any_match(&self.config.routes, &path) {
+            return Action::Continue;
+        }
+        let authority = self
+            .get_http_request_header(":authority")
+            .unwrap_or_default();
+        let key = cache::response_key(&authority, &path);
+        self.authenticated = self.get_http_request_header("authorization").is_some();
+
+        // The client asked for a fresh response; fetch it, but it may still refresh the entry
+        let request_control = CacheControl::parse(
+            &self
+                .get_http_request_header("cache-control")
+                .unwrap_or_default(),
+        );
+        if request_control.no_store {
+            return Action::Continue;
+        }
+        self.key = Some(key);
+        if request_control.no_cache {
+            return Action::Continue;
+        }
+
+        let now_ms = time::now_ms(self);
+        let Some(cached) = self
+            .key
+            .as_deref()
+            .and_then(|key| cache::lookup(self, key, now_ms))
+        else {
+            log_debug!(fields: self.log_fields(); "Cache miss for {}", path);
+            return Action::Continue;
+        };
+        log_debug!(fields: self.log_fields(); "Cache hit for {}", path);
+        self.key = None;
+        let age = ((now_ms - cached.stored_at_ms) / 1000).to_string();
+        let mut headers: Vec<(&str, &str)> = cached
+            .headers
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .collect();
+        headers.push((CACHE_HEADER, "HIT"));
+        headers.push(("age", &age));
+        self.send_http_response(cached.status, headers, Some(&cached.body()));
+        Action::Pause
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        let Some(key) = self.key.take() else {
+            return Action::Continue;
+        };
+        self.set_http_response_header(CACHE_HEADER, Some("MISS"));
+        let status = self
+            .get_http_response_header(":status")
+            .and_then(|s| s.parse().ok());
+        if status != Some(200) || self.get_http_response_header("set-cookie").is_some() {
+            return Action::Continue;
+        }
+        let too_large = self
+            .get_http_response_header("content-length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .is_some_and(|length| length > self.config.max_object_bytes);
+        let control = CacheControl::parse(
+            &self
+                .get_http_response_header("cache-control")
+                .unwrap_or_default(),
+        );
+        let Some(ttl_ms) = control
+            .ttl_ms(self.config.default_ttl_ms, self.authenticated)
+            .filter(|_| !too_large)
+        else {
+            return Action::Continue;
+        };
+
+        let headers = self.get_http_response_headers();
+        let cached = CachedResponse::new(200, &headers, time::now_ms(self), ttl_ms);
+        if end_of_stream {
+            cache::store(self, &key, &cached);
+            return Action::Continue;
+        }
+        self.key = Some(key);
+        self.pending = Some(cached);
+        Action::Continue
+    }
+
+    fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        if self.pending.is_none() {
+            return Action::Continue;
+        }
+        if body_size > self.config.max_object_bytes {
+            log_debug!(fields: self.log_fields(); "Response too large to cache ({} bytes)", body_size);
+            self.pending = None;
+            return Action::Continue;
+        }
+        // Hold the body back until it is complete
+        if !end_of_stream {
+            return Action::Pause;
+        }
+        let body = self
+            .get_http_response_body(0, body_size)
+            .unwrap_or_default();
+        if let (Some(key), Some(pending)) = (self.key.take(), self.pending.take()) {
+            cache::store(self, &key, &pending.with_body(&body));
+        }
+        Action::Continue
+    }
+}
+
+impl ResponseCacheHttp {
+    fn log_fields(&self) -> LogFields<'static> {
+        LogFields {
+            context_id: Some(self.context_id),
+            ..Default::default()
+        }
+    }
+}
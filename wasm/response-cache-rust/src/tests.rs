@@ -0,0 +1,76 @@
+//! Caching of responses against the mock host.
+
+use proxy_wasm::traits::HttpContext;
+use proxy_wasm::types::{Action, BufferType};
+use std::rc::Rc;
+use wasm_common::mock_host;
+
+use crate::config::FilterConfig;
+use crate::ResponseCacheHttp;
+
+fn filter(context_id: u32) -> ResponseCacheHttp {
+    let config: FilterConfig = serde_json::from_value(serde_json::json!({
+        "routes": [{"prefix": "/static/"}],
+        "max_object_bytes": 16
+    }))
+    .unwrap();
+    ResponseCacheHttp {
+        context_id,
+        config: Rc::new(config),
+        key: None,
+        authenticated: false,
+        pending: None,
+    }
+}
+
+/// Runs a GET of `path` through a fresh filter, answering it with `body` and
+/// `cache_control` if it reaches the upstream. Returns the request's action.
+fn get(context_id: u32, path: &str, cache_control: &str, body: &[u8]) -> Action {
+    let mut filter = filter(context_id);
+    mock_host::set_request_headers(&[
+        (":method", "GET"),
+        (":path", path),
+        (":authority", "service-b"),
+    ]);
+    let action = filter.on_http_request_headers(3, true);
+    if action == Action::Continue {
+        mock_host::set_response_headers(&[(":status", "200"), ("cache-control", cache_control)]);
+        filter.on_http_response_headers(2, false);
+        mock_host::set_buffer(BufferType::HttpResponseBody, body);
+        filter.on_http_response_body(body.len(), true);
+    }
+    action
+}
+
+#[test]
+fn stored_response_is_served_locally() {
+    mock_host::reset();
+    assert_eq!(
+        get(2, "/static/a", "max-age=60", b"static data"),
+        Action::Continue
+    );
+    assert_eq!(
+        mock_host::response_header("x-cache").as_deref(),
+        Some("MISS")
+    );
+
+    assert_eq!(get(3, "/static/a", "", b""), Action::Pause);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body_str(), "static data");
+    assert_eq!(response.header("x-cache"), Some("HIT"));
+    assert_eq!(response.header("cache-control"), Some("max-age=60"));
+}
+
+#[test]
+fn uncacheable_responses_are_not_stored() {
+    mock_host::reset();
+    get(2, "/static/a", "no-store", b"secret");
+    get(3, "/static/b", "max-age=60", b"more than sixteen bytes");
+    get(4, "/api/c", "max-age=60", b"dynamic");
+
+    assert_eq!(get(5, "/static/a", "", b""), Action::Continue);
+    assert_eq!(get(6, "/static/b", "", b""), Action::Continue);
+    assert_eq!(get(7, "/api/c", "", b""), Action::Continue);
+    assert!(mock_host::local_response().is_none());
+}
@@ -0,0 +1,68 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Derives the PDP action from the request method so policies can tell read
+/// and write access apart. Methods without a mapping fall back to the
+/// filter-wide `action`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ActionMapping {
+    /// Method to action, e.g. `{"GET": "read"}`. Method names are matched
+    /// case-insensitively.
+    pub methods: HashMap<String, String>,
+    /// Path-prefix specific mappings. The longest matching prefix is
+    /// consulted first, then `methods`.
+    pub prefixes: Vec<PrefixActions>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PrefixActions {
+    pub prefix: String,
+    pub methods: HashMap<String, String>,
+}
+
+impl Default for ActionMapping {
+    fn default() -> Self {
+        let methods = [
+            ("GET", "read"),
+            ("HEAD", "read"),
+            ("POST", "write"),
+            ("PUT", "write"),
+            ("PATCH", "write"),
+            ("DELETE", "delete"),
+        ];
+        ActionMapping {
+            methods: methods
+                .iter()
+                .map(|(m, a)| (m.to_string(), a.to_string()))
+                .collect(),
+            prefixes: Vec::new(),
+        }
+    }
+}
+
+impl ActionMapping {
+    /// Returns the mapped action for `method` on `path`, ignoring any query
+    /// string, or `None` if neither a prefix rule nor the global map covers it.
+    pub fn resolve(&self, method: &str, path: &str) -> Option<String> {
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+        let prefix_rule = self
+            .prefixes
+            .iter()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len());
+
+        prefix_rule
+            .and_then(|rule| lookup(&rule.methods, method))
+            .or_else(|| lookup(&self.methods, method))
+            .cloned()
+    }
+}
+
+fn lookup<'a>(methods: &'a HashMap<String, String>, method: &str) -> Option<&'a String> {
+    methods
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(method))
+        .map(|(_, action)| action)
+}
@@ -0,0 +1,203 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Shared-data keys of the generation counters bumped by flushes. Shared data
+/// can't be enumerated, so rather than deleting entries a flush moves on to a
+/// new generation and entries from older ones are treated as absent.
+pub const DECISIONS_GENERATION_KEY: &str = "server_filter.admin.decisions_generation";
+pub const JWKS_GENERATION_KEY: &str = "server_filter.admin.jwks_generation";
+
+/// Endpoints answered by the filter itself. On `path`, `GET` dumps cache
+/// statistics as JSON and `DELETE` flushes the caches, or only the one named
+/// by a `cache=decisions` or `cache=jwks` query. On `status_path`, `GET`
+/// reports what the filter is running with. Requests must carry the secret in
+/// `secret_header`; with no secret set, all are refused.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub path: String,
+    pub status_path: String,
+    pub secret_header: String,
+    pub secret: Option<String>,
+    /// Environment variable holding the secret, set through
+    /// `vm_config.environment_variables`. Takes precedence over `secret`.
+    pub secret_env: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            path: "/__wasm/admin/cache".to_string(),
+            status_path: "/__wasm/status".to_string(),
+            secret_header: "x-admin-secret".to_string(),
+            secret: None,
+            secret_env: None,
+        }
+    }
+}
+
+impl AdminConfig {
+    /// The admin endpoint `path` is for, ignoring its query, if any.
+    pub fn endpoint(&self, path: &str) -> Option<Endpoint> {
+        let path = path.split('?').next().unwrap_or_default();
+        if path == self.path {
+            Some(Endpoint::Caches)
+        } else if path == self.status_path {
+            Some(Endpoint::Status)
+        } else {
+            None
+        }
+    }
+
+    /// Checks the secret a request presented, in constant time.
+    pub fn authorized(&self, presented: Option<&str>) -> bool {
+        let secret = self
+            .secret_env
+            .as_ref()
+            .and_then(|name| std::env::var(name).ok())
+            .or_else(|| self.secret.clone())
+            .filter(|secret| !secret.is_empty());
+        match (secret, presented) {
+            (Some(secret), Some(presented)) if secret.len() == presented.len() => {
+                secret
+                    .bytes()
+                    .zip(presented.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Caches,
+    Status,
+}
+
+/// A cache that can be flushed through the admin endpoint.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Cache {
+    /// The decision cache.
+    Decisions,
+    /// The published JWKS documents, which the root contexts refetch.
+    Jwks,
+}
+
+impl Cache {
+    /// The caches a `cache=` query value names; all of them when absent.
+    pub fn parse(name: Option<&str>) -> Result<Vec<Cache>, String> {
+        match name {
+            None | Some("all") => Ok(vec![Cache::Decisions, Cache::Jwks]),
+            Some("decisions") => Ok(vec![Cache::Decisions]),
+            Some("jwks") => Ok(vec![Cache::Jwks]),
+            Some(other) => Err(format!("Unknown cache {:?}", other)),
+        }
+    }
+
+    pub fn generation_key(self) -> &'static str {
+        match self {
+            Cache::Decisions => DECISIONS_GENERATION_KEY,
+            Cache::Jwks => JWKS_GENERATION_KEY,
+        }
+    }
+}
+
+/// Current generation of the cache whose counter is under `key`.
+pub fn generation<C: Context + ?Sized>(ctx: &C, key: &str) -> u64 {
+    parse_generation(ctx.get_shared_data(key).0)
+}
+
+/// Moves the cache whose counter is under `key` on to a new generation.
+/// Returns it, or `None` if other workers kept winning the update.
+pub fn flush<C: Context + ?Sized>(ctx: &C, key: &str) -> Option<u64> {
+    for _ in 0..8 {
+        let (data, cas) = ctx.get_shared_data(key);
+        let next = parse_generation(data) + 1;
+        if ctx
+            .set_shared_data(key, Some(next.to_string().as_bytes()), cas)
+            .is_ok()
+        {
+            return Some(next);
+        }
+    }
+    None
+}
+
+fn parse_generation(data: Option<Vec<u8>>) -> u64 {
+    data.and_then(|data| String::from_utf8(data).ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Statistics of the decision cache.
+#[derive(Serialize, Debug)]
+pub struct DecisionCacheStats {
+    pub enabled: bool,
+    pub generation: u64,
+    /// Read from the `decision_cache.hits` and `decision_cache.misses`
+    /// counters.
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Statistics of one remote JWKS.
+#[derive(Serialize, Debug)]
+pub struct JwksStats {
+    /// Shared-data key the document is published under.
+    pub source: String,
+    /// Usable keys in the published document, `None` until one is fetched.
+    pub keys: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    pub decision_cache: DecisionCacheStats,
+    pub jwks_generation: u64,
+    pub jwks: Vec<JwksStats>,
+    /// Entries of the fetched revocation list, `None` until one is fetched.
+    pub revocations: Option<usize>,
+    /// Keys of the fetched API key table, `None` until one is fetched.
+    pub api_keys: Option<usize>,
+    /// Audit records buffered by this VM, waiting to be posted.
+    pub audit_buffered: usize,
+}
+
+/// Hex SHA-256 of a raw plugin configuration.
+pub fn config_hash(raw: &[u8]) -> String {
+    Sha256::digest(raw)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// What the status endpoint reports.
+#[derive(Serialize, Debug)]
+pub struct FilterStatus {
+    pub version: &'static str,
+    /// SHA-256 of the plugin configuration, to tell which one an instance
+    /// runs with.
+    pub config_hash: String,
+    pub pdp: PdpStatus,
+    pub caches: CacheStats,
+    /// Counter values, by name without the stat prefix.
+    pub counters: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PdpStatus {
+    pub cluster: String,
+    /// Health as last probed, `None` without `health_check`.
+    pub healthy: Option<bool>,
+    /// `None` without `circuit_breaker`.
+    pub circuit_open: Option<bool>,
+    /// Callouts in flight across workers, `None` without `concurrency_limit`.
+    pub in_flight: Option<u64>,
+    /// Whether each cluster is up as far as failover is concerned, `None`
+    /// without `failover`.
+    pub clusters: Option<BTreeMap<String, bool>>,
+}
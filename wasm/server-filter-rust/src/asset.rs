@@ -0,0 +1,184 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use wasm_common::query::QueryParams;
+
+/// Where an asset id may be taken from. Rules are tried in order and the
+/// first one producing a non-empty value wins:
+///
+/// ```json
+/// "asset_rules": [
+///   {"header": "x-asset-id"},
+///   {"path_regex": "^/assets/([^/?]+)"},
+///   {"query": "asset"},
+///   {"body_pointer": "/resource/id"}
+/// ]
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "RawAssetRule")]
+pub enum AssetRule {
+    /// Value of the named query parameter.
+    Query(String),
+    /// Capture group of a regex matched against the request path.
+    PathRegex { regex: Regex, group: usize },
+    /// Value of the named request header.
+    Header(String),
+    /// String or number at a JSON pointer into the request body. Requests
+    /// with a body are held until it has been fully received.
+    BodyPointer(String),
+}
+
+/// Wire form of a rule. Exactly one source must be set; `group` only applies
+/// to `path_regex` and defaults to the first capture group.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAssetRule {
+    query: Option<String>,
+    path_regex: Option<String>,
+    group: Option<usize>,
+    header: Option<String>,
+    body_pointer: Option<String>,
+}
+
+impl TryFrom<RawAssetRule> for AssetRule {
+    type Error = String;
+
+    fn try_from(raw: RawAssetRule) -> Result<Self, Self::Error> {
+        match (raw.query, raw.path_regex, raw.header, raw.body_pointer) {
+            (Some(name), None, None, None) => Ok(AssetRule::Query(name)),
+            (None, Some(pattern), None, None) => {
+                let regex =
+                    Regex::new(&pattern).map_err(|e| format!("invalid path_regex: {}", e))?;
+                let group = raw.group.unwrap_or(1);
+                if group >= regex.captures_len() {
+                    return Err(format!(
+                        "path_regex {:?} has no capture group {}",
+                        pattern, group
+                    ));
+                }
+                Ok(AssetRule::PathRegex { regex, group })
+            }
+            (None, None, Some(name), None) => Ok(AssetRule::Header(name)),
+            (None, None, None, Some(pointer)) => {
+                if !pointer.is_empty() && !pointer.starts_with('/') {
+                    return Err(format!("body_pointer {:?} is not a JSON pointer", pointer));
+                }
+                Ok(AssetRule::BodyPointer(pointer))
+            }
+            _ => Err(
+                "asset rule needs exactly one of query, path_regex, header or body_pointer"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// An extra PDP query evaluated in the same call as the request's primary
+/// (asset, action) query, e.g. for a parent resource or per requested scope.
+/// Its rules use the same syntax as `asset_rules`.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AdditionalQuery {
+    /// Rules producing the asset id. Empty means the request's asset.
+    pub asset_rules: Vec<AssetRule>,
+    /// Rules producing the action. Empty means `action`, or the request's
+    /// action when that is unset too.
+    pub action_rules: Vec<AssetRule>,
+    pub action: Option<String>,
+    /// Splits extracted values on this separator into one query per part,
+    /// e.g. `" "` for a space-delimited scope list.
+    pub separator: Option<String>,
+}
+
+impl AdditionalQuery {
+    pub fn needs_body(&self) -> bool {
+        needs_body(&self.asset_rules) || needs_body(&self.action_rules)
+    }
+
+    /// Builds the (asset, action) pairs for this query. Produces nothing if
+    /// a rule list is configured but matches nothing.
+    pub fn expand<F>(
+        &self,
+        path: &str,
+        body: Option<&[u8]>,
+        header: F,
+        asset: &str,
+        action: &str,
+    ) -> Vec<(String, String)>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let values = |rules: &[AssetRule], fallback: &str| -> Vec<String> {
+            let value = if rules.is_empty() {
+                fallback.to_string()
+            } else {
+                match extract(rules, path, body, &header) {
+                    Some(value) => value,
+                    None => return Vec::new(),
+                }
+            };
+            match &self.separator {
+                Some(sep) if !rules.is_empty() => value
+                    .split(sep.as_str())
+                    .filter(|part| !part.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                _ => vec![value],
+            }
+        };
+
+        let assets = values(&self.asset_rules, asset);
+        let actions = values(&self.action_rules, self.action.as_deref().unwrap_or(action));
+        assets
+            .iter()
+            .flat_map(|asset| {
+                actions
+                    .iter()
+                    .map(move |action| (asset.clone(), action.clone()))
+            })
+            .collect()
+    }
+}
+
+/// The rules used when none are configured: the `asset` query parameter.
+pub fn default_rules() -> Vec<AssetRule> {
+    vec![AssetRule::Query("asset".to_string())]
+}
+
+/// Whether any rule reads the request body.
+pub fn needs_body(rules: &[AssetRule]) -> bool {
+    rules
+        .iter()
+        .any(|rule| matches!(rule, AssetRule::BodyPointer(_)))
+}
+
+/// Applies `rules` to the request, returning the first non-empty asset id.
+/// `header` looks up a request header by name; `body` is the buffered request
+/// body, if any. Body rules never match a body that isn't valid JSON.
+pub fn extract<F>(rules: &[AssetRule], path: &str, body: Option<&[u8]>, header: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let query = QueryParams::from_path(path);
+    let body: Option<Value> = body.and_then(|b| serde_json::from_slice(b).ok());
+    rules.iter().find_map(|rule| {
+        let value = match rule {
+            AssetRule::Query(name) => query.get(name).map(str::to_string),
+            AssetRule::PathRegex { regex, group } => {
+                let path = path.split(['?', '#']).next().unwrap_or(path);
+                regex
+                    .captures(path)
+                    .and_then(|caps| caps.get(*group))
+                    .map(|m| m.as_str().to_string())
+            }
+            AssetRule::Header(name) => header(name),
+            AssetRule::BodyPointer(pointer) => match body.as_ref().and_then(|b| b.pointer(pointer))
+            {
+                Some(Value::String(s)) => Some(s.clone()),
+                Some(Value::Number(n)) => Some(n.to_string()),
+                _ => None,
+            },
+        };
+        value.filter(|v| !v.is_empty())
+    })
+}
@@ -0,0 +1,167 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Where audit records are sent. Records are buffered per VM and posted as a
+/// JSON array by the root context, so there is at most one callout per
+/// `max_batch` records rather than one per request.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub cluster: String,
+    pub path: String,
+    pub authority: String,
+    pub timeout_ms: u64,
+    pub flush_interval_ms: u64,
+    /// Records per callout.
+    pub max_batch: usize,
+    /// Records held while the sink is slow or down; the oldest are dropped
+    /// beyond this.
+    pub max_buffered: usize,
+    /// Hand records to a singleton flusher over a shared queue instead of
+    /// posting them from every VM. Disabled when absent.
+    pub queue: Option<AuditQueueConfig>,
+    /// Request and response trailers copied into records, e.g. gRPC
+    /// metadata sent at the end of a stream. When set, a request's record
+    /// is held until its stream ends so it can include them.
+    pub trailers: Vec<String>,
+}
+
+/// Delivery through an Envoy shared queue. The flusher is this filter loaded
+/// as a singleton wasm service with the same `audit` settings and `flusher`
+/// set: it registers the queue, drains it every `flush_interval_ms` and posts
+/// the batches. Until the queue exists, VMs buffer and post records
+/// themselves.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AuditQueueConfig {
+    pub name: String,
+    /// `vm_id` of the flusher's VM, which owns the queue.
+    pub vm_id: String,
+    /// Set on the flusher's configuration only.
+    pub flusher: bool,
+}
+
+impl Default for AuditQueueConfig {
+    fn default() -> Self {
+        AuditQueueConfig {
+            name: "server_filter.audit".to_string(),
+            vm_id: String::new(),
+            flusher: false,
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            cluster: "audit-sink".to_string(),
+            path: "/audit/events".to_string(),
+            authority: "audit-sink".to_string(),
+            timeout_ms: 5000,
+            flush_interval_ms: 1000,
+            max_batch: 100,
+            max_buffered: 1000,
+            queue: None,
+            trailers: Vec::new(),
+        }
+    }
+}
+
+impl AuditConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval_ms)
+    }
+}
+
+/// One authorization outcome.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub timestamp_ms: u64,
+    pub request_id: String,
+    pub principal: String,
+    pub asset: String,
+    pub action: String,
+    /// `Allow`, `Deny`, or `Error` when the PDP produced no decision.
+    pub decision: String,
+    pub reason: String,
+    /// Where the decision came from: `pdp`, `coalesced` for a request
+    /// sharing another's PDP call, `cache`, `failure_mode`, `local_policy`,
+    /// `deny_list`, `block`, `rate_limit`, `quota`, or `response` for a
+    /// response withheld by an obligation.
+    pub source: Cow<'static, str>,
+    /// PDP round trip; zero for cached decisions.
+    pub latency_ms: u64,
+    /// Set for denies let through in `shadow` enforcement mode.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shadow: bool,
+    /// Values of the configured `trailers` the stream carried.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trailers: BTreeMap<String, String>,
+}
+
+/// Records waiting to be flushed, shared by a root context and its HTTP
+/// contexts.
+pub type AuditBuffer = Rc<RefCell<VecDeque<AuditRecord>>>;
+
+pub fn push(buffer: &AuditBuffer, record: AuditRecord, max_buffered: usize) {
+    let mut buffer = buffer.borrow_mut();
+    while buffer.len() >= max_buffered.max(1) {
+        buffer.pop_front();
+    }
+    buffer.push_back(record);
+}
+
+/// Removes and returns up to `max_batch` of the oldest records.
+pub fn take_batch(buffer: &AuditBuffer, max_batch: usize) -> Vec<AuditRecord> {
+    let mut buffer = buffer.borrow_mut();
+    let count = buffer.len().min(max_batch.max(1));
+    buffer.drain(..count).collect()
+}
+
+/// Id of the audit queue once registered or resolved, shared by a root
+/// context and its HTTP contexts.
+pub type AuditQueue = Rc<Cell<Option<u32>>>;
+
+/// Hands a record to the flusher. Returns false when there is no queue to
+/// put it on.
+pub fn enqueue<C: Context + ?Sized>(ctx: &C, queue: &AuditQueue, record: &AuditRecord) -> bool {
+    let Some(queue_id) = queue.get() else {
+        return false;
+    };
+    let Ok(value) = serde_json::to_vec(record) else {
+        return false;
+    };
+    if ctx.enqueue_shared_queue(queue_id, Some(&value)).is_err() {
+        // The flusher went away; resolve its queue anew
+        queue.set(None);
+        return false;
+    }
+    true
+}
+
+/// Moves the records waiting on the queue into the buffer. Returns how many
+/// were moved.
+pub fn drain<C: Context + ?Sized>(
+    ctx: &C,
+    queue_id: u32,
+    buffer: &AuditBuffer,
+    max_buffered: usize,
+) -> usize {
+    let mut drained = 0;
+    while let Ok(Some(value)) = ctx.dequeue_shared_queue(queue_id) {
+        if let Ok(record) = serde_json::from_slice(&value) {
+            push(buffer, record, max_buffered);
+            drained += 1;
+        }
+    }
+    drained
+}
@@ -0,0 +1,142 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use wasm_common::log_info;
+
+/// Shared-data key holding the PDP circuit state. Keeping it in shared data
+/// lets every worker see failures observed by the others.
+const BREAKER_KEY: &str = "server_filter.pdp_breaker";
+
+/// Attempts at a compare-and-swap update before an outcome is dropped.
+const CAS_RETRIES: usize = 4;
+
+/// Opens the PDP circuit once the error rate over the recent window crosses
+/// `error_rate`. While open, requests skip the PDP callout entirely.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Length of the sliding window failures are counted over.
+    pub window_ms: u64,
+    /// Callouts required within the window before the circuit may open, so
+    /// a couple of early failures don't trip it.
+    pub min_requests: u32,
+    /// Failure ratio (0.0-1.0) at which the circuit opens.
+    pub error_rate: f64,
+    /// How long the circuit stays open before callouts are attempted again.
+    pub cooldown_ms: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            window_ms: 10_000,
+            min_requests: 20,
+            error_rate: 0.5,
+            cooldown_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct Counts {
+    total: u32,
+    failures: u32,
+}
+
+/// The window is approximated with two fixed buckets: the current one and
+/// the previous one, weighted by how much of it still overlaps the window.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct BreakerState {
+    bucket_start_ms: u64,
+    current: Counts,
+    previous: Counts,
+    open_until_ms: u64,
+}
+
+impl BreakerState {
+    fn roll(&mut self, window_ms: u64, now_ms: u64) {
+        let elapsed = now_ms.saturating_sub(self.bucket_start_ms);
+        if elapsed < window_ms {
+            return;
+        }
+        self.previous = if elapsed < 2 * window_ms {
+            self.current
+        } else {
+            Counts::default()
+        };
+        self.current = Counts::default();
+        self.bucket_start_ms = now_ms - elapsed % window_ms;
+    }
+
+    /// Estimated (total, failures) over the trailing window.
+    fn window(&self, window_ms: u64, now_ms: u64) -> (f64, f64) {
+        let elapsed = now_ms.saturating_sub(self.bucket_start_ms).min(window_ms);
+        let weight = 1.0 - elapsed as f64 / window_ms.max(1) as f64;
+        (
+            self.current.total as f64 + self.previous.total as f64 * weight,
+            self.current.failures as f64 + self.previous.failures as f64 * weight,
+        )
+    }
+}
+
+/// Whether a PDP callout may be attempted. Returns `false` while the circuit
+/// is open.
+pub fn allows_request<C: Context + ?Sized>(ctx: &C, now_ms: u64) -> bool {
+    let (state, _) = load(ctx);
+    state.open_until_ms <= now_ms
+}
+
+/// Records the outcome of a PDP callout, opening the circuit if the error
+/// rate over the window reaches the configured threshold.
+pub fn record<C: Context + ?Sized>(
+    ctx: &C,
+    config: &CircuitBreakerConfig,
+    now_ms: u64,
+    success: bool,
+) {
+    for _ in 0..CAS_RETRIES {
+        let (mut state, cas) = load(ctx);
+        if state.open_until_ms > now_ms {
+            // Late responses from before the circuit opened don't count.
+            return;
+        }
+
+        state.roll(config.window_ms, now_ms);
+        state.current.total += 1;
+        if !success {
+            state.current.failures += 1;
+        }
+
+        let (total, failures) = state.window(config.window_ms, now_ms);
+        if !success && total >= config.min_requests as f64 && failures / total >= config.error_rate
+        {
+            log_info!(
+                "PDP circuit opened for {}ms ({:.0} of {:.0} callouts failed)",
+                config.cooldown_ms,
+                failures,
+                total
+            );
+            state = BreakerState {
+                bucket_start_ms: now_ms,
+                open_until_ms: now_ms + config.cooldown_ms,
+                ..Default::default()
+            };
+        }
+
+        let Ok(value) = serde_json::to_vec(&state) else {
+            return;
+        };
+        match ctx.set_shared_data(BREAKER_KEY, Some(&value), cas) {
+            Err(Status::CasMismatch) => continue,
+            _ => return,
+        }
+    }
+}
+
+fn load<C: Context + ?Sized>(ctx: &C) -> (BreakerState, Option<u32>) {
+    let (data, cas) = ctx.get_shared_data(BREAKER_KEY);
+    let state = data
+        .and_then(|d| serde_json::from_slice(&d).ok())
+        .unwrap_or_default();
+    (state, cas)
+}
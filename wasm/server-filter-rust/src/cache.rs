@@ -0,0 +1,106 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use wasm_common::connection::ConnectionAttributes;
+use wasm_common::pdp::{Obligations, Principal, Query};
+
+use crate::admin;
+
+const DECISION_KEY_PREFIX: &str = "server_filter.decision:";
+
+/// Decision cache settings. A TTL of zero disables caching for that outcome.
+/// For cached outcomes, a validity the PDP reports with a decision
+/// (`ttl_secs` or `expires_at`) is used in place of the configured TTL.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DecisionCacheConfig {
+    /// TTL for Allow decisions.
+    pub ttl_ms: u64,
+    /// TTL for Deny decisions, typically much shorter than `ttl_ms` so policy
+    /// changes granting access take effect quickly.
+    pub deny_ttl_ms: u64,
+}
+
+impl DecisionCacheConfig {
+    pub fn enabled(&self) -> bool {
+        self.ttl_ms > 0 || self.deny_ttl_ms > 0
+    }
+
+    pub fn ttl_for(&self, decision: &str, pdp_validity_ms: Option<u64>) -> u64 {
+        let ttl_ms = if decision == "Allow" {
+            self.ttl_ms
+        } else {
+            self.deny_ttl_ms
+        };
+        match ttl_ms {
+            0 => 0,
+            ttl_ms => pdp_validity_ms.unwrap_or(ttl_ms),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedDecision {
+    pub decision: String,
+    pub reason: String,
+    #[serde(default)]
+    pub obligations: Obligations,
+    pub expires_at_ms: u64,
+    /// Decision cache generation the entry was stored in; entries from
+    /// before the last flush are ignored.
+    #[serde(default)]
+    pub generation: u64,
+}
+
+/// Shared-data key for a principal, along with any actor, and the queries
+/// evaluated for it, in the request context and connection sent along, kept
+/// apart per tenant. The parts are JSON-encoded so values containing
+/// separators cannot collide.
+pub fn decision_key(
+    tenant: Option<&str>,
+    principal: &Principal,
+    queries: &[Query],
+    context: &BTreeMap<String, String>,
+    connection: Option<&ConnectionAttributes>,
+) -> String {
+    let parts = match (context.is_empty(), connection) {
+        (true, None) => serde_json::to_string(&(principal, queries)),
+        (false, None) => serde_json::to_string(&(principal, queries, context)),
+        (_, Some(connection)) => serde_json::to_string(&(principal, queries, context, connection)),
+    }
+    .unwrap_or_default();
+    match tenant {
+        Some(tenant) => format!(
+            "{}{}{}",
+            DECISION_KEY_PREFIX,
+            serde_json::json!(tenant),
+            parts
+        ),
+        None => format!("{}{}", DECISION_KEY_PREFIX, parts),
+    }
+}
+
+/// Returns the cached decision for `key` if present, not yet expired and
+/// not flushed. Dead entries are cleared so shared data doesn't accumulate
+/// them.
+pub fn lookup<C: Context + ?Sized>(ctx: &C, key: &str, now_ms: u64) -> Option<CachedDecision> {
+    let (data, cas) = ctx.get_shared_data(key);
+    let cached: CachedDecision = serde_json::from_slice(&data?).ok()?;
+    if cached.expires_at_ms <= now_ms || cached.generation != generation(ctx) {
+        // A CAS mismatch means another worker refreshed the entry; leave it.
+        let _ = ctx.set_shared_data(key, None, cas);
+        return None;
+    }
+    Some(cached)
+}
+
+/// Current generation of the decision cache, moved on by admin flushes.
+pub fn generation<C: Context + ?Sized>(ctx: &C) -> u64 {
+    admin::generation(ctx, admin::DECISIONS_GENERATION_KEY)
+}
+
+pub fn store<C: Context + ?Sized>(ctx: &C, key: &str, cached: &CachedDecision) {
+    if let Ok(value) = serde_json::to_vec(cached) {
+        let _ = ctx.set_shared_data(key, Some(&value), None);
+    }
+}
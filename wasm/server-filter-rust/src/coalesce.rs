@@ -0,0 +1,130 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use wasm_common::pdp::{Obligations, Quota};
+
+const FLIGHT_KEY_PREFIX: &str = "server_filter.pdp_flight:";
+
+/// Coalescing of identical PDP evaluations. While one request's callout for
+/// a principal, set of queries and context is outstanding, requests needing
+/// the same evaluation are parked and resumed with its result instead of
+/// calling the PDP themselves.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CoalescingConfig {
+    /// How long requests in other VMs wait for an evaluation before giving
+    /// up on it and applying the failure mode. Should exceed
+    /// `pdp_timeout_ms`.
+    pub lease_ms: u64,
+    /// How often parked requests check for an evaluation finished by
+    /// another VM.
+    pub poll_ms: u64,
+}
+
+impl Default for CoalescingConfig {
+    fn default() -> Self {
+        CoalescingConfig {
+            lease_ms: 10_000,
+            poll_ms: 50,
+        }
+    }
+}
+
+impl CoalescingConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_ms)
+    }
+}
+
+/// The decision of an evaluation, as applied to every request sharing it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Outcome {
+    pub decision: String,
+    pub reason: String,
+    #[serde(default)]
+    pub obligations: Obligations,
+    #[serde(default)]
+    pub quota: Option<Quota>,
+}
+
+/// Per-VM bookkeeping for an evaluation. At most one HTTP context per VM
+/// dispatches the callout; the others park here.
+pub struct Flight<W> {
+    /// True while a context in this VM has the callout outstanding.
+    pub leading: bool,
+    pub waiters: Vec<W>,
+}
+
+impl<W> Default for Flight<W> {
+    fn default() -> Self {
+        Flight {
+            leading: false,
+            waiters: Vec::new(),
+        }
+    }
+}
+
+/// Evaluations keyed by their decision cache key.
+pub type Flights<W> = Rc<RefCell<HashMap<String, Flight<W>>>>;
+
+/// Progress of an evaluation as seen by every VM.
+#[derive(Serialize, Deserialize)]
+enum FlightState {
+    /// Outstanding in some VM until the lease runs out.
+    Evaluating {
+        lease_until_ms: u64,
+    },
+    Decided(Box<Outcome>),
+    /// Finished without a usable decision.
+    Failed,
+}
+
+/// Shared-data key tracking the evaluation with `decision_key` across VMs.
+pub fn flight_key(decision_key: &str) -> String {
+    format!("{}{}", FLIGHT_KEY_PREFIX, decision_key)
+}
+
+/// Claims the evaluation for this VM unless another holds an unexpired
+/// lease on it.
+pub fn try_lead<C: Context + ?Sized>(ctx: &C, key: &str, now_ms: u64, lease_ms: u64) -> bool {
+    let (data, cas) = ctx.get_shared_data(key);
+    if let Some(FlightState::Evaluating { lease_until_ms }) = parse(data.as_deref()) {
+        if lease_until_ms > now_ms {
+            return false;
+        }
+    }
+    let state = FlightState::Evaluating {
+        lease_until_ms: now_ms + lease_ms,
+    };
+    let state = serde_json::to_vec(&state).unwrap_or_default();
+    ctx.set_shared_data(key, Some(&state), cas).is_ok()
+}
+
+/// Publishes the end of an evaluation, with its decision if it produced one.
+pub fn finish<C: Context + ?Sized>(ctx: &C, key: &str, outcome: Option<&Outcome>) {
+    let state = match outcome {
+        Some(outcome) => FlightState::Decided(Box::new(outcome.clone())),
+        None => FlightState::Failed,
+    };
+    let state = serde_json::to_vec(&state).unwrap_or_default();
+    let _ = ctx.set_shared_data(key, Some(&state), None);
+}
+
+/// What became of an evaluation led by another VM: `None` while it is
+/// still outstanding, otherwise its decision, if any. A lease that ran out
+/// counts as failed.
+pub fn poll<C: Context + ?Sized>(ctx: &C, key: &str, now_ms: u64) -> Option<Option<Outcome>> {
+    let (data, _) = ctx.get_shared_data(key);
+    match parse(data.as_deref()) {
+        Some(FlightState::Evaluating { lease_until_ms }) if lease_until_ms > now_ms => None,
+        Some(FlightState::Decided(outcome)) => Some(Some(*outcome)),
+        _ => Some(None),
+    }
+}
+
+fn parse(data: Option<&[u8]>) -> Option<FlightState> {
+    serde_json::from_slice(data?).ok()
+}
@@ -0,0 +1,84 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::Deserialize;
+
+/// Shared-data key counting the PDP callouts in flight across all workers.
+const IN_FLIGHT_KEY: &str = "server_filter.pdp_in_flight";
+
+/// Attempts at a compare-and-swap update before giving up.
+const CAS_RETRIES: usize = 8;
+
+/// Caps the PDP callouts in flight at once, so a slow PDP can't leave a
+/// worker with thousands of paused streams. Requests over the cap are
+/// handled according to `overflow` without calling the PDP.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConcurrencyLimitConfig {
+    pub max_in_flight: u64,
+    pub overflow: Overflow,
+}
+
+impl Default for ConcurrencyLimitConfig {
+    fn default() -> Self {
+        ConcurrencyLimitConfig {
+            max_in_flight: 100,
+            overflow: Overflow::Reject,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Reply with the `overloaded` response, a 503 by default.
+    #[default]
+    Reject,
+    /// Let the request through without a decision.
+    FailOpen,
+}
+
+/// Takes a slot for a PDP callout. Returns false if `max_in_flight` are
+/// already taken.
+pub fn acquire<C: Context + ?Sized>(ctx: &C, max_in_flight: u64) -> bool {
+    for _ in 0..CAS_RETRIES {
+        let (in_flight, cas) = load(ctx);
+        if in_flight >= max_in_flight {
+            return false;
+        }
+        match ctx.set_shared_data(
+            IN_FLIGHT_KEY,
+            Some((in_flight + 1).to_string().as_bytes()),
+            cas,
+        ) {
+            Err(Status::CasMismatch) => continue,
+            result => return result.is_ok(),
+        }
+    }
+    false
+}
+
+/// Gives back a slot taken by `acquire`.
+pub fn release<C: Context + ?Sized>(ctx: &C) {
+    for _ in 0..CAS_RETRIES {
+        let (in_flight, cas) = load(ctx);
+        let value = in_flight.saturating_sub(1).to_string();
+        match ctx.set_shared_data(IN_FLIGHT_KEY, Some(value.as_bytes()), cas) {
+            Err(Status::CasMismatch) => continue,
+            _ => return,
+        }
+    }
+}
+
+/// PDP callouts currently in flight.
+pub fn in_flight<C: Context + ?Sized>(ctx: &C) -> u64 {
+    load(ctx).0
+}
+
+fn load<C: Context + ?Sized>(ctx: &C) -> (u64, Option<u32>) {
+    let (data, cas) = ctx.get_shared_data(IN_FLIGHT_KEY);
+    let in_flight = data
+        .and_then(|d| String::from_utf8(d).ok())
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    (in_flight, cas)
+}
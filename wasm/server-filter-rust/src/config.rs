@@ -0,0 +1,530 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::Duration;
+use wasm_common::logging::LoggingConfig;
+use wasm_common::paths::PathMatch;
+use wasm_common::pdp::{CombineMode, PdpTransport};
+use wasm_common::token;
+
+use crate::action::ActionMapping;
+use crate::admin::AdminConfig;
+use crate::asset::{self, AdditionalQuery, AssetRule};
+use crate::audit::AuditConfig;
+use crate::breaker::CircuitBreakerConfig;
+use crate::cache::DecisionCacheConfig;
+use crate::coalesce::CoalescingConfig;
+use crate::concurrency::ConcurrencyLimitConfig;
+use crate::credentials::ApiKeyConfig;
+use crate::delegation::DelegationConfig;
+use crate::ext_authz::ExtAuthzConfig;
+use crate::failover::FailoverConfig;
+use crate::grpc::GrpcConfig;
+use crate::health::HealthCheckConfig;
+use crate::hedge::HedgingConfig;
+use crate::identity_chain::IdentityChainConfig;
+use crate::jwks::{self, RemoteJwks};
+use crate::jwt::{Jwks, KeySet, ValidationRules};
+use crate::local_policy::LocalPolicy;
+use crate::pip::PipConfig;
+use crate::protocol::{AuthzenConfig, PdpEncoding, PdpProtocol};
+use crate::ratelimit::RateLimitConfig;
+use crate::redact::RedactionConfig;
+use crate::replay::ReplayConfig;
+use crate::response::ResponseTemplates;
+use crate::revocation::RevocationConfig;
+use crate::security_events::SecurityEventsConfig;
+use crate::signature::RequestSigningConfig;
+use crate::spiffe::SpiffeConfig;
+use crate::tenant::TenancyConfig;
+use crate::upgrade::UpgradeConfig;
+
+/// Plugin configuration for the server filter, supplied as JSON through the
+/// Envoy `configuration` field. Every field is optional and falls back to the
+/// values the filter used before it became configurable.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FilterConfig {
+    pub pdp_cluster: String,
+    pub pdp_protocol: PdpProtocol,
+    /// Transport for the `sgnl` protocol; other protocols always use HTTP.
+    pub pdp_transport: PdpTransport,
+    /// Body encoding for the `sgnl` protocol over HTTP.
+    pub pdp_encoding: PdpEncoding,
+    pub pdp_path: String,
+    pub pdp_authority: String,
+    /// Fully-qualified gRPC service and method used with the `grpc` transport.
+    pub pdp_grpc_service: String,
+    pub pdp_grpc_method: String,
+    /// OPA package evaluated with the `opa` protocol, e.g. `envoy.authz`.
+    pub opa_package: String,
+    /// Endpoints and entity types used with the `authzen` protocol.
+    pub authzen: AuthzenConfig,
+    /// Settings for the `ext_authz` protocol.
+    pub ext_authz: ExtAuthzConfig,
+    pub pdp_timeout_ms: u64,
+    /// Stops calling the PDP for a while after repeated failures. Disabled
+    /// when absent.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Shares one PDP callout between concurrent requests needing the same
+    /// evaluation. Disabled when absent.
+    pub coalescing: Option<CoalescingConfig>,
+    /// Probes the PDP in the background, so requests fail fast while it is
+    /// down. Disabled when absent.
+    pub health_check: Option<HealthCheckConfig>,
+    /// Secondary PDP clusters to retry on when `pdp_cluster` fails. With
+    /// failover, requests go to a secondary instead of failing fast while
+    /// `health_check` finds the primary down. Disabled when absent.
+    pub failover: Option<FailoverConfig>,
+    /// Sends a second PDP call when the first is slow. Disabled when absent.
+    pub hedging: Option<HedgingConfig>,
+    /// Adds attributes of the principal fetched from a Policy Information
+    /// Point to the evaluation context. Disabled when absent.
+    pub pip: Option<PipConfig>,
+    /// Per-principal request rate limit, checked before the PDP is called.
+    /// Disabled when absent.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// What to do when the PDP cannot produce a decision.
+    pub failure_mode: FailureMode,
+    /// Whether the policy's denies are enforced or only recorded.
+    pub enforcement_mode: EnforcementMode,
+    /// Rules applied under the `local_fallback` failure mode.
+    pub local_policy: LocalPolicy,
+    /// Action sent to the PDP when no method mapping applies.
+    pub action: String,
+    /// Paths passed through without authentication or a PDP call, such as
+    /// health checks and public metadata.
+    pub bypass_paths: Vec<PathMatch>,
+    /// Paths rejected with 403 before any other check. Takes precedence over
+    /// `bypass_paths`.
+    pub deny_paths: Vec<PathMatch>,
+    /// Method-based action mapping. When absent every request uses `action`.
+    pub action_mapping: Option<ActionMapping>,
+    /// Ordered rules for extracting the asset id from the request.
+    pub asset_rules: Vec<AssetRule>,
+    /// Extra queries sent in the same PDP evaluation as the primary one.
+    pub additional_queries: Vec<AdditionalQuery>,
+    /// How the decisions for all queries combine.
+    pub combine: CombineMode,
+    /// Authorize gRPC requests by service and method and reject them with
+    /// gRPC statuses. Disabled when absent.
+    pub grpc: Option<GrpcConfig>,
+    /// Handling of WebSocket and other upgrade requests.
+    pub upgrades: UpgradeConfig,
+    /// Largest request body buffered for body-based asset rules. Larger
+    /// bodies are rejected with 413, up front when their `content-length`
+    /// gives them away, otherwise as soon as the buffered bytes exceed it.
+    pub max_request_body_bytes: usize,
+    /// Local JWT verification. When absent the token is forwarded to the PDP
+    /// without being checked.
+    pub jwt: Option<JwtConfig>,
+    /// Rejection of revoked tokens, by a list fetched from an endpoint.
+    /// Disabled when absent.
+    pub revocation: Option<RevocationConfig>,
+    /// Require HMAC-signed requests, rejecting others with 401. Disabled
+    /// when absent.
+    pub request_signing: Option<RequestSigningConfig>,
+    /// Reject requests replaying a token, nonce or signature. Disabled when
+    /// absent.
+    pub replay: Option<ReplayConfig>,
+    /// Endpoint for inspecting and flushing the filter's caches. Disabled
+    /// when absent.
+    pub admin: Option<AdminConfig>,
+    /// Cap on PDP callouts in flight across workers. Disabled when absent.
+    pub concurrency_limit: Option<ConcurrencyLimitConfig>,
+    /// Names of the request headers carrying credentials and identity.
+    pub headers: HeaderNames,
+    /// Request headers copied into the evaluation's `context`, keyed by
+    /// lowercase name, e.g. `user-agent`, `x-forwarded-for` or business
+    /// headers policies should see. Headers absent from a request are left
+    /// out. Each distinct context is cached as a separate decision.
+    pub context_headers: Vec<String>,
+    /// Token claims copied into the evaluation's `context`, as a map of
+    /// attribute name to claim path, e.g. `{"tenant": "/ext/tenant_id"}`.
+    /// Paths are JSON pointers or dotted paths as for `principal.claim`;
+    /// arrays such as `groups` are joined with commas. Claims absent from a
+    /// token are left out.
+    pub claim_attributes: BTreeMap<String, String>,
+    /// Verify the identity chain of multi-hop calls and send it to the PDP.
+    /// Disabled when absent.
+    pub identity_chain: Option<IdentityChainConfig>,
+    /// Send the connection's source and destination addresses, SNI, TLS
+    /// version and mTLS peer identity to the PDP as `connection`, for
+    /// network-level policy conditions. Since the source address is part of
+    /// it, decisions are then cached per client address.
+    pub connection_attributes: bool,
+    /// Add the annotation left by a client filter earlier in the same chain
+    /// to the evaluation's `context`, as `caller.service_id`,
+    /// `caller.audience` and `caller.scope`.
+    pub caller_annotation: bool,
+    /// Headers upstreams trust because only this filter sets them. They are
+    /// removed from every inbound request, including bypassed ones, so a
+    /// client can't forge them. Add the `replace` header of `upstream_token`
+    /// here when using it.
+    pub trusted_headers: Vec<String>,
+    /// What happens to the inbound token once the request is let through.
+    pub upstream_token: TokenForwarding,
+    pub principal: PrincipalConfig,
+    pub decision_cache: DecisionCacheConfig,
+    /// Filter metadata namespace holding per-route overrides.
+    pub route_metadata_namespace: String,
+    /// Prefix for the filter's Envoy stats.
+    pub stat_prefix: String,
+    pub logging: LoggingConfig,
+    /// Buffering and masking of responses with `redactFields` obligations.
+    pub response_redaction: RedactionConfig,
+    /// Bodies and headers of the 401 and 403 replies.
+    pub responses: ResponseTemplates,
+    /// Export of authorization outcomes to an audit sink. Disabled when
+    /// absent.
+    pub audit: Option<AuditConfig>,
+    /// Events and risk tagging for principals denied repeatedly. Disabled
+    /// when absent.
+    pub security_events: Option<SecurityEventsConfig>,
+    /// Key prefix for publishing each decision (decision, reason, principal,
+    /// asset, action) via `set_property`. Envoy stores these as filter state
+    /// named `wasm.<prefix>.<field>`, which access logs
+    /// (`%FILTER_STATE(wasm.server_filter.decision:PLAIN)%`) and CEL-based
+    /// filters such as RBAC can read. Disabled when absent.
+    pub decision_metadata_prefix: Option<String>,
+    /// Tenant-scoped settings for a deployment serving several tenants.
+    /// Disabled when absent.
+    pub tenancy: Option<TenancyConfig>,
+    /// The tenant these settings are for, set on those derived from a
+    /// `tenancy.tenants` block.
+    #[serde(skip)]
+    pub tenant: Option<String>,
+}
+
+/// Behavior when the PDP call fails, times out, returns something
+/// unparseable, or is skipped because the circuit breaker is open.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Reject the request with 403.
+    #[default]
+    #[serde(rename = "fail_closed")]
+    Closed,
+    /// Let the request through.
+    #[serde(rename = "fail_open")]
+    Open,
+    /// Let the request through, marked with an `X-PDP-Fail-Open` header so
+    /// the upstream can apply its own checks.
+    #[serde(rename = "fail_open_with_header")]
+    OpenWithHeader,
+    /// Decide the request with the static `local_policy` rules.
+    #[serde(rename = "local_fallback")]
+    LocalFallback,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnforcementMode {
+    #[default]
+    Enforce,
+    /// Evaluate every request but let those the policy denies through, for
+    /// trying out new policies. Such requests carry `X-Shadow-Decision` and
+    /// `X-Shadow-Reason` upstream, count as `shadow.denied` and are audited
+    /// with `shadow` set. Denies from the PDP, the decision cache and
+    /// `local_policy` are shadowed, as are responses the response policy or
+    /// a failed redaction would withhold, which are let through instead.
+    /// Authentication, rate limits and the other local checks are still
+    /// enforced.
+    Shadow,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrincipalSource {
+    /// Read the principal from a claim of the bearer token.
+    #[default]
+    Jwt,
+    /// Trust the `headers.service_id` request header, `X-Service-ID` by
+    /// default (legacy PoC behavior).
+    Header,
+    /// Use the downstream mTLS client certificate's URI SAN (e.g. a SPIFFE
+    /// ID), falling back to its DNS SAN.
+    Mtls,
+    /// Use the user name of HTTP Basic credentials in the token header, for
+    /// legacy clients. The password is not checked here, so the upstream or
+    /// the PDP must not rely on it having been.
+    Basic,
+    /// Look the `headers.api_key` header up in the `principal.api_keys`
+    /// table, for legacy clients. Unknown keys are rejected.
+    ApiKey,
+}
+
+/// Request headers the filter reads, for environments that don't use the
+/// standard names.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HeaderNames {
+    /// Header carrying the token, e.g. `X-Internal-Token`.
+    pub token: String,
+    /// Auth scheme preceding the token, matched case-insensitively. Empty
+    /// when the header holds the bare token.
+    pub token_scheme: String,
+    /// Header holding the principal with the `header` principal source.
+    pub service_id: String,
+    /// Header holding the key with the `api_key` principal source.
+    pub api_key: String,
+}
+
+impl Default for HeaderNames {
+    fn default() -> Self {
+        HeaderNames {
+            token: "Authorization".to_string(),
+            token_scheme: "Bearer".to_string(),
+            service_id: "X-Service-ID".to_string(),
+            api_key: "X-API-Key".to_string(),
+        }
+    }
+}
+
+impl HeaderNames {
+    /// Extracts the token from the token header's value, or `None` if it
+    /// doesn't use the expected scheme.
+    pub fn token_from<'a>(&self, value: &'a str) -> Option<&'a str> {
+        token::from_header(value, &self.token_scheme)
+    }
+}
+
+/// Whether the upstream sees the raw credential the client presented.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "mode")]
+pub enum TokenForwarding {
+    /// Forward the token header unchanged.
+    #[default]
+    Keep,
+    /// Remove the token header.
+    Strip,
+    /// Remove the token header and set `header` to the resolved principal,
+    /// overwriting any value the client sent.
+    Replace { header: String },
+}
+
+/// How the PDP principal is derived from the request.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PrincipalConfig {
+    pub source: PrincipalSource,
+    /// Claim holding the principal: a top-level name such as `sub` or
+    /// `client_id`, a dotted path, or a JSON pointer.
+    pub claim: String,
+    /// Principal used when the claim or header is absent, but only if
+    /// `allow_default` is set; otherwise such requests are rejected.
+    pub default: Option<String>,
+    pub allow_default: bool,
+    /// Whether a bearer token is required even when the principal doesn't
+    /// come from it. Clearing this with the `mtls` source authenticates by
+    /// certificate alone; a token that is sent is still verified.
+    pub require_token: bool,
+    /// When set, the resolved identity must be a SPIFFE ID from a trusted
+    /// domain; it is mapped to the PDP principal format.
+    pub spiffe: Option<SpiffeConfig>,
+    /// Keys accepted with the `api_key` source.
+    pub api_keys: ApiKeyConfig,
+    /// Accepts tokens acting on behalf of their subject. Without it, any
+    /// `act` claim is ignored and the subject is the sole principal.
+    pub delegation: Option<DelegationConfig>,
+    /// Accepts a principal taken from a token whose signature isn't checked,
+    /// because no `jwt` config is set. Any client can then name itself.
+    pub allow_unverified_principal: bool,
+}
+
+impl Default for PrincipalConfig {
+    fn default() -> Self {
+        PrincipalConfig {
+            source: PrincipalSource::Jwt,
+            claim: "sub".to_string(),
+            default: None,
+            allow_default: false,
+            require_token: true,
+            spiffe: None,
+            api_keys: ApiKeyConfig::default(),
+            delegation: None,
+            allow_unverified_principal: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct JwtConfig {
+    #[serde(flatten)]
+    pub rules: ValidationRules,
+    /// Inline JWKS document with the keys tokens may be signed with.
+    pub jwks: Jwks,
+    /// JWKS endpoint fetched and periodically refreshed by the root context.
+    /// Once a document has been fetched it takes precedence over `jwks`.
+    pub remote_jwks: Option<RemoteJwks>,
+    /// Further identity providers, each with its own keys and audiences. A
+    /// token whose `iss` names one of them is verified with that entry's
+    /// settings; any other token with the settings above.
+    pub issuers: Vec<TrustedIssuer>,
+}
+
+/// An identity provider trusted alongside the default one. Its `issuer` rule
+/// is required and selects the entry.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct TrustedIssuer {
+    #[serde(flatten)]
+    pub rules: ValidationRules,
+    pub jwks: Jwks,
+    pub remote_jwks: Option<RemoteJwks>,
+}
+
+impl JwtConfig {
+    /// The trusted issuer entry for tokens from `issuer`, if any.
+    pub fn issuer(&self, issuer: &str) -> Option<&TrustedIssuer> {
+        self.issuers
+            .iter()
+            .find(|trusted| trusted.rules.issuer.as_deref() == Some(issuer))
+    }
+
+    /// The inline keys of each trusted issuer.
+    pub fn issuer_keys(&self) -> HashMap<String, Rc<KeySet>> {
+        self.issuers
+            .iter()
+            .filter_map(|trusted| {
+                let issuer = trusted.rules.issuer.clone()?;
+                Some((issuer, Rc::new(KeySet::from_jwks(&trusted.jwks))))
+            })
+            .collect()
+    }
+
+    /// Every remote JWKS to fetch, with the shared-data key it is published
+    /// under; the default one under `default_key`.
+    pub fn remote_jwks_sources(&self, default_key: String) -> Vec<(String, RemoteJwks)> {
+        let default = self.remote_jwks.clone().map(|remote| (default_key, remote));
+        let issuers = self.issuers.iter().filter_map(|trusted| {
+            let issuer = trusted.rules.issuer.as_deref()?;
+            let remote = trusted.remote_jwks.clone()?;
+            Some((jwks::issuer_shared_key(issuer), remote))
+        });
+        default.into_iter().chain(issuers).collect()
+    }
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            pdp_cluster: "sgnl-pdp-service".to_string(),
+            pdp_protocol: PdpProtocol::Sgnl,
+            pdp_transport: PdpTransport::Http,
+            pdp_encoding: PdpEncoding::Json,
+            pdp_path: "/access/v2/evaluations".to_string(),
+            pdp_authority: "sgnl-pdp-service:8082".to_string(),
+            pdp_grpc_service: "sgnl.access.v2.EvaluationService".to_string(),
+            pdp_grpc_method: "Evaluate".to_string(),
+            opa_package: "envoy.authz".to_string(),
+            authzen: AuthzenConfig::default(),
+            ext_authz: ExtAuthzConfig::default(),
+            pdp_timeout_ms: 5000,
+            circuit_breaker: None,
+            coalescing: None,
+            health_check: None,
+            failover: None,
+            hedging: None,
+            pip: None,
+            rate_limit: None,
+            failure_mode: FailureMode::Closed,
+            enforcement_mode: EnforcementMode::Enforce,
+            local_policy: LocalPolicy::default(),
+            action: "call".to_string(),
+            bypass_paths: Vec::new(),
+            deny_paths: Vec::new(),
+            action_mapping: None,
+            asset_rules: asset::default_rules(),
+            additional_queries: Vec::new(),
+            combine: CombineMode::All,
+            grpc: None,
+            upgrades: UpgradeConfig::default(),
+            max_request_body_bytes: 64 * 1024,
+            jwt: None,
+            revocation: None,
+            request_signing: None,
+            replay: None,
+            admin: None,
+            concurrency_limit: None,
+            headers: HeaderNames::default(),
+            context_headers: Vec::new(),
+            claim_attributes: BTreeMap::new(),
+            identity_chain: None,
+            connection_attributes: false,
+            caller_annotation: false,
+            trusted_headers: [
+                "X-PDP-Decision",
+                "X-PDP-Reason",
+                "X-Principal-ID",
+                "X-PDP-Fail-Open",
+                "X-Shadow-Decision",
+                "X-Shadow-Reason",
+            ]
+            .iter()
+            .map(|h| h.to_string())
+            .collect(),
+            upstream_token: TokenForwarding::Keep,
+            principal: PrincipalConfig::default(),
+            decision_cache: DecisionCacheConfig::default(),
+            route_metadata_namespace: "server_filter".to_string(),
+            stat_prefix: "server_filter".to_string(),
+            logging: LoggingConfig::default(),
+            response_redaction: RedactionConfig::default(),
+            responses: ResponseTemplates::default(),
+            audit: None,
+            security_events: None,
+            decision_metadata_prefix: None,
+            tenancy: None,
+            tenant: None,
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn pdp_timeout(&self) -> Duration {
+        Duration::from_millis(self.pdp_timeout_ms)
+    }
+
+    /// Shared-data key of the default remote JWKS document, kept apart for
+    /// each tenant.
+    pub fn jwks_shared_key(&self) -> String {
+        match &self.tenant {
+            Some(tenant) => jwks::tenant_shared_key(tenant),
+            None => jwks::JWKS_SHARED_KEY.to_string(),
+        }
+    }
+
+    /// Rejects a principal taken from unverified tokens, unless
+    /// `principal.allow_unverified_principal` opts in. Checked for the
+    /// top-level settings when they serve requests, and for each tenant.
+    /// Tenants named by a claim need issuers of their own.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(tenancy) = &self.tenancy {
+            tenancy.validate()?;
+        }
+        if self.principal.source != PrincipalSource::Jwt
+            || self.principal.allow_unverified_principal
+        {
+            return Ok(());
+        }
+        let tenancy = self.tenancy.as_ref();
+        if self.jwt.is_none() && tenancy.is_none_or(|tenancy| !tenancy.require_tenant) {
+            return Err(
+                "principal.source is jwt but no jwt config verifies tokens; \
+                 set principal.allow_unverified_principal to accept them unverified"
+                    .to_string(),
+            );
+        }
+        let tenants = tenancy.iter().flat_map(|tenancy| &tenancy.tenants);
+        for (name, tenant) in tenants {
+            if self.jwt.is_none() && tenant.jwt.is_none() {
+                return Err(format!(
+                    "tenant {} has principal.source jwt but no jwt config",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use proxy_wasm::traits::Context;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use wasm_common::token;
+
+/// Shared-data key under which the root context stores the fetched API key
+/// table, so every VM of the plugin sees a refresh made by any of them.
+pub const API_KEYS_SHARED_KEY: &str = "server_filter.api_keys";
+
+/// API keys of legacy clients that can't send JWTs, mapped to the principals
+/// they stand for, used with the `api_key` principal source.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ApiKeyConfig {
+    /// Inline table of API key to principal.
+    pub keys: HashMap<String, String>,
+    /// Endpoint serving the table as a JSON object of the same shape, e.g.
+    /// rendered from a ConfigMap. Fetched and periodically refreshed by the
+    /// root context; once a table has been fetched it takes precedence over
+    /// `keys`.
+    pub remote: Option<RemoteApiKeys>,
+}
+
+/// Where to fetch the API key table from and how often to refresh it.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RemoteApiKeys {
+    pub cluster: String,
+    pub path: String,
+    pub authority: String,
+    pub timeout_ms: u64,
+    pub refresh_interval_ms: u64,
+}
+
+impl Default for RemoteApiKeys {
+    fn default() -> Self {
+        RemoteApiKeys {
+            cluster: "api-key-service".to_string(),
+            path: "/api-keys".to_string(),
+            authority: "api-key-service".to_string(),
+            timeout_ms: 5000,
+            refresh_interval_ms: 60_000,
+        }
+    }
+}
+
+impl RemoteApiKeys {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_millis(self.refresh_interval_ms)
+    }
+}
+
+type ApiKeyTable = HashMap<String, String>;
+
+thread_local! {
+    // Parsed form of the shared table, tagged with the CAS it was read at
+    static PARSED: RefCell<Option<(u32, Rc<ApiKeyTable>)>> = const { RefCell::new(None) };
+}
+
+/// Returns the principal `api_key` stands for, from the fetched table if
+/// there is one and the inline table otherwise.
+pub fn principal_for_key<C: Context + ?Sized>(
+    ctx: &C,
+    config: &ApiKeyConfig,
+    api_key: &str,
+) -> Option<String> {
+    let shared = match config.remote {
+        Some(_) => shared_table(ctx),
+        None => None,
+    };
+    let principal = match &shared {
+        Some(table) => table.get(api_key),
+        None => config.keys.get(api_key),
+    };
+    principal.cloned()
+}
+
+/// Keys in the fetched table, or `None` if none has been fetched.
+pub fn shared_size<C: Context + ?Sized>(ctx: &C) -> Option<usize> {
+    shared_table(ctx).map(|table| table.len())
+}
+
+fn shared_table<C: Context + ?Sized>(ctx: &C) -> Option<Rc<ApiKeyTable>> {
+    let (data, cas) = ctx.get_shared_data(API_KEYS_SHARED_KEY);
+    let data = data?;
+    let cas = cas.unwrap_or(0);
+
+    PARSED.with(|parsed| {
+        let mut parsed = parsed.borrow_mut();
+        if let Some((cached_cas, table)) = parsed.as_ref() {
+            if *cached_cas == cas {
+                return Some(table.clone());
+            }
+        }
+        let table: ApiKeyTable = serde_json::from_slice(&data).ok()?;
+        let table = Rc::new(table);
+        *parsed = Some((cas, table.clone()));
+        Some(table)
+    })
+}
+
+/// Validates a fetched API key table and publishes it to shared data.
+/// Returns the number of keys.
+pub fn store<C: Context + ?Sized>(ctx: &C, body: &[u8]) -> Result<usize, String> {
+    let table: ApiKeyTable =
+        serde_json::from_slice(body).map_err(|e| format!("invalid API key table: {}", e))?;
+    ctx.set_shared_data(API_KEYS_SHARED_KEY, Some(body), None)
+        .map_err(|e| format!("failed to store API key table: {:?}", e))?;
+    Ok(table.len())
+}
+
+/// The user name of HTTP Basic credentials (RFC 7617), or `None` if the
+/// header value isn't well-formed Basic credentials.
+pub fn basic_username(value: &str) -> Option<String> {
+    let encoded = token::from_header(value, "Basic")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (username, _password) = decoded.split_once(':')?;
+    Some(username.to_string())
+}
@@ -0,0 +1,78 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::jwt::Claims;
+
+/// On-behalf-of requests, as in RFC 8693 token exchange. A token's `act`
+/// claim names the party acting for its subject, with any earlier actors
+/// nested in its own `act`. Every actor in the chain must be allowlisted.
+/// The PDP gets the subject as `principal.id` and the party making the
+/// request as `principal.actor`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DelegationConfig {
+    /// Identities allowed to act for others, matched against each actor's
+    /// `sub`, or its `client_id` when it has none.
+    pub allowed_actors: Vec<String>,
+    /// Most actors accepted in a chain.
+    pub max_chain: usize,
+    /// Treat an `azp` other than the subject as the actor of a token without
+    /// `act`. Many identity providers set `azp` on every token, so its client
+    /// then has to be allowlisted.
+    pub azp: bool,
+}
+
+impl Default for DelegationConfig {
+    fn default() -> Self {
+        DelegationConfig {
+            allowed_actors: Vec::new(),
+            max_chain: 3,
+            azp: false,
+        }
+    }
+}
+
+impl DelegationConfig {
+    /// The actor of a token acting for `subject`, `None` when it acts for
+    /// itself, or why the delegation isn't accepted.
+    pub fn actor(&self, claims: &Claims, subject: &str) -> Result<Option<String>, String> {
+        let chain = match claims.get("act") {
+            Some(act) => chain(act)?,
+            None => match claims.get_str("azp") {
+                Some(azp) if self.azp && azp != subject => vec![azp.to_string()],
+                _ => Vec::new(),
+            },
+        };
+        if chain.len() > self.max_chain {
+            return Err(format!(
+                "Delegation chain of {} actors is too long",
+                chain.len()
+            ));
+        }
+        if let Some(actor) = chain
+            .iter()
+            .find(|actor| !self.allowed_actors.contains(actor))
+        {
+            return Err(format!("{} may not act for others", actor));
+        }
+        Ok(chain.into_iter().next())
+    }
+}
+
+/// The actors of an `act` claim, the current one first.
+fn chain(mut act: &Value) -> Result<Vec<String>, String> {
+    let mut actors = Vec::new();
+    loop {
+        let id = ["sub", "client_id"]
+            .iter()
+            .find_map(|claim| act.get(claim)?.as_str());
+        let Some(id) = id else {
+            return Err("Malformed act claim".to_string());
+        };
+        actors.push(id.to_string());
+        match act.get("act") {
+            Some(next) => act = next,
+            None => return Ok(actors),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use wasm_common::pdp::{Decision, EvaluationRequest, EvaluationResponse, Obligations};
+
+/// Service and method of the ext_authz v3 API over the `grpc` transport.
+pub const GRPC_SERVICE: &str = "envoy.service.auth.v3.Authorization";
+pub const GRPC_METHOD: &str = "Check";
+
+/// Settings for the `ext_authz` protocol, which speaks Envoy's ext_authz v3
+/// `CheckRequest`/`CheckResponse` so any existing authorization server can
+/// act as the PDP. The messages are sent as protobuf over the `grpc`
+/// transport, or in their proto3 JSON mapping over HTTP.
+///
+/// A request is allowed when the response's `status.code` is 0. The headers
+/// of an `ok_response` are set on the request forwarded upstream,
+/// `headers_to_remove` are removed from it and `response_headers_to_add` are
+/// set on the response. The `status.message`, or else the body of a
+/// `denied_response`, is the reason.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ExtAuthzConfig {
+    /// Path the JSON `CheckRequest` is posted to over HTTP.
+    pub path: String,
+    /// Sent as `context_extensions`, along with the principal, any actor and
+    /// the asset and action of the first query as `principal`, `actor`,
+    /// `asset_id` and `action`.
+    pub context_extensions: BTreeMap<String, String>,
+}
+
+impl Default for ExtAuthzConfig {
+    fn default() -> Self {
+        ExtAuthzConfig {
+            path: "/check".to_string(),
+            context_extensions: BTreeMap::new(),
+        }
+    }
+}
+
+/// The downstream request as described by a `CheckRequest`, captured before
+/// the callout since the request's headers may not be readable when it is
+/// made.
+#[derive(Clone, Debug, Default)]
+pub struct HttpAttributes {
+    pub id: String,
+    pub method: String,
+    pub path: String,
+    pub host: String,
+    pub scheme: String,
+    /// Values of repeated headers are joined with commas.
+    pub headers: BTreeMap<String, String>,
+}
+
+impl HttpAttributes {
+    pub fn new(id: &str, headers: Vec<(String, String)>) -> Self {
+        let mut joined: BTreeMap<String, String> = BTreeMap::new();
+        for (name, value) in headers {
+            joined
+                .entry(name.to_lowercase())
+                .and_modify(|joined| {
+                    joined.push(',');
+                    joined.push_str(&value);
+                })
+                .or_insert(value);
+        }
+        let header = |name: &str| joined.get(name).cloned().unwrap_or_default();
+        HttpAttributes {
+            id: id.to_string(),
+            method: header(":method"),
+            path: header(":path"),
+            host: header(":authority"),
+            scheme: header(":scheme"),
+            headers: joined,
+        }
+    }
+}
+
+/// Builds the `CheckRequest` for an evaluation of the request `http`.
+pub fn check_request(
+    config: &ExtAuthzConfig,
+    request: &EvaluationRequest,
+    http: &HttpAttributes,
+) -> CheckRequest {
+    let mut context_extensions = config.context_extensions.clone();
+    context_extensions.insert("principal".to_string(), request.principal.id.clone());
+    if let Some(actor) = &request.principal.actor {
+        context_extensions.insert("actor".to_string(), actor.clone());
+    }
+    if let Some(query) = request.queries.first() {
+        context_extensions.insert("asset_id".to_string(), query.asset_id.clone());
+        context_extensions.insert("action".to_string(), query.action.clone());
+    }
+    let connection = request.connection.as_ref();
+    CheckRequest {
+        attributes: Some(AttributeContext {
+            source: connection.map(|connection| Peer {
+                address: connection.source_address.as_deref().map(Address::new),
+                principal: connection.peer_identity.clone().unwrap_or_default(),
+            }),
+            destination: connection.map(|connection| Peer {
+                address: connection.destination_address.as_deref().map(Address::new),
+                principal: String::new(),
+            }),
+            request: Some(Request {
+                http: Some(HttpRequest {
+                    id: http.id.clone(),
+                    method: http.method.clone(),
+                    headers: http.headers.clone(),
+                    path: http.path.clone(),
+                    host: http.host.clone(),
+                    scheme: http.scheme.clone(),
+                }),
+            }),
+            context_extensions,
+        }),
+    }
+}
+
+pub fn decode_json(body: &[u8], expected: usize) -> Result<EvaluationResponse, String> {
+    let response: CheckResponse = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    Ok(evaluation_response(response, expected))
+}
+
+pub fn decode_proto(body: &[u8], expected: usize) -> Result<EvaluationResponse, String> {
+    let response = CheckResponse::decode(body).map_err(|e| e.to_string())?;
+    Ok(evaluation_response(response, expected))
+}
+
+/// The verdict of a `CheckResponse`, applying to every query.
+fn evaluation_response(response: CheckResponse, expected: usize) -> EvaluationResponse {
+    let status = response.status.unwrap_or_default();
+    let denied_body = response
+        .denied_response
+        .map(|denied| denied.body)
+        .unwrap_or_default();
+    let ok = response.ok_response.unwrap_or_default();
+    let decision = Decision {
+        decision: if status.code == 0 { "Allow" } else { "Deny" }.to_string(),
+        reason: if status.message.is_empty() {
+            denied_body
+        } else {
+            status.message
+        },
+        obligations: Obligations {
+            request_headers: header_map(ok.headers),
+            request_headers_to_remove: ok.headers_to_remove,
+            response_headers: header_map(ok.response_headers_to_add),
+            ..Default::default()
+        },
+        quota: None,
+        ttl_secs: None,
+        expires_at: None,
+    };
+    EvaluationResponse {
+        decisions: vec![decision; expected.max(1)],
+    }
+}
+
+fn header_map(options: Vec<HeaderValueOption>) -> BTreeMap<String, String> {
+    options
+        .into_iter()
+        .filter_map(|option| option.header)
+        .map(|header| (header.key.to_lowercase(), header.value))
+        .collect()
+}
+
+// The subset of the ext_authz v3 messages the filter uses, with the field
+// numbers of `envoy/service/auth/v3/external_auth.proto` and its imports.
+// Members of a oneof are plain fields, which is the same on the wire.
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckRequest {
+    #[prost(message, optional, tag = "1")]
+    attributes: Option<AttributeContext>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AttributeContext {
+    #[prost(message, optional, tag = "1")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Peer>,
+    #[prost(message, optional, tag = "2")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<Peer>,
+    #[prost(message, optional, tag = "4")]
+    request: Option<Request>,
+    #[prost(btree_map = "string, string", tag = "10")]
+    context_extensions: BTreeMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Peer {
+    #[prost(message, optional, tag = "1")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<Address>,
+    #[prost(string, tag = "4")]
+    principal: String,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Address {
+    #[prost(message, optional, tag = "1")]
+    socket_address: Option<SocketAddress>,
+}
+
+impl Address {
+    /// From `host:port`, `[host]:port` or a bare host.
+    fn new(address: &str) -> Self {
+        let (host, port) = match address.rsplit_once(':') {
+            // A bare IPv6 address has several colons
+            Some((host, port)) if host.ends_with(']') || !host.contains(':') => {
+                (host, port.parse().unwrap_or_default())
+            }
+            _ => (address, 0),
+        };
+        Address {
+            socket_address: Some(SocketAddress {
+                address: host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                port_value: port,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SocketAddress {
+    #[prost(string, tag = "2")]
+    address: String,
+    #[prost(uint32, tag = "3")]
+    port_value: u32,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    #[prost(message, optional, tag = "2")]
+    http: Option<HttpRequest>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HttpRequest {
+    #[prost(string, tag = "1")]
+    id: String,
+    #[prost(string, tag = "2")]
+    method: String,
+    #[prost(btree_map = "string, string", tag = "3")]
+    headers: BTreeMap<String, String>,
+    #[prost(string, tag = "4")]
+    path: String,
+    #[prost(string, tag = "5")]
+    host: String,
+    #[prost(string, tag = "6")]
+    scheme: String,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct CheckResponse {
+    #[prost(message, optional, tag = "1")]
+    status: Option<RpcStatus>,
+    #[prost(message, optional, tag = "2")]
+    denied_response: Option<DeniedHttpResponse>,
+    #[prost(message, optional, tag = "3")]
+    ok_response: Option<OkHttpResponse>,
+}
+
+/// `google.rpc.Status`.
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default)]
+struct DeniedHttpResponse {
+    #[prost(string, tag = "3")]
+    body: String,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct OkHttpResponse {
+    #[prost(message, repeated, tag = "2")]
+    headers: Vec<HeaderValueOption>,
+    #[prost(string, repeated, tag = "5")]
+    headers_to_remove: Vec<String>,
+    #[prost(message, repeated, tag = "6")]
+    response_headers_to_add: Vec<HeaderValueOption>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default)]
+struct HeaderValueOption {
+    #[prost(message, optional, tag = "1")]
+    header: Option<HeaderValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default)]
+struct HeaderValue {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
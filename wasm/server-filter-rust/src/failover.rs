@@ -0,0 +1,120 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use wasm_common::log_info;
+
+/// Prefix of the shared-data keys holding each PDP cluster's failover state,
+/// so every worker skips a cluster any of them found down.
+const STATE_KEY_PREFIX: &str = "server_filter.pdp_failover.";
+
+/// Attempts at a compare-and-swap update before an outcome is dropped.
+const CAS_RETRIES: usize = 4;
+
+/// Secondary PDP clusters. A callout that fails or times out is retried on
+/// the next cluster, and a cluster failing `unhealthy_threshold` callouts in
+/// a row is tried last by later requests until `cooldown_ms` has passed.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FailoverConfig {
+    /// Tried in order after `pdp_cluster`, with the same authority, path and
+    /// timeout.
+    pub secondary_clusters: Vec<String>,
+    pub unhealthy_threshold: u32,
+    pub cooldown_ms: u64,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            secondary_clusters: Vec::new(),
+            unhealthy_threshold: 3,
+            cooldown_ms: 30_000,
+        }
+    }
+}
+
+impl FailoverConfig {
+    /// `primary` and the secondaries in the order to try them: clusters up
+    /// first, then those marked down, as a last resort. `primary_healthy`
+    /// folds in the primary's health probes.
+    pub fn clusters<C: Context + ?Sized>(
+        &self,
+        ctx: &C,
+        primary: &str,
+        primary_healthy: bool,
+        now_ms: u64,
+    ) -> Vec<String> {
+        let (up, down): (Vec<String>, Vec<String>) = std::iter::once(primary)
+            .chain(self.secondary_clusters.iter().map(String::as_str))
+            .map(String::from)
+            .partition(|cluster| {
+                is_up(ctx, cluster, now_ms) && (cluster != primary || primary_healthy)
+            });
+        up.into_iter().chain(down).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct ClusterState {
+    /// Consecutive failed callouts.
+    failures: u32,
+    down_until_ms: u64,
+}
+
+/// Whether `cluster` isn't marked down.
+pub fn is_up<C: Context + ?Sized>(ctx: &C, cluster: &str, now_ms: u64) -> bool {
+    load(ctx, cluster).0.down_until_ms <= now_ms
+}
+
+/// Records the outcome of a callout to `cluster`, marking it down once
+/// `unhealthy_threshold` have failed in a row.
+pub fn record<C: Context + ?Sized>(
+    ctx: &C,
+    config: &FailoverConfig,
+    cluster: &str,
+    now_ms: u64,
+    success: bool,
+) {
+    for _ in 0..CAS_RETRIES {
+        let (mut state, cas) = load(ctx, cluster);
+        if success {
+            if state.failures == 0 && state.down_until_ms == 0 {
+                return;
+            }
+            state = ClusterState::default();
+        } else {
+            state.failures += 1;
+            if state.failures >= config.unhealthy_threshold {
+                log_info!(
+                    "PDP cluster {} marked down for {}ms",
+                    cluster,
+                    config.cooldown_ms
+                );
+                state = ClusterState {
+                    failures: 0,
+                    down_until_ms: now_ms + config.cooldown_ms,
+                };
+            }
+        }
+
+        let Ok(value) = serde_json::to_vec(&state) else {
+            return;
+        };
+        match ctx.set_shared_data(&state_key(cluster), Some(&value), cas) {
+            Err(Status::CasMismatch) => continue,
+            _ => return,
+        }
+    }
+}
+
+fn state_key(cluster: &str) -> String {
+    format!("{}{}", STATE_KEY_PREFIX, cluster)
+}
+
+fn load<C: Context + ?Sized>(ctx: &C, cluster: &str) -> (ClusterState, Option<u32>) {
+    let (data, cas) = ctx.get_shared_data(&state_key(cluster));
+    let state = data
+        .and_then(|d| serde_json::from_slice(&d).ok())
+        .unwrap_or_default();
+    (state, cas)
+}
@@ -0,0 +1,55 @@
+use proxy_wasm::types::GrpcStatusCode;
+use serde::Deserialize;
+
+/// Handling of gRPC requests, recognised by an `application/grpc` content
+/// type. Their rejections are sent as gRPC statuses (HTTP 200 with
+/// `grpc-status`), which gRPC clients can parse where a JSON 403 is just a
+/// protocol error.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct GrpcConfig {
+    /// Authorize gRPC requests on the service (`package.Service`) as asset
+    /// and the method as action. Route overrides still take precedence.
+    /// When false the asset rules and action mapping apply as for any other
+    /// request.
+    pub derive_attributes: bool,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            derive_attributes: true,
+        }
+    }
+}
+
+/// Whether a request's `content-type` marks it as gRPC, including
+/// `application/grpc+proto` and the like.
+pub fn is_grpc(content_type: &str) -> bool {
+    content_type
+        .strip_prefix("application/grpc")
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['+', ';']))
+}
+
+/// Splits a gRPC path `/package.Service/Method` into service and method.
+pub fn service_and_method(path: &str) -> Option<(&str, &str)> {
+    let path = path.split('?').next().unwrap_or(path);
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+    Some((service, method))
+}
+
+/// The gRPC status standing in for a rejection with `http_status`.
+pub fn status_for(http_status: u32) -> GrpcStatusCode {
+    match http_status {
+        401 => GrpcStatusCode::Unauthenticated,
+        403 => GrpcStatusCode::PermissionDenied,
+        413 | 429 => GrpcStatusCode::ResourceExhausted,
+        404 => GrpcStatusCode::Unimplemented,
+        502..=504 => GrpcStatusCode::Unavailable,
+        400..=499 => GrpcStatusCode::InvalidArgument,
+        _ => GrpcStatusCode::Unknown,
+    }
+}
@@ -0,0 +1,122 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use wasm_common::log_info;
+
+/// Shared-data key holding the PDP health observed by the probes, so a VM
+/// that hasn't probed yet still knows the PDP is down.
+const HEALTH_KEY: &str = "server_filter.pdp_health";
+
+/// Background probing of the PDP from the root context. While the PDP is
+/// known to be down, requests apply the failure mode right away instead of
+/// waiting out `pdp_timeout_ms`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    /// Health endpoint on `pdp_cluster`, answering 2xx while healthy.
+    pub path: String,
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    /// Consecutive failed probes before the PDP is considered down.
+    pub unhealthy_threshold: u32,
+    /// Consecutive successful probes before it is considered up again.
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            path: "/health".to_string(),
+            interval_ms: 5000,
+            timeout_ms: 1000,
+            unhealthy_threshold: 3,
+            healthy_threshold: 1,
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct HealthState {
+    healthy: bool,
+    /// Consecutive probes disagreeing with `healthy`.
+    streak: u32,
+    checked_at_ms: u64,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        HealthState {
+            healthy: true,
+            streak: 0,
+            checked_at_ms: 0,
+        }
+    }
+}
+
+/// Whether the PDP may be called. Health that hasn't been confirmed by a
+/// probe for three intervals, e.g. because probing stopped, isn't trusted
+/// and counts as healthy.
+pub fn is_healthy<C: Context + ?Sized>(ctx: &C, config: &HealthCheckConfig, now_ms: u64) -> bool {
+    let state = load(ctx);
+    state.healthy || now_ms.saturating_sub(state.checked_at_ms) > 3 * config.interval_ms
+}
+
+/// Whether a probe is due, none having been made by any VM for an interval.
+/// `slack_ms` tolerates ticks firing slightly early.
+pub fn probe_due<C: Context + ?Sized>(
+    ctx: &C,
+    config: &HealthCheckConfig,
+    now_ms: u64,
+    slack_ms: u64,
+) -> bool {
+    now_ms + slack_ms >= load(ctx).checked_at_ms + config.interval_ms
+}
+
+/// Records a probe result. Returns the resulting health.
+pub fn record<C: Context + ?Sized>(
+    ctx: &C,
+    config: &HealthCheckConfig,
+    now_ms: u64,
+    success: bool,
+) -> bool {
+    let mut state = load(ctx);
+    state.checked_at_ms = now_ms;
+    if success == state.healthy {
+        state.streak = 0;
+    } else {
+        state.streak += 1;
+        let threshold = match state.healthy {
+            true => config.unhealthy_threshold,
+            false => config.healthy_threshold,
+        };
+        if state.streak >= threshold {
+            log_info!(
+                "PDP marked {} after {} probe(s)",
+                if success { "healthy" } else { "unhealthy" },
+                state.streak
+            );
+            state.healthy = success;
+            state.streak = 0;
+        }
+    }
+    if let Ok(value) = serde_json::to_vec(&state) {
+        let _ = ctx.set_shared_data(HEALTH_KEY, Some(&value), None);
+    }
+    state.healthy
+}
+
+fn load<C: Context + ?Sized>(ctx: &C) -> HealthState {
+    let (data, _) = ctx.get_shared_data(HEALTH_KEY);
+    data.and_then(|d| serde_json::from_slice(&d).ok())
+        .unwrap_or_default()
+}
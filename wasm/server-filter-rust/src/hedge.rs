@@ -0,0 +1,74 @@
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Hedging of slow PDP callouts. Once a callout has been outstanding for
+/// `threshold_ms`, a second one is sent and whichever response arrives first
+/// decides the request. Envoy can't cancel a callout, so the other response
+/// is ignored when it arrives.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HedgingConfig {
+    /// Typically around the PDP's p95 latency.
+    pub threshold_ms: u64,
+    /// Cluster the hedged call goes to. Defaults to the next of the
+    /// `failover` clusters, or else the original call's cluster, where it
+    /// likely reaches another host.
+    pub cluster: Option<String>,
+    /// How often overdue callouts are looked for, which bounds how late
+    /// past `threshold_ms` the hedged call may go out.
+    pub poll_ms: u64,
+}
+
+impl Default for HedgingConfig {
+    fn default() -> Self {
+        HedgingConfig {
+            threshold_ms: 200,
+            cluster: None,
+            poll_ms: 20,
+        }
+    }
+}
+
+impl HedgingConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_ms)
+    }
+}
+
+/// Progress of a request's PDP callout and its hedge, shared between the
+/// request and the copy of it the hedged response is handled through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HedgeState {
+    /// No hedged call has been made.
+    #[default]
+    Pending,
+    /// The hedged call is outstanding alongside the original.
+    Sent,
+    /// One of the two calls failed, so the other decides.
+    OneFailed,
+    /// The original call's response was acted on, or the request went away.
+    Done,
+    /// The hedged call's response was acted on.
+    HedgeWon,
+}
+
+impl HedgeState {
+    /// Whether a response was acted on, so any other is to be ignored.
+    pub fn decided(self) -> bool {
+        matches!(self, HedgeState::Done | HedgeState::HedgeWon)
+    }
+}
+
+/// A callout that may be hedged. The root context makes the hedged call
+/// once it is due and hands its response to `request`.
+pub struct Hedge<R> {
+    pub due_ms: u64,
+    /// Token of the hedged call, once made.
+    pub call: Option<u32>,
+    pub request: R,
+}
+
+/// Callouts that may be hedged, shared by a VM's root and HTTP contexts.
+pub type Hedges<R> = Rc<RefCell<Vec<Hedge<R>>>>;
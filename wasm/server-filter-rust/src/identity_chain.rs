@@ -0,0 +1,74 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use wasm_common::identity;
+
+use crate::signature::SigningSecret;
+
+/// Verification of the `X-Forwarded-Identity` chain that the client filters
+/// of calling services append to. The verified chain goes to the PDP in the
+/// evaluation's `context`: `identity.caller` is the party that started it
+/// and `identity.chain` the services it passed through, comma-separated, for
+/// policies that trust callers transitively.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct IdentityChainConfig {
+    pub header: String,
+    /// Secrets the calling services sign their hops with, by key id.
+    pub keys: BTreeMap<String, SigningSecret>,
+    pub max_hops: usize,
+    /// Oldest hop accepted, which bounds how long a chain can be replayed.
+    pub max_age_secs: u64,
+    /// Require the last hop to be the principal's own, so a caller can't
+    /// present a chain another service was handed.
+    pub match_principal: bool,
+    /// Reject requests without a chain, rather than evaluating them without.
+    pub required: bool,
+}
+
+impl Default for IdentityChainConfig {
+    fn default() -> Self {
+        IdentityChainConfig {
+            header: identity::HEADER.to_string(),
+            keys: BTreeMap::new(),
+            max_hops: 8,
+            max_age_secs: 300,
+            match_principal: true,
+            required: false,
+        }
+    }
+}
+
+impl IdentityChainConfig {
+    /// Context attributes describing the request's chain, or why it isn't
+    /// accepted.
+    pub fn attributes(
+        &self,
+        chain: Option<&str>,
+        principal: &str,
+        now_secs: u64,
+    ) -> Result<Vec<(String, String)>, String> {
+        let Some(chain) = chain.filter(|chain| !chain.trim().is_empty()) else {
+            return match self.required {
+                true => Err("Missing identity chain".to_string()),
+                false => Ok(Vec::new()),
+            };
+        };
+        let secret = |key_id: &str| self.keys.get(key_id)?.resolve();
+        let hops = identity::verify(chain, secret, now_secs, self.max_age_secs, self.max_hops)
+            .map_err(|e| e.to_string())?;
+        let (Some(first), Some(last)) = (hops.first(), hops.last()) else {
+            return Ok(Vec::new());
+        };
+        if self.match_principal && last.service != principal {
+            return Err(format!(
+                "Identity chain ends at {}, not the caller",
+                last.service
+            ));
+        }
+        let services: Vec<&str> = hops.iter().map(|hop| hop.service.as_str()).collect();
+        Ok(vec![
+            ("identity.caller".to_string(), first.caller.clone()),
+            ("identity.chain".to_string(), services.join(",")),
+        ])
+    }
+}
@@ -0,0 +1,257 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::jwt::{Jwks, KeySet};
+
+/// Shared-data key under which the root context stores the raw JWKS document.
+/// Shared data is visible to every VM of the plugin, so a refresh performed by
+/// one worker is picked up by HTTP contexts on all of them.
+pub const JWKS_SHARED_KEY: &str = "server_filter.jwks";
+
+/// Attempts at a compare-and-swap publish before a fetched JWKS is dropped.
+const CAS_RETRIES: usize = 4;
+
+/// Shared-data key of the JWKS document of one of several trusted issuers.
+pub fn issuer_shared_key(issuer: &str) -> String {
+    format!("{}.{}", JWKS_SHARED_KEY, issuer)
+}
+
+/// Shared-data key of a tenant's default JWKS document.
+pub fn tenant_shared_key(tenant: &str) -> String {
+    format!("{}:{}", JWKS_SHARED_KEY, tenant)
+}
+
+/// Where to fetch the JWKS document from and how often to refresh it.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RemoteJwks {
+    pub cluster: String,
+    pub path: String,
+    pub authority: String,
+    pub timeout_ms: u64,
+    pub refresh_interval_ms: u64,
+    /// Fetch the document on behalf of a request whose token names a key not
+    /// published yet, e.g. right after a rotation, instead of rejecting it
+    /// until the next refresh. The PDP is called while the fetch is
+    /// outstanding, and the request is only let through once the token
+    /// verifies.
+    pub fetch_on_unknown_key: bool,
+    /// Minimum time between such fetches on one VM, so tokens naming bogus
+    /// keys can't be used to hammer the JWKS endpoint.
+    pub unknown_key_fetch_interval_ms: u64,
+    /// How long a key dropped from the document stays valid, so tokens
+    /// signed with it shortly before a rotation still verify. Zero stops
+    /// accepting it at once.
+    pub rotation_overlap_ms: u64,
+}
+
+impl Default for RemoteJwks {
+    fn default() -> Self {
+        RemoteJwks {
+            cluster: "jwt-vending-service".to_string(),
+            path: "/.well-known/jwks.json".to_string(),
+            authority: "jwt-vending-service:8081".to_string(),
+            timeout_ms: 5000,
+            refresh_interval_ms: 300_000,
+            fetch_on_unknown_key: false,
+            unknown_key_fetch_interval_ms: 10_000,
+            rotation_overlap_ms: 0,
+        }
+    }
+}
+
+impl RemoteJwks {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_millis(self.refresh_interval_ms)
+    }
+}
+
+/// The root context's periodic fetch of one remote JWKS.
+pub struct JwksFetch {
+    /// Shared-data key the fetched document is published under.
+    pub shared_key: String,
+    pub remote: RemoteJwks,
+    /// The outstanding callout, if any.
+    pub call: Option<u32>,
+    pub next_fetch_ms: u64,
+}
+
+impl JwksFetch {
+    pub fn new(shared_key: String, remote: RemoteJwks) -> Self {
+        JwksFetch {
+            shared_key,
+            remote,
+            call: None,
+            next_fetch_ms: 0,
+        }
+    }
+}
+
+/// A JWKS as published to shared data: the keys of the fetched document,
+/// and those of earlier documents it no longer lists, each valid until its
+/// rotation overlap ends.
+#[derive(Serialize, Deserialize, Default)]
+struct Published {
+    keys: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    retired: Vec<RetiredKey>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RetiredKey {
+    key: Value,
+    until_ms: u64,
+}
+
+impl Published {
+    /// The keys valid at `now_ms`, and when the first retired one among
+    /// them expires.
+    fn key_set(self, now_ms: u64) -> (KeySet, u64) {
+        let retired: Vec<RetiredKey> = self
+            .retired
+            .into_iter()
+            .filter(|retired| retired.until_ms > now_ms)
+            .collect();
+        let valid_until_ms = retired
+            .iter()
+            .map(|retired| retired.until_ms)
+            .min()
+            .unwrap_or(u64::MAX);
+        let keys = self
+            .keys
+            .into_iter()
+            .chain(retired.into_iter().map(|retired| retired.key));
+        let jwks = Jwks {
+            keys: keys
+                .filter_map(|key| serde_json::from_value(key).ok())
+                .collect(),
+        };
+        (KeySet::from_jwks(&jwks), valid_until_ms)
+    }
+}
+
+/// Parsed form of a shared JWKS, tagged with the CAS it was read at and when
+/// a retired key in it expires, so HTTP contexts only rebuild a key set after
+/// a refresh or expiry.
+struct Parsed {
+    cas: u32,
+    valid_until_ms: u64,
+    keys: Rc<KeySet>,
+}
+
+thread_local! {
+    static PARSED: RefCell<HashMap<String, Parsed>> = RefCell::new(HashMap::new());
+    // When each JWKS was last fetched for a request with an unknown key.
+    static LAST_UNKNOWN_KEY_FETCH: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+}
+
+/// Claims this VM's next request-driven fetch of the JWKS under
+/// `shared_key`, allowing one per `interval_ms`. Returns false while
+/// throttled.
+pub fn claim_unknown_key_fetch(shared_key: &str, now_ms: u64, interval_ms: u64) -> bool {
+    LAST_UNKNOWN_KEY_FETCH.with(|last| {
+        let mut last = last.borrow_mut();
+        if last
+            .get(shared_key)
+            .is_some_and(|&at_ms| now_ms < at_ms + interval_ms)
+        {
+            return false;
+        }
+        last.insert(shared_key.to_string(), now_ms);
+        true
+    })
+}
+
+/// Returns the key set currently published under `shared_key`, or `None` if
+/// that JWKS hasn't been fetched yet.
+pub fn shared_keys<C: Context + ?Sized>(
+    ctx: &C,
+    shared_key: &str,
+    now_ms: u64,
+) -> Option<Rc<KeySet>> {
+    let (data, cas) = ctx.get_shared_data(shared_key);
+    let data = data?;
+    let cas = cas.unwrap_or(0);
+
+    PARSED.with(|parsed| {
+        let mut parsed = parsed.borrow_mut();
+        if let Some(cached) = parsed.get(shared_key) {
+            if cached.cas == cas && now_ms < cached.valid_until_ms {
+                return Some(cached.keys.clone());
+            }
+        }
+        let published: Published = serde_json::from_slice(&data).ok()?;
+        let (keys, valid_until_ms) = published.key_set(now_ms);
+        let keys = Rc::new(keys);
+        let cached = Parsed {
+            cas,
+            valid_until_ms,
+            keys: keys.clone(),
+        };
+        parsed.insert(shared_key.to_string(), cached);
+        Some(keys)
+    })
+}
+
+/// Validates a JWKS body fetched from `remote` and publishes it to shared
+/// data under `shared_key`, keeping the keys it drops for the rotation
+/// overlap. Returns the number of usable keys.
+pub fn store<C: Context + ?Sized>(
+    ctx: &C,
+    shared_key: &str,
+    remote: &RemoteJwks,
+    body: &[u8],
+    now_ms: u64,
+) -> Result<usize, String> {
+    let jwks: Jwks = serde_json::from_slice(body).map_err(|e| format!("invalid JWKS: {}", e))?;
+    let count = KeySet::from_jwks(&jwks).len();
+    if count == 0 {
+        return Err("JWKS contains no usable keys".to_string());
+    }
+    let fetched: Published =
+        serde_json::from_slice(body).map_err(|e| format!("invalid JWKS: {}", e))?;
+
+    for _ in 0..CAS_RETRIES {
+        let (data, cas) = ctx.get_shared_data(shared_key);
+        let previous: Published = data
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        let mut published = Published {
+            keys: fetched.keys.clone(),
+            retired: Vec::new(),
+        };
+        if remote.rotation_overlap_ms > 0 {
+            let until_ms = now_ms + remote.rotation_overlap_ms;
+            let dropped = previous
+                .keys
+                .into_iter()
+                .map(|key| RetiredKey { key, until_ms });
+            published.retired = previous
+                .retired
+                .into_iter()
+                .filter(|retired| retired.until_ms > now_ms)
+                .chain(dropped)
+                .filter(|retired| !published.keys.contains(&retired.key))
+                .collect();
+        }
+
+        let value =
+            serde_json::to_vec(&published).map_err(|e| format!("failed to store JWKS: {}", e))?;
+        match ctx.set_shared_data(shared_key, Some(&value), cas) {
+            Ok(()) => return Ok(count),
+            Err(Status::CasMismatch) => continue,
+            Err(e) => return Err(format!("failed to store JWKS: {:?}", e)),
+        }
+    }
+    Err("failed to store JWKS: concurrent updates".to_string())
+}
@@ -0,0 +1,375 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use p256::ecdsa::signature::Verifier;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// A single JSON Web Key as published in a JWKS document. Only the members
+/// needed for RS256 and ES256 verification are read.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    pub alg: Option<String>,
+    pub n: Option<String>,
+    pub e: Option<String>,
+    pub crv: Option<String>,
+    pub x: Option<String>,
+    pub y: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+enum KeyMaterial {
+    Rsa(RsaPublicKey),
+    Ec(p256::ecdsa::VerifyingKey),
+}
+
+pub struct VerifyingKey {
+    kid: Option<String>,
+    material: KeyMaterial,
+}
+
+/// The set of keys a token may be signed with.
+#[derive(Default)]
+pub struct KeySet {
+    keys: Vec<VerifyingKey>,
+}
+
+impl KeySet {
+    /// Builds a key set from a JWKS document, skipping keys of unsupported
+    /// types rather than rejecting the whole set.
+    pub fn from_jwks(jwks: &Jwks) -> Self {
+        let keys = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| VerifyingKey::from_jwk(jwk).ok())
+            .collect();
+        KeySet { keys }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn candidates<'a>(
+        &'a self,
+        kid: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a VerifyingKey> + 'a {
+        self.keys
+            .iter()
+            .filter(move |key| match (kid, key.kid.as_deref()) {
+                (Some(wanted), Some(have)) => wanted == have,
+                _ => true,
+            })
+    }
+}
+
+impl VerifyingKey {
+    pub fn from_jwk(jwk: &Jwk) -> Result<Self, JwtError> {
+        let material = match jwk.kty.as_str() {
+            "RSA" => {
+                let n = decode_member(jwk.n.as_deref())?;
+                let e = decode_member(jwk.e.as_deref())?;
+                let key = RsaPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from_bytes_be(&e))
+                    .map_err(|_| JwtError::InvalidKey)?;
+                KeyMaterial::Rsa(key)
+            }
+            "EC" if jwk.crv.as_deref() == Some("P-256") => {
+                let x: [u8; 32] = decode_member(jwk.x.as_deref())?
+                    .try_into()
+                    .map_err(|_| JwtError::InvalidKey)?;
+                let y: [u8; 32] = decode_member(jwk.y.as_deref())?
+                    .try_into()
+                    .map_err(|_| JwtError::InvalidKey)?;
+                let point =
+                    p256::EncodedPoint::from_affine_coordinates(&x.into(), &y.into(), false);
+                let key = p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map_err(|_| JwtError::InvalidKey)?;
+                KeyMaterial::Ec(key)
+            }
+            _ => return Err(JwtError::InvalidKey),
+        };
+        Ok(VerifyingKey {
+            kid: jwk.kid.clone(),
+            material,
+        })
+    }
+
+    fn verify(&self, alg: &str, signing_input: &[u8], signature: &[u8]) -> bool {
+        match (&self.material, alg) {
+            (KeyMaterial::Rsa(key), "RS256") => {
+                let digest = Sha256::digest(signing_input);
+                key.verify(Pkcs1v15Sign::new::<Sha256>(), &digest, signature)
+                    .is_ok()
+            }
+            (KeyMaterial::Ec(key), "ES256") => {
+                match p256::ecdsa::Signature::from_slice(signature) {
+                    Ok(sig) => key.verify(signing_input, &sig).is_ok(),
+                    Err(_) => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Claim requirements applied after the signature has been verified.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ValidationRules {
+    /// Required `iss` value. Unset means any issuer is accepted.
+    pub issuer: Option<String>,
+    /// Accepted `aud` values. The token must carry at least one of them;
+    /// an empty list disables the audience check.
+    pub audiences: Vec<String>,
+    /// Clock skew tolerated when checking `exp`, `nbf` and `iat`.
+    pub leeway_secs: u64,
+    /// Oldest accepted token, by its `iat`, however far off its `exp` is.
+    /// Tokens without `iat` are then rejected. Unset means no limit.
+    pub max_token_age_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// The verified claim set of a token.
+#[derive(Clone, Debug, Default)]
+pub struct Claims {
+    raw: Map<String, Value>,
+}
+
+impl Claims {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.raw.get(name)
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.raw.get(name).and_then(Value::as_str)
+    }
+
+    pub fn get_u64(&self, name: &str) -> Option<u64> {
+        self.raw
+            .get(name)
+            .and_then(|v| v.as_u64().or_else(|| v.as_f64().map(|f| f as u64)))
+    }
+
+    pub fn issuer(&self) -> Option<&str> {
+        self.get_str("iss")
+    }
+
+    /// Looks up a claim by path: either a JSON pointer (`/ext/service_id`) or
+    /// a dotted path (`ext.service_id`). Strings are returned as-is and other
+    /// scalars in their JSON form; objects, arrays and null yield `None`.
+    pub fn lookup(&self, path: &str) -> Option<String> {
+        scalar(self.value(path)?)
+    }
+
+    /// Like `lookup`, but arrays of scalars are joined with commas, for
+    /// claims such as `groups` copied into the evaluation context.
+    pub fn attribute(&self, path: &str) -> Option<String> {
+        match self.value(path)? {
+            Value::Array(items) => Some(
+                items
+                    .iter()
+                    .filter_map(scalar)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            value => scalar(value),
+        }
+    }
+
+    fn value(&self, path: &str) -> Option<&Value> {
+        let segments: Vec<String> = match path.strip_prefix('/') {
+            Some(pointer) => pointer
+                .split('/')
+                .map(|s| s.replace("~1", "/").replace("~0", "~"))
+                .collect(),
+            None => path.split('.').map(str::to_string).collect(),
+        };
+        let (first, rest) = segments.split_first()?;
+        let mut current = self.raw.get(first)?;
+        for segment in rest {
+            current = match current {
+                Value::Object(map) => map.get(segment)?,
+                Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    /// `aud` may be a single string or an array of strings.
+    pub fn audiences(&self) -> Vec<&str> {
+        match self.raw.get("aud") {
+            Some(Value::String(aud)) => vec![aud.as_str()],
+            Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JwtError {
+    Malformed(&'static str),
+    UnsupportedAlgorithm(String),
+    InvalidKey,
+    NoMatchingKey,
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    InvalidIssuer,
+    InvalidAudience,
+    TooOld,
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JwtError::Malformed(what) => write!(f, "Malformed JWT: {}", what),
+            JwtError::UnsupportedAlgorithm(alg) => write!(f, "Unsupported JWT algorithm: {}", alg),
+            JwtError::InvalidKey => write!(f, "Invalid JWT verification key"),
+            JwtError::NoMatchingKey => write!(f, "No key available to verify JWT"),
+            JwtError::InvalidSignature => write!(f, "Invalid JWT signature"),
+            JwtError::Expired => write!(f, "JWT has expired"),
+            JwtError::NotYetValid => write!(f, "JWT is not yet valid"),
+            JwtError::InvalidIssuer => write!(f, "JWT issuer is not trusted"),
+            JwtError::InvalidAudience => write!(f, "JWT audience is not accepted"),
+            JwtError::TooOld => write!(f, "JWT exceeds the maximum accepted age"),
+        }
+    }
+}
+
+/// Decodes the token's claims without checking the signature. Only use this
+/// when local verification is disabled and the PDP is trusted to judge the
+/// token on its own.
+pub fn decode_unverified(token: &str) -> Result<Claims, JwtError> {
+    let mut parts = token.split('.');
+    let (Some(_), Some(payload), Some(_), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(JwtError::Malformed("expected three segments"));
+    };
+    parse_claims(payload)
+}
+
+/// Verifies the token's signature against `keys`, then checks `exp`, `nbf`,
+/// `iss` and `aud`. `now` is the current time in seconds since the epoch.
+pub fn verify(
+    token: &str,
+    keys: &KeySet,
+    rules: &ValidationRules,
+    now: u64,
+) -> Result<Claims, JwtError> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(JwtError::Malformed("expected three segments"));
+    };
+
+    let header: Header = serde_json::from_slice(&decode_segment(header)?)
+        .map_err(|_| JwtError::Malformed("invalid header"))?;
+    if header.alg != "RS256" && header.alg != "ES256" {
+        return Err(JwtError::UnsupportedAlgorithm(header.alg));
+    }
+    let signature = decode_segment(signature)?;
+    let signing_input = &token.as_bytes()[..token.len() - signature_len(token)];
+
+    let mut candidates = keys.candidates(header.kid.as_deref()).peekable();
+    if candidates.peek().is_none() {
+        return Err(JwtError::NoMatchingKey);
+    }
+    if !candidates.any(|key| key.verify(&header.alg, signing_input, &signature)) {
+        return Err(JwtError::InvalidSignature);
+    }
+
+    let claims = parse_claims(payload)?;
+    validate_claims(&claims, rules, now)?;
+    Ok(claims)
+}
+
+fn validate_claims(claims: &Claims, rules: &ValidationRules, now: u64) -> Result<(), JwtError> {
+    let leeway = rules.leeway_secs;
+    match claims.get_u64("exp") {
+        Some(exp) if now >= exp.saturating_add(leeway) => return Err(JwtError::Expired),
+        Some(_) => {}
+        None => return Err(JwtError::Malformed("missing exp claim")),
+    }
+    if let Some(nbf) = claims.get_u64("nbf") {
+        if now.saturating_add(leeway) < nbf {
+            return Err(JwtError::NotYetValid);
+        }
+    }
+    if let Some(max_age) = rules.max_token_age_secs {
+        let Some(iat) = claims.get_u64("iat") else {
+            return Err(JwtError::Malformed("missing iat claim"));
+        };
+        if now.saturating_add(leeway) < iat {
+            return Err(JwtError::NotYetValid);
+        }
+        if now > iat.saturating_add(max_age).saturating_add(leeway) {
+            return Err(JwtError::TooOld);
+        }
+    }
+    if let Some(issuer) = &rules.issuer {
+        if claims.issuer() != Some(issuer.as_str()) {
+            return Err(JwtError::InvalidIssuer);
+        }
+    }
+    if !rules.audiences.is_empty() {
+        let audiences = claims.audiences();
+        if !rules
+            .audiences
+            .iter()
+            .any(|aud| audiences.contains(&aud.as_str()))
+        {
+            return Err(JwtError::InvalidAudience);
+        }
+    }
+    Ok(())
+}
+
+/// Length of the signature segment including its leading '.'.
+fn signature_len(token: &str) -> usize {
+    token.rfind('.').map(|idx| token.len() - idx).unwrap_or(0)
+}
+
+fn parse_claims(payload: &str) -> Result<Claims, JwtError> {
+    match serde_json::from_slice(&decode_segment(payload)?) {
+        Ok(Value::Object(raw)) => Ok(Claims { raw }),
+        _ => Err(JwtError::Malformed("invalid claims")),
+    }
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>, JwtError> {
+    URL_SAFE_NO_PAD
+        .decode(segment.trim_end_matches('='))
+        .map_err(|_| JwtError::Malformed("invalid base64url encoding"))
+}
+
+fn decode_member(value: Option<&str>) -> Result<Vec<u8>, JwtError> {
+    value
+        .ok_or(JwtError::InvalidKey)
+        .and_then(|v| decode_segment(v).map_err(|_| JwtError::InvalidKey))
+}
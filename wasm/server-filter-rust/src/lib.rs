@@ -1,30 +1,348 @@
+mod action;
+mod admin;
+mod asset;
+mod audit;
+mod breaker;
+mod cache;
+mod coalesce;
+mod concurrency;
+mod config;
+mod credentials;
+mod delegation;
+mod ext_authz;
+mod failover;
+mod grpc;
+mod health;
+mod hedge;
+mod identity_chain;
+mod jwks;
+mod jwt;
+mod local_policy;
+mod metrics;
+mod pip;
+mod protocol;
+mod quota;
+mod ratelimit;
+mod redact;
+mod replay;
+mod response;
+mod revocation;
+mod route;
+mod security_events;
+mod signature;
+mod slots;
+mod spiffe;
+mod tenant;
+#[cfg(test)]
+mod tests;
+mod upgrade;
+
+use proxy_wasm::hostcalls;
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
-use log::info;
-use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use wasm_common::annotation::RequestAnnotation;
+use wasm_common::callout::{self, HttpCallout};
+use wasm_common::connection::{self, ConnectionAttributes};
+use wasm_common::logging::{self, LogFields};
+use wasm_common::pdp::{
+    EvaluationRequest, EvaluationResponse, Obligations, PdpTransport, Principal, Query, Quota,
+};
+use wasm_common::query::QueryParams;
+use wasm_common::response::{send_problem, Problem};
+use wasm_common::{log_debug, log_info, log_warn, paths, time, trace};
 
-const PDP_SERVICE_CLUSTER: &str = "sgnl-pdp-service";
-const PDP_SERVICE_PATH: &str = "/access/v2/evaluations";
+use crate::admin::{
+    AdminConfig, CacheStats, DecisionCacheStats, Endpoint, FilterStatus, JwksStats, PdpStatus,
+};
+use crate::audit::{AuditBuffer, AuditQueue, AuditRecord};
+use crate::cache::CachedDecision;
+use crate::coalesce::{Flights, Outcome};
+use crate::concurrency::Overflow;
+use crate::config::{
+    EnforcementMode, FailureMode, FilterConfig, JwtConfig, PrincipalSource, TokenForwarding,
+};
+use crate::credentials::RemoteApiKeys;
+use crate::ext_authz::HttpAttributes;
+use crate::hedge::{Hedge, HedgeState, Hedges};
+use crate::jwks::{JwksFetch, RemoteJwks};
+use crate::jwt::{Claims, JwtError, KeySet};
+use crate::metrics::Metrics;
+use crate::protocol::PdpProtocol;
+use crate::response::{RenderedResponse, ResponseTemplate, TemplateVars};
+use crate::security_events::{SecurityEvent, SecurityEventBuffer};
+use crate::signature::{SignatureError, SignedRequest};
+use crate::tenant::{Tenant, TenantSource, Tenants};
 
 proxy_wasm::main! {{
     proxy_wasm::set_log_level(LogLevel::Info);
+    wasm_common::logging::init("server_filter");
     proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
-        Box::new(ServerFilterRoot)
+        Box::new(ServerFilterRoot::default())
     });
 }}
 
-struct ServerFilterRoot;
+#[derive(Default)]
+struct ServerFilterRoot {
+    config: Rc<FilterConfig>,
+    /// See `admin::config_hash`.
+    config_hash: Rc<String>,
+    jwt_keys: Rc<KeySet>,
+    /// Inline keys of each of `jwt.issuers`, by issuer.
+    issuer_keys: Rc<HashMap<String, Rc<KeySet>>>,
+    jwks_fetches: Vec<JwksFetch>,
+    api_keys_call: Option<u32>,
+    revocation_call: Option<u32>,
+    health_call: Option<u32>,
+    metrics: Metrics,
+    /// The settings of each of `tenancy.tenants`.
+    tenants: Tenants,
+    audit: AuditBuffer,
+    audit_queue: AuditQueue,
+    security_events: SecurityEventBuffer,
+    flights: Flights<ParkedRequest>,
+    hedges: Hedges<ParkedRequest>,
+    tick_period_ms: u64,
+    next_api_keys_fetch_ms: u64,
+    next_revocation_fetch_ms: u64,
+    /// JWKS generation last seen; an admin flush moving it on makes the root
+    /// refetch every JWKS.
+    jwks_generation: u64,
+}
+
+impl Context for ServerFilterRoot {
+    fn on_http_call_response(
+        &mut self,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        num_trailers: usize,
+    ) {
+        if let Some(request) = self.take_hedge(token_id) {
+            if let Some(mut request) = enter(&request) {
+                request.on_http_call_response(token_id, num_headers, body_size, num_trailers);
+            }
+            return;
+        }
+        if self.api_keys_call == Some(token_id) {
+            self.api_keys_call = None;
+            self.store_api_keys(body_size);
+            return;
+        }
+        if self.revocation_call == Some(token_id) {
+            self.revocation_call = None;
+            self.store_revocations(body_size);
+            return;
+        }
+        if self.health_call == Some(token_id) {
+            self.health_call = None;
+            self.record_health();
+            return;
+        }
+        let Some(fetch) = self
+            .jwks_fetches
+            .iter_mut()
+            .find(|fetch| fetch.call == Some(token_id))
+        else {
+            // Audit and security event batches are fire-and-forget; a rejected batch is dropped
+            let status = callout::response_status(self);
+            if !callout::is_success(&status) {
+                log_warn!(
+                    "Audit sink or security endpoint rejected batch with status {:?}",
+                    status
+                );
+            }
+            return;
+        };
+        fetch.call = None;
+        let shared_key = fetch.shared_key.clone();
+        let remote = fetch.remote.clone();
+
+        let status = callout::response_status(self);
+        if !callout::is_success(&status) {
+            log_warn!("JWKS fetch failed with status {:?}", status);
+            return;
+        }
+
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        match jwks::store(self, &shared_key, &remote, &body, time::now_ms(self)) {
+            Ok(count) => log_info!("JWKS refreshed ({} key(s))", count),
+            Err(e) => log_warn!("JWKS refresh rejected: {}", e),
+        }
+    }
 
-impl Context for ServerFilterRoot {}
+    fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, response_size: usize) {
+        if let Some(request) = self.take_hedge(token_id) {
+            if let Some(mut request) = enter(&request) {
+                request.on_grpc_call_response(token_id, status_code, response_size);
+            }
+        }
+    }
+}
 
 impl RootContext for ServerFilterRoot {
     fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
-        info!("[Server WASM Rust] VM started");
-        true
+        let raw = self.get_vm_configuration().unwrap_or_default();
+        match wasm_common::vm::configure(&raw) {
+            Ok(vm_config) => {
+                log_info!(
+                    "VM started (log_level: {:?}, {} constant(s))",
+                    vm_config.log_level,
+                    vm_config.constants.len()
+                );
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid VM configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let raw = self.get_plugin_configuration().unwrap_or_default();
+        let parsed = wasm_common::config::parse::<FilterConfig>(&raw).map_err(|e| e.to_string());
+        match parsed.and_then(|config| config.validate().map(|()| config)) {
+            Ok(config) => {
+                log_info!(
+                    "Configured: pdp_cluster={}, pdp_path={}, timeout={}ms",
+                    config.pdp_cluster,
+                    config.pdp_path,
+                    config.pdp_timeout_ms
+                );
+                self.jwt_keys = match &config.jwt {
+                    Some(jwt_config) => {
+                        let keys = KeySet::from_jwks(&jwt_config.jwks);
+                        log_info!("JWT verification enabled with {} key(s)", keys.len());
+                        Rc::new(keys)
+                    }
+                    None if config.principal.source == PrincipalSource::Jwt => {
+                        log_warn!(
+                            "JWT verification disabled (no jwt config), principals are unverified"
+                        );
+                        Rc::new(KeySet::default())
+                    }
+                    None => {
+                        log_info!("JWT verification disabled (no jwt config)");
+                        Rc::new(KeySet::default())
+                    }
+                };
+                self.issuer_keys = Rc::new(
+                    config
+                        .jwt
+                        .as_ref()
+                        .map(JwtConfig::issuer_keys)
+                        .unwrap_or_default(),
+                );
+                for (issuer, keys) in self.issuer_keys.iter() {
+                    log_info!("Trusting issuer {} with {} key(s)", issuer, keys.len());
+                }
+                let tenants = config.tenancy.iter().flat_map(|tenancy| &tenancy.tenants);
+                let tenants: BTreeMap<String, Tenant> = tenants
+                    .map(|(name, tenant)| (name.clone(), Tenant::new(tenant.apply(name, &config))))
+                    .collect();
+                if !tenants.is_empty() {
+                    log_info!("Serving {} tenant(s)", tenants.len());
+                }
+                self.jwks_fetches = tenant::jwks_sources(&config, &tenants)
+                    .into_iter()
+                    .map(|(shared_key, remote)| JwksFetch::new(shared_key, remote))
+                    .collect();
+                self.tenants = Rc::new(tenants);
+                wasm_common::logging::configure(&config.logging);
+                self.metrics = Metrics::define(&config.stat_prefix);
+                self.config = Rc::new(config);
+                self.config_hash = Rc::new(admin::config_hash(&raw));
+
+                // One tick drives every periodic task, at the shortest interval
+                let intervals = [
+                    self.jwks_fetches
+                        .iter()
+                        .map(|fetch| fetch.remote.refresh_interval())
+                        .min(),
+                    self.remote_api_keys()
+                        .map(|remote| remote.refresh_interval()),
+                    self.config
+                        .revocation
+                        .as_ref()
+                        .map(|revocation| revocation.refresh_interval()),
+                    self.config
+                        .audit
+                        .as_ref()
+                        .map(|audit| audit.flush_interval()),
+                    self.config
+                        .security_events
+                        .as_ref()
+                        .map(|events| events.flush_interval()),
+                    self.config
+                        .coalescing
+                        .as_ref()
+                        .map(|coalescing| coalescing.poll_interval()),
+                    self.config
+                        .health_check
+                        .as_ref()
+                        .map(|health_check| health_check.interval()),
+                    self.config
+                        .hedging
+                        .as_ref()
+                        .map(|hedging| hedging.poll_interval()),
+                ];
+                if let Some(period) = intervals.into_iter().flatten().min() {
+                    self.tick_period_ms = period.as_millis() as u64;
+                    self.set_tick_period(period);
+                }
+                // Fetch immediately rather than waiting a full interval for the first tick
+                self.fetch_jwks();
+                self.fetch_api_keys();
+                self.fetch_revocations();
+                self.open_audit_queue();
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid plugin configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_tick(&mut self) {
+        // Ticks can fire slightly early, so allow half a period of slack
+        let now_ms = time::now_ms(self);
+        self.fetch_jwks();
+        if now_ms + self.tick_period_ms / 2 >= self.next_api_keys_fetch_ms {
+            self.fetch_api_keys();
+        }
+        if now_ms + self.tick_period_ms / 2 >= self.next_revocation_fetch_ms {
+            self.fetch_revocations();
+        }
+        self.open_audit_queue();
+        self.flush_audit();
+        self.flush_security_events();
+        self.check_pdp_health(now_ms);
+        self.resume_remote_flights(now_ms);
+        self.send_hedges(now_ms);
     }
 
-    fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
-        Some(Box::new(ServerFilterHttp::default()))
+    fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(ServerFilterHttp {
+            context_id,
+            config: self.config.clone(),
+            config_hash: self.config_hash.clone(),
+            jwt_keys: self.jwt_keys.clone(),
+            issuer_keys: self.issuer_keys.clone(),
+            metrics: self.metrics,
+            tenants: self.tenants.clone(),
+            audit: self.audit.clone(),
+            audit_queue: self.audit_queue.clone(),
+            security_events: self.security_events.clone(),
+            flights: self.flights.clone(),
+            hedges: self.hedges.clone(),
+            ..Default::default()
+        }))
     }
 
     fn get_type(&self) -> Option<ContextType> {
@@ -32,100 +350,657 @@ impl RootContext for ServerFilterRoot {
     }
 }
 
-#[derive(Default)]
-struct ServerFilterHttp {
-    jwt_token: String,
-    principal_id: String,
-    asset_id: String,
+impl ServerFilterRoot {
+    /// Probes the PDP's health endpoint when due, and reports the health
+    /// known from probes by any VM.
+    fn check_pdp_health(&mut self, now_ms: u64) {
+        let Some(health_check) = self.config.health_check.clone() else {
+            return;
+        };
+        let healthy = health::is_healthy(self, &health_check, now_ms);
+        metrics::record(self.metrics.pdp_healthy, healthy as u64);
+        if self.health_call.is_some()
+            || !health::probe_due(self, &health_check, now_ms, self.tick_period_ms / 2)
+        {
+            return;
+        }
+
+        let dispatched = HttpCallout::get(
+            &self.config.pdp_cluster,
+            &self.config.pdp_authority,
+            &health_check.path,
+        )
+        .timeout(health_check.timeout())
+        .dispatch(self);
+        match dispatched {
+            Ok(call_id) => self.health_call = Some(call_id),
+            Err(e) => {
+                log_warn!("Failed to dispatch PDP health probe: {:?}", e);
+                health::record(self, &health_check, now_ms, false);
+            }
+        }
+    }
+
+    fn record_health(&self) {
+        let Some(health_check) = &self.config.health_check else {
+            return;
+        };
+        // Timeouts and resets surface as a missing status
+        let status = callout::response_status(self);
+        let success = callout::is_success(&status);
+        if !success {
+            log_warn!("PDP health probe failed with status {:?}", status);
+        }
+        let healthy = health::record(self, health_check, time::now_ms(self), success);
+        metrics::record(self.metrics.pdp_healthy, healthy as u64);
+    }
+
+    /// Resumes requests parked behind evaluations that another VM finished
+    /// or gave up on.
+    fn resume_remote_flights(&self, now_ms: u64) {
+        let mut finished = Vec::new();
+        {
+            let mut flights = self.flights.borrow_mut();
+            for (key, flight) in flights.iter_mut() {
+                if flight.waiters.is_empty() || flight.leading {
+                    continue;
+                }
+                if let Some(outcome) = coalesce::poll(self, &coalesce::flight_key(key), now_ms) {
+                    finished.push((std::mem::take(&mut flight.waiters), outcome));
+                }
+            }
+            flights.retain(|_, flight| flight.leading || !flight.waiters.is_empty());
+        }
+
+        // Resuming switches the effective context, so it comes last
+        for (waiters, outcome) in finished {
+            log_info!("Resuming {} parked request(s) from tick", waiters.len());
+            resume_waiters(waiters, outcome.as_ref());
+        }
+    }
+
+    /// Makes the hedged calls that are due, and forgets callouts answered
+    /// before theirs was needed. The calls are made through the requests'
+    /// copies, but their responses come back to the root context.
+    fn send_hedges(&self, now_ms: u64) {
+        let slack_ms = self.tick_period_ms / 2;
+        self.hedges.borrow_mut().retain_mut(|hedge| {
+            if hedge.call.is_some() {
+                return true;
+            }
+            let mut request = hedge.request.borrow_mut();
+            if request.hedge.get() != HedgeState::Pending {
+                return false;
+            }
+            if now_ms + slack_ms < hedge.due_ms {
+                return true;
+            }
+            match request.dispatch_pdp() {
+                Ok(call_id) => {
+                    log_info!(
+                        "Hedging slow PDP call on {} (call_id: {})",
+                        request.pdp_clusters[0],
+                        call_id
+                    );
+                    metrics::increment(self.metrics.pdp_hedges);
+                    request.hedge.set(HedgeState::Sent);
+                    request.pdp_dispatched_at_ms = now_ms;
+                    hedge.call = Some(call_id);
+                    true
+                }
+                Err(e) => {
+                    log_warn!("Failed to dispatch hedged PDP call: {}", e);
+                    false
+                }
+            }
+        });
+    }
+
+    /// The request a hedged call was made for, if `token_id` is one.
+    fn take_hedge(&self, token_id: u32) -> Option<ParkedRequest> {
+        let mut hedges = self.hedges.borrow_mut();
+        let index = hedges
+            .iter()
+            .position(|hedge| hedge.call == Some(token_id))?;
+        Some(hedges.swap_remove(index).request)
+    }
+
+    /// Dispatches the JWKS fetches that are due.
+    fn fetch_jwks(&mut self) {
+        let now_ms = time::now_ms(self);
+        let generation = admin::generation(self, admin::JWKS_GENERATION_KEY);
+        let flushed = generation != self.jwks_generation;
+        self.jwks_generation = generation;
+        let mut fetches = std::mem::take(&mut self.jwks_fetches);
+        for fetch in &mut fetches {
+            // Ticks can fire slightly early, so allow half a period of slack
+            if !flushed && now_ms + self.tick_period_ms / 2 < fetch.next_fetch_ms {
+                continue;
+            }
+            if fetch.call.is_some() {
+                log_info!("JWKS fetch already in flight, skipping");
+                continue;
+            }
+
+            let remote = &fetch.remote;
+            let dispatched = HttpCallout::get(&remote.cluster, &remote.authority, &remote.path)
+                .header("accept", "application/json")
+                .timeout(remote.timeout())
+                .dispatch(self);
+            match dispatched {
+                Ok(call_id) => {
+                    log_info!("Dispatched JWKS fetch (call_id: {})", call_id);
+                    fetch.call = Some(call_id);
+                    fetch.next_fetch_ms = now_ms + remote.refresh_interval_ms;
+                }
+                Err(e) => log_warn!("Failed to dispatch JWKS fetch: {:?}", e),
+            }
+        }
+        self.jwks_fetches = fetches;
+    }
+
+    /// The API key table endpoint, if API keys are in use and fetched.
+    fn remote_api_keys(&self) -> Option<RemoteApiKeys> {
+        let principal = &self.config.principal;
+        if principal.source != PrincipalSource::ApiKey {
+            return None;
+        }
+        principal.api_keys.remote.clone()
+    }
+
+    fn fetch_api_keys(&mut self) {
+        let Some(remote) = self.remote_api_keys() else {
+            return;
+        };
+        if self.api_keys_call.is_some() {
+            log_info!("API key table fetch already in flight, skipping");
+            return;
+        }
+
+        let dispatched = HttpCallout::get(&remote.cluster, &remote.authority, &remote.path)
+            .header("accept", "application/json")
+            .timeout(remote.timeout())
+            .dispatch(self);
+        match dispatched {
+            Ok(call_id) => {
+                log_info!("Dispatched API key table fetch (call_id: {})", call_id);
+                self.api_keys_call = Some(call_id);
+                self.next_api_keys_fetch_ms = time::now_ms(self) + remote.refresh_interval_ms;
+            }
+            Err(e) => log_warn!("Failed to dispatch API key table fetch: {:?}", e),
+        }
+    }
+
+    fn store_api_keys(&self, body_size: usize) {
+        let status = callout::response_status(self);
+        if !callout::is_success(&status) {
+            log_warn!("API key table fetch failed with status {:?}", status);
+            return;
+        }
+
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        match credentials::store(self, &body) {
+            Ok(count) => log_info!("API key table refreshed ({} key(s))", count),
+            Err(e) => log_warn!("API key table refresh rejected: {}", e),
+        }
+    }
+
+    fn fetch_revocations(&mut self) {
+        let Some(revocation) = self.config.revocation.clone() else {
+            return;
+        };
+        if self.revocation_call.is_some() {
+            log_info!("Revocation list fetch already in flight, skipping");
+            return;
+        }
+
+        let dispatched =
+            HttpCallout::get(&revocation.cluster, &revocation.authority, &revocation.path)
+                .header("accept", "application/json")
+                .timeout(revocation.timeout())
+                .dispatch(self);
+        match dispatched {
+            Ok(call_id) => {
+                log_info!("Dispatched revocation list fetch (call_id: {})", call_id);
+                self.revocation_call = Some(call_id);
+                self.next_revocation_fetch_ms = time::now_ms(self) + revocation.refresh_interval_ms;
+            }
+            Err(e) => log_warn!("Failed to dispatch revocation list fetch: {:?}", e),
+        }
+    }
+
+    fn store_revocations(&self, body_size: usize) {
+        let status = callout::response_status(self);
+        if !callout::is_success(&status) {
+            log_warn!("Revocation list fetch failed with status {:?}", status);
+            return;
+        }
+
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        match revocation::store(self, &body) {
+            Ok(count) => log_info!("Revocation list refreshed ({} entry(ies))", count),
+            Err(e) => log_warn!("Revocation list refresh rejected: {}", e),
+        }
+    }
+
+    /// Registers the audit queue on the flusher, or finds the flusher's queue
+    /// on other VMs, unless that is done already.
+    fn open_audit_queue(&mut self) {
+        let Some(queue) = self
+            .config
+            .audit
+            .as_ref()
+            .and_then(|audit| audit.queue.as_ref())
+        else {
+            return;
+        };
+        if self.audit_queue.get().is_some() {
+            return;
+        }
+        let queue_id = match queue.flusher {
+            true => Some(self.register_shared_queue(&queue.name)),
+            false => self.resolve_shared_queue(&queue.vm_id, &queue.name),
+        };
+        if let Some(queue_id) = queue_id {
+            log_info!(
+                "Audit queue {} open (flusher: {})",
+                queue.name,
+                queue.flusher
+            );
+            self.audit_queue.set(Some(queue_id));
+        }
+    }
+
+    /// Posts buffered audit records to the sink, `max_batch` per callout. The
+    /// flusher first takes the records queued by other VMs.
+    fn flush_audit(&mut self) {
+        let Some(audit_config) = self.config.audit.clone() else {
+            return;
+        };
+        let flusher = audit_config
+            .queue
+            .as_ref()
+            .is_some_and(|queue| queue.flusher);
+        if let Some(queue_id) = self.audit_queue.get().filter(|_| flusher) {
+            audit::drain(self, queue_id, &self.audit, audit_config.max_buffered);
+        }
+        loop {
+            let batch = audit::take_batch(&self.audit, audit_config.max_batch);
+            if batch.is_empty() {
+                return;
+            }
+            let body = match serde_json::to_vec(&batch) {
+                Ok(body) => body,
+                Err(e) => {
+                    log_warn!("Failed to serialize audit batch: {}", e);
+                    continue;
+                }
+            };
+            let dispatched = HttpCallout::post(
+                &audit_config.cluster,
+                &audit_config.authority,
+                &audit_config.path,
+            )
+            .json(&body)
+            .timeout(audit_config.timeout())
+            .dispatch(self);
+            if let Err(e) = dispatched {
+                log_warn!("Failed to dispatch audit batch of {}: {:?}", batch.len(), e);
+            }
+        }
+    }
+
+    /// Posts the security events raised since the last tick.
+    fn flush_security_events(&mut self) {
+        let Some(events_config) = &self.config.security_events else {
+            return;
+        };
+        let events = std::mem::take(&mut *self.security_events.borrow_mut());
+        if events.is_empty() {
+            return;
+        }
+        let body = match serde_json::to_vec(&events) {
+            Ok(body) => body,
+            Err(e) => {
+                log_warn!("Failed to serialize security events: {}", e);
+                return;
+            }
+        };
+        let dispatched = HttpCallout::post(
+            &events_config.cluster,
+            &events_config.authority,
+            &events_config.path,
+        )
+        .json(&body)
+        .timeout(events_config.timeout())
+        .dispatch(self);
+        if let Err(e) = dispatched {
+            log_warn!(
+                "Failed to dispatch {} security event(s): {:?}",
+                events.len(),
+                e
+            );
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct Principal {
-    id: String,
+/// Logs from an HTTP context with the request's fields, for correlation.
+macro_rules! req_info {
+    ($ctx:expr, $($arg:tt)+) => {
+        log_info!(fields: $ctx.log_fields(); $($arg)+)
+    };
 }
 
-#[derive(Serialize)]
-struct Query {
-    #[serde(rename = "assetId")]
-    asset_id: String,
-    action: String,
+/// Like `req_info!`, for failures, which are logged even when sampling.
+macro_rules! req_warn {
+    ($ctx:expr, $($arg:tt)+) => {
+        log_warn!(fields: $ctx.log_fields(); $($arg)+)
+    };
 }
 
-#[derive(Serialize)]
-struct EvaluationRequest {
-    principal: Principal,
-    queries: Vec<Query>,
+/// Like `req_info!`, for detail such as headers and bodies, which must go
+/// through `logging::headers` and `logging::body`.
+macro_rules! req_debug {
+    ($ctx:expr, $($arg:tt)+) => {
+        log_debug!(fields: $ctx.log_fields(); $($arg)+)
+    };
 }
 
-#[derive(Deserialize)]
-struct Decision {
-    decision: String,
-    reason: String,
+/// State of a request parked behind an identical evaluation, through which
+/// the evaluation's result is applied to it. The request's own context
+/// shares it to pick up the obligations and quota for the response phase.
+type ParkedRequest = Rc<RefCell<ServerFilterHttp>>;
+
+#[derive(Default, Clone)]
+struct ServerFilterHttp {
+    context_id: u32,
+    config: Rc<FilterConfig>,
+    config_hash: Rc<String>,
+    jwt_keys: Rc<KeySet>,
+    issuer_keys: Rc<HashMap<String, Rc<KeySet>>>,
+    metrics: Metrics,
+    tenants: Tenants,
+    audit: AuditBuffer,
+    audit_queue: AuditQueue,
+    security_events: SecurityEventBuffer,
+    flights: Flights<ParkedRequest>,
+    /// Key of the evaluation this context dispatches for the requests
+    /// coalesced onto it.
+    flight: Option<String>,
+    /// Set while the request is parked behind another's evaluation.
+    parked: Option<ParkedRequest>,
+    /// The request's `x-request-id`, generated if the client sent none.
+    request_id: String,
+    jwt_token: String,
+    path: String,
+    /// Set while the request is held for body-based asset extraction.
+    awaiting_body: bool,
+    /// Set when `request_signing` is configured and the signature headers
+    /// checked out, until the HMAC is verified over the body.
+    signed_request: Option<SignedRequest>,
+    principal_id: String,
+    /// The party acting on behalf of the principal, with `delegation`.
+    actor: Option<String>,
+    asset_id: String,
+    action: String,
+    /// Every query sent to the PDP, starting with (asset_id, action).
+    queries: Vec<Query>,
+    /// Values of `context_headers` sent along with the queries.
+    context: BTreeMap<String, String>,
+    /// Sent along with `connection_attributes`.
+    connection: Option<ConnectionAttributes>,
+    /// Described to the PDP with the `ext_authz` protocol.
+    http_attributes: HttpAttributes,
+    /// The PIP callout for the principal's attributes, while outstanding.
+    pip_call: Option<u32>,
+    failure_mode: FailureMode,
+    /// When the outstanding PDP callout was dispatched.
+    pdp_dispatched_at_ms: u64,
+    /// Enforced on the upstream response of an allowed request.
+    obligations: Obligations,
+    /// Set while the response is held for field redaction.
+    redacting_response: bool,
+    /// Budget reported to the client in `X-RateLimit-*` response headers.
+    quota: Option<Quota>,
+    /// Set for gRPC requests when `grpc` is configured.
+    grpc: bool,
+    /// Set when rejections are sent as an HTML page, see `responses.format`.
+    html_replies: bool,
+    /// The request's audit record, held until the stream ends when
+    /// `audit.trailers` is set. Shared with the request's parked copy.
+    held_audit: Rc<RefCell<Option<AuditRecord>>>,
+    /// Values of `audit.trailers` seen on the stream.
+    trailers: BTreeMap<String, String>,
+    /// Set for WebSocket and other upgrade requests.
+    upgrade: bool,
+    /// The JWKS fetch made for a token signed with a key not published yet,
+    /// outstanding alongside the PDP call.
+    key_fetch: Option<KeyFetch>,
+    /// The claim of the still unverified token that named the request's
+    /// tenant, checked against the token once it verifies.
+    tenant_claim: Option<String>,
+    /// Set once the request was rejected after such a fetch, for its token or
+    /// by the checks held back until it verified, so a late PDP response is
+    /// ignored.
+    rejected: bool,
+    /// Set while the request is paused on a callout or behind an evaluation
+    /// in flight. Shared with the request's parked copy.
+    waiting: Rc<Cell<bool>>,
+    /// When the request's headers reached the filter.
+    started_ms: u64,
+    /// When the request was let through or answered, for the latency the
+    /// filter added. Never set for requests the filter passes over, such as
+    /// bypassed and admin ones. Shared with the request's parked copy.
+    released_ms: Rc<Cell<Option<u64>>>,
+    /// Set while the request's PDP callout holds a `concurrency_limit` slot.
+    pdp_slot: bool,
+    /// Clusters the evaluation may still be tried on, starting with the one
+    /// the outstanding callout went to.
+    pdp_clusters: Vec<String>,
+    hedges: Hedges<ParkedRequest>,
+    /// Shared with the copy of the request registered for `hedging`.
+    hedge: Rc<Cell<HedgeState>>,
+    /// That copy, from which the request takes on the obligations of the
+    /// hedged call's response if it was the one acted on.
+    hedged: Option<ParkedRequest>,
+    /// Set on that copy.
+    hedged_call: bool,
 }
 
-#[derive(Deserialize)]
-struct EvaluationResponse {
-    decisions: Vec<Decision>,
+/// A JWKS fetch made on behalf of one request.
+#[derive(Clone, Debug)]
+struct KeyFetch {
+    call: u32,
+    shared_key: String,
+    remote: RemoteJwks,
+    /// Set when the request was allowed while the fetch was outstanding; it
+    /// is resumed once the token verifies.
+    allowed: bool,
+    /// The evaluation's decision, with its source, when it came in while the
+    /// fetch was outstanding. It is enforced once the token verifies.
+    outcome: Option<(Outcome, &'static str)>,
+    /// Set when the PDP's own decision came in while the fetch was
+    /// outstanding, to how long it is cached for once the token verifies.
+    cache_ttl_ms: Option<u64>,
 }
 
+/// Added to requests let through because the PDP could not be reached, when
+/// `failure_mode` is `fail_open_with_header`.
+const FAIL_OPEN_HEADER: &str = "X-PDP-Fail-Open";
+
+/// Added to requests let through despite a deny, in `shadow` enforcement
+/// mode.
+const SHADOW_DECISION_HEADER: &str = "X-Shadow-Decision";
+const SHADOW_REASON_HEADER: &str = "X-Shadow-Reason";
+
 impl Context for ServerFilterHttp {
-    fn on_http_call_response(&mut self, _token_id: u32, _num_headers: usize, body_size: usize, _num_trailers: usize) {
-        info!("[Server WASM Rust] Received PDP response (body size: {})", body_size);
+    fn on_http_call_response(
+        &mut self,
+        token_id: u32,
+        _num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        if self
+            .key_fetch
+            .as_ref()
+            .is_some_and(|fetch| fetch.call == token_id)
+        {
+            self.on_key_fetch_response(body_size);
+            return;
+        }
+        if self.pip_call == Some(token_id) {
+            self.on_pip_response(body_size);
+            return;
+        }
+        self.release_pdp_slot();
+        if self.rejected {
+            return;
+        }
+        let Some(hedge) = self.settle_hedge() else {
+            return;
+        };
+        req_info!(self, "Received PDP response (body size: {})", body_size);
+        self.record_pdp_latency();
+
+        // Timeouts and resets surface as a missing or 5xx status
+        let status = callout::response_status(self);
+        if !callout::is_success(&status) {
+            req_warn!(self, "PDP call failed with status {:?}", status);
+            self.on_pdp_failure(hedge);
+            return;
+        }
 
         // Get response body
-        let response_body = match self.get_http_call_response_body(0, body_size) {
-            Some(body) => body,
-            None => {
-                info!("[Server WASM Rust] Failed to get PDP response body");
-                self.send_forbidden_response("Policy evaluation failed", "");
-                return;
-            }
+        let Some(response_body) = self.get_http_call_response_body(0, body_size) else {
+            req_warn!(self, "Failed to get PDP response body");
+            self.on_pdp_failure(hedge);
+            return;
         };
+        req_debug!(self, "PDP response body: {}", logging::body(&response_body));
 
         // Parse PDP response
-        let eval_resp: EvaluationResponse = match serde_json::from_slice(&response_body) {
-            Ok(resp) => resp,
+        match self
+            .config
+            .pdp_protocol
+            .decode(&self.config, &response_body, self.queries.len())
+        {
+            Ok(resp) => {
+                self.record_pdp_outcome(true);
+                self.on_pdp_response(resp);
+            }
             Err(e) => {
-                info!("[Server WASM Rust] Failed to parse PDP response: {}", e);
-                self.send_forbidden_response("Policy evaluation failed", "");
-                return;
+                req_warn!(self, "Failed to parse PDP response: {}", e);
+                metrics::increment(self.metrics.pdp_parse_errors);
+                self.record_pdp_outcome(false);
+                self.fail_pdp_response();
             }
-        };
+        }
+    }
 
-        if eval_resp.decisions.is_empty() {
-            info!("[Server WASM Rust] No decisions in PDP response");
-            self.send_forbidden_response("Policy evaluation failed", "");
+    fn on_grpc_call_response(&mut self, _token_id: u32, status_code: u32, response_size: usize) {
+        self.release_pdp_slot();
+        if self.rejected {
             return;
         }
+        let Some(hedge) = self.settle_hedge() else {
+            return;
+        };
+        req_info!(
+            self,
+            "Received PDP gRPC response (status: {}, size: {})",
+            status_code,
+            response_size
+        );
+        self.record_pdp_latency();
 
-        let decision = &eval_resp.decisions[0];
-        info!("[Server WASM Rust] PDP decision: {} ({})", decision.decision, decision.reason);
-
-        if decision.decision != "Allow" {
-            // Access denied - send 403
-            self.send_forbidden_response("Access denied by policy", &decision.reason);
+        if status_code != 0 {
+            let (_, message) = self.get_grpc_status();
+            req_warn!(
+                self,
+                "PDP gRPC call failed: {}",
+                message.unwrap_or_default()
+            );
+            self.on_pdp_failure(hedge);
             return;
         }
 
-        // Access allowed - add headers to indicate PDP validation succeeded
-        self.add_http_request_header("X-PDP-Decision", "Allow");
-        self.add_http_request_header("X-PDP-Reason", &decision.reason);
-        self.add_http_request_header("X-Principal-ID", &self.principal_id);
-
-        info!("[Server WASM Rust] Access granted, resuming request");
+        let response_body = self
+            .get_grpc_call_response_body(0, response_size)
+            .unwrap_or_default();
+        match self
+            .config
+            .pdp_protocol
+            .decode_grpc(&response_body, self.queries.len())
+        {
+            Ok(resp) => {
+                self.record_pdp_outcome(true);
+                self.on_pdp_response(resp);
+            }
+            Err(e) => {
+                req_warn!(self, "Failed to decode PDP response: {}", e);
+                metrics::increment(self.metrics.pdp_parse_errors);
+                self.record_pdp_outcome(false);
+                self.fail_pdp_response();
+            }
+        }
+    }
 
-        // Resume the request to service-b
-        self.resume_http_request();
+    fn on_done(&mut self) -> bool {
+        // The request went away with the callout still outstanding; apply the
+        // failure mode to the requests parked behind it rather than leaving
+        // them waiting for a response no one will handle.
+        // A hedged response acted on has finished the evaluation already
+        if self.flight.is_some() && self.hedge.get() != HedgeState::HedgeWon {
+            req_info!(self, "PDP evaluation abandoned");
+            self.finish_flight(None);
+        }
+        if !self.hedge.get().decided() {
+            self.hedge.set(HedgeState::Done);
+        }
+        self.set_waiting(false);
+        self.release_pdp_slot();
+        if let Some(released_ms) = self.released_ms.get() {
+            metrics::record(
+                self.metrics.added_latency_ms,
+                released_ms.saturating_sub(self.started_ms),
+            );
+        }
+        if let Some(mut record) = self.held_audit.take() {
+            record.trailers = std::mem::take(&mut self.trailers);
+            self.submit_audit(record);
+        }
+        true
     }
 }
 
 impl HttpContext for ServerFilterHttp {
-    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+    fn on_http_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        self.started_ms = time::now_ms(self);
+        // Only this filter may set the headers upstreams trust
+        for header in &self.config.trusted_headers {
+            self.set_http_request_header(header, None);
+        }
+
+        // Correlate everything about this request, including upstream, by one id
+        self.request_id = match self.get_http_request_header("x-request-id") {
+            Some(id) if !id.is_empty() => id,
+            _ => {
+                let id = generate_request_id(time::now_nanos(self), self.context_id);
+                self.set_http_request_header("x-request-id", Some(&id));
+                id
+            }
+        };
+
         // Get request path and method for context
         let path = match self.get_http_request_header(":path") {
             Some(p) => p,
             None => {
-                info!("[Server WASM Rust] No path header found");
+                req_info!(self, "No path header found");
                 return Action::Continue;
             }
         };
@@ -135,122 +1010,2092 @@ impl HttpContext for ServerFilterHttp {
             None => "GET".to_string(),
         };
 
-        info!("[Server WASM Rust] Intercepted inbound request: {} {}", method, path);
+        req_info!(self, "Intercepted inbound request: {} {}", method, path);
+        if logging::enabled(logging::Level::Debug) {
+            req_debug!(
+                self,
+                "Request headers: {}",
+                logging::headers(&self.get_http_request_headers())
+            );
+        }
 
-        // Extract JWT token from Authorization header
-        let auth_header = match self.get_http_request_header("Authorization") {
-            Some(h) => h,
-            None => {
-                info!("[Server WASM Rust] Missing Authorization header");
-                self.send_unauthorized_response("Missing Authorization header");
-                return Action::Pause;
-            }
-        };
+        // gRPC clients can only make sense of rejections sent as gRPC statuses
+        let content_type = self
+            .get_http_request_header("content-type")
+            .unwrap_or_default();
+        self.grpc = self.config.grpc.is_some() && grpc::is_grpc(&content_type);
+        let accept = self.get_http_request_header("accept").unwrap_or_default();
+        self.html_replies = !self.grpc && self.config.responses.wants_html(&accept);
 
-        // Parse Bearer token
-        if !auth_header.starts_with("Bearer ") {
-            info!("[Server WASM Rust] Invalid Authorization header format");
-            self.send_unauthorized_response("Invalid Authorization header format");
-            return Action::Pause;
+        let upgrade = upgrade::requested_protocol(|name| self.get_http_request_header(name));
+        if let Some(protocol) = &upgrade {
+            req_info!(self, "Request asks to upgrade to {}", protocol);
         }
+        self.upgrade = upgrade.is_some();
 
-        self.jwt_token = auth_header.trim_start_matches("Bearer ").to_string();
-        info!("[Server WASM Rust] JWT token extracted (length: {})", self.jwt_token.len());
+        // Blocked paths are rejected outright, even if also listed for bypass
+        if paths::any_match(&self.config.deny_paths, &path) {
+            req_info!(self, "Path {} is on the deny list", path);
+            metrics::increment(self.metrics.denied);
+            self.record_decision("Deny", "path_denied", "deny_list", 0);
+            self.send_forbidden_response("Access denied", "path_denied");
+            return Action::Pause;
+        }
 
-        // Get principal from X-Service-ID header (simplified - in production, decode JWT)
-        self.principal_id = self.get_http_request_header("X-Service-ID")
-            .unwrap_or_else(|| "service-a".to_string());
+        // The filter answers its admin endpoint itself
+        let admin = self.config.admin.as_ref();
+        if let Some((admin, endpoint)) =
+            admin.and_then(|admin| Some((admin, admin.endpoint(&path)?)))
+        {
+            self.serve_admin(admin, endpoint, &method, &path);
+            return Action::Pause;
+        }
 
-        // Extract asset ID from query parameters
-        self.asset_id = self.extract_asset_from_path(&path);
-        if self.asset_id.is_empty() {
-            self.asset_id = "default-asset".to_string();
+        // Public endpoints need neither credentials nor a PDP decision
+        if paths::any_match(&self.config.bypass_paths, &path) {
+            req_info!(self, "Bypassing authorization for {}", path);
+            return Action::Continue;
         }
 
-        info!("[Server WASM Rust] Calling PDP: principal={}, asset={}", self.principal_id, self.asset_id);
+        // The rest of the request is handled with its tenant's settings
+        if let Some(tenancy) = self.config.tenancy.clone() {
+            let token = self.get_http_request_header(&self.config.headers.token);
+            let token = token
+                .as_deref()
+                .and_then(|value| self.config.headers.token_from(value));
+            let authority = self.get_http_request_header(":authority");
+            match tenancy
+                .tenant(authority.as_deref(), &path, token)
+                .and_then(|name| self.tenants.get(&name))
+            {
+                Some(tenant) => {
+                    req_info!(
+                        self,
+                        "Request is for tenant {}",
+                        tenant.config.tenant.as_deref().unwrap_or_default()
+                    );
+                    self.config = tenant.config.clone();
+                    self.jwt_keys = tenant.jwt_keys.clone();
+                    self.issuer_keys = tenant.issuer_keys.clone();
+                    self.metrics = tenant.metrics;
+                    if tenancy.source == TenantSource::Claim {
+                        self.tenant_claim = Some(tenancy.claim.clone());
+                    }
+                }
+                None if tenancy.require_tenant => {
+                    req_info!(self, "No tenant configured for the request");
+                    metrics::increment(self.metrics.denied);
+                    self.send_forbidden_response("Unknown tenant", "unknown_tenant");
+                    return Action::Pause;
+                }
+                None => {}
+            }
+        }
 
-        // Call PDP to evaluate authorization
-        let eval_request = EvaluationRequest {
-            principal: Principal {
-                id: self.principal_id.clone(),
-            },
-            queries: vec![Query {
-                asset_id: self.asset_id.clone(),
-                action: "call".to_string(),
-            }],
+        // Apply per-route overrides
+        let route = route::load(self, &self.config.route_metadata_namespace);
+        if route.skip {
+            req_info!(self, "Authorization skipped for route");
+            return Action::Continue;
+        }
+        let grpc_call = match &self.config.grpc {
+            Some(grpc_config) if self.grpc && grpc_config.derive_attributes => {
+                grpc::service_and_method(&path)
+            }
+            _ => None,
         };
+        self.action = route
+            .action
+            .or_else(|| grpc_call.map(|(_, grpc_method)| grpc_method.to_string()))
+            .or_else(|| {
+                self.config
+                    .action_mapping
+                    .as_ref()
+                    .and_then(|m| m.resolve(&method, &path))
+            })
+            .unwrap_or_else(|| self.config.action.clone());
+        self.failure_mode = route.failure_mode.unwrap_or(self.config.failure_mode);
 
-        let request_body = match serde_json::to_vec(&eval_request) {
-            Ok(body) => body,
-            Err(e) => {
-                info!("[Server WASM Rust] Failed to marshal PDP request: {}", e);
-                self.send_unauthorized_response("Internal error");
+        // Signed requests are checked as far as the headers allow; the HMAC
+        // itself is verified once the body it covers is in
+        if let Some(signing) = &self.config.request_signing {
+            let now_secs = time::now_secs(self);
+            match signing.check_headers(
+                |name| self.get_http_request_header(name),
+                &method,
+                &path,
+                now_secs,
+            ) {
+                Ok(signed) => self.signed_request = Some(signed),
+                Err(e) => {
+                    self.reject_signature(&e);
+                    return Action::Pause;
+                }
+            }
+        }
+
+        // Extract the JWT from the token header. It may be omitted only when
+        // the principal comes from the peer certificate alone. Legacy clients
+        // authenticate with Basic credentials or an API key instead.
+        let headers = &self.config.headers;
+        let source = self.config.principal.source;
+        let claims = match self.get_http_request_header(&headers.token) {
+            _ if matches!(source, PrincipalSource::Basic | PrincipalSource::ApiKey) => None,
+            None if !self.config.principal.require_token => None,
+            None => {
+                let message = format!("Missing {} header", headers.token);
+                req_info!(self, "{}", message);
+                metrics::increment(self.metrics.missing_auth);
+                self.send_unauthorized_response(&message);
                 return Action::Pause;
             }
-        };
+            Some(auth_header) => {
+                let Some(token) = headers.token_from(&auth_header) else {
+                    let message = format!("Invalid {} header format", headers.token);
+                    req_info!(self, "{}", message);
+                    metrics::increment(self.metrics.missing_auth);
+                    self.send_unauthorized_response(&message);
+                    return Action::Pause;
+                };
 
-        // Make HTTP callout to PDP
-        let headers = vec![
-            (":method", "POST"),
-            (":path", PDP_SERVICE_PATH),
-            (":authority", "sgnl-pdp-service:8082"),
-            ("content-type", "application/json"),
-        ];
+                self.jwt_token = token.to_string();
+                req_info!(
+                    self,
+                    "JWT token extracted (length: {})",
+                    self.jwt_token.len()
+                );
 
-        match self.dispatch_http_call(
-            PDP_SERVICE_CLUSTER,
-            headers,
-            Some(&request_body),
-            vec![],
-            std::time::Duration::from_secs(5),
-        ) {
-            Ok(call_id) => {
-                info!("[Server WASM Rust] Dispatched HTTP call to PDP (call_id: {})", call_id);
-                Action::Pause
+                match self.verify_token() {
+                    Ok(claims) => claims,
+                    // Go on to the PDP while the key is fetched
+                    Err(JwtError::NoMatchingKey) if self.fetch_unknown_key() => {
+                        jwt::decode_unverified(&self.jwt_token).ok()
+                    }
+                    Err(e) => {
+                        req_warn!(self, "JWT validation failed: {}", e);
+                        self.send_unauthorized_response(&e.to_string());
+                        return Action::Pause;
+                    }
+                }
             }
-            Err(e) => {
-                info!("[Server WASM Rust] Failed to dispatch HTTP call to PDP: {:?}", e);
-                self.send_forbidden_response("Policy evaluation failed", "");
-                Action::Pause
+        };
+
+        // A token awaiting its key is checked for its tenant once it verifies
+        if self.key_fetch.is_none() {
+            if let Err(message) = self.check_tenant(claims.as_ref()) {
+                self.reject_tenant(&message);
+                return Action::Pause;
             }
         }
-    }
-}
 
-impl ServerFilterHttp {
-    fn extract_asset_from_path(&self, path: &str) -> String {
-        // Simple parsing of ?asset=value
-        if let Some(idx) = path.find("asset=") {
-            let start = idx + 6;
-            let asset = &path[start..];
-            if let Some(end) = asset.find('&') {
-                asset[..end].to_string()
-            } else {
-                asset.to_string()
+        if let Some(revoked) = self.revoked(claims.as_ref()) {
+            let message = "Token has been revoked";
+            req_info!(self, "{} (by {})", message, revoked);
+            metrics::increment(self.metrics.revoked_tokens);
+            self.send_unauthorized_response(message);
+            return Action::Pause;
+        }
+
+        self.principal_id = match self.resolve_principal(claims.as_ref()) {
+            Ok(principal) => principal,
+            Err(message) => {
+                req_info!(self, "{}", message);
+                self.send_unauthorized_response(&message);
+                return Action::Pause;
+            }
+        };
+        if let Some(delegation) = &self.config.principal.delegation {
+            match claims.as_ref().map_or(Ok(None), |claims| {
+                delegation.actor(claims, &self.principal_id)
+            }) {
+                Ok(actor) => self.actor = actor,
+                Err(message) => {
+                    req_info!(self, "Delegation rejected: {}", message);
+                    metrics::increment(self.metrics.delegation_denied);
+                    self.send_forbidden_response(&message, "delegation_denied");
+                    return Action::Pause;
+                }
             }
-        } else {
-            String::new()
         }
-    }
+        // A token awaiting its key is checked for replay once it verifies
+        let replay = match self.key_fetch {
+            Some(_) => Ok(()),
+            None => self.check_replay(claims.as_ref()),
+        };
+        if let Err(message) = replay {
+            self.reject_replay(&message);
+            return Action::Pause;
+        }
 
-    fn send_unauthorized_response(&self, message: &str) {
-        let body = format!(r#"{{"error":"{}"}}"#, message);
-        self.send_http_response(
-            401,
-            vec![("content-type", "application/json")],
-            Some(body.as_bytes()),
-        );
+        // Give policies the request attributes they asked for
+        self.context = self
+            .config
+            .context_headers
+            .iter()
+            .filter_map(|name| {
+                Some((
+                    name.to_ascii_lowercase(),
+                    self.get_http_request_header(name)?,
+                ))
+            })
+            .collect();
+        for (attribute, path) in &self.config.claim_attributes {
+            if let Some(value) = claims.as_ref().and_then(|claims| claims.attribute(path)) {
+                self.context.insert(attribute.clone(), value);
+            }
+        }
+        if self.config.connection_attributes {
+            self.connection = Some(ConnectionAttributes::read(self));
+        }
+        if let Some(caller) = self
+            .config
+            .caller_annotation
+            .then(|| RequestAnnotation::read(self))
+            .flatten()
+        {
+            self.context
+                .insert("caller.service_id".to_string(), caller.service_id);
+            self.context.extend(
+                caller
+                    .audience
+                    .map(|audience| ("caller.audience".to_string(), audience)),
+            );
+            self.context.extend(
+                caller
+                    .scope
+                    .map(|scope| ("caller.scope".to_string(), scope)),
+            );
+        }
+        if let Some(chain_config) = &self.config.identity_chain {
+            let chain = self.get_http_request_header(&chain_config.header);
+            match chain_config.attributes(
+                chain.as_deref(),
+                &self.principal_id,
+                time::now_secs(self),
+            ) {
+                Ok(attributes) => self.context.extend(attributes),
+                Err(message) => {
+                    req_info!(self, "{}", message);
+                    metrics::increment(self.metrics.identity_chain_invalid);
+                    self.send_unauthorized_response(&message);
+                    return Action::Pause;
+                }
+            }
+        }
+        // Let the upstream know of principals denied repeatedly, and only of them
+        if let Some(events_config) = &self.config.security_events {
+            let now_ms = time::now_ms(self);
+            let flagged =
+                security_events::is_flagged(self, events_config, &self.principal_id, now_ms);
+            let risk = flagged.then_some(events_config.risk_value.as_str());
+            self.set_http_request_header(&events_config.risk_header, risk);
+        }
+
+        // Extract the asset ID using the configured rules unless the route or
+        // gRPC service fixes it
+        let fixed_asset_id = route
+            .asset_id
+            .or_else(|| grpc_call.map(|(service, _)| service.to_string()));
+        self.path = path;
+        // An upgrade's body is the upgraded stream, never held for asset rules
+        // or signatures
+        let body_rules = (fixed_asset_id.is_none() && asset::needs_body(&self.config.asset_rules))
+            || self
+                .config
+                .additional_queries
+                .iter()
+                .any(|q| q.needs_body());
+        let needs_body = !self.upgrade && (body_rules || self.signed_request.is_some());
+        if let Some(asset_id) = fixed_asset_id {
+            self.asset_id = asset_id;
+        }
+        if !end_of_stream && needs_body {
+            let declared = self
+                .get_http_request_header("content-length")
+                .and_then(|length| length.parse().ok());
+            if declared.is_some_and(|length: usize| length > self.config.max_request_body_bytes) {
+                self.reject_body_too_large();
+                return Action::Pause;
+            }
+            req_info!(self, "Waiting for request body");
+            self.awaiting_body = true;
+            return Action::Pause;
+        }
+
+        if !self.verify_signature(&[]) {
+            return Action::Pause;
+        }
+        self.build_queries(None);
+        let action = self.authorize();
+        let action = self.hold_unverified(action);
+        if action == Action::Continue {
+            self.release();
+        }
+        action
     }
 
-    fn send_forbidden_response(&self, message: &str, reason: &str) {
-        let body = format!(
-            r#"{{"error":"{}","pdp_response":{{"decision":"Deny","reason":"{}"}}}}"#,
-            message, reason
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        if !self.awaiting_body {
+            return Action::Continue;
+        }
+        if body_size > self.config.max_request_body_bytes {
+            self.awaiting_body = false;
+            self.reject_body_too_large();
+            return Action::Pause;
+        }
+        if !end_of_stream {
+            return Action::Pause;
+        }
+
+        self.awaiting_body = false;
+        let body = self.get_http_request_body(0, body_size);
+        if !self.verify_signature(body.as_deref().unwrap_or_default()) {
+            return Action::Pause;
+        }
+        self.build_queries(body.as_deref());
+        let action = self.authorize();
+        let action = self.hold_unverified(action);
+        if action == Action::Continue {
+            self.release();
+        }
+        action
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        // Take on the obligations of the evaluation the request was parked
+        // behind, or of the hedged call acted on in its place
+        let hedged = self
+            .hedged
+            .take()
+            .filter(|_| self.hedge.get() == HedgeState::HedgeWon);
+        if let Some(parked) = self.parked.take().or(hedged) {
+            let parked = parked.borrow();
+            self.obligations = parked.obligations.clone();
+            self.quota = parked.quota;
+        }
+        if let Some(quota) = &self.quota {
+            for (name, value) in quota.headers() {
+                self.set_http_response_header(name, Some(&value));
+            }
+        }
+        if self.obligations.is_empty() {
+            return Action::Continue;
+        }
+
+        // Response-phase policy: withhold successful responses the principal may not see
+        let status = self.get_http_response_header(":status").unwrap_or_default();
+        if callout::is_success(&status)
+            && self
+                .obligations
+                .forbids(|name| self.get_http_response_header(name))
+        {
+            req_info!(self, "Upstream response withheld by response policy");
+            if self.withhold_response("response_policy") == Action::Pause {
+                return Action::Pause;
+            }
+        }
+
+        for (name, value) in &self.obligations.response_headers {
+            self.set_http_response_header(name, Some(value));
+        }
+
+        // An upgraded stream can't be redacted, and isn't let through unredacted
+        if !self.obligations.redact_fields.is_empty() && self.upgrade {
+            req_info!(self, "Cannot redact upgraded stream");
+            return self.withhold_response("redaction_failed");
+        }
+
+        // Hold the headers too, so the response can still be withheld if redaction fails
+        if !self.obligations.redact_fields.is_empty() && !end_of_stream {
+            let content_type = self
+                .get_http_response_header("content-type")
+                .unwrap_or_default();
+            if !content_type.contains("json") {
+                req_info!(self, "Cannot redact {:?} response", content_type);
+                return self.withhold_response("redaction_failed");
+            }
+            self.set_http_response_header("content-length", None);
+            self.redacting_response = true;
+            return Action::Pause;
+        }
+        Action::Continue
+    }
+
+    fn on_http_request_trailers(&mut self, _num_trailers: usize) -> Action {
+        self.capture_trailers(self.get_http_request_trailers());
+        Action::Continue
+    }
+
+    fn on_http_response_trailers(&mut self, _num_trailers: usize) -> Action {
+        self.capture_trailers(self.get_http_response_trailers());
+        for (name, value) in &self.obligations.response_trailers {
+            self.set_http_response_trailer(name, Some(value));
+        }
+        Action::Continue
+    }
+
+    fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        if !self.redacting_response {
+            return Action::Continue;
+        }
+        let redaction = &self.config.response_redaction;
+        if body_size > redaction.max_body_bytes {
+            req_info!(
+                self,
+                "Response body exceeds {} bytes, cannot redact",
+                redaction.max_body_bytes
+            );
+            self.redacting_response = false;
+            return self.withhold_response("redaction_failed");
+        }
+        if !end_of_stream {
+            return Action::Pause;
+        }
+
+        self.redacting_response = false;
+        let body = self
+            .get_http_response_body(0, body_size)
+            .unwrap_or_default();
+        match redact::redact(
+            &body,
+            &self.obligations.redact_fields,
+            redaction.replacement.as_ref(),
+        ) {
+            Ok(redacted) => {
+                req_info!(
+                    self,
+                    "Redacted {} field(s) from response",
+                    self.obligations.redact_fields.len()
+                );
+                self.set_http_response_body(0, body_size, &redacted);
+                Action::Continue
+            }
+            Err(e) => {
+                req_warn!(self, "Failed to parse response for redaction: {}", e);
+                self.withhold_response("redaction_failed")
+            }
+        }
+    }
+}
+
+impl ServerFilterHttp {
+    /// What is known of the request so far, for its log lines.
+    fn log_fields(&self) -> LogFields<'_> {
+        LogFields {
+            context_id: Some(self.context_id),
+            request_id: logging::known(&self.request_id),
+            principal: logging::known(&self.principal_id),
+            asset: logging::known(&self.asset_id),
+            decision: None,
+        }
+    }
+
+    /// Resolves the decision for the extracted request attributes, either
+    /// from the cache or by dispatching the PDP call.
+    fn authorize(&mut self) -> Action {
+        if self.upgrade && self.config.upgrades.denies(&self.asset_id) {
+            req_info!(self, "Upgrade refused for asset={}", self.asset_id);
+            metrics::increment(self.metrics.denied);
+            self.record_decision("Deny", "upgrade_denied", "upgrade", 0);
+            self.send_forbidden_response("Upgrade not allowed", "upgrade_denied");
+            return Action::Pause;
+        }
+
+        // The principal's limits are only checked, and charged, for a
+        // verified token
+        if self.key_fetch.is_none() && !self.admit() {
+            return Action::Pause;
+        }
+
+        // Add the principal's attributes before they go into the cache key
+        if let Some(action) = self.enrich() {
+            return action;
+        }
+        self.evaluate()
+    }
+
+    /// Turns away principals blocked, over their rate limit or out of quota,
+    /// answering the request. Returns whether the request may go on.
+    fn admit(&mut self) -> bool {
+        // Turn principals blocked for repeated denies away without asking the PDP
+        let now_ms = time::now_ms(self);
+        let events_config = self.config.security_events.as_ref();
+        let block = events_config
+            .and_then(|events_config| Some((events_config, events_config.block.as_ref()?)));
+        if let Some((events_config, block)) = block {
+            if let Some(remaining_ms) =
+                security_events::blocked_for_ms(self, events_config, &self.principal_id, now_ms)
+            {
+                req_info!(self, "Principal blocked for another {} ms", remaining_ms);
+                metrics::increment(self.metrics.blocked);
+                self.record_decision("Deny", "principal_blocked", "block", 0);
+                let message = "Too many denied requests";
+                if block.too_many_requests {
+                    self.send_too_many_requests(
+                        message,
+                        "principal_blocked",
+                        remaining_ms.div_ceil(1000),
+                    );
+                } else {
+                    self.send_forbidden_response(message, "principal_blocked");
+                }
+                return false;
+            }
+        }
+
+        // Shield the PDP from clients sending more than their share
+        if let Some(rate_limit) = &self.config.rate_limit {
+            let bucket = rate_limit.bucket_id(&self.principal_id, &self.asset_id);
+            match ratelimit::acquire(self, rate_limit, &bucket, time::now_ms(self)) {
+                Ok(true) => {}
+                Ok(false) => {
+                    req_info!(self, "Rate limit slot taken, request not counted");
+                    metrics::increment(self.metrics.rate_limit_untracked);
+                }
+                Err(retry_after_ms) => {
+                    req_info!(
+                        self,
+                        "Rate limit exceeded for principal={}",
+                        self.principal_id
+                    );
+                    metrics::increment(self.metrics.rate_limited);
+                    self.record_decision("Deny", "rate_limited", "rate_limit", 0);
+                    self.send_too_many_requests(
+                        "Rate limit exceeded",
+                        "rate_limited",
+                        retry_after_ms.div_ceil(1000),
+                    );
+                    return false;
+                }
+            }
+        }
+
+        // Enforce the PDP-reported quota between callouts
+        match quota::consume(self, &self.principal_id, time::now_secs(self)) {
+            Ok(quota) => self.quota = quota,
+            Err(exhausted) => {
+                req_info!(self, "Quota exhausted for principal={}", self.principal_id);
+                metrics::increment(self.metrics.quota_exceeded);
+                self.quota = Some(exhausted);
+                self.record_decision("Deny", "quota_exceeded", "quota", 0);
+                let retry_after_secs = exhausted.reset.saturating_sub(time::now_secs(self));
+                self.send_too_many_requests("Quota exceeded", "quota_exceeded", retry_after_secs);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Decides the request from the decision cache or with the PDP.
+    fn evaluate(&mut self) -> Action {
+        // Serve repeat requests from the decision cache, once their token
+        // is verified
+        if self.config.decision_cache.enabled() && self.key_fetch.is_none() {
+            let key = self.decision_key();
+            if let Some(cached) = cache::lookup(self, &key, time::now_ms(self)) {
+                metrics::increment(self.metrics.decision_cache_hits);
+                req_info!(
+                    self,
+                    "Decision cache hit: {} ({})",
+                    cached.decision,
+                    cached.reason
+                );
+                self.record_decision(&cached.decision, &cached.reason, "cache", 0);
+                if cached.decision != "Allow" {
+                    if self.shadow_deny(&cached.reason, "cache") {
+                        return Action::Continue;
+                    }
+                    metrics::increment(self.metrics.denied);
+                    self.send_denied_response(&cached.reason);
+                    return Action::Pause;
+                }
+                self.obligations = cached.obligations;
+                self.allow_request(&cached.reason);
+                return Action::Continue;
+            }
+            metrics::increment(self.metrics.decision_cache_misses);
+        }
+
+        // Don't pile more callouts onto a PDP that keeps failing
+        if self.config.circuit_breaker.is_some()
+            && !breaker::allows_request(self, time::now_ms(self))
+        {
+            req_info!(self, "PDP circuit open, skipping callout");
+            return self.fail_pdp();
+        }
+
+        // Fail fast rather than waiting out the timeout of a PDP known to be
+        // down. With failover, it is tried after the secondaries instead.
+        let health_check = self
+            .config
+            .health_check
+            .as_ref()
+            .filter(|_| self.config.failover.is_none());
+        if health_check
+            .is_some_and(|health_check| !health::is_healthy(self, health_check, time::now_ms(self)))
+        {
+            req_info!(self, "PDP unhealthy, skipping callout");
+            return self.fail_pdp();
+        }
+
+        // Share an identical evaluation already in flight instead of repeating it
+        if self.join_flight() {
+            return Action::Pause;
+        }
+
+        // Shed load rather than pile paused streams onto a slow PDP
+        if let Some(limit) = &self.config.concurrency_limit {
+            if !concurrency::acquire(self, limit.max_in_flight) {
+                let action = self.overflow(limit.overflow);
+                self.finish_flight(None);
+                return action;
+            }
+            self.pdp_slot = true;
+        }
+
+        req_info!(self, "Calling PDP ({} queries)", self.queries.len());
+
+        // Call PDP to evaluate authorization
+        if self.config.pdp_protocol == PdpProtocol::ExtAuthz {
+            self.http_attributes =
+                HttpAttributes::new(&self.request_id, self.get_http_request_headers());
+        }
+        self.pdp_clusters = self.pdp_clusters();
+        match self.dispatch_pdp() {
+            Ok(call_id) => {
+                req_info!(self, "Dispatched call to PDP (call_id: {})", call_id);
+                self.pdp_dispatched_at_ms = time::now_ms(self);
+                self.set_waiting(true);
+                self.register_hedge();
+                Action::Pause
+            }
+            Err(e) => {
+                req_warn!(self, "Failed to dispatch call to PDP: {}", e);
+                self.release_pdp_slot();
+                self.record_pdp_outcome(false);
+                let action = self.fail_pdp();
+                self.finish_flight(None);
+                action
+            }
+        }
+    }
+
+    /// With `pip`, adds the principal's attributes to the context, from the
+    /// cache or else by fetching them. Returns the action for the current
+    /// filter callback if the request can't go on to be evaluated yet.
+    fn enrich(&mut self) -> Option<Action> {
+        let pip = self.config.pip.clone()?;
+        if pip.cache_ttl_ms > 0 {
+            if let Some(attributes) = pip::lookup(self, &self.principal_id, time::now_ms(self)) {
+                self.context.extend(attributes);
+                return None;
+            }
+        }
+        let body = serde_json::json!({"principal": {"id": self.principal_id}}).to_string();
+        let trace_headers = self.trace_headers();
+        let dispatched = HttpCallout::post(&pip.cluster, &pip.authority, &pip.path)
+            .headers(&trace_headers)
+            .header("x-request-id", &self.request_id)
+            .json(body.as_bytes())
+            .timeout(pip.timeout())
+            .dispatch(self);
+        match dispatched {
+            Ok(call_id) => {
+                req_info!(
+                    self,
+                    "Fetching principal attributes from PIP (call_id: {})",
+                    call_id
+                );
+                self.pip_call = Some(call_id);
+                self.set_waiting(true);
+                Some(Action::Pause)
+            }
+            Err(e) => {
+                req_warn!(self, "Failed to dispatch call to PIP: {:?}", e);
+                metrics::increment(self.metrics.pip_errors);
+                pip.required.then(|| self.fail_pdp())
+            }
+        }
+    }
+
+    /// Adds the attributes the PIP answered with to the context and goes on
+    /// to evaluate the request.
+    fn on_pip_response(&mut self, body_size: usize) {
+        self.pip_call = None;
+        let Some(pip) = self.config.pip.clone() else {
+            return;
+        };
+        let status = callout::response_status(self);
+        let attributes = match self.get_http_call_response_body(0, body_size) {
+            Some(body) if callout::is_success(&status) => pip.parse(&body),
+            _ => Err(format!("status {:?}", status)),
+        };
+        match attributes {
+            Ok(attributes) => {
+                req_debug!(self, "PIP attributes: {:?}", attributes.keys());
+                if pip.cache_ttl_ms > 0 {
+                    let expires_at_ms = time::now_ms(self) + pip.cache_ttl_ms;
+                    pip::store(self, &self.principal_id, &attributes, expires_at_ms);
+                }
+                self.context.extend(attributes);
+            }
+            Err(e) => {
+                req_warn!(self, "PIP lookup failed: {}", e);
+                metrics::increment(self.metrics.pip_errors);
+                if pip.required {
+                    self.fail_pdp_response();
+                    return;
+                }
+            }
+        }
+        if self.evaluate() == Action::Continue {
+            self.resume_verified();
+        }
+    }
+
+    /// With `coalescing`, parks the request behind an identical evaluation
+    /// in flight, or else claims the evaluation for this context. Returns
+    /// true if the request was parked.
+    fn join_flight(&mut self) -> bool {
+        let Some(coalescing) = &self.config.coalescing else {
+            return false;
+        };
+        // The decision for a token still awaiting its key is held back, so
+        // such a request can't stand in for others
+        if self.key_fetch.is_some() {
+            return false;
+        }
+        let key = self.decision_key();
+        let now_ms = time::now_ms(self);
+        let flights = self.flights.clone();
+        let mut flights = flights.borrow_mut();
+        let flight = flights.entry(key.clone()).or_default();
+        if flight.leading
+            || !coalesce::try_lead(
+                self,
+                &coalesce::flight_key(&key),
+                now_ms,
+                coalescing.lease_ms,
+            )
+        {
+            req_info!(self, "Identical PDP evaluation in flight, parking request");
+            metrics::increment(self.metrics.pdp_coalesced);
+            let parked = Rc::new(RefCell::new(ServerFilterHttp {
+                pdp_dispatched_at_ms: now_ms,
+                ..self.clone()
+            }));
+            flight.waiters.push(parked.clone());
+            self.parked = Some(parked);
+            self.set_waiting(true);
+            return true;
+        }
+        flight.leading = true;
+        self.flight = Some(key);
+        false
+    }
+
+    /// Ends the evaluation this context leads, if any, publishing its result
+    /// to other VMs and resuming the requests parked behind it in this one.
+    fn finish_flight(&mut self, outcome: Option<&Outcome>) {
+        let Some(key) = self.flight.take() else {
+            return;
+        };
+        coalesce::finish(self, &coalesce::flight_key(&key), outcome);
+        let waiters = self
+            .flights
+            .borrow_mut()
+            .remove(&key)
+            .map(|flight| flight.waiters)
+            .unwrap_or_default();
+        if !waiters.is_empty() {
+            req_info!(self, "Resuming {} parked request(s)", waiters.len());
+            resume_waiters(waiters, outcome);
+            let _ = hostcalls::set_effective_context(self.context_id);
+        }
+    }
+
+    /// Clusters to try the evaluation on, in order; see `failover`.
+    fn pdp_clusters(&self) -> Vec<String> {
+        let Some(failover) = &self.config.failover else {
+            return vec![self.config.pdp_cluster.clone()];
+        };
+        let now_ms = time::now_ms(self);
+        let health_check = self.config.health_check.as_ref();
+        let primary_healthy =
+            health_check.is_none_or(|health_check| health::is_healthy(self, health_check, now_ms));
+        failover.clusters(self, &self.config.pdp_cluster, primary_healthy, now_ms)
+    }
+
+    /// Dispatches the evaluation to the first of `pdp_clusters`, moving on
+    /// past clusters the callout can't be dispatched to.
+    fn dispatch_pdp(&mut self) -> Result<u32, String> {
+        let eval_request = EvaluationRequest {
+            principal: self.principal(),
+            queries: self.queries.clone(),
+            context: self.context.clone(),
+            connection: self.connection.clone(),
+        };
+        let grpc = self.config.pdp_transport == PdpTransport::Grpc
+            && self.config.pdp_protocol.supports_grpc();
+        loop {
+            let cluster = self.pdp_clusters.first().cloned().unwrap_or_default();
+            let dispatched = if grpc {
+                self.dispatch_pdp_grpc(&cluster, &eval_request)
+            } else {
+                self.dispatch_pdp_http(&cluster, &eval_request)
+            };
+            match dispatched {
+                Err(e) if self.pdp_clusters.len() > 1 => {
+                    req_warn!(
+                        self,
+                        "Failed to dispatch call to PDP cluster {}: {}",
+                        cluster,
+                        e
+                    );
+                    self.record_cluster_outcome(false);
+                    self.pdp_clusters.remove(0);
+                    metrics::increment(self.metrics.pdp_failovers);
+                }
+                dispatched => return dispatched,
+            }
+        }
+    }
+
+    /// With `hedging`, registers a copy of the request through which the
+    /// root context makes the hedged call once the callout is overdue.
+    fn register_hedge(&mut self) {
+        let Some(hedging) = &self.config.hedging else {
+            return;
+        };
+        // The decision for a token still awaiting its key is held back here
+        if self.key_fetch.is_some() {
+            return;
+        }
+        let cluster = hedging
+            .cluster
+            .clone()
+            .or_else(|| self.pdp_clusters.get(1).cloned())
+            .unwrap_or_else(|| self.pdp_clusters[0].clone());
+        let request = Rc::new(RefCell::new(ServerFilterHttp {
+            pdp_clusters: vec![cluster],
+            pdp_slot: false,
+            hedged_call: true,
+            ..self.clone()
+        }));
+        self.hedges.borrow_mut().push(Hedge {
+            due_ms: self.pdp_dispatched_at_ms + hedging.threshold_ms,
+            call: None,
+            request: request.clone(),
+        });
+        self.hedged = Some(request);
+    }
+
+    /// Claims a PDP response for acting on. Returns the hedge state from
+    /// before, or `None` if a response was acted on already.
+    fn settle_hedge(&self) -> Option<HedgeState> {
+        let settled = if self.hedged_call {
+            HedgeState::HedgeWon
+        } else {
+            HedgeState::Done
+        };
+        let hedge = self.hedge.replace(settled);
+        if hedge.decided() {
+            self.hedge.set(hedge);
+            req_info!(self, "Ignoring PDP response, the other call's was acted on");
+            return None;
+        }
+        Some(hedge)
+    }
+
+    /// Handles a PDP callout that failed. While the hedged call and the
+    /// original are both outstanding the other one decides; otherwise the
+    /// evaluation fails over or the failure mode applies.
+    fn on_pdp_failure(&mut self, hedge: HedgeState) {
+        if hedge == HedgeState::Sent {
+            self.record_cluster_outcome(false);
+            self.hedge.set(HedgeState::OneFailed);
+            return;
+        }
+        if hedge == HedgeState::Pending && self.fail_over() {
+            self.hedge.set(HedgeState::Pending);
+            return;
+        }
+        self.record_pdp_outcome(false);
+        self.fail_pdp_response();
+    }
+
+    /// With `failover`, retries an evaluation whose callout failed on the
+    /// next cluster. Returns true if the retry was dispatched.
+    fn fail_over(&mut self) -> bool {
+        if self.pdp_clusters.len() < 2 {
+            return false;
+        }
+        if let Some(limit) = &self.config.concurrency_limit {
+            if !concurrency::acquire(self, limit.max_in_flight) {
+                return false;
+            }
+            self.pdp_slot = true;
+        }
+        self.record_cluster_outcome(false);
+        let failed = self.pdp_clusters.remove(0);
+        metrics::increment(self.metrics.pdp_failovers);
+        match self.dispatch_pdp() {
+            Ok(call_id) => {
+                let cluster = &self.pdp_clusters[0];
+                req_info!(
+                    self,
+                    "PDP cluster {} failed, retrying on {} (call_id: {})",
+                    failed,
+                    cluster,
+                    call_id
+                );
+                self.pdp_dispatched_at_ms = time::now_ms(self);
+                true
+            }
+            Err(e) => {
+                req_warn!(self, "Failed to dispatch call to PDP: {}", e);
+                self.release_pdp_slot();
+                false
+            }
+        }
+    }
+
+    fn dispatch_pdp_http(
+        &self,
+        cluster: &str,
+        eval_request: &EvaluationRequest,
+    ) -> Result<u32, String> {
+        let protocol = self.config.pdp_protocol;
+        let request_body = protocol
+            .encode(&self.config, eval_request, &self.http_attributes)
+            .map_err(|e| format!("failed to marshal request: {}", e))?;
+        let content_type = protocol.content_type(&self.config);
+        let path = protocol.http_path(&self.config, eval_request);
+        let trace_headers = self.trace_headers();
+        HttpCallout::post(cluster, &self.config.pdp_authority, &path)
+            .headers(&trace_headers)
+            .header("x-request-id", &self.request_id)
+            .body(content_type, &request_body)
+            .timeout(self.config.pdp_timeout())
+            .dispatch(self)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn dispatch_pdp_grpc(
+        &self,
+        cluster: &str,
+        eval_request: &EvaluationRequest,
+    ) -> Result<u32, String> {
+        let protocol = self.config.pdp_protocol;
+        let message = protocol.encode_grpc(&self.config, eval_request, &self.http_attributes);
+        let (service, method) = protocol.grpc_method(&self.config);
+        let trace_headers = self.trace_headers();
+        let mut metadata: Vec<(&str, &[u8])> = trace_headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_bytes()))
+            .collect();
+        metadata.push(("x-request-id", self.request_id.as_bytes()));
+        self.dispatch_grpc_call(
+            cluster,
+            service,
+            method,
+            metadata,
+            Some(&message),
+            self.config.pdp_timeout(),
+        )
+        .map_err(|e| format!("{:?}", e))
+    }
+
+    /// Trace context of the inbound request, so the PDP call joins its trace.
+    fn trace_headers(&self) -> Vec<(&'static str, String)> {
+        trace::propagation_headers(|name| self.get_http_request_header(name))
+    }
+
+    /// Handles a request over `concurrency_limit` without calling the PDP.
+    /// Returns the action for the current filter callback.
+    fn overflow(&self, overflow: Overflow) -> Action {
+        req_warn!(self, "PDP concurrency limit reached ({:?})", overflow);
+        metrics::increment(self.metrics.pdp_overflow);
+        self.record_decision("Error", "pdp_overloaded", "concurrency_limit", 0);
+        if overflow == Overflow::Reject {
+            let message = "Too many authorization requests in flight";
+            self.send_templated_response(
+                &self.config.responses.overloaded,
+                message,
+                "pdp_overloaded",
+            );
+            return Action::Pause;
+        }
+        metrics::increment(self.metrics.pdp_fail_open);
+        self.forward_credentials();
+        Action::Continue
+    }
+
+    /// Gives back the request's `concurrency_limit` slot, if it holds one.
+    fn release_pdp_slot(&mut self) {
+        if std::mem::take(&mut self.pdp_slot) {
+            concurrency::release(self);
+        }
+    }
+
+    /// Handles a PDP callout that produced no usable decision according to
+    /// the failure mode. Returns the action for the current filter callback.
+    fn fail_pdp(&mut self) -> Action {
+        metrics::increment(self.metrics.pdp_errors);
+        if self.failure_mode == FailureMode::LocalFallback {
+            return self.apply_local_policy();
+        }
+        self.record_decision(
+            "Error",
+            "Policy evaluation failed",
+            "failure_mode",
+            self.pdp_latency_ms(),
+        );
+        if self.failure_mode == FailureMode::Closed {
+            self.send_forbidden_response("Policy evaluation failed", "");
+            return Action::Pause;
+        }
+
+        req_info!(
+            self,
+            "PDP unavailable, failing open for principal={}",
+            self.principal_id
+        );
+        metrics::increment(self.metrics.pdp_fail_open);
+        if self.failure_mode == FailureMode::OpenWithHeader {
+            self.add_http_request_header(FAIL_OPEN_HEADER, "true");
+        }
+        self.forward_credentials();
+        Action::Continue
+    }
+
+    /// Decides the request with the static fallback rules in place of the
+    /// PDP.
+    fn apply_local_policy(&mut self) -> Action {
+        let allowed =
+            self.config
+                .local_policy
+                .allows(&self.principal_id, &self.queries, self.config.combine);
+        let decision = if allowed { "Allow" } else { "Deny" };
+        req_info!(self, "PDP unavailable, local policy decision: {}", decision);
+        self.record_decision(
+            decision,
+            "local_policy",
+            "local_policy",
+            self.pdp_latency_ms(),
+        );
+        if !allowed {
+            if self.shadow_deny("local_policy", "local_policy") {
+                return Action::Continue;
+            }
+            metrics::increment(self.metrics.denied);
+            self.send_denied_response("local_policy");
+            return Action::Pause;
+        }
+        self.allow_request("local_policy");
+        Action::Continue
+    }
+
+    /// Like `fail_pdp`, for failures detected in a callout response where the
+    /// request has to be resumed explicitly.
+    fn fail_pdp_response(&mut self) {
+        if self.fail_pdp() == Action::Continue {
+            self.resume_verified();
+        }
+        self.finish_flight(None);
+    }
+
+    /// Time since the PDP callout was dispatched, or zero if none was.
+    fn pdp_latency_ms(&self) -> u64 {
+        if self.pdp_dispatched_at_ms == 0 {
+            return 0;
+        }
+        time::now_ms(self).saturating_sub(self.pdp_dispatched_at_ms)
+    }
+
+    fn record_pdp_latency(&self) {
+        metrics::record(self.metrics.pdp_latency_ms, self.pdp_latency_ms());
+    }
+
+    /// Publishes a decision to the audit sink and to filter state, as
+    /// configured. `source` is one of `pdp`, `coalesced`, `cache`,
+    /// `failure_mode`, `local_policy`, `deny_list`, `block`, `rate_limit`,
+    /// `quota`, `concurrency_limit` or `response`.
+    fn record_decision(&self, decision: &str, reason: &str, source: &'static str, latency_ms: u64) {
+        let fields = LogFields {
+            decision: Some(decision),
+            ..self.log_fields()
+        };
+        log_info!(fields: fields; "Decision from {} ({})", source, reason);
+        if let Some(prefix) = &self.config.decision_metadata_prefix {
+            let fields = [
+                ("decision", decision),
+                ("reason", reason),
+                ("principal", self.principal_id.as_str()),
+                ("asset", self.asset_id.as_str()),
+                ("action", self.action.as_str()),
+            ];
+            for (name, value) in fields {
+                let key = format!("{}.{}", prefix, name);
+                // Failures are logged but never affect the request
+                if let Err(e) = hostcalls::set_property(vec![&key], Some(value.as_bytes())) {
+                    req_warn!(self, "Failed to set property {}: {:?}", key, e);
+                }
+            }
+        }
+        self.audit(decision, reason, source, latency_ms);
+        // Shadowed denies mustn't get the principal flagged or blocked, nor
+        // those of a token yet to verify
+        if decision == "Deny"
+            && source != "block"
+            && !self.shadowed(source)
+            && self.key_fetch.is_none()
+        {
+            self.count_deny(reason);
+        }
+    }
+
+    /// Counts a deny towards the principal's `security_events` thresholds,
+    /// raising an event for each it crosses.
+    fn count_deny(&self, reason: &str) {
+        let Some(events_config) = &self.config.security_events else {
+            return;
+        };
+        if self.principal_id.is_empty() {
+            return;
+        }
+        let now_ms = time::now_ms(self);
+        let crossed = security_events::record_deny(self, events_config, &self.principal_id, now_ms);
+        let event = |kind: &str, blocked_until_ms| SecurityEvent {
+            timestamp_ms: now_ms,
+            kind: kind.to_string(),
+            principal: self.principal_id.clone(),
+            denies: crossed.denies,
+            window_ms: events_config.window_ms,
+            request_id: self.request_id.clone(),
+            reason: reason.to_string(),
+            blocked_until_ms,
+        };
+        if crossed.event {
+            req_warn!(
+                self,
+                "Principal denied {} times within {} ms",
+                crossed.denies,
+                events_config.window_ms
+            );
+            metrics::increment(self.metrics.security_events);
+            self.security_events
+                .borrow_mut()
+                .push(event("repeated_denies", None));
+        }
+        if let Some(blocked_until_ms) = crossed.blocked_until_ms {
+            req_warn!(self, "Principal blocked after {} denies", crossed.denies);
+            metrics::increment(self.metrics.security_events);
+            self.security_events
+                .borrow_mut()
+                .push(event("blocked", Some(blocked_until_ms)));
+        }
+    }
+
+    fn audit(&self, decision: &str, reason: &str, source: &'static str, latency_ms: u64) {
+        let Some(audit_config) = &self.config.audit else {
+            return;
+        };
+        let record = AuditRecord {
+            timestamp_ms: time::now_ms(self),
+            request_id: self.request_id.clone(),
+            principal: self.principal_id.clone(),
+            asset: self.asset_id.clone(),
+            action: self.action.clone(),
+            decision: decision.to_string(),
+            reason: reason.to_string(),
+            source: source.into(),
+            latency_ms,
+            shadow: decision != "Allow" && self.shadowed(source),
+            trailers: BTreeMap::new(),
+        };
+        if !audit_config.trailers.is_empty() {
+            *self.held_audit.borrow_mut() = Some(record);
+            return;
+        }
+        self.submit_audit(record);
+    }
+
+    fn submit_audit(&self, record: AuditRecord) {
+        let Some(audit_config) = &self.config.audit else {
+            return;
+        };
+        // Records the flusher can't take are posted by this VM
+        if !audit::enqueue(self, &self.audit_queue, &record) {
+            audit::push(&self.audit, record, audit_config.max_buffered);
+        }
+    }
+
+    /// Records the values of `audit.trailers` among `trailers`.
+    fn capture_trailers(&mut self, trailers: Vec<(String, String)>) {
+        let Some(audit_config) = &self.config.audit else {
+            return;
+        };
+        for (name, value) in trailers {
+            if audit_config
+                .trailers
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(&name))
+            {
+                self.trailers.insert(name.to_lowercase(), value);
+            }
+        }
+    }
+
+    /// Records the final outcome of an evaluation's callouts. Failures
+    /// followed by a successful failover don't count against the breaker.
+    fn record_pdp_outcome(&self, success: bool) {
+        if let Some(breaker_config) = &self.config.circuit_breaker {
+            breaker::record(self, breaker_config, time::now_ms(self), success);
+        }
+        self.record_cluster_outcome(success);
+    }
+
+    /// Records the outcome of the callout to the first of `pdp_clusters`.
+    fn record_cluster_outcome(&self, success: bool) {
+        if let (Some(failover), Some(cluster)) = (&self.config.failover, self.pdp_clusters.first())
+        {
+            failover::record(self, failover, cluster, time::now_ms(self), success);
+        }
+    }
+
+    /// Applies the PDP's combined verdict and resumes or rejects the request
+    /// accordingly.
+    fn on_pdp_response(&mut self, eval_resp: EvaluationResponse) {
+        let Some(decision) = eval_resp.combine(self.config.combine, self.queries.len()) else {
+            req_info!(
+                self,
+                "PDP answered {} of {} queries",
+                eval_resp.decisions.len(),
+                self.queries.len()
+            );
+            self.fail_pdp_response();
+            return;
+        };
+        let outcome = Outcome {
+            decision: decision.decision.clone(),
+            reason: decision.reason.clone(),
+            obligations: eval_resp.obligations(),
+            quota: eval_resp.quota(),
+        };
+
+        let validity_ms = eval_resp.validity_ms(time::now_ms(self));
+        let ttl_ms = self
+            .config
+            .decision_cache
+            .ttl_for(&outcome.decision, validity_ms);
+        match &mut self.key_fetch {
+            Some(fetch) => fetch.cache_ttl_ms = Some(ttl_ms),
+            None => self.store_outcome(&outcome, ttl_ms),
+        }
+
+        self.apply_outcome(&outcome, "pdp");
+        self.finish_flight(Some(&outcome));
+    }
+
+    /// Records the quota the PDP reported for the principal and caches its
+    /// decision for `ttl_ms`, if at all.
+    fn store_outcome(&self, outcome: &Outcome, ttl_ms: u64) {
+        if let Some(quota) = &outcome.quota {
+            quota::store(self, &self.principal_id, quota, time::now_secs(self));
+        }
+        if ttl_ms > 0 {
+            let key = self.decision_key();
+            let cached = CachedDecision {
+                decision: outcome.decision.clone(),
+                reason: outcome.reason.clone(),
+                obligations: outcome.obligations.clone(),
+                expires_at_ms: time::now_ms(self) + ttl_ms,
+                generation: cache::generation(self),
+            };
+            cache::store(self, &key, &cached);
+        }
+    }
+
+    /// Enforces an evaluation's decision on a request waiting for it,
+    /// resuming or rejecting it. A request whose token awaits its key gets
+    /// it once the token verifies.
+    fn apply_outcome(&mut self, outcome: &Outcome, source: &'static str) {
+        if let Some(fetch) = &mut self.key_fetch {
+            fetch.outcome = Some((outcome.clone(), source));
+            return;
+        }
+        self.record_decision(
+            &outcome.decision,
+            &outcome.reason,
+            source,
+            self.pdp_latency_ms(),
+        );
+        if outcome.quota.is_some() {
+            self.quota = outcome.quota;
+        }
+
+        if outcome.decision != "Allow" {
+            if self.shadow_deny(&outcome.reason, source) {
+                self.resume_verified();
+                return;
+            }
+            // Access denied - send 403
+            metrics::increment(self.metrics.denied);
+            self.send_denied_response(&outcome.reason);
+            return;
+        }
+
+        // Access allowed - add headers to indicate PDP validation succeeded
+        self.obligations = outcome.obligations.clone();
+        self.allow_request(&outcome.reason);
+
+        req_info!(self, "Access granted, resuming request");
+
+        // Resume the request to service-b
+        self.resume_verified();
+    }
+
+    /// Marks the request as paused on a callout or not, keeping the
+    /// `requests_waiting` gauge in step.
+    fn set_waiting(&self, waiting: bool) {
+        if self.waiting.replace(waiting) != waiting {
+            metrics::add(self.metrics.requests_waiting, if waiting { 1 } else { -1 });
+            if waiting {
+                self.released_ms.set(None);
+            } else {
+                self.release();
+            }
+        }
+    }
+
+    /// Notes when the request was let through or answered. Only the first
+    /// release counts, so a response withheld later doesn't add the
+    /// upstream's time.
+    fn release(&self) {
+        if self.released_ms.get().is_none() {
+            self.released_ms.set(Some(time::now_ms(self)));
+        }
+    }
+
+    /// Resumes the request, unless its token is still awaiting its key.
+    fn resume_verified(&mut self) {
+        if self.hold_unverified(Action::Continue) == Action::Continue {
+            self.set_waiting(false);
+            self.resume_http_request();
+        }
+    }
+
+    /// Holds back a request let through while its token's key is being
+    /// fetched, until the token verifies.
+    fn hold_unverified(&mut self, action: Action) -> Action {
+        match &mut self.key_fetch {
+            Some(fetch) if action == Action::Continue => {
+                fetch.allowed = true;
+                Action::Pause
+            }
+            _ => action,
+        }
+    }
+
+    /// Extracts the asset (unless a route override already set it) and
+    /// builds the PDP queries for the request.
+    fn build_queries(&mut self, body: Option<&[u8]>) {
+        let header = |name: &str| self.get_http_request_header(name);
+        let asset_id = if self.asset_id.is_empty() {
+            asset::extract(&self.config.asset_rules, &self.path, body, header)
+                .unwrap_or_else(|| "default-asset".to_string())
+        } else {
+            self.asset_id.clone()
+        };
+
+        let mut queries = vec![Query {
+            asset_id: asset_id.clone(),
+            action: self.action.clone(),
+        }];
+        for additional in &self.config.additional_queries {
+            let pairs = additional.expand(&self.path, body, header, &asset_id, &self.action);
+            queries.extend(
+                pairs
+                    .into_iter()
+                    .map(|(asset_id, action)| Query { asset_id, action }),
+            );
+        }
+        self.asset_id = asset_id;
+        self.queries = queries;
+    }
+
+    fn allow_request(&self, reason: &str) {
+        metrics::increment(self.metrics.allowed);
+        self.add_http_request_header("X-PDP-Decision", "Allow");
+        self.add_http_request_header("X-PDP-Reason", reason);
+        self.add_http_request_header("X-Principal-ID", &self.principal_id);
+        self.forward_credentials();
+        for (name, value) in &self.obligations.request_headers {
+            self.set_http_request_header(name, Some(value));
+        }
+        for name in &self.obligations.request_headers_to_remove {
+            self.set_http_request_header(name, None);
+        }
+    }
+
+    /// Lets a request the policy denied through in `shadow` enforcement
+    /// mode, marked with the decision it would have got. Returns false when
+    /// the deny is to be enforced.
+    fn shadow_deny(&self, reason: &str, source: &str) -> bool {
+        if !self.shadowed(source) {
+            return false;
+        }
+        req_info!(
+            self,
+            "Shadow mode, letting denied request through ({})",
+            reason
+        );
+        metrics::increment(self.metrics.shadow_denied);
+        self.set_http_request_header(SHADOW_DECISION_HEADER, Some("Deny"));
+        self.set_http_request_header(SHADOW_REASON_HEADER, Some(reason));
+        self.set_http_request_header("X-Principal-ID", Some(&self.principal_id));
+        self.forward_credentials();
+        true
+    }
+
+    /// Whether denies from `source` are only recorded.
+    fn shadowed(&self, source: &str) -> bool {
+        self.config.enforcement_mode == EnforcementMode::Shadow
+            && matches!(
+                source,
+                "pdp" | "coalesced" | "cache" | "local_policy" | "response"
+            )
+    }
+
+    /// Applies `upstream_token` to a request about to be forwarded.
+    fn forward_credentials(&self) {
+        let token_header = &self.config.headers.token;
+        match &self.config.upstream_token {
+            TokenForwarding::Keep => {}
+            TokenForwarding::Strip => self.set_http_request_header(token_header, None),
+            TokenForwarding::Replace { header } => {
+                self.set_http_request_header(token_header, None);
+                self.set_http_request_header(header, Some(&self.principal_id));
+            }
+        }
+    }
+
+    /// Replaces the upstream response with a denial for `reason`. Returns the
+    /// action for the response: `Pause` once withheld, or `Continue` when
+    /// the deny is only recorded in `shadow` enforcement mode.
+    fn withhold_response(&self, reason: &str) -> Action {
+        if self.shadowed("response") {
+            req_info!(self, "Shadow mode, letting response through ({})", reason);
+            metrics::increment(self.metrics.shadow_denied);
+            self.record_decision("Deny", reason, "response", 0);
+            return Action::Continue;
+        }
+        metrics::increment(self.metrics.denied);
+        self.record_decision("Deny", reason, "response", 0);
+        self.send_denied_response(reason);
+        Action::Pause
+    }
+
+    /// Verifies the JWT locally before involving the PDP. Without a `jwt`
+    /// config the claims are decoded unverified, or `None` if undecodable.
+    fn verify_token(&self) -> Result<Option<Claims>, JwtError> {
+        let Some(jwt_config) = &self.config.jwt else {
+            return Ok(jwt::decode_unverified(&self.jwt_token).ok());
+        };
+
+        // The unverified `iss` only picks the keys; verification confirms it
+        let issuer = match jwt_config.issuers.is_empty() {
+            true => None,
+            false => jwt::decode_unverified(&self.jwt_token)?
+                .issuer()
+                .map(str::to_string),
+        };
+        let trusted = issuer
+            .as_deref()
+            .and_then(|iss| Some((iss, jwt_config.issuer(iss)?)));
+        let now_ms = time::now_ms(self);
+        let (keys, rules) = match trusted {
+            Some((iss, trusted)) => {
+                let inline = self.issuer_keys.get(iss).cloned().unwrap_or_default();
+                let keys = match trusted.remote_jwks {
+                    Some(_) => jwks::shared_keys(self, &jwks::issuer_shared_key(iss), now_ms)
+                        .unwrap_or(inline),
+                    None => inline,
+                };
+                (keys, &trusted.rules)
+            }
+            None => {
+                let shared_key = self.config.jwks_shared_key();
+                let keys = match jwt_config.remote_jwks {
+                    Some(_) => jwks::shared_keys(self, &shared_key, now_ms)
+                        .unwrap_or_else(|| self.jwt_keys.clone()),
+                    None => self.jwt_keys.clone(),
+                };
+                (keys, &jwt_config.rules)
+            }
+        };
+        let claims = jwt::verify(&self.jwt_token, &keys, rules, time::now_secs(self))?;
+        req_info!(
+            self,
+            "JWT verified (iss: {})",
+            claims.issuer().unwrap_or("-")
         );
+        Ok(Some(claims))
+    }
+
+    /// The remote JWKS the token's keys come from, with the shared-data key
+    /// it is published under.
+    fn token_jwks(&self) -> Option<(String, RemoteJwks)> {
+        let jwt_config = self.config.jwt.as_ref()?;
+        let claims = jwt::decode_unverified(&self.jwt_token).ok();
+        let issuer = claims.as_ref().and_then(|claims| claims.issuer());
+        match issuer.and_then(|iss| Some((iss, jwt_config.issuer(iss)?))) {
+            Some((iss, trusted)) => {
+                Some((jwks::issuer_shared_key(iss), trusted.remote_jwks.clone()?))
+            }
+            None => Some((
+                self.config.jwks_shared_key(),
+                jwt_config.remote_jwks.clone()?,
+            )),
+        }
+    }
+
+    /// Fetches the token's JWKS for this request when its key isn't
+    /// published and `fetch_on_unknown_key` is set. Returns true if a fetch
+    /// was dispatched.
+    fn fetch_unknown_key(&mut self) -> bool {
+        let Some((shared_key, remote)) = self
+            .token_jwks()
+            .filter(|(_, remote)| remote.fetch_on_unknown_key)
+        else {
+            return false;
+        };
+        if !jwks::claim_unknown_key_fetch(
+            &shared_key,
+            time::now_ms(self),
+            remote.unknown_key_fetch_interval_ms,
+        ) {
+            req_info!(
+                self,
+                "JWKS fetched recently, not fetching again for unknown key"
+            );
+            return false;
+        }
+        let dispatched = HttpCallout::get(&remote.cluster, &remote.authority, &remote.path)
+            .header("accept", "application/json")
+            .timeout(remote.timeout())
+            .dispatch(self);
+        match dispatched {
+            Ok(call) => {
+                req_info!(self, "Token key unknown, fetching JWKS (call_id: {})", call);
+                metrics::increment(self.metrics.unknown_key_fetches);
+                self.set_waiting(true);
+                self.key_fetch = Some(KeyFetch {
+                    call,
+                    shared_key,
+                    remote,
+                    allowed: false,
+                    outcome: None,
+                    cache_ttl_ms: None,
+                });
+                true
+            }
+            Err(e) => {
+                req_warn!(self, "Failed to dispatch JWKS fetch: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Publishes the JWKS fetched for the request's token, then verifies the
+    /// token. The request is rejected, or checked against the principal's
+    /// limits and given the decision held back for it.
+    fn on_key_fetch_response(&mut self, body_size: usize) {
+        let Some(fetch) = self.key_fetch.take() else {
+            return;
+        };
+        let status = callout::response_status(self);
+        if callout::is_success(&status) {
+            let body = self
+                .get_http_call_response_body(0, body_size)
+                .unwrap_or_default();
+            match jwks::store(
+                self,
+                &fetch.shared_key,
+                &fetch.remote,
+                &body,
+                time::now_ms(self),
+            ) {
+                Ok(count) => req_info!(self, "JWKS fetched for unknown key ({} key(s))", count),
+                Err(e) => req_warn!(self, "Fetched JWKS rejected: {}", e),
+            }
+        } else {
+            req_warn!(self, "JWKS fetch failed with status {:?}", status);
+        }
+
+        let claims = match self.verify_token() {
+            Ok(claims) => claims,
+            Err(e) => {
+                req_warn!(self, "JWT validation failed: {}", e);
+                self.rejected = true;
+                self.send_unauthorized_response(&e.to_string());
+                return;
+            }
+        };
+        if let Err(message) = self.check_tenant(claims.as_ref()) {
+            self.rejected = true;
+            self.reject_tenant(&message);
+            return;
+        }
+        if let Err(message) = self.check_replay(claims.as_ref()) {
+            self.rejected = true;
+            self.reject_replay(&message);
+            return;
+        }
+        if !self.admit() {
+            self.rejected = true;
+            return;
+        }
+        if let Some((outcome, source)) = fetch.outcome {
+            if let Some(ttl_ms) = fetch.cache_ttl_ms {
+                self.store_outcome(&outcome, ttl_ms);
+            }
+            req_info!(self, "Token verified, applying decision");
+            self.apply_outcome(&outcome, source);
+            return;
+        }
+        if fetch.allowed {
+            req_info!(self, "Token verified, resuming request");
+            self.set_waiting(false);
+            self.resume_http_request();
+        }
+    }
+
+    /// Answers a request to an admin endpoint with the filter's status or
+    /// cache statistics, or flushes the caches it names.
+    fn serve_admin(&self, admin: &AdminConfig, endpoint: Endpoint, method: &str, path: &str) {
+        if !admin.authorized(
+            self.get_http_request_header(&admin.secret_header)
+                .as_deref(),
+        ) {
+            req_warn!(self, "Admin request without a valid secret");
+            self.send_unauthorized_response("Invalid admin secret");
+            return;
+        }
+        let body = match (endpoint, method) {
+            (Endpoint::Status, "GET") => serde_json::to_string(&self.status()).unwrap_or_default(),
+            (Endpoint::Status, _) => {
+                return send_problem(self, &Problem::new(405, "Use GET for the status"))
+            }
+            (Endpoint::Caches, "GET") => {
+                serde_json::to_string(&self.cache_stats()).unwrap_or_default()
+            }
+            (Endpoint::Caches, "DELETE") => {
+                let query = QueryParams::from_path(path);
+                let caches = match admin::Cache::parse(query.get("cache")) {
+                    Ok(caches) => caches,
+                    Err(message) => return send_problem(self, &Problem::new(400, &message)),
+                };
+                let flushed: BTreeMap<_, _> = caches
+                    .into_iter()
+                    .map(|cache| (cache, admin::flush(self, cache.generation_key())))
+                    .collect();
+                req_info!(self, "Admin flushed caches: {:?}", flushed);
+                serde_json::json!({ "flushed": flushed }).to_string()
+            }
+            (Endpoint::Caches, _) => {
+                return send_problem(
+                    self,
+                    &Problem::new(405, "Use GET for statistics or DELETE to flush"),
+                );
+            }
+        };
         self.send_http_response(
-            403,
+            200,
             vec![("content-type", "application/json")],
             Some(body.as_bytes()),
         );
     }
-}
\ No newline at end of file
+
+    fn cache_stats(&self) -> CacheStats {
+        let sources = tenant::jwks_sources(&self.config, &self.tenants);
+        CacheStats {
+            decision_cache: DecisionCacheStats {
+                enabled: self.config.decision_cache.enabled(),
+                generation: cache::generation(self),
+                hits: metrics::value(self.metrics.decision_cache_hits),
+                misses: metrics::value(self.metrics.decision_cache_misses),
+            },
+            jwks_generation: admin::generation(self, admin::JWKS_GENERATION_KEY),
+            jwks: sources
+                .into_keys()
+                .map(|source| JwksStats {
+                    keys: jwks::shared_keys(self, &source, time::now_ms(self))
+                        .map(|keys| keys.len()),
+                    source,
+                })
+                .collect(),
+            revocations: revocation::size(self),
+            api_keys: credentials::shared_size(self),
+            audit_buffered: self.audit.borrow().len(),
+        }
+    }
+
+    fn status(&self) -> FilterStatus {
+        let now_ms = time::now_ms(self);
+        let health_check = self.config.health_check.as_ref();
+        FilterStatus {
+            version: env!("CARGO_PKG_VERSION"),
+            config_hash: self.config_hash.to_string(),
+            pdp: PdpStatus {
+                cluster: self.config.pdp_cluster.clone(),
+                healthy: health_check
+                    .map(|health_check| health::is_healthy(self, health_check, now_ms)),
+                circuit_open: self
+                    .config
+                    .circuit_breaker
+                    .as_ref()
+                    .map(|_| !breaker::allows_request(self, now_ms)),
+                in_flight: self
+                    .config
+                    .concurrency_limit
+                    .as_ref()
+                    .map(|_| concurrency::in_flight(self)),
+                clusters: self.config.failover.as_ref().map(|failover| {
+                    std::iter::once(&self.config.pdp_cluster)
+                        .chain(&failover.secondary_clusters)
+                        .map(|cluster| (cluster.clone(), failover::is_up(self, cluster, now_ms)))
+                        .collect()
+                }),
+            },
+            caches: self.cache_stats(),
+            counters: self.metrics.counters(),
+        }
+    }
+
+    /// Shared-data key of the request's decision.
+    fn decision_key(&self) -> String {
+        let tenant = self.config.tenant.as_deref();
+        cache::decision_key(
+            tenant,
+            &self.principal(),
+            &self.queries,
+            &self.context,
+            self.connection.as_ref(),
+        )
+    }
+
+    fn principal(&self) -> Principal {
+        Principal {
+            id: self.principal_id.clone(),
+            actor: self.actor.clone(),
+        }
+    }
+
+    /// Resolves the PDP principal, or the reason the request can't be
+    /// attributed to one.
+    fn resolve_principal(&self, claims: Option<&Claims>) -> Result<String, String> {
+        let principal = &self.config.principal;
+        let resolved = match principal.source {
+            PrincipalSource::Jwt => claims.and_then(|c| c.lookup(&principal.claim)),
+            PrincipalSource::Header => {
+                self.get_http_request_header(&self.config.headers.service_id)
+            }
+            PrincipalSource::Mtls => connection::peer_identity(self),
+            PrincipalSource::Basic => self.basic_principal()?,
+            PrincipalSource::ApiKey => self.api_key_principal()?,
+        };
+        match resolved.filter(|p| !p.is_empty()) {
+            Some(id) => match &principal.spiffe {
+                Some(spiffe_config) => {
+                    spiffe::map_principal(spiffe_config, &id).map_err(|e| e.to_string())
+                }
+                None => Ok(id),
+            },
+            None if principal.allow_default => principal
+                .default
+                .clone()
+                .ok_or_else(|| "Unable to determine principal".to_string()),
+            None => Err("Unable to determine principal".to_string()),
+        }
+    }
+
+    /// User name of the request's Basic credentials, `None` without any.
+    fn basic_principal(&self) -> Result<Option<String>, String> {
+        let header = &self.config.headers.token;
+        let Some(value) = self.get_http_request_header(header) else {
+            return Ok(None);
+        };
+        credentials::basic_username(&value)
+            .map(Some)
+            .ok_or_else(|| format!("Invalid {} header format", header))
+    }
+
+    /// Principal the request's API key maps to, `None` without a key.
+    fn api_key_principal(&self) -> Result<Option<String>, String> {
+        let Some(api_key) = self.get_http_request_header(&self.config.headers.api_key) else {
+            return Ok(None);
+        };
+        credentials::principal_for_key(self, &self.config.principal.api_keys, &api_key)
+            .map(Some)
+            .ok_or_else(|| "Invalid API key".to_string())
+    }
+
+    fn send_unauthorized_response(&self, message: &str) {
+        self.send_templated_response(&self.config.responses.unauthorized, message, "");
+    }
+
+    fn send_forbidden_response(&self, message: &str, reason: &str) {
+        self.send_templated_response(&self.config.responses.forbidden, message, reason);
+    }
+
+    /// Rejects a request the PDP denied, using the reply configured for the
+    /// deny reason if there is one.
+    fn send_denied_response(&self, reason: &str) {
+        let template = self.config.responses.for_denial(reason);
+        self.send_templated_response(&template, "Access denied by policy", reason);
+    }
+
+    /// Rejects a request over the rate limit or quota with the `rate_limited`
+    /// template.
+    fn send_too_many_requests(&self, message: &str, reason: &str, retry_after_secs: u64) {
+        let mut response =
+            self.render_response(&self.config.responses.rate_limited, message, reason);
+        response.headers.push((
+            "retry-after".to_string(),
+            retry_after_secs.max(1).to_string(),
+        ));
+        if let Some(quota) = &self.quota {
+            response.headers.extend(
+                quota
+                    .headers()
+                    .map(|(name, value)| (name.to_string(), value)),
+            );
+        }
+        self.send_rendered_response(&response, message, reason);
+    }
+
+    /// Verifies the HMAC of a signed request over its complete `body`,
+    /// rejecting the request on a mismatch. Returns whether it may proceed.
+    fn verify_signature(&mut self, body: &[u8]) -> bool {
+        let Some(signed) = self.signed_request.take() else {
+            return true;
+        };
+        match signed.verify(body) {
+            Ok(()) if !self.first_use("signature", &signed.nonce()) => {
+                self.reject_replay("Replayed request signature");
+                false
+            }
+            Ok(()) => {
+                req_debug!(self, "Request signature verified");
+                true
+            }
+            Err(e) => {
+                self.reject_signature(&e);
+                false
+            }
+        }
+    }
+
+    /// Which identifier of the token is on the revocation list, if any.
+    fn revoked(&self, claims: Option<&Claims>) -> Option<&'static str> {
+        let claims = claims.filter(|_| self.config.revocation.is_some())?;
+        revocation::revoked(self, claims.get_str("jti"), claims.get_str("sub"))
+    }
+
+    /// Checks that a verified token naming the request's tenant was issued
+    /// for it, by one of the issuers of the tenant's `jwt`.
+    fn check_tenant(&self, claims: Option<&Claims>) -> Result<(), String> {
+        let Some(claim) = &self.tenant_claim else {
+            return Ok(());
+        };
+        let (Some(jwt_config), Some(claims)) = (&self.config.jwt, claims) else {
+            return Err("Token not verified for the tenant".to_string());
+        };
+        if claims.lookup(claim).as_deref() != self.config.tenant.as_deref() {
+            return Err("Token not issued for the tenant".to_string());
+        }
+        let trusted = claims.issuer().is_some_and(|iss| {
+            jwt_config.rules.issuer.as_deref() == Some(iss) || jwt_config.issuer(iss).is_some()
+        });
+        match trusted {
+            true => Ok(()),
+            false => Err("Token issuer not trusted by the tenant".to_string()),
+        }
+    }
+
+    fn reject_tenant(&self, message: &str) {
+        req_warn!(self, "Tenant check failed: {}", message);
+        metrics::increment(self.metrics.denied);
+        self.send_unauthorized_response(message);
+    }
+
+    /// Checks the token's `jti` and the request's nonce against earlier
+    /// requests when `replay` is configured.
+    fn check_replay(&self, claims: Option<&Claims>) -> Result<(), String> {
+        let Some(replay) = &self.config.replay else {
+            return Ok(());
+        };
+        let jti = claims
+            .filter(|_| replay.jti)
+            .and_then(|claims| claims.get_str("jti"));
+        let nonce = replay
+            .nonce_header
+            .as_ref()
+            .and_then(|name| self.get_http_request_header(name));
+        if jti.is_none() && nonce.is_none() && replay.require_nonce {
+            return Err("Request carries no jti or nonce".to_string());
+        }
+        if jti.is_some_and(|jti| !self.first_use("jti", jti)) {
+            return Err("Replayed token".to_string());
+        }
+        if nonce.is_some_and(|nonce| !self.first_use("nonce", &nonce)) {
+            return Err("Replayed request nonce".to_string());
+        }
+        Ok(())
+    }
+
+    /// Records a use of `value` when `replay` is configured. Returns false
+    /// for a replay.
+    fn first_use(&self, kind: &str, value: &str) -> bool {
+        match &self.config.replay {
+            Some(replay) => replay::first_use(self, replay, kind, value, time::now_secs(self)),
+            None => true,
+        }
+    }
+
+    fn reject_replay(&self, message: &str) {
+        req_warn!(self, "{}", message);
+        metrics::increment(self.metrics.replayed);
+        self.send_unauthorized_response(message);
+    }
+
+    fn reject_signature(&self, error: &SignatureError) {
+        req_info!(self, "Request signature rejected: {}", error);
+        metrics::increment(self.metrics.signature_invalid);
+        self.send_unauthorized_response(&error.to_string());
+    }
+
+    /// Rejects a request whose body is too large to buffer.
+    fn reject_body_too_large(&self) {
+        req_info!(
+            self,
+            "Request body exceeds {} bytes",
+            self.config.max_request_body_bytes
+        );
+        metrics::increment(self.metrics.body_too_large);
+        self.send_templated_response(
+            &self.config.responses.payload_too_large,
+            "Request body too large",
+            "",
+        );
+    }
+
+    fn send_templated_response(&self, template: &ResponseTemplate, message: &str, reason: &str) {
+        let response = self.render_response(template, message, reason);
+        self.send_rendered_response(&response, message, reason);
+    }
+
+    /// Sends a rejection, as the equivalent gRPC status for gRPC requests.
+    /// Those carry the template's headers as metadata but not its body.
+    fn send_rendered_response(&self, response: &RenderedResponse, message: &str, reason: &str) {
+        self.set_waiting(false);
+        self.release();
+        if !self.grpc {
+            self.send_http_response(
+                response.status,
+                response.header_refs(),
+                Some(response.body.as_bytes()),
+            );
+            return;
+        }
+        let grpc_message = match reason {
+            "" => message.to_string(),
+            reason => format!("{}: {}", message, reason),
+        };
+        let metadata = response
+            .headers
+            .iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("content-type"))
+            .map(|(name, value)| (name.as_str(), value.as_bytes()))
+            .collect();
+        self.send_grpc_response(
+            grpc::status_for(response.status),
+            Some(&grpc_message),
+            metadata,
+        );
+    }
+
+    fn render_response(
+        &self,
+        template: &ResponseTemplate,
+        message: &str,
+        reason: &str,
+    ) -> RenderedResponse {
+        let vars = TemplateVars {
+            message,
+            reason,
+            request_id: &self.request_id,
+        };
+        match self.html_replies {
+            true => template.render_html(&vars, &self.config.responses.html_body),
+            false => template.render(&vars),
+        }
+    }
+}
+
+/// Makes a request handled from another context the effective one, unless it
+/// was reset in the meantime.
+fn enter(request: &ParkedRequest) -> Option<RefMut<'_, ServerFilterHttp>> {
+    let request = request.borrow_mut();
+    hostcalls::set_effective_context(request.context_id).ok()?;
+    Some(request)
+}
+
+/// Applies an evaluation's decision to the requests parked behind it, or
+/// their failure mode if it produced none.
+fn resume_waiters(waiters: Vec<ParkedRequest>, outcome: Option<&Outcome>) {
+    for waiter in waiters {
+        let mut waiter = waiter.borrow_mut();
+        // The request may have been reset while it was parked
+        if hostcalls::set_effective_context(waiter.context_id).is_err() {
+            continue;
+        }
+        match outcome {
+            Some(outcome) => waiter.apply_outcome(outcome, "coalesced"),
+            None => waiter.fail_pdp_response(),
+        }
+    }
+}
+
+thread_local! {
+    static REQUEST_COUNTER: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Builds a UUID-formatted request id from the time, the context id and a
+/// per-VM counter. Unique enough to correlate logs, but not unpredictable, so
+/// it must not be used as a secret.
+fn generate_request_id(now_nanos: u128, context_id: u32) -> String {
+    let counter = REQUEST_COUNTER.with(|c| {
+        c.set(c.get().wrapping_add(1));
+        c.get()
+    });
+    let hi = splitmix64(now_nanos as u64 ^ counter.rotate_left(32));
+    let lo = splitmix64(hi ^ ((context_id as u64) << 16) ^ counter);
+    format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        hi >> 32,
+        (hi >> 16) & 0xffff,
+        hi & 0x0fff,
+        ((lo >> 48) & 0x3fff) | 0x8000,
+        lo & 0xffff_ffff_ffff
+    )
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,78 @@
+use serde::Deserialize;
+use wasm_common::pdp::{CombineMode, Query};
+
+/// Static rules consulted instead of the PDP under the `local_fallback`
+/// failure mode, so critical traffic keeps a known-safe policy during PDP
+/// outages:
+///
+/// ```json
+/// "local_policy": {
+///   "rules": [
+///     {"principal": "svc-billing", "action": "read", "effect": "allow"},
+///     {"asset": "admin-*", "effect": "deny"}
+///   ]
+/// }
+/// ```
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct LocalPolicy {
+    /// Tried in order; the first rule matching a query decides it.
+    pub rules: Vec<LocalRule>,
+    /// Effect for queries no rule matches.
+    pub default_effect: Effect,
+}
+
+/// A rule matching on any combination of principal, asset and action. An
+/// absent field or `*` matches anything, and a trailing `*` matches by
+/// prefix.
+#[derive(Deserialize, Clone, Debug)]
+pub struct LocalRule {
+    pub principal: Option<String>,
+    pub asset: Option<String>,
+    pub action: Option<String>,
+    pub effect: Effect,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    Allow,
+    #[default]
+    Deny,
+}
+
+impl LocalRule {
+    fn matches(&self, principal: &str, query: &Query) -> bool {
+        pattern_matches(self.principal.as_deref(), principal)
+            && pattern_matches(self.asset.as_deref(), &query.asset_id)
+            && pattern_matches(self.action.as_deref(), &query.action)
+    }
+}
+
+impl LocalPolicy {
+    /// Whether the queries are allowed, combined as the PDP decisions would be.
+    pub fn allows(&self, principal: &str, queries: &[Query], combine: CombineMode) -> bool {
+        let allowed = |query: &Query| {
+            let effect = self
+                .rules
+                .iter()
+                .find(|rule| rule.matches(principal, query))
+                .map_or(self.default_effect, |rule| rule.effect);
+            effect == Effect::Allow
+        };
+        match combine {
+            CombineMode::All => !queries.is_empty() && queries.iter().all(allowed),
+            CombineMode::Any => queries.iter().any(allowed),
+        }
+    }
+}
+
+pub fn pattern_matches(pattern: Option<&str>, value: &str) -> bool {
+    match pattern {
+        None | Some("*") => true,
+        Some(pattern) => match pattern.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => pattern == value,
+        },
+    }
+}
@@ -0,0 +1,187 @@
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MetricType;
+use std::collections::BTreeMap;
+use wasm_common::log_warn;
+
+/// Envoy stats exported by the server filter. Metric ids are per VM, so each
+/// root context defines them once and hands them to its HTTP contexts.
+///
+/// Names are `<stat_prefix>.<name>`; Envoy exposes them on `/stats` under
+/// `wasmcustom.`.
+#[derive(Default, Clone, Copy)]
+pub struct Metrics {
+    pub allowed: Option<u32>,
+    pub denied: Option<u32>,
+    /// Requests the policy denied, let through in `shadow` enforcement mode.
+    pub shadow_denied: Option<u32>,
+    /// PDP callouts that produced no decision, for any reason.
+    pub pdp_errors: Option<u32>,
+    /// The subset of `pdp_errors` where the response could not be parsed.
+    pub pdp_parse_errors: Option<u32>,
+    /// Requests rejected for a missing or malformed Authorization header.
+    pub missing_auth: Option<u32>,
+    pub decision_cache_hits: Option<u32>,
+    pub decision_cache_misses: Option<u32>,
+    pub pdp_fail_open: Option<u32>,
+    /// Requests rejected by the local rate limiter.
+    pub rate_limited: Option<u32>,
+    /// Requests let through uncounted because another principal's bucket
+    /// held the slot theirs hashes to.
+    pub rate_limit_untracked: Option<u32>,
+    /// Requests rejected because the PDP-reported quota was used up.
+    pub quota_exceeded: Option<u32>,
+    /// Requests parked behind an identical PDP evaluation in flight.
+    pub pdp_coalesced: Option<u32>,
+    /// Requests rejected for a body over `max_request_body_bytes`.
+    pub body_too_large: Option<u32>,
+    /// Requests rejected for a missing or invalid HMAC signature.
+    pub signature_invalid: Option<u32>,
+    /// Requests rejected as replays of an earlier one.
+    pub replayed: Option<u32>,
+    /// Requests rejected for a token on the revocation list.
+    pub revoked_tokens: Option<u32>,
+    /// Requests rejected for a token acting on behalf of its subject without
+    /// an allowed delegation chain.
+    pub delegation_denied: Option<u32>,
+    /// Requests rejected for a missing, altered or expired identity chain.
+    pub identity_chain_invalid: Option<u32>,
+    /// PDP callouts retried on another cluster after failing on one.
+    pub pdp_failovers: Option<u32>,
+    /// Second PDP calls sent for callouts outstanding past
+    /// `hedging.threshold_ms`.
+    pub pdp_hedges: Option<u32>,
+    /// Principals found crossing the deny or block threshold of
+    /// `security_events`.
+    pub security_events: Option<u32>,
+    /// Requests of principals blocked for repeated denies.
+    pub blocked: Option<u32>,
+    /// PIP lookups that failed or returned an unusable body.
+    pub pip_errors: Option<u32>,
+    /// Requests over `concurrency_limit`, handled without calling the PDP.
+    pub pdp_overflow: Option<u32>,
+    /// JWKS fetches made for requests whose token named an unknown key.
+    pub unknown_key_fetches: Option<u32>,
+    /// Requests paused on a PDP or JWKS callout, or parked behind an
+    /// identical evaluation.
+    pub requests_waiting: Option<u32>,
+    /// 1 while health probes find the PDP up, 0 while down.
+    pub pdp_healthy: Option<u32>,
+    /// Time from dispatching a PDP callout to receiving its response.
+    pub pdp_latency_ms: Option<u32>,
+    /// Time from a request's headers reaching the filter to its being let
+    /// through or answered, PDP callouts and body buffering included.
+    pub added_latency_ms: Option<u32>,
+}
+
+impl Metrics {
+    pub fn define(prefix: &str) -> Self {
+        let counter = |name: &str| define(MetricType::Counter, &format!("{}.{}", prefix, name));
+        Metrics {
+            allowed: counter("allowed"),
+            denied: counter("denied"),
+            shadow_denied: counter("shadow.denied"),
+            pdp_errors: counter("pdp.errors"),
+            pdp_parse_errors: counter("pdp.parse_errors"),
+            missing_auth: counter("missing_auth"),
+            decision_cache_hits: counter("decision_cache.hits"),
+            decision_cache_misses: counter("decision_cache.misses"),
+            pdp_fail_open: counter("pdp.fail_open"),
+            rate_limited: counter("rate_limited"),
+            rate_limit_untracked: counter("rate_limit.untracked"),
+            quota_exceeded: counter("quota_exceeded"),
+            pdp_coalesced: counter("pdp.coalesced"),
+            body_too_large: counter("body_too_large"),
+            signature_invalid: counter("signature_invalid"),
+            replayed: counter("replayed"),
+            revoked_tokens: counter("revoked_tokens"),
+            delegation_denied: counter("delegation_denied"),
+            identity_chain_invalid: counter("identity_chain.invalid"),
+            pdp_failovers: counter("pdp.failovers"),
+            pdp_hedges: counter("pdp.hedges"),
+            pdp_overflow: counter("pdp.overflow"),
+            security_events: counter("security_events"),
+            blocked: counter("blocked"),
+            pip_errors: counter("pip.errors"),
+            unknown_key_fetches: counter("jwks.unknown_key_fetches"),
+            requests_waiting: define(MetricType::Gauge, &format!("{}.requests_waiting", prefix)),
+            pdp_healthy: define(MetricType::Gauge, &format!("{}.pdp.healthy", prefix)),
+            pdp_latency_ms: define(MetricType::Histogram, &format!("{}.pdp.latency_ms", prefix)),
+            added_latency_ms: define(
+                MetricType::Histogram,
+                &format!("{}.added_latency_ms", prefix),
+            ),
+        }
+    }
+
+    /// Current values of the counters, by name.
+    pub fn counters(&self) -> BTreeMap<&'static str, u64> {
+        let counters = [
+            ("allowed", self.allowed),
+            ("denied", self.denied),
+            ("shadow.denied", self.shadow_denied),
+            ("pdp.errors", self.pdp_errors),
+            ("pdp.parse_errors", self.pdp_parse_errors),
+            ("missing_auth", self.missing_auth),
+            ("decision_cache.hits", self.decision_cache_hits),
+            ("decision_cache.misses", self.decision_cache_misses),
+            ("pdp.fail_open", self.pdp_fail_open),
+            ("rate_limited", self.rate_limited),
+            ("rate_limit.untracked", self.rate_limit_untracked),
+            ("quota_exceeded", self.quota_exceeded),
+            ("pdp.coalesced", self.pdp_coalesced),
+            ("body_too_large", self.body_too_large),
+            ("signature_invalid", self.signature_invalid),
+            ("replayed", self.replayed),
+            ("revoked_tokens", self.revoked_tokens),
+            ("delegation_denied", self.delegation_denied),
+            ("identity_chain.invalid", self.identity_chain_invalid),
+            ("pdp.failovers", self.pdp_failovers),
+            ("pdp.hedges", self.pdp_hedges),
+            ("pdp.overflow", self.pdp_overflow),
+            ("security_events", self.security_events),
+            ("blocked", self.blocked),
+            ("pip.errors", self.pip_errors),
+            ("jwks.unknown_key_fetches", self.unknown_key_fetches),
+        ];
+        counters
+            .into_iter()
+            .map(|(name, metric)| (name, value(metric)))
+            .collect()
+    }
+}
+
+fn define(metric_type: MetricType, name: &str) -> Option<u32> {
+    match hostcalls::define_metric(metric_type, name) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            log_warn!("Failed to define metric {}: {:?}", name, e);
+            None
+        }
+    }
+}
+
+pub fn increment(metric: Option<u32>) {
+    if let Some(id) = metric {
+        let _ = hostcalls::increment_metric(id, 1);
+    }
+}
+
+/// Moves a gauge by `delta`.
+pub fn add(metric: Option<u32>, delta: i64) {
+    if let Some(id) = metric {
+        let _ = hostcalls::increment_metric(id, delta);
+    }
+}
+
+/// Current value of a metric, zero if it couldn't be defined.
+pub fn value(metric: Option<u32>) -> u64 {
+    metric
+        .and_then(|id| hostcalls::get_metric(id).ok())
+        .unwrap_or(0)
+}
+
+pub fn record(metric: Option<u32>, value: u64) {
+    if let Some(id) = metric {
+        let _ = hostcalls::record_metric(id, value);
+    }
+}
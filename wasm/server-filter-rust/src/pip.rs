@@ -0,0 +1,108 @@
+use proxy_wasm::traits::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const ATTRIBUTES_KEY_PREFIX: &str = "server_filter.pip:";
+
+/// Enrichment of PDP evaluations with attributes of the principal, such as
+/// groups or a risk score, fetched from a Policy Information Point before
+/// the PDP is called. The PIP is posted `{"principal": {"id": ...}}` and
+/// answers with a JSON object, whose members are added to the evaluation's
+/// `context` under `context_prefix`: strings as they are, arrays joined with
+/// commas and other values as JSON.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PipConfig {
+    pub cluster: String,
+    pub authority: String,
+    pub path: String,
+    pub timeout_ms: u64,
+    /// How long a principal's attributes are reused across requests and
+    /// workers. Zero fetches them for every request.
+    pub cache_ttl_ms: u64,
+    pub context_prefix: String,
+    /// Whether a request whose attributes can't be fetched gets the failure
+    /// mode, rather than being evaluated without them.
+    pub required: bool,
+}
+
+impl Default for PipConfig {
+    fn default() -> Self {
+        PipConfig {
+            cluster: "pip-service".to_string(),
+            authority: "pip-service".to_string(),
+            path: "/attributes".to_string(),
+            timeout_ms: 500,
+            cache_ttl_ms: 60_000,
+            context_prefix: "principal.".to_string(),
+            required: false,
+        }
+    }
+}
+
+impl PipConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// Context entries for the attributes in a PIP response body.
+    pub fn parse(&self, body: &[u8]) -> Result<BTreeMap<String, String>, String> {
+        let attributes: serde_json::Map<String, Value> =
+            serde_json::from_slice(body).map_err(|e| e.to_string())?;
+        Ok(attributes
+            .into_iter()
+            .map(|(name, value)| (format!("{}{}", self.context_prefix, name), flatten(value)))
+            .collect())
+    }
+}
+
+fn flatten(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        Value::Array(items) => items.into_iter().map(flatten).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedAttributes {
+    attributes: BTreeMap<String, String>,
+    expires_at_ms: u64,
+}
+
+/// The cached attributes of `principal`, if not expired.
+pub fn lookup<C: Context + ?Sized>(
+    ctx: &C,
+    principal: &str,
+    now_ms: u64,
+) -> Option<BTreeMap<String, String>> {
+    let key = format!("{}{}", ATTRIBUTES_KEY_PREFIX, principal);
+    let (data, cas) = ctx.get_shared_data(&key);
+    let cached: CachedAttributes = serde_json::from_slice(&data?).ok()?;
+    if cached.expires_at_ms <= now_ms {
+        let _ = ctx.set_shared_data(&key, None, cas);
+        return None;
+    }
+    Some(cached.attributes)
+}
+
+pub fn store<C: Context + ?Sized>(
+    ctx: &C,
+    principal: &str,
+    attributes: &BTreeMap<String, String>,
+    expires_at_ms: u64,
+) {
+    let cached = CachedAttributes {
+        attributes: attributes.clone(),
+        expires_at_ms,
+    };
+    if let Ok(value) = serde_json::to_vec(&cached) {
+        let _ = ctx.set_shared_data(
+            &format!("{}{}", ATTRIBUTES_KEY_PREFIX, principal),
+            Some(&value),
+            None,
+        );
+    }
+}
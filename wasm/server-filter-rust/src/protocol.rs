@@ -0,0 +1,320 @@
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use wasm_common::pdp::{
+    Decision, EvaluationRequest, EvaluationResponse, Obligations, Query, Quota,
+};
+
+use crate::config::FilterConfig;
+use crate::ext_authz::{self, HttpAttributes};
+
+/// The request and response schema spoken to the PDP. Every protocol maps
+/// onto the filter's `EvaluationRequest`/`EvaluationResponse`, so caching,
+/// combining and obligations work the same whichever is used.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PdpProtocol {
+    /// SGNL access evaluation API, over either `pdp_transport`.
+    #[default]
+    Sgnl,
+    /// OPA data API: the evaluation is posted as `input` to
+    /// `/v1/data/<opa_package>`. Always uses HTTP.
+    Opa,
+    /// OpenID AuthZEN access evaluation API, see `AuthzenConfig`. Always
+    /// uses HTTP.
+    Authzen,
+    /// Envoy ext_authz v3 API, over either `pdp_transport`; see
+    /// `ExtAuthzConfig`.
+    ExtAuthz,
+}
+
+/// Body encoding of HTTP callouts with the `sgnl` protocol. The `grpc`
+/// transport always uses protobuf, the other protocols always JSON.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PdpEncoding {
+    #[default]
+    Json,
+    /// The messages of `proto/evaluation.proto`, as
+    /// `application/x-protobuf`. Smaller and cheaper to produce and parse
+    /// than JSON.
+    Protobuf,
+}
+
+/// Settings for the `authzen` protocol. A request with a single query uses
+/// the evaluation endpoint; additional queries are sent in one call to the
+/// batch evaluations endpoint.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AuthzenConfig {
+    pub evaluation_path: String,
+    pub evaluations_path: String,
+    /// `type` of the subject, whose `id` is the principal. The actor of an
+    /// on-behalf-of request is sent as its `properties.actor`.
+    pub subject_type: String,
+    /// `type` of the resource, whose `id` is the asset id.
+    pub resource_type: String,
+}
+
+impl Default for AuthzenConfig {
+    fn default() -> Self {
+        AuthzenConfig {
+            evaluation_path: "/access/v1/evaluation".to_string(),
+            evaluations_path: "/access/v1/evaluations".to_string(),
+            subject_type: "user".to_string(),
+            resource_type: "asset".to_string(),
+        }
+    }
+}
+
+impl PdpProtocol {
+    /// Whether the protocol can use the gRPC transport.
+    pub fn supports_grpc(&self) -> bool {
+        matches!(self, PdpProtocol::Sgnl | PdpProtocol::ExtAuthz)
+    }
+
+    /// Service and method of the gRPC callout.
+    pub fn grpc_method<'a>(&self, config: &'a FilterConfig) -> (&'a str, &'a str) {
+        match self {
+            PdpProtocol::ExtAuthz => (ext_authz::GRPC_SERVICE, ext_authz::GRPC_METHOD),
+            _ => (&config.pdp_grpc_service, &config.pdp_grpc_method),
+        }
+    }
+
+    /// Path of the HTTP callout for `request`.
+    pub fn http_path(&self, config: &FilterConfig, request: &EvaluationRequest) -> String {
+        match self {
+            PdpProtocol::Sgnl => config.pdp_path.clone(),
+            PdpProtocol::Opa => format!("/v1/data/{}", config.opa_package.replace('.', "/")),
+            PdpProtocol::Authzen if request.queries.len() == 1 => {
+                config.authzen.evaluation_path.clone()
+            }
+            PdpProtocol::Authzen => config.authzen.evaluations_path.clone(),
+            PdpProtocol::ExtAuthz => config.ext_authz.path.clone(),
+        }
+    }
+
+    /// Encoding of the HTTP callout's request and response bodies.
+    pub fn encoding(&self, config: &FilterConfig) -> PdpEncoding {
+        match self {
+            PdpProtocol::Sgnl => config.pdp_encoding,
+            PdpProtocol::Opa | PdpProtocol::Authzen | PdpProtocol::ExtAuthz => PdpEncoding::Json,
+        }
+    }
+
+    /// Content type of the HTTP callout's body.
+    pub fn content_type(&self, config: &FilterConfig) -> &'static str {
+        match self.encoding(config) {
+            PdpEncoding::Json => "application/json",
+            PdpEncoding::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    /// Body of the HTTP callout. `http` is only used by `ext_authz`.
+    pub fn encode(
+        &self,
+        config: &FilterConfig,
+        request: &EvaluationRequest,
+        http: &HttpAttributes,
+    ) -> Result<Vec<u8>, serde_json::Error> {
+        match self {
+            PdpProtocol::Sgnl if config.pdp_encoding == PdpEncoding::Protobuf => {
+                Ok(request.encode_proto())
+            }
+            PdpProtocol::Sgnl => serde_json::to_vec(request),
+            PdpProtocol::Opa => serde_json::to_vec(&OpaRequest { input: request }),
+            PdpProtocol::Authzen => serde_json::to_vec(&authzen_request(&config.authzen, request)),
+            PdpProtocol::ExtAuthz => {
+                serde_json::to_vec(&ext_authz::check_request(&config.ext_authz, request, http))
+            }
+        }
+    }
+
+    /// Message of the gRPC callout.
+    pub fn encode_grpc(
+        &self,
+        config: &FilterConfig,
+        request: &EvaluationRequest,
+        http: &HttpAttributes,
+    ) -> Vec<u8> {
+        match self {
+            PdpProtocol::ExtAuthz => {
+                ext_authz::check_request(&config.ext_authz, request, http).encode_to_vec()
+            }
+            _ => request.encode_proto(),
+        }
+    }
+
+    /// Parses the HTTP callout's response for `expected` queries.
+    pub fn decode(
+        &self,
+        config: &FilterConfig,
+        body: &[u8],
+        expected: usize,
+    ) -> Result<EvaluationResponse, String> {
+        match self {
+            PdpProtocol::Sgnl if config.pdp_encoding == PdpEncoding::Protobuf => {
+                EvaluationResponse::decode_proto(body).map_err(|e| e.to_string())
+            }
+            PdpProtocol::Sgnl => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            PdpProtocol::Opa => decode_opa(body, expected),
+            PdpProtocol::Authzen => decode_authzen(body, expected),
+            PdpProtocol::ExtAuthz => ext_authz::decode_json(body, expected),
+        }
+    }
+
+    /// Parses the gRPC callout's response for `expected` queries.
+    pub fn decode_grpc(&self, body: &[u8], expected: usize) -> Result<EvaluationResponse, String> {
+        match self {
+            PdpProtocol::ExtAuthz => ext_authz::decode_proto(body, expected),
+            _ => EvaluationResponse::decode_proto(body).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OpaRequest<'a> {
+    input: &'a EvaluationRequest,
+}
+
+#[derive(Deserialize)]
+struct OpaResponse {
+    result: Option<Value>,
+}
+
+/// An OPA result deciding every query at once.
+#[derive(Deserialize)]
+struct OpaVerdict {
+    allow: bool,
+    #[serde(default)]
+    reason: String,
+    #[serde(default)]
+    obligations: Obligations,
+    #[serde(default)]
+    quota: Option<Quota>,
+    #[serde(default)]
+    ttl_secs: Option<u64>,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+/// Accepts a policy result that is a boolean, an object with `allow` (and
+/// optionally `reason`, `obligations`, `quota`, `ttl_secs` and
+/// `expires_at`), or an object with
+/// per-query `decisions` in the SGNL format. An undefined result is an
+/// error, as it usually means `opa_package` names no rule.
+fn decode_opa(body: &[u8], expected: usize) -> Result<EvaluationResponse, String> {
+    let response: OpaResponse = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let verdict = match response.result {
+        None => return Err("policy result is undefined".to_string()),
+        Some(Value::Bool(allow)) => OpaVerdict {
+            allow,
+            reason: String::new(),
+            obligations: Obligations::default(),
+            quota: None,
+            ttl_secs: None,
+            expires_at: None,
+        },
+        Some(result @ Value::Object(_)) if result.get("decisions").is_some() => {
+            return serde_json::from_value(result).map_err(|e| e.to_string());
+        }
+        Some(result @ Value::Object(_)) => {
+            serde_json::from_value(result).map_err(|e| e.to_string())?
+        }
+        Some(_) => return Err("policy result is neither a boolean nor an object".to_string()),
+    };
+
+    let decision = Decision {
+        decision: if verdict.allow { "Allow" } else { "Deny" }.to_string(),
+        reason: verdict.reason,
+        obligations: verdict.obligations,
+        quota: verdict.quota,
+        ttl_secs: verdict.ttl_secs,
+        expires_at: verdict.expires_at,
+    };
+    Ok(EvaluationResponse {
+        decisions: vec![decision; expected.max(1)],
+    })
+}
+
+/// Builds a single evaluation for one query, or a batch sharing the subject
+/// for several. Request context goes in the top-level `context`, connection
+/// attributes under its `connection`.
+fn authzen_request(config: &AuthzenConfig, request: &EvaluationRequest) -> Value {
+    let mut subject = json!({"type": config.subject_type, "id": request.principal.id});
+    if let Some(actor) = &request.principal.actor {
+        subject["properties"] = json!({"actor": actor});
+    }
+    let evaluation = |query: &Query| {
+        json!({
+            "resource": {"type": config.resource_type, "id": query.asset_id},
+            "action": {"name": query.action},
+        })
+    };
+    let mut body = match request.queries.as_slice() {
+        [query] => {
+            let mut single = evaluation(query);
+            single["subject"] = subject;
+            single
+        }
+        queries => json!({
+            "subject": subject,
+            "evaluations": queries.iter().map(evaluation).collect::<Vec<_>>(),
+        }),
+    };
+    if !request.context.is_empty() {
+        body["context"] = json!(request.context);
+    }
+    if let Some(connection) = &request.connection {
+        body["context"]["connection"] = json!(connection);
+    }
+    body
+}
+
+/// One AuthZEN decision. The filter reads `reason` (when it is a string),
+/// `obligations`, `quota`, `ttl_secs` and `expires_at` from its `context`,
+/// in the same shapes as the SGNL API.
+#[derive(Deserialize)]
+struct AuthzenDecision {
+    decision: bool,
+    #[serde(default)]
+    context: AuthzenContext,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct AuthzenContext {
+    reason: Value,
+    obligations: Obligations,
+    quota: Option<Quota>,
+    ttl_secs: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct AuthzenEvaluations {
+    evaluations: Vec<AuthzenDecision>,
+}
+
+fn decode_authzen(body: &[u8], expected: usize) -> Result<EvaluationResponse, String> {
+    let decisions = if expected == 1 {
+        vec![serde_json::from_slice::<AuthzenDecision>(body).map_err(|e| e.to_string())?]
+    } else {
+        serde_json::from_slice::<AuthzenEvaluations>(body)
+            .map_err(|e| e.to_string())?
+            .evaluations
+    };
+    Ok(EvaluationResponse {
+        decisions: decisions
+            .into_iter()
+            .map(|d| Decision {
+                decision: if d.decision { "Allow" } else { "Deny" }.to_string(),
+                reason: d.context.reason.as_str().unwrap_or_default().to_string(),
+                obligations: d.context.obligations,
+                quota: d.context.quota,
+                ttl_secs: d.context.ttl_secs,
+                expires_at: d.context.expires_at,
+            })
+            .collect(),
+    })
+}
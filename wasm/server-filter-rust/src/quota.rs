@@ -0,0 +1,68 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use wasm_common::pdp::Quota;
+
+use crate::slots::{self, Entry};
+
+const QUOTA_KEY_PREFIX: &str = "server_filter.quota:";
+
+/// Attempts at a compare-and-swap update before the request is let through
+/// uncounted.
+const CAS_RETRIES: usize = 4;
+
+/// Principals whose quotas are tracked at once. A principal whose slot holds
+/// another's unexpired quota isn't tracked locally; each of its requests goes
+/// to the PDP, which reports the quota anew.
+const SLOTS: u32 = slots::DEFAULT_SLOTS;
+
+fn key(principal: &str) -> String {
+    slots::key(QUOTA_KEY_PREFIX, principal, SLOTS)
+}
+
+fn read<C: Context + ?Sized>(
+    ctx: &C,
+    key: &str,
+    principal: &str,
+    now_secs: u64,
+) -> (Entry<Quota>, Option<u32>) {
+    slots::read(ctx, key, principal, |quota: &Quota| quota.reset <= now_secs)
+}
+
+/// Records the quota the PDP reported for `principal`, replacing the local
+/// count.
+pub fn store<C: Context + ?Sized>(ctx: &C, principal: &str, quota: &Quota, now_secs: u64) {
+    let key = key(principal);
+    if let (Entry::Own(_) | Entry::Vacant, cas) = read(ctx, &key, principal, now_secs) {
+        let _ = slots::write(ctx, &key, principal, quota, cas);
+    }
+}
+
+/// Charges one request against the principal's quota. Returns the updated
+/// quota, `None` if none is being tracked, or `Err` with the exhausted quota
+/// once it is used up.
+pub fn consume<C: Context + ?Sized>(
+    ctx: &C,
+    principal: &str,
+    now_secs: u64,
+) -> Result<Option<Quota>, Quota> {
+    let key = key(principal);
+    for _ in 0..CAS_RETRIES {
+        let (Entry::Own(mut quota), cas) = read(ctx, &key, principal, now_secs) else {
+            return Ok(None);
+        };
+        if quota.reset <= now_secs {
+            // The PDP reports the renewed quota on the next callout
+            return Ok(None);
+        }
+        if quota.remaining == 0 {
+            return Err(quota);
+        }
+        quota.remaining -= 1;
+
+        match slots::write(ctx, &key, principal, &quota, cas) {
+            Err(Status::CasMismatch) => continue,
+            _ => return Ok(Some(quota)),
+        }
+    }
+    Ok(None)
+}
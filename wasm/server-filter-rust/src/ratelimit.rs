@@ -0,0 +1,114 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+
+use crate::slots::{self, Entry};
+
+const BUCKET_KEY_PREFIX: &str = "server_filter.ratelimit:";
+
+/// Attempts at a compare-and-swap update before the request is let through
+/// uncounted.
+const CAS_RETRIES: usize = 4;
+
+/// Token bucket per principal, shared by all workers through shared data.
+/// Each bucket holds up to `requests_per_window` tokens and refills at that
+/// many per `window_ms`, so short bursts are absorbed while the sustained
+/// rate is capped.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub requests_per_window: u32,
+    pub window_ms: u64,
+    /// Give each (principal, asset) pair its own bucket instead of one per
+    /// principal.
+    pub per_asset: bool,
+    /// Buckets kept at once. A bucket's slot passes to another principal
+    /// only once the bucket has refilled; until then, that principal's
+    /// requests go uncounted.
+    pub slots: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_window: 100,
+            window_ms: 1000,
+            per_asset: false,
+            slots: slots::DEFAULT_SLOTS,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_ms: u64,
+}
+
+impl RateLimitConfig {
+    /// Identifies the bucket a request draws from.
+    pub fn bucket_id(&self, principal: &str, asset: &str) -> String {
+        let parts = if self.per_asset {
+            serde_json::to_string(&(principal, asset))
+        } else {
+            serde_json::to_string(&(principal,))
+        };
+        parts.unwrap_or_default()
+    }
+
+    fn tokens_per_ms(&self) -> f64 {
+        self.requests_per_window as f64 / self.window_ms.max(1) as f64
+    }
+
+    /// Tokens in `bucket` by `now_ms`, refilled for the time since its last
+    /// update.
+    fn refilled(&self, bucket: &Bucket, now_ms: u64) -> f64 {
+        let elapsed = now_ms.saturating_sub(bucket.updated_ms) as f64;
+        (bucket.tokens + elapsed * self.tokens_per_ms()).min(self.requests_per_window as f64)
+    }
+}
+
+/// Takes a token from the bucket identified by `bucket_id`. Returns `Err`
+/// with the number of milliseconds until a token is available when the
+/// bucket is empty, and `Ok(false)` when the request goes uncounted because
+/// another bucket holds the slot.
+pub fn acquire<C: Context + ?Sized>(
+    ctx: &C,
+    config: &RateLimitConfig,
+    bucket_id: &str,
+    now_ms: u64,
+) -> Result<bool, u64> {
+    let capacity = config.requests_per_window as f64;
+    let rate = config.tokens_per_ms();
+    let key = slots::key(BUCKET_KEY_PREFIX, bucket_id, config.slots);
+    // A full bucket is no different from a fresh one, so its slot can go
+    let full = |bucket: &Bucket| config.refilled(bucket, now_ms) >= capacity;
+    for _ in 0..CAS_RETRIES {
+        let (entry, cas) = slots::read(ctx, &key, bucket_id, full);
+        let mut bucket = match entry {
+            Entry::Own(bucket) => bucket,
+            Entry::Vacant => Bucket {
+                tokens: capacity,
+                updated_ms: now_ms,
+            },
+            Entry::Taken => return Ok(false),
+        };
+
+        bucket.tokens = config.refilled(&bucket, now_ms);
+        bucket.updated_ms = now_ms;
+        if bucket.tokens < 1.0 {
+            if rate <= 0.0 {
+                return Err(config.window_ms);
+            }
+            return Err(((1.0 - bucket.tokens) / rate).ceil() as u64);
+        }
+        bucket.tokens -= 1.0;
+
+        match slots::write(ctx, &key, bucket_id, &bucket, cas) {
+            Err(Status::CasMismatch) => continue,
+            _ => return Ok(true),
+        }
+    }
+    // Sustained contention on one bucket; don't reject for our own bookkeeping.
+    Ok(true)
+}
@@ -0,0 +1,101 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// How `redactFields` obligations are applied to upstream JSON responses.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RedactionConfig {
+    /// Largest response body buffered for redaction. Larger responses are
+    /// withheld, since they can't be forwarded unredacted.
+    pub max_body_bytes: usize,
+    /// Value written in place of a redacted field. Fields are removed when
+    /// absent.
+    pub replacement: Option<Value>,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        RedactionConfig {
+            max_body_bytes: 1024 * 1024,
+            replacement: None,
+        }
+    }
+}
+
+/// Removes or replaces the fields named by JSON pointers in a JSON document.
+/// A `*` segment matches every member of an object or element of an array,
+/// so `/items/*/ssn` covers a whole list. Pointers that match nothing are
+/// ignored.
+pub fn redact(
+    body: &[u8],
+    pointers: &[String],
+    replacement: Option<&Value>,
+) -> Result<Vec<u8>, serde_json::Error> {
+    let mut document: Value = serde_json::from_slice(body)?;
+    for pointer in pointers {
+        let Some(path) = pointer.strip_prefix('/') else {
+            continue;
+        };
+        let tokens: Vec<String> = path
+            .split('/')
+            .map(|t| t.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        apply(&mut document, &tokens, replacement);
+    }
+    serde_json::to_vec(&document)
+}
+
+fn apply(value: &mut Value, tokens: &[String], replacement: Option<&Value>) {
+    let Some((token, rest)) = tokens.split_first() else {
+        return;
+    };
+
+    if !rest.is_empty() {
+        match value {
+            Value::Object(map) if token == "*" => {
+                map.values_mut().for_each(|v| apply(v, rest, replacement))
+            }
+            Value::Array(items) if token == "*" => {
+                items.iter_mut().for_each(|v| apply(v, rest, replacement))
+            }
+            Value::Object(map) => {
+                if let Some(child) = map.get_mut(token) {
+                    apply(child, rest, replacement);
+                }
+            }
+            Value::Array(items) => {
+                if let Some(child) = token.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                    apply(child, rest, replacement);
+                }
+            }
+            _ => {}
+        }
+        return;
+    }
+
+    match (value, replacement) {
+        (Value::Object(map), Some(replacement)) => map
+            .iter_mut()
+            .filter(|(key, _)| token == "*" || *key == token)
+            .for_each(|(_, v)| *v = replacement.clone()),
+        (Value::Object(map), None) if token == "*" => map.clear(),
+        (Value::Object(map), None) => {
+            map.remove(token);
+        }
+        (Value::Array(items), Some(replacement)) if token == "*" => {
+            items.iter_mut().for_each(|v| *v = replacement.clone())
+        }
+        (Value::Array(items), Some(replacement)) => {
+            if let Some(item) = token.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                *item = replacement.clone();
+            }
+        }
+        (Value::Array(items), None) if token == "*" => items.clear(),
+        (Value::Array(items), None) => {
+            if let Some(i) = token.parse::<usize>().ok().filter(|i| *i < items.len()) {
+                items.remove(i);
+            }
+        }
+        _ => {}
+    }
+}
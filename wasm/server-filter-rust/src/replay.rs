@@ -0,0 +1,82 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::slots;
+
+const REPLAY_KEY_PREFIX: &str = "server_filter.replay:";
+
+/// Attempts at a compare-and-swap update before the request is taken for a
+/// replay.
+const CAS_RETRIES: usize = 4;
+
+/// Rejects requests reusing a token `jti`, a nonce or an HMAC signature
+/// already seen within the window, so a captured request or short-lived
+/// token can't be played back. Values are remembered in shared data, across
+/// VMs, hashed onto `slots` keys. Each key holds digests of its values still
+/// within the window, so memory follows the values seen in one window rather
+/// than every value ever seen.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReplayConfig {
+    /// How long a value is remembered. Should cover the lifetime of the
+    /// tokens and `request_signing.max_skew_secs` either side of now.
+    pub window_secs: u64,
+    /// Check the `jti` claim of the request's token.
+    pub jti: bool,
+    /// Request header carrying a single-use nonce, checked when present.
+    pub nonce_header: Option<String>,
+    /// Reject requests carrying neither a `jti` nor a nonce.
+    pub require_nonce: bool,
+    pub slots: u32,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            window_secs: 600,
+            jti: true,
+            nonce_header: Some("x-request-nonce".to_string()),
+            require_nonce: false,
+            slots: slots::DEFAULT_SLOTS,
+        }
+    }
+}
+
+/// Records a use of `value`, of the given kind (`jti`, `nonce` or
+/// `signature`). Returns false if it was already used within the window.
+pub fn first_use<C: Context + ?Sized>(
+    ctx: &C,
+    config: &ReplayConfig,
+    kind: &str,
+    value: &str,
+    now_secs: u64,
+) -> bool {
+    let value = format!("{}:{}", kind, value);
+    let key = slots::key(REPLAY_KEY_PREFIX, &value, config.slots);
+    let digest = URL_SAFE_NO_PAD.encode(&Sha256::digest(value.as_bytes())[..12]);
+    for _ in 0..CAS_RETRIES {
+        let (data, cas) = ctx.get_shared_data(&key);
+        // When each value seen in the slot expires, by digest
+        let mut seen: BTreeMap<String, u64> = data
+            .and_then(|d| serde_json::from_slice(&d).ok())
+            .unwrap_or_default();
+        seen.retain(|_, expires_at| *expires_at > now_secs);
+        if seen.contains_key(&digest) {
+            return false;
+        }
+        seen.insert(digest.clone(), now_secs + config.window_secs);
+        let Ok(data) = serde_json::to_vec(&seen) else {
+            return true;
+        };
+        match ctx.set_shared_data(&key, Some(&data), cas) {
+            Err(Status::CasMismatch) => continue,
+            _ => return true,
+        }
+    }
+    false
+}
@@ -0,0 +1,291 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use wasm_common::response::{self, PROBLEM_CONTENT_TYPE};
+
+/// A local reply sent when a request is rejected. `body` and header values
+/// may contain the placeholders `{message}`, `{reason}`, `{request_id}`,
+/// `{status}` and `{title}` (the status's reason phrase). Values substituted
+/// into the body are JSON-escaped when the reply's `content-type` is JSON,
+/// so templates can place them inside string literals.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ResponseTemplate {
+    pub status: u32,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+/// RFC 7807 problem details, with the request id as the occurrence's
+/// `instance`.
+const PROBLEM_BODY: &str = r#"{"type":"about:blank","title":"{title}","status":{status},"detail":"{message}","instance":"{request_id}"}"#;
+
+/// Problem details of a policy denial, carrying the PDP's reason as an
+/// extension member.
+const DENIAL_PROBLEM_BODY: &str = concat!(
+    r#"{"type":"about:blank","title":"{title}","status":{status},"detail":"{message}","instance":"{request_id}","#,
+    r#""reason":"{reason}"}"#
+);
+
+/// Minimal page for clients preferring HTML, such as browsers.
+const HTML_BODY: &str = "<!DOCTYPE html>\n<html><head><title>{status} {title}</title></head>\
+    <body><h1>{title}</h1><p>{message}</p><p>Request ID: {request_id}</p></body></html>\n";
+
+/// Templates for the filter's rejection responses. They default to
+/// `application/problem+json` bodies.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ResponseTemplates {
+    /// How the reply body is chosen for non-gRPC requests. gRPC requests
+    /// always get a gRPC status when `grpc` is configured.
+    pub format: ResponseFormat,
+    /// Page sent in place of a template's body when HTML is chosen, with
+    /// the same placeholders. Values are HTML-escaped.
+    pub html_body: String,
+    /// Authentication failures: missing, malformed or invalid credentials.
+    pub unauthorized: ResponseTemplate,
+    /// Policy denials and PDP failures.
+    pub forbidden: ResponseTemplate,
+    /// Requests over the rate limit or PDP quota. A `retry-after` header is
+    /// added.
+    pub rate_limited: ResponseTemplate,
+    /// Requests whose body is over `max_request_body_bytes`.
+    pub payload_too_large: ResponseTemplate,
+    /// Requests shed by `concurrency_limit`.
+    pub overloaded: ResponseTemplate,
+    /// Replies for specific PDP deny reasons, keyed by the exact reason
+    /// string, e.g. `"quota_exceeded"` mapped to a 429 with `retry-after`.
+    pub deny_reasons: HashMap<String, ReasonResponse>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// The template's body, or `html_body` when the request's `Accept`
+    /// header ranks HTML above JSON, as browsers' do.
+    #[default]
+    Negotiate,
+    /// Always the template's body.
+    Template,
+    /// Always `html_body`.
+    Html,
+}
+
+/// Override of the `forbidden` reply for one deny reason. Headers are added
+/// to the `forbidden` headers, and the `forbidden` body is used when `body`
+/// is unset.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ReasonResponse {
+    pub status: u32,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: Option<String>,
+}
+
+impl Default for ResponseTemplates {
+    fn default() -> Self {
+        ResponseTemplates {
+            format: ResponseFormat::Negotiate,
+            html_body: HTML_BODY.to_string(),
+            unauthorized: ResponseTemplate::problem(401, PROBLEM_BODY),
+            forbidden: ResponseTemplate::problem(403, DENIAL_PROBLEM_BODY),
+            rate_limited: ResponseTemplate::problem(429, PROBLEM_BODY),
+            payload_too_large: ResponseTemplate::problem(413, PROBLEM_BODY),
+            overloaded: ResponseTemplate::problem(503, PROBLEM_BODY),
+            deny_reasons: HashMap::new(),
+        }
+    }
+}
+
+impl ResponseTemplates {
+    /// Whether a request with the given `Accept` header gets `html_body`.
+    pub fn wants_html(&self, accept: &str) -> bool {
+        match self.format {
+            ResponseFormat::Negotiate => prefers_html(accept),
+            ResponseFormat::Template => false,
+            ResponseFormat::Html => true,
+        }
+    }
+
+    /// The template for a policy denial with the given PDP reason.
+    pub fn for_denial(&self, reason: &str) -> ResponseTemplate {
+        let Some(over) = self.deny_reasons.get(reason) else {
+            return self.forbidden.clone();
+        };
+        let mut headers = self.forbidden.headers.clone();
+        headers.extend(over.headers.clone());
+        ResponseTemplate {
+            status: over.status,
+            headers,
+            body: over
+                .body
+                .clone()
+                .unwrap_or_else(|| self.forbidden.body.clone()),
+        }
+    }
+}
+
+/// Values available to a template.
+pub struct TemplateVars<'a> {
+    pub message: &'a str,
+    pub reason: &'a str,
+    pub request_id: &'a str,
+}
+
+/// A rendered template, ready for `send_http_response`.
+pub struct RenderedResponse {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RenderedResponse {
+    pub fn header_refs(&self) -> Vec<(&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect()
+    }
+}
+
+impl ResponseTemplate {
+    pub fn problem(status: u32, body: &str) -> Self {
+        ResponseTemplate {
+            status,
+            headers: BTreeMap::from([(
+                "content-type".to_string(),
+                PROBLEM_CONTENT_TYPE.to_string(),
+            )]),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn render(&self, vars: &TemplateVars) -> RenderedResponse {
+        let is_json = self
+            .headers
+            .iter()
+            .any(|(k, v)| k.eq_ignore_ascii_case("content-type") && v.contains("json"));
+        let escape = if is_json { Escape::Json } else { Escape::None };
+        self.render_body(vars, &self.body, escape)
+    }
+
+    /// Renders `html_body` with the template's status and other headers.
+    pub fn render_html(&self, vars: &TemplateVars, html_body: &str) -> RenderedResponse {
+        let mut response = self.render_body(vars, html_body, Escape::Html);
+        response
+            .headers
+            .retain(|(k, _)| !k.eq_ignore_ascii_case("content-type"));
+        response.headers.push((
+            "content-type".to_string(),
+            "text/html; charset=utf-8".to_string(),
+        ));
+        response
+    }
+
+    fn render_body(&self, vars: &TemplateVars, body: &str, escape: Escape) -> RenderedResponse {
+        let status = self.status.to_string();
+        let values = [
+            ("message", vars.message),
+            ("reason", vars.reason),
+            ("request_id", vars.request_id),
+            ("status", status.as_str()),
+            ("title", response::title(self.status)),
+        ];
+        RenderedResponse {
+            status: self.status,
+            headers: self
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), substitute(v, &values, Escape::None)))
+                .collect(),
+            body: substitute(body, &values, escape),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    Json,
+    Html,
+}
+
+/// Replaces placeholders in a single pass, so substituted values are never
+/// themselves scanned for placeholders.
+fn substitute(template: &str, values: &[(&str, &str)], escape: Escape) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = values.iter().find_map(|(name, value)| {
+            let placeholder_len = name.len() + 2;
+            let matches = rest.len() >= placeholder_len
+                && rest[1..].starts_with(name)
+                && rest[placeholder_len - 1..].starts_with('}');
+            matches.then_some((*value, placeholder_len))
+        });
+        match value {
+            Some((value, len)) => {
+                match escape {
+                    Escape::None => out.push_str(value),
+                    Escape::Json => {
+                        let quoted = serde_json::to_string(value).unwrap_or_default();
+                        out.push_str(&quoted[1..quoted.len() - 1]);
+                    }
+                    Escape::Html => {
+                        for c in value.chars() {
+                            match c {
+                                '&' => out.push_str("&amp;"),
+                                '<' => out.push_str("&lt;"),
+                                '>' => out.push_str("&gt;"),
+                                '"' => out.push_str("&quot;"),
+                                '\'' => out.push_str("&#39;"),
+                                c => out.push(c),
+                            }
+                        }
+                    }
+                }
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whether an `Accept` header ranks HTML above JSON. The most specific range
+/// matching each decides its quality; JSON wins ties, and a missing header.
+pub fn prefers_html(accept: &str) -> bool {
+    let html = quality(accept, "text", "html");
+    let json =
+        quality(accept, "application", "json").max(quality(accept, "application", "problem+json"));
+    html > 0.0 && html > json
+}
+
+fn quality(accept: &str, main: &str, sub: &str) -> f32 {
+    let mut best = (0, 0.0);
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or_default().trim();
+        let (range_main, range_sub) = media.split_once('/').unwrap_or((media, ""));
+        let specificity = match (range_main, range_sub) {
+            (m, s) if m.eq_ignore_ascii_case(main) && s.eq_ignore_ascii_case(sub) => 3,
+            (m, "*") if m.eq_ignore_ascii_case(main) => 2,
+            ("*", "*") => 1,
+            _ => continue,
+        };
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if specificity > best.0 {
+            best = (specificity, q);
+        }
+    }
+    best.1
+}
@@ -0,0 +1,109 @@
+use proxy_wasm::traits::Context;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Shared-data key under which the root context stores the fetched
+/// revocation list, so every VM of the plugin sees a refresh made by any of
+/// them.
+pub const REVOCATIONS_SHARED_KEY: &str = "server_filter.revocations";
+
+/// Endpoint serving the list of revoked tokens as
+/// `{"jti": ["..."], "sub": ["..."]}`, fetched and periodically refreshed by
+/// the root context. Tokens whose `jti` or `sub` is listed are rejected even
+/// if otherwise valid. Until a list has been fetched none are.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RevocationConfig {
+    pub cluster: String,
+    pub path: String,
+    pub authority: String,
+    pub timeout_ms: u64,
+    pub refresh_interval_ms: u64,
+}
+
+impl Default for RevocationConfig {
+    fn default() -> Self {
+        RevocationConfig {
+            cluster: "revocation-service".to_string(),
+            path: "/revocations".to_string(),
+            authority: "revocation-service".to_string(),
+            timeout_ms: 5000,
+            refresh_interval_ms: 30_000,
+        }
+    }
+}
+
+impl RevocationConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_millis(self.refresh_interval_ms)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct RevocationList {
+    jti: HashSet<String>,
+    sub: HashSet<String>,
+}
+
+thread_local! {
+    // Parsed form of the shared list, tagged with the CAS it was read at
+    static PARSED: RefCell<Option<(u32, Rc<RevocationList>)>> = const { RefCell::new(None) };
+}
+
+/// Which of the token's identifiers is revoked, if any: `jti` or `sub`.
+pub fn revoked<C: Context + ?Sized>(
+    ctx: &C,
+    jti: Option<&str>,
+    sub: Option<&str>,
+) -> Option<&'static str> {
+    let list = shared_list(ctx)?;
+    if jti.is_some_and(|jti| list.jti.contains(jti)) {
+        return Some("jti");
+    }
+    if sub.is_some_and(|sub| list.sub.contains(sub)) {
+        return Some("sub");
+    }
+    None
+}
+
+/// Entries of the fetched list, or `None` if none has been fetched.
+pub fn size<C: Context + ?Sized>(ctx: &C) -> Option<usize> {
+    shared_list(ctx).map(|list| list.jti.len() + list.sub.len())
+}
+
+fn shared_list<C: Context + ?Sized>(ctx: &C) -> Option<Rc<RevocationList>> {
+    let (data, cas) = ctx.get_shared_data(REVOCATIONS_SHARED_KEY);
+    let data = data?;
+    let cas = cas.unwrap_or(0);
+
+    PARSED.with(|parsed| {
+        let mut parsed = parsed.borrow_mut();
+        if let Some((cached_cas, list)) = parsed.as_ref() {
+            if *cached_cas == cas {
+                return Some(list.clone());
+            }
+        }
+        let list: RevocationList = serde_json::from_slice(&data).ok()?;
+        let list = Rc::new(list);
+        *parsed = Some((cas, list.clone()));
+        Some(list)
+    })
+}
+
+/// Validates a fetched revocation list and publishes it to shared data.
+/// Returns the number of entries.
+pub fn store<C: Context + ?Sized>(ctx: &C, body: &[u8]) -> Result<usize, String> {
+    let list: RevocationList =
+        serde_json::from_slice(body).map_err(|e| format!("invalid revocation list: {}", e))?;
+    ctx.set_shared_data(REVOCATIONS_SHARED_KEY, Some(body), None)
+        .map_err(|e| format!("failed to store revocation list: {:?}", e))?;
+    Ok(list.jti.len() + list.sub.len())
+}
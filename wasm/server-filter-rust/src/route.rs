@@ -0,0 +1,50 @@
+use proxy_wasm::traits::Context;
+use serde::Deserialize;
+use wasm_common::log_info;
+
+use crate::config::FailureMode;
+
+/// Per-route overrides, read from the route's filter metadata so a single
+/// plugin instance can treat routes differently:
+///
+/// ```yaml
+/// metadata:
+///   filter_metadata:
+///     server_filter:
+///       config: '{"action": "read", "asset_id": "reports"}'
+/// ```
+///
+/// The value is a JSON string rather than a nested struct because Envoy
+/// returns non-string metadata values protobuf-encoded.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RouteConfig {
+    /// Skip authorization for this route entirely.
+    pub skip: bool,
+    /// Fixed asset id for the route, bypassing asset extraction.
+    pub asset_id: Option<String>,
+    pub action: Option<String>,
+    pub failure_mode: Option<FailureMode>,
+}
+
+/// Reads the overrides for the current route. Routes without metadata, or
+/// with metadata that fails to parse, get no overrides.
+pub fn load<C: Context + ?Sized>(ctx: &C, namespace: &str) -> RouteConfig {
+    let path = vec![
+        "xds",
+        "route_metadata",
+        "filter_metadata",
+        namespace,
+        "config",
+    ];
+    let Some(raw) = ctx.get_property(path) else {
+        return RouteConfig::default();
+    };
+    match serde_json::from_slice(&raw) {
+        Ok(route) => route,
+        Err(e) => {
+            log_info!("Ignoring invalid route config: {}", e);
+            RouteConfig::default()
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::slots::{self, Entry};
+
+const DENIES_KEY_PREFIX: &str = "server_filter.denies:";
+
+/// Attempts at a compare-and-swap update before a deny goes uncounted.
+const CAS_RETRIES: usize = 4;
+
+/// Reaction to principals denied repeatedly. Denies are counted per
+/// principal across workers; once `threshold` fall within `window_ms`, an
+/// event is posted to the security endpoint and the principal's requests
+/// carry `risk_header` upstream for `flag_ms`, so the application can step
+/// up its own checks. Events are posted by the root, as JSON arrays. With
+/// `block`, principals denied more often still are turned away for a while.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SecurityEventsConfig {
+    pub cluster: String,
+    pub authority: String,
+    pub path: String,
+    pub timeout_ms: u64,
+    pub flush_interval_ms: u64,
+    pub threshold: u32,
+    pub window_ms: u64,
+    pub flag_ms: u64,
+    /// Set to `risk_value` on flagged principals' requests, and removed from
+    /// everyone else's.
+    pub risk_header: String,
+    pub risk_value: String,
+    /// Principals whose denies are tracked at once. Denies of a principal
+    /// whose slot another principal's live count holds go uncounted.
+    pub slots: u32,
+    /// Temporary block of principals past a second threshold. Disabled when
+    /// absent.
+    pub block: Option<BlockConfig>,
+}
+
+impl Default for SecurityEventsConfig {
+    fn default() -> Self {
+        SecurityEventsConfig {
+            cluster: "security-events".to_string(),
+            authority: "security-events".to_string(),
+            path: "/events".to_string(),
+            timeout_ms: 5000,
+            flush_interval_ms: 1000,
+            threshold: 10,
+            window_ms: 60_000,
+            flag_ms: 300_000,
+            risk_header: "X-Risk-Signal".to_string(),
+            risk_value: "repeated_denies".to_string(),
+            slots: slots::DEFAULT_SLOTS,
+            block: None,
+        }
+    }
+}
+
+/// Once `threshold` denies of a principal fall within the `window_ms` of
+/// `security_events`, its requests are rejected without calling the PDP for
+/// `cooldown_ms`, which shields the PDP and backend from probing. Rejections
+/// of a blocked principal don't count as denies, so the block lifts on time.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BlockConfig {
+    pub threshold: u32,
+    pub cooldown_ms: u64,
+    /// Reject with 429 and `Retry-After`, rather than 403.
+    pub too_many_requests: bool,
+}
+
+impl Default for BlockConfig {
+    fn default() -> Self {
+        BlockConfig {
+            threshold: 20,
+            cooldown_ms: 600_000,
+            too_many_requests: true,
+        }
+    }
+}
+
+impl SecurityEventsConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval_ms)
+    }
+}
+
+/// A principal crossing the deny threshold, or the block threshold.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SecurityEvent {
+    pub timestamp_ms: u64,
+    /// `repeated_denies`, or `blocked` with the principal blocked until
+    /// `blocked_until_ms`.
+    pub kind: String,
+    pub principal: String,
+    pub denies: u32,
+    pub window_ms: u64,
+    /// The request whose deny crossed the threshold, and why it was denied.
+    pub request_id: String,
+    pub reason: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked_until_ms: Option<u64>,
+}
+
+/// Events waiting to be posted, shared by a root context and its HTTP
+/// contexts.
+pub type SecurityEventBuffer = Rc<RefCell<Vec<SecurityEvent>>>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct Denies {
+    count: u32,
+    window_start_ms: u64,
+    flagged_until_ms: u64,
+    #[serde(default)]
+    blocked_until_ms: u64,
+}
+
+/// The thresholds a deny took its principal over.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crossed {
+    /// Denies of the principal in the window, this one included.
+    pub denies: u32,
+    pub event: bool,
+    /// Set to when the principal's block ends, if this deny started one.
+    pub blocked_until_ms: Option<u64>,
+}
+
+impl Denies {
+    /// Whether the count no longer bears on the principal, so its slot may
+    /// pass to another.
+    fn lapsed(&self, config: &SecurityEventsConfig, now_ms: u64) -> bool {
+        now_ms.saturating_sub(self.window_start_ms) >= config.window_ms
+            && self.flagged_until_ms <= now_ms
+            && self.blocked_until_ms <= now_ms
+    }
+}
+
+fn key(config: &SecurityEventsConfig, principal: &str) -> String {
+    slots::key(DENIES_KEY_PREFIX, principal, config.slots)
+}
+
+/// Counts a deny of `principal`, flagging or blocking it when the deny
+/// crosses a threshold.
+pub fn record_deny<C: Context + ?Sized>(
+    ctx: &C,
+    config: &SecurityEventsConfig,
+    principal: &str,
+    now_ms: u64,
+) -> Crossed {
+    let key = key(config, principal);
+    for _ in 0..CAS_RETRIES {
+        let lapsed = |denies: &Denies| denies.lapsed(config, now_ms);
+        let (mut denies, cas) = match slots::read(ctx, &key, principal, lapsed) {
+            (Entry::Own(denies), cas) => (denies, cas),
+            (Entry::Vacant, cas) => (Denies::default(), cas),
+            (Entry::Taken, _) => return Crossed::default(),
+        };
+        if now_ms.saturating_sub(denies.window_start_ms) >= config.window_ms {
+            denies.count = 0;
+            denies.window_start_ms = now_ms;
+        }
+        denies.count += 1;
+        let mut crossed = Crossed {
+            denies: denies.count,
+            ..Default::default()
+        };
+        if denies.count == config.threshold.max(1) {
+            crossed.event = true;
+            denies.flagged_until_ms = now_ms + config.flag_ms;
+        }
+        if let Some(block) = config
+            .block
+            .as_ref()
+            .filter(|block| denies.count == block.threshold.max(1))
+        {
+            denies.blocked_until_ms = now_ms + block.cooldown_ms;
+            crossed.blocked_until_ms = Some(denies.blocked_until_ms);
+        }
+
+        match slots::write(ctx, &key, principal, &denies, cas) {
+            Err(Status::CasMismatch) => continue,
+            _ => return crossed,
+        }
+    }
+    Crossed::default()
+}
+
+fn lookup<C: Context + ?Sized>(
+    ctx: &C,
+    config: &SecurityEventsConfig,
+    principal: &str,
+) -> Option<Denies> {
+    match slots::read(ctx, &key(config, principal), principal, |_: &Denies| false) {
+        (Entry::Own(denies), _) => Some(denies),
+        _ => None,
+    }
+}
+
+/// Whether `principal` crossed the deny threshold within the last `flag_ms`.
+pub fn is_flagged<C: Context + ?Sized>(
+    ctx: &C,
+    config: &SecurityEventsConfig,
+    principal: &str,
+    now_ms: u64,
+) -> bool {
+    lookup(ctx, config, principal).is_some_and(|denies| denies.flagged_until_ms > now_ms)
+}
+
+/// How long `principal` remains blocked, if it is.
+pub fn blocked_for_ms<C: Context + ?Sized>(
+    ctx: &C,
+    config: &SecurityEventsConfig,
+    principal: &str,
+    now_ms: u64,
+) -> Option<u64> {
+    let remaining_ms = lookup(ctx, config, principal)?
+        .blocked_until_ms
+        .saturating_sub(now_ms);
+    (remaining_ms > 0).then_some(remaining_ms)
+}
@@ -0,0 +1,156 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+
+/// HMAC request signatures, verified before the PDP is consulted. A signed
+/// request carries three headers:
+///
+/// ```text
+/// x-signature-key-id: <key id>
+/// x-signature-timestamp: <unix seconds>
+/// x-signature: base64(HMAC-SHA256(secret, "<method>\n<path>\n<timestamp>\n" + body))
+/// ```
+///
+/// The body is buffered for verification, up to `max_request_body_bytes`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RequestSigningConfig {
+    /// Shared secrets by key id.
+    pub keys: HashMap<String, SigningSecret>,
+    pub key_id_header: String,
+    pub timestamp_header: String,
+    pub signature_header: String,
+    /// How far the timestamp may be from the filter's clock, either way.
+    pub max_skew_secs: u64,
+}
+
+impl Default for RequestSigningConfig {
+    fn default() -> Self {
+        RequestSigningConfig {
+            keys: HashMap::new(),
+            key_id_header: "x-signature-key-id".to_string(),
+            timestamp_header: "x-signature-timestamp".to_string(),
+            signature_header: "x-signature".to_string(),
+            max_skew_secs: 300,
+        }
+    }
+}
+
+/// A signing secret, inline or read from an environment variable set through
+/// `vm_config.environment_variables`.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SigningSecret {
+    pub secret: Option<String>,
+    /// Takes precedence over `secret`.
+    pub secret_env: Option<String>,
+}
+
+impl SigningSecret {
+    pub fn resolve(&self) -> Option<String> {
+        self.secret_env
+            .as_ref()
+            .and_then(|name| std::env::var(name).ok())
+            .or_else(|| self.secret.clone())
+            .filter(|secret| !secret.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing(String),
+    UnknownKey(String),
+    BadTimestamp,
+    Expired,
+    Malformed,
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing(header) => write!(f, "Missing {} header", header),
+            SignatureError::UnknownKey(key_id) => write!(f, "Unknown signing key {}", key_id),
+            SignatureError::BadTimestamp => write!(f, "Invalid signature timestamp"),
+            SignatureError::Expired => write!(f, "Signature timestamp outside the accepted window"),
+            SignatureError::Malformed => write!(f, "Malformed signature"),
+            SignatureError::Mismatch => write!(f, "Signature mismatch"),
+        }
+    }
+}
+
+/// A request whose signature headers checked out, awaiting its body.
+#[derive(Clone, Debug, Default)]
+pub struct SignedRequest {
+    secret: String,
+    /// Signed input preceding the body.
+    prefix: String,
+    signature: Vec<u8>,
+}
+
+impl RequestSigningConfig {
+    /// Checks the signature headers read by `header`: the key must be known
+    /// and the timestamp within `max_skew_secs` of `now_secs`.
+    pub fn check_headers<F>(
+        &self,
+        header: F,
+        method: &str,
+        path: &str,
+        now_secs: u64,
+    ) -> Result<SignedRequest, SignatureError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let required = |name: &String| {
+            header(name)
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| SignatureError::Missing(name.clone()))
+        };
+        let key_id = required(&self.key_id_header)?;
+        let timestamp = required(&self.timestamp_header)?;
+        let signature = required(&self.signature_header)?;
+
+        let secret = self
+            .keys
+            .get(&key_id)
+            .and_then(SigningSecret::resolve)
+            .ok_or(SignatureError::UnknownKey(key_id))?;
+        let signed_at: u64 = timestamp
+            .trim()
+            .parse()
+            .map_err(|_| SignatureError::BadTimestamp)?;
+        if signed_at.abs_diff(now_secs) > self.max_skew_secs {
+            return Err(SignatureError::Expired);
+        }
+        let signature = STANDARD
+            .decode(signature.trim())
+            .map_err(|_| SignatureError::Malformed)?;
+        Ok(SignedRequest {
+            secret,
+            prefix: format!("{}\n{}\n{}\n", method, path, timestamp.trim()),
+            signature,
+        })
+    }
+}
+
+impl SignedRequest {
+    /// The signature as sent, unique to the request it covers.
+    pub fn nonce(&self) -> String {
+        STANDARD.encode(&self.signature)
+    }
+
+    /// Recomputes the HMAC over the request's `body` and compares it in
+    /// constant time.
+    pub fn verify(&self, body: &[u8]) -> Result<(), SignatureError> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .map_err(|_| SignatureError::Mismatch)?;
+        mac.update(self.prefix.as_bytes());
+        mac.update(body);
+        mac.verify_slice(&self.signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+}
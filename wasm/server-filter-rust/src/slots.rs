@@ -0,0 +1,78 @@
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Slots kept for each kind of per-value state, unless configured.
+pub const DEFAULT_SLOTS: u32 = 4096;
+
+/// A value's entry, stored with the value so a slot's holder is known.
+#[derive(Serialize, Deserialize)]
+struct Slot<V, T> {
+    value: V,
+    entry: T,
+}
+
+/// What the slot a value hashes to holds for it.
+pub enum Entry<T> {
+    /// The value's own entry.
+    Own(T),
+    /// Nothing, or the lapsed entry of another value.
+    Vacant,
+    /// The live entry of another value.
+    Taken,
+}
+
+/// Shared-data key of the slot `value` hashes to, out of `slots`.
+///
+/// Shared data can't be deleted from, so a key per principal, nonce or other
+/// value a client picks would grow it without bound. Such values share a
+/// fixed number of slots instead. A slot holds one value's entry at a time,
+/// and passes to another value only once that entry has lapsed.
+pub fn key(prefix: &str, value: &str, slots: u32) -> String {
+    let digest = Sha256::digest(value.as_bytes());
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&digest[..8]);
+    let slot = u64::from_be_bytes(hash) % u64::from(slots.max(1));
+    format!("{}{}", prefix, slot)
+}
+
+/// Reads the slot at `key` for `value`, with the CAS token to `write` it
+/// back with.
+pub fn read<C, T>(
+    ctx: &C,
+    key: &str,
+    value: &str,
+    lapsed: impl Fn(&T) -> bool,
+) -> (Entry<T>, Option<u32>)
+where
+    C: Context + ?Sized,
+    T: DeserializeOwned,
+{
+    let (data, cas) = ctx.get_shared_data(key);
+    let slot = data.and_then(|d| serde_json::from_slice::<Slot<String, T>>(&d).ok());
+    let entry = match slot {
+        Some(slot) if slot.value == value => Entry::Own(slot.entry),
+        Some(slot) if !lapsed(&slot.entry) => Entry::Taken,
+        _ => Entry::Vacant,
+    };
+    (entry, cas)
+}
+
+/// Stores `entry` as `value`'s in the slot at `key`. Fails with
+/// `CasMismatch` if the slot changed since it was read.
+pub fn write<C, T>(
+    ctx: &C,
+    key: &str,
+    value: &str,
+    entry: &T,
+    cas: Option<u32>,
+) -> Result<(), Status>
+where
+    C: Context + ?Sized,
+    T: Serialize,
+{
+    let data = serde_json::to_vec(&Slot { value, entry }).map_err(|_| Status::InternalFailure)?;
+    ctx.set_shared_data(key, Some(&data), cas)
+}
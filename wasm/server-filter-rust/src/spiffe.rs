@@ -0,0 +1,118 @@
+use serde::Deserialize;
+use std::fmt;
+
+/// Accepts SPIFFE IDs (from a peer certificate URI SAN or a JWT-SVID `sub`)
+/// as principals, provided they belong to a trusted domain.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SpiffeConfig {
+    /// Trust domains whose identities are accepted. Must not be empty.
+    pub trust_domains: Vec<String>,
+    /// How the ID is presented to the PDP. Placeholders: `{trust_domain}`,
+    /// `{path}` (the workload path without its leading `/`) and `{name}`
+    /// (the last path segment). The default passes the full ID through.
+    pub principal_format: String,
+}
+
+impl Default for SpiffeConfig {
+    fn default() -> Self {
+        SpiffeConfig {
+            trust_domains: Vec::new(),
+            principal_format: "spiffe://{trust_domain}/{path}".to_string(),
+        }
+    }
+}
+
+/// A parsed `spiffe://trust-domain/path` identity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpiffeId {
+    pub trust_domain: String,
+    /// Workload path including its leading `/`; may be empty.
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpiffeError {
+    Invalid(&'static str),
+    UntrustedDomain(String),
+}
+
+impl fmt::Display for SpiffeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpiffeError::Invalid(what) => write!(f, "Invalid SPIFFE ID: {}", what),
+            SpiffeError::UntrustedDomain(domain) => {
+                write!(f, "SPIFFE trust domain {} is not trusted", domain)
+            }
+        }
+    }
+}
+
+impl SpiffeId {
+    /// Parses an ID following the SPIFFE ID rules: lowercase scheme and
+    /// trust domain, no port, user info, query or fragment, and no empty,
+    /// `.` or `..` path segments.
+    pub fn parse(id: &str) -> Result<Self, SpiffeError> {
+        let rest = id
+            .strip_prefix("spiffe://")
+            .ok_or(SpiffeError::Invalid("scheme must be spiffe"))?;
+        let (trust_domain, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, ""),
+        };
+
+        if trust_domain.is_empty() {
+            return Err(SpiffeError::Invalid("missing trust domain"));
+        }
+        let domain_ok = trust_domain.bytes().all(|b| {
+            b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'.' | b'-' | b'_')
+        });
+        if !domain_ok {
+            return Err(SpiffeError::Invalid("trust domain has invalid characters"));
+        }
+
+        if !path.is_empty() {
+            for segment in path[1..].split('/') {
+                if segment.is_empty() || segment == "." || segment == ".." {
+                    return Err(SpiffeError::Invalid(
+                        "path has an empty or relative segment",
+                    ));
+                }
+                if !segment
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+                {
+                    return Err(SpiffeError::Invalid("path has invalid characters"));
+                }
+            }
+        }
+
+        Ok(SpiffeId {
+            trust_domain: trust_domain.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Renders the ID using a `principal_format` template.
+    pub fn format(&self, template: &str) -> String {
+        let path = self.path.trim_start_matches('/');
+        let name = path.rsplit('/').next().unwrap_or("");
+        template
+            .replace("{trust_domain}", &self.trust_domain)
+            .replace("{path}", path)
+            .replace("{name}", name)
+    }
+}
+
+/// Parses `id`, checks its trust domain and maps it to the PDP principal.
+pub fn map_principal(config: &SpiffeConfig, id: &str) -> Result<String, SpiffeError> {
+    let spiffe_id = SpiffeId::parse(id)?;
+    if spiffe_id.path.is_empty() {
+        // The bare trust domain ID names no workload
+        return Err(SpiffeError::Invalid("missing workload path"));
+    }
+    if !config.trust_domains.contains(&spiffe_id.trust_domain) {
+        return Err(SpiffeError::UntrustedDomain(spiffe_id.trust_domain));
+    }
+    Ok(spiffe_id.format(&config.principal_format))
+}
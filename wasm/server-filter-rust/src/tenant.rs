@@ -0,0 +1,195 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use crate::cache::DecisionCacheConfig;
+use crate::config::{FilterConfig, JwtConfig};
+use crate::jwks::RemoteJwks;
+use crate::jwt::{self, KeySet};
+use crate::metrics::Metrics;
+
+/// Serving several tenants from one deployment. Each request's tenant is
+/// named by its host, the first segment of its path or a claim of its token,
+/// and the tenant's block in `tenants` overrides the top-level settings for
+/// it. Cached decisions are kept apart per tenant.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TenancyConfig {
+    pub source: TenantSource,
+    /// Claim naming the tenant with the `claim` source, as for
+    /// `principal.claim`. It is read before the token is verified, to pick
+    /// the tenant's keys, so each tenant needs its own `jwt` naming its
+    /// issuers. Once the token verifies, its `iss` must be one of them and
+    /// the claim must still name the tenant.
+    pub claim: String,
+    pub tenants: BTreeMap<String, TenantConfig>,
+    /// Whether a request for no configured tenant is rejected, rather than
+    /// handled with the top-level settings.
+    pub require_tenant: bool,
+}
+
+impl Default for TenancyConfig {
+    fn default() -> Self {
+        TenancyConfig {
+            source: TenantSource::Host,
+            claim: "tenant".to_string(),
+            tenants: BTreeMap::new(),
+            require_tenant: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TenantSource {
+    /// The `:authority` header, without the port.
+    #[default]
+    Host,
+    /// The first segment of the path, e.g. `acme` for `/acme/orders`.
+    PathPrefix,
+    Claim,
+}
+
+impl TenancyConfig {
+    /// Rejects tenants named by a claim without issuers of their own to
+    /// verify it with.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.source != TenantSource::Claim {
+            return Ok(());
+        }
+        for (name, tenant) in &self.tenants {
+            let named = tenant
+                .jwt
+                .as_ref()
+                .is_some_and(|jwt| jwt.rules.issuer.is_some() || !jwt.issuers.is_empty());
+            if !named {
+                return Err(format!(
+                    "tenant {} is named by a claim but its jwt config names no issuer",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the request's tenant, if one is configured. `token` is the
+    /// bearer token, still unverified.
+    pub fn tenant(
+        &self,
+        authority: Option<&str>,
+        path: &str,
+        token: Option<&str>,
+    ) -> Option<String> {
+        let name = match self.source {
+            TenantSource::Host => {
+                let authority = authority?;
+                let host = match authority.rsplit_once(':') {
+                    Some((host, port)) if port.parse::<u16>().is_ok() => host,
+                    _ => authority,
+                };
+                host.to_ascii_lowercase()
+            }
+            TenantSource::PathPrefix => path
+                .trim_start_matches('/')
+                .split(['/', '?'])
+                .next()?
+                .to_string(),
+            TenantSource::Claim => jwt::decode_unverified(token?).ok()?.lookup(&self.claim)?,
+        };
+        self.tenants.contains_key(&name).then_some(name)
+    }
+}
+
+/// Settings of one tenant, each replacing the top-level one when present.
+/// Health checks, audit and the other background tasks stay top-level.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct TenantConfig {
+    pub pdp_cluster: Option<String>,
+    pub pdp_authority: Option<String>,
+    pub pdp_path: Option<String>,
+    /// The tenant's token verification, typically with its own `issuers`.
+    pub jwt: Option<JwtConfig>,
+    pub decision_cache: Option<DecisionCacheConfig>,
+    /// Prefix of the tenant's stats, `<stat_prefix>.tenant.<name>` by
+    /// default. The tenant's requests aren't counted in the top-level stats.
+    pub stat_prefix: Option<String>,
+}
+
+impl TenantConfig {
+    /// The settings for tenant `name`: `base` with this block's overrides.
+    pub fn apply(&self, name: &str, base: &FilterConfig) -> FilterConfig {
+        let mut config = base.clone();
+        config.tenancy = None;
+        config.tenant = Some(name.to_string());
+        if let Some(pdp_cluster) = &self.pdp_cluster {
+            config.pdp_cluster = pdp_cluster.clone();
+        }
+        if let Some(pdp_authority) = &self.pdp_authority {
+            config.pdp_authority = pdp_authority.clone();
+        }
+        if let Some(pdp_path) = &self.pdp_path {
+            config.pdp_path = pdp_path.clone();
+        }
+        if let Some(jwt) = &self.jwt {
+            config.jwt = Some(jwt.clone());
+        }
+        if let Some(decision_cache) = &self.decision_cache {
+            config.decision_cache = decision_cache.clone();
+        }
+        config.stat_prefix = match &self.stat_prefix {
+            Some(stat_prefix) => stat_prefix.clone(),
+            None => format!("{}.tenant.{}", base.stat_prefix, name),
+        };
+        config
+    }
+}
+
+/// A tenant's settings and what the root context derives from them, handed
+/// to the HTTP contexts serving the tenant's requests.
+#[derive(Clone)]
+pub struct Tenant {
+    pub config: Rc<FilterConfig>,
+    pub jwt_keys: Rc<KeySet>,
+    pub issuer_keys: Rc<HashMap<String, Rc<KeySet>>>,
+    pub metrics: Metrics,
+}
+
+impl Tenant {
+    pub fn new(config: FilterConfig) -> Self {
+        let jwt = config.jwt.as_ref();
+        Tenant {
+            jwt_keys: Rc::new(
+                jwt.map(|jwt| KeySet::from_jwks(&jwt.jwks))
+                    .unwrap_or_default(),
+            ),
+            issuer_keys: Rc::new(jwt.map(JwtConfig::issuer_keys).unwrap_or_default()),
+            metrics: Metrics::define(&config.stat_prefix),
+            config: Rc::new(config),
+        }
+    }
+}
+
+/// Every tenant by name, shared by a VM's root and HTTP contexts.
+pub type Tenants = Rc<BTreeMap<String, Tenant>>;
+
+/// Every remote JWKS of the top-level and the tenants' settings, by the
+/// shared-data key it is published under. Tenants trusting the same issuer
+/// share its fetch.
+pub fn jwks_sources(
+    config: &FilterConfig,
+    tenants: &BTreeMap<String, Tenant>,
+) -> BTreeMap<String, RemoteJwks> {
+    std::iter::once(config)
+        .chain(tenants.values().map(|tenant| tenant.config.as_ref()))
+        .filter_map(|config| {
+            Some(
+                config
+                    .jwt
+                    .as_ref()?
+                    .remote_jwks_sources(config.jwks_shared_key()),
+            )
+        })
+        .flatten()
+        .collect()
+}
@@ -0,0 +1,2281 @@
+//! Request flows through `ServerFilterHttp` against the mock host.
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use proxy_wasm::traits::{Context, HttpContext, RootContext};
+use proxy_wasm::types::{Action, BufferType};
+use std::rc::Rc;
+use wasm_common::annotation::RequestAnnotation;
+use wasm_common::identity;
+use wasm_common::logging::{self, LoggingConfig};
+use wasm_common::paths::PathMatch;
+use wasm_common::pdp::{Principal, Query, Quota};
+use wasm_common::{mock_host, time};
+
+use crate::admin::AdminConfig;
+use crate::audit::{AuditConfig, AuditQueueConfig};
+use crate::cache::{self, DecisionCacheConfig};
+use crate::coalesce::{self, CoalescingConfig, Outcome};
+use crate::concurrency::{self, ConcurrencyLimitConfig};
+use crate::config::{
+    EnforcementMode, FailureMode, FilterConfig, JwtConfig, PrincipalConfig, PrincipalSource,
+    TrustedIssuer,
+};
+use crate::delegation::DelegationConfig;
+use crate::failover::FailoverConfig;
+use crate::grpc::GrpcConfig;
+use crate::health::HealthCheckConfig;
+use crate::hedge::HedgingConfig;
+use crate::identity_chain::IdentityChainConfig;
+use crate::jwks::{self, RemoteJwks};
+use crate::jwt::{Jwk, Jwks, KeySet, ValidationRules};
+use crate::metrics::Metrics;
+use crate::pip::PipConfig;
+use crate::protocol::{PdpEncoding, PdpProtocol};
+use crate::quota;
+use crate::ratelimit::{self, RateLimitConfig};
+use crate::replay::{self, ReplayConfig};
+use crate::security_events::{self, BlockConfig, SecurityEventsConfig};
+use crate::signature::SigningSecret;
+use crate::slots;
+use crate::tenant::{TenancyConfig, Tenant, TenantConfig, TenantSource};
+use crate::upgrade::UpgradeConfig;
+use crate::{ServerFilterHttp, ServerFilterRoot};
+
+/// An unsigned token; without a `jwt` config the filter only decodes it.
+fn token(sub: &str) -> String {
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"none"}"#);
+    let claims = URL_SAFE_NO_PAD.encode(format!(r#"{{"sub":"{}"}}"#, sub));
+    format!("{}.{}.sig", header, claims)
+}
+
+/// An ES256 token with `claims`, and the JWK of the fixed key signing it.
+fn signed_token(claims: serde_json::Value) -> (String, Jwk) {
+    let key = SigningKey::from_slice(&[7; 32]).unwrap();
+    let point = key.verifying_key().to_encoded_point(false);
+    let jwk = Jwk {
+        kty: "EC".to_string(),
+        crv: Some("P-256".to_string()),
+        x: point.x().map(|x| URL_SAFE_NO_PAD.encode(x)),
+        y: point.y().map(|y| URL_SAFE_NO_PAD.encode(y)),
+        ..Default::default()
+    };
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"ES256"}"#);
+    let signing_input = format!("{}.{}", header, URL_SAFE_NO_PAD.encode(claims.to_string()));
+    let signature: Signature = key.sign(signing_input.as_bytes());
+    (
+        format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ),
+        jwk,
+    )
+}
+
+fn filter(config: FilterConfig) -> ServerFilterHttp {
+    mock_host::reset();
+    ServerFilterHttp {
+        context_id: 2,
+        config: Rc::new(config),
+        ..Default::default()
+    }
+}
+
+/// Runs the request headers of an authenticated `GET /api?asset=doc-1`.
+fn request(filter: &mut ServerFilterHttp) -> Action {
+    let authorization = format!("Bearer {}", token("alice"));
+    mock_host::set_request_headers(&[
+        (":method", "GET"),
+        (":path", "/api?asset=doc-1"),
+        ("authorization", &authorization),
+        ("x-request-id", "req-1"),
+    ]);
+    filter.on_http_request_headers(4, true)
+}
+
+fn principal_source(source: PrincipalSource) -> FilterConfig {
+    let mut config = FilterConfig::default();
+    config.principal.source = source;
+    config
+}
+
+/// The principal sent to the PDP by the only callout made.
+fn pdp_principal() -> serde_json::Value {
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 1);
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    body["principal"]["id"].clone()
+}
+
+fn pdp_response(filter: &mut ServerFilterHttp, status: &str, body: &str) {
+    mock_host::set_http_call_response(status, body.as_bytes());
+    filter.on_http_call_response(1, 1, body.len(), 0);
+}
+
+#[test]
+fn missing_authorization_is_rejected() {
+    let mut filter = filter(FilterConfig::default());
+    mock_host::set_request_headers(&[(":method", "GET"), (":path", "/api")]);
+
+    assert_eq!(filter.on_http_request_headers(2, true), Action::Pause);
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(response.body_str().contains("Missing Authorization header"));
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn malformed_authorization_is_rejected() {
+    let mut filter = filter(FilterConfig::default());
+    mock_host::set_request_headers(&[(":path", "/api"), ("authorization", "Basic abc")]);
+
+    assert_eq!(filter.on_http_request_headers(2, true), Action::Pause);
+    assert_eq!(mock_host::local_response().map(|r| r.status), Some(401));
+}
+
+#[test]
+fn trusted_headers_are_stripped() {
+    let mut filter = filter(FilterConfig::default());
+    mock_host::set_request_headers(&[(":path", "/api"), ("x-pdp-decision", "Allow")]);
+
+    filter.on_http_request_headers(2, true);
+    assert_eq!(mock_host::request_header("x-pdp-decision"), None);
+}
+
+#[test]
+fn request_is_sent_to_pdp() {
+    let mut filter = filter(FilterConfig::default());
+
+    assert_eq!(request(&mut filter), Action::Pause);
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 1);
+    let call = &calls[0];
+    assert_eq!(call.upstream, "sgnl-pdp-service");
+    assert_eq!(call.header(":path"), Some("/access/v2/evaluations"));
+    assert_eq!(call.header("x-request-id"), Some("req-1"));
+    let body: serde_json::Value = serde_json::from_slice(&call.body).unwrap();
+    assert_eq!(body["principal"]["id"], "alice");
+    assert_eq!(body["queries"][0]["assetId"], "doc-1");
+    assert_eq!(body["queries"][0]["action"], "call");
+}
+
+#[test]
+fn allowed_request_is_resumed() {
+    let mut filter = filter(FilterConfig::default());
+    request(&mut filter);
+    assert!(filter.waiting.get());
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+
+    assert!(!filter.waiting.get());
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+    assert_eq!(
+        mock_host::request_header("X-PDP-Decision").as_deref(),
+        Some("Allow")
+    );
+    assert_eq!(
+        mock_host::request_header("X-PDP-Reason").as_deref(),
+        Some("granted")
+    );
+    assert_eq!(
+        mock_host::request_header("X-Principal-ID").as_deref(),
+        Some("alice")
+    );
+}
+
+#[test]
+fn denied_request_is_rejected() {
+    let mut filter = filter(FilterConfig::default());
+    request(&mut filter);
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Deny","reason":"not_owner"}]}"#,
+    );
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 403);
+    assert!(response.body_str().contains("not_owner"));
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 0);
+}
+
+#[test]
+fn denial_is_sent_as_problem_details() {
+    let mut filter = filter(FilterConfig::default());
+    request(&mut filter);
+
+    let reason = r#"owner is \"bob\""#;
+    pdp_response(
+        &mut filter,
+        "200",
+        &format!(
+            r#"{{"decisions":[{{"decision":"Deny","reason":"{}"}}]}}"#,
+            reason
+        ),
+    );
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(
+        response.header("content-type"),
+        Some("application/problem+json")
+    );
+    let problem: serde_json::Value = serde_json::from_slice(&response.body).expect("valid JSON");
+    assert_eq!(problem["type"], "about:blank");
+    assert_eq!(problem["title"], "Forbidden");
+    assert_eq!(problem["status"], 403);
+    assert_eq!(problem["reason"], r#"owner is "bob""#);
+    assert_eq!(problem["instance"], filter.request_id.as_str());
+}
+
+#[test]
+fn browsers_get_an_html_denial() {
+    let mut config = FilterConfig::default();
+    config.responses.html_body = "<h1>{title}</h1><p>{reason}</p>".to_string();
+    let mut filter = filter(config);
+    let authorization = format!("Bearer {}", token("alice"));
+    mock_host::set_request_headers(&[
+        (":path", "/api?asset=doc-1"),
+        ("authorization", &authorization),
+        (
+            "accept",
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+        ),
+    ]);
+    filter.on_http_request_headers(3, true);
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Deny","reason":"<script>"}]}"#,
+    );
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 403);
+    assert_eq!(
+        response.header("content-type"),
+        Some("text/html; charset=utf-8")
+    );
+    assert_eq!(
+        response.body_str(),
+        "<h1>Forbidden</h1><p>&lt;script&gt;</p>"
+    );
+}
+
+#[test]
+fn accept_header_negotiation() {
+    use crate::response::prefers_html;
+
+    assert!(prefers_html("text/html"));
+    assert!(prefers_html("text/html, */*;q=0.8"));
+    assert!(!prefers_html(""));
+    assert!(!prefers_html("*/*"));
+    assert!(!prefers_html("application/json, text/html;q=0.5"));
+    assert!(!prefers_html("text/*;q=0.5, application/problem+json"));
+}
+
+#[test]
+fn pdp_failure_fails_closed() {
+    let mut filter = filter(FilterConfig::default());
+    request(&mut filter);
+
+    pdp_response(&mut filter, "503", "");
+
+    assert_eq!(mock_host::local_response().map(|r| r.status), Some(403));
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 0);
+}
+
+#[test]
+fn pdp_failure_fails_open_with_header() {
+    let mut filter = filter(FilterConfig {
+        failure_mode: FailureMode::OpenWithHeader,
+        ..Default::default()
+    });
+    request(&mut filter);
+
+    pdp_response(&mut filter, "200", "not json");
+
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+    assert_eq!(
+        mock_host::request_header("X-PDP-Fail-Open").as_deref(),
+        Some("true")
+    );
+}
+
+#[test]
+fn protobuf_encoding_is_used_over_http() {
+    let mut filter = filter(FilterConfig {
+        pdp_encoding: PdpEncoding::Protobuf,
+        ..Default::default()
+    });
+    request(&mut filter);
+
+    let calls = mock_host::http_calls();
+    assert_eq!(
+        calls[0].header("content-type"),
+        Some("application/x-protobuf")
+    );
+    assert!(calls[0].body.starts_with(b"\x0a\x07\x0a\x05alice"));
+
+    // decisions { decision: "Allow" reason: "granted" }
+    let body = b"\x0a\x10\x0a\x05Allow\x12\x07granted";
+    mock_host::set_http_call_response("200", body);
+    filter.on_http_call_response(1, 1, body.len(), 0);
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+    assert_eq!(
+        mock_host::request_header("X-PDP-Reason").as_deref(),
+        Some("granted")
+    );
+}
+
+#[test]
+fn ext_authz_check_response_mutates_the_request() {
+    let mut filter = filter(FilterConfig {
+        pdp_protocol: PdpProtocol::ExtAuthz,
+        ..Default::default()
+    });
+    request(&mut filter);
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls[0].header(":path"), Some("/check"));
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    let attributes = &body["attributes"];
+    assert_eq!(attributes["request"]["http"]["method"], "GET");
+    assert_eq!(attributes["request"]["http"]["path"], "/api?asset=doc-1");
+    assert_eq!(
+        attributes["request"]["http"]["headers"]["x-request-id"],
+        "req-1"
+    );
+    assert_eq!(attributes["contextExtensions"]["principal"], "alice");
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"status":{"code":0},"okResponse":{"headers":[{"header":{"key":"x-user","value":"alice"}}],
+            "headersToRemove":["x-request-id"]}}"#,
+    );
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+    assert_eq!(
+        mock_host::request_header("x-user").as_deref(),
+        Some("alice")
+    );
+    assert_eq!(mock_host::request_header("x-request-id"), None);
+}
+
+#[test]
+fn ext_authz_denial_carries_the_status_message() {
+    let mut filter = filter(FilterConfig {
+        pdp_protocol: PdpProtocol::ExtAuthz,
+        ..Default::default()
+    });
+    request(&mut filter);
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"status":{"code":7,"message":"not_owner"}}"#,
+    );
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 403);
+    assert!(response.body_str().contains("not_owner"));
+}
+
+#[test]
+fn basic_credentials_name_the_principal() {
+    let mut filter = filter(principal_source(PrincipalSource::Basic));
+    let authorization = format!("Basic {}", STANDARD.encode("legacy-batch:secret"));
+    mock_host::set_request_headers(&[
+        (":path", "/api?asset=doc-1"),
+        ("authorization", &authorization),
+    ]);
+
+    assert_eq!(filter.on_http_request_headers(2, true), Action::Pause);
+    assert_eq!(pdp_principal(), "legacy-batch");
+}
+
+#[test]
+fn malformed_basic_credentials_are_rejected() {
+    let mut filter = filter(principal_source(PrincipalSource::Basic));
+    mock_host::set_request_headers(&[(":path", "/api"), ("authorization", "Basic not-base64!")]);
+
+    assert_eq!(filter.on_http_request_headers(2, true), Action::Pause);
+    assert_eq!(mock_host::local_response().map(|r| r.status), Some(401));
+}
+
+#[test]
+fn api_key_maps_to_principal() {
+    let mut config = principal_source(PrincipalSource::ApiKey);
+    config
+        .principal
+        .api_keys
+        .keys
+        .insert("k-123".to_string(), "legacy-reporting".to_string());
+    let mut filter = filter(config);
+    mock_host::set_request_headers(&[(":path", "/api?asset=doc-1"), ("x-api-key", "k-123")]);
+
+    assert_eq!(filter.on_http_request_headers(2, true), Action::Pause);
+    assert_eq!(pdp_principal(), "legacy-reporting");
+}
+
+#[test]
+fn unknown_api_key_is_rejected() {
+    let mut config = principal_source(PrincipalSource::ApiKey);
+    config
+        .principal
+        .api_keys
+        .keys
+        .insert("k-123".to_string(), "legacy-reporting".to_string());
+    let mut filter = filter(config);
+    mock_host::set_request_headers(&[(":path", "/api"), ("x-api-key", "k-999")]);
+
+    assert_eq!(filter.on_http_request_headers(2, true), Action::Pause);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(mock_host::http_calls().is_empty());
+}
+
+/// Claims of a token for `alice` from `iss` to `aud`, valid for decades.
+fn claims(iss: &str, aud: &str) -> serde_json::Value {
+    serde_json::json!({ "sub": "alice", "iss": iss, "aud": aud, "exp": 4_102_444_800u64 })
+}
+
+/// Trusts `https://idp-a` by default and `https://idp-b` for audience
+/// `service-b` with the key of `signed_token`.
+fn multi_issuer_filter(jwk: Jwk) -> ServerFilterHttp {
+    let idp_b = TrustedIssuer {
+        rules: ValidationRules {
+            issuer: Some("https://idp-b".to_string()),
+            audiences: vec!["service-b".to_string()],
+            ..Default::default()
+        },
+        jwks: Jwks { keys: vec![jwk] },
+        remote_jwks: None,
+    };
+    let jwt = JwtConfig {
+        rules: ValidationRules {
+            issuer: Some("https://idp-a".to_string()),
+            ..Default::default()
+        },
+        issuers: vec![idp_b],
+        ..Default::default()
+    };
+    let issuer_keys = Rc::new(jwt.issuer_keys());
+    let mut filter = filter(FilterConfig {
+        jwt: Some(jwt),
+        ..Default::default()
+    });
+    filter.issuer_keys = issuer_keys;
+    filter
+}
+
+fn bearer_request(filter: &mut ServerFilterHttp, token: &str) -> Action {
+    let authorization = format!("Bearer {}", token);
+    mock_host::set_request_headers(&[
+        (":path", "/api?asset=doc-1"),
+        ("authorization", &authorization),
+    ]);
+    filter.on_http_request_headers(2, true)
+}
+
+#[test]
+fn token_is_verified_with_its_issuers_keys() {
+    let (token, jwk) = signed_token(claims("https://idp-b", "service-b"));
+    let mut filter = multi_issuer_filter(jwk);
+
+    assert_eq!(bearer_request(&mut filter, &token), Action::Pause);
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(pdp_principal(), "alice");
+}
+
+/// Fetches its JWKS on demand for tokens with unknown keys.
+fn unknown_key_filter() -> ServerFilterHttp {
+    filter(FilterConfig {
+        jwt: Some(JwtConfig {
+            remote_jwks: Some(RemoteJwks {
+                fetch_on_unknown_key: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+fn jwks_body(jwk: &Jwk) -> String {
+    serde_json::json!({ "keys": [{ "kty": "EC", "crv": "P-256", "x": jwk.x, "y": jwk.y }] })
+        .to_string()
+}
+
+#[test]
+fn unknown_key_is_fetched_alongside_the_pdp_call() {
+    let (token, jwk) = signed_token(claims("https://idp-a", "service-b"));
+    let mut filter = unknown_key_filter();
+
+    assert_eq!(bearer_request(&mut filter, &token), Action::Pause);
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].upstream, "jwt-vending-service");
+    assert_eq!(calls[1].upstream, "sgnl-pdp-service");
+
+    // The PDP answers first; the request waits for the token to verify
+    let allow = r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#;
+    mock_host::set_http_call_response("200", allow.as_bytes());
+    filter.on_http_call_response(calls[1].token, 1, allow.len(), 0);
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 0);
+
+    let body = jwks_body(&jwk);
+    mock_host::set_http_call_response("200", body.as_bytes());
+    filter.on_http_call_response(calls[0].token, 1, body.len(), 0);
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+}
+
+#[test]
+fn rotated_out_key_stays_valid_for_the_overlap() {
+    let (token, jwk) = signed_token(claims("https://idp-a", "service-b"));
+    let remote = RemoteJwks {
+        rotation_overlap_ms: 60_000,
+        ..Default::default()
+    };
+    let mut filter = filter(FilterConfig {
+        jwt: Some(JwtConfig {
+            remote_jwks: Some(remote.clone()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let now_ms = time::now_ms(&filter);
+    jwks::store(
+        &filter,
+        jwks::JWKS_SHARED_KEY,
+        &remote,
+        jwks_body(&jwk).as_bytes(),
+        now_ms,
+    )
+    .unwrap();
+
+    // The next document only lists a new key
+    let point = SigningKey::from_slice(&[9; 32])
+        .unwrap()
+        .verifying_key()
+        .to_encoded_point(false);
+    let rotated = Jwk {
+        x: point.x().map(|x| URL_SAFE_NO_PAD.encode(x)),
+        y: point.y().map(|y| URL_SAFE_NO_PAD.encode(y)),
+        ..jwk
+    };
+    jwks::store(
+        &filter,
+        jwks::JWKS_SHARED_KEY,
+        &remote,
+        jwks_body(&rotated).as_bytes(),
+        now_ms,
+    )
+    .unwrap();
+    bearer_request(&mut filter, &token);
+    assert_eq!(mock_host::http_calls().len(), 1);
+
+    let mut late = ServerFilterHttp {
+        config: filter.config.clone(),
+        ..Default::default()
+    };
+    mock_host::with(|host| host.time_nanos += 60_000_000_000);
+    late.on_http_request_headers(2, true);
+    assert_eq!(
+        mock_host::local_response().expect("local reply").status,
+        401
+    );
+}
+
+#[test]
+fn token_is_rejected_when_its_key_cannot_be_fetched() {
+    let (token, _) = signed_token(claims("https://idp-a", "service-b"));
+    let mut filter = unknown_key_filter();
+
+    bearer_request(&mut filter, &token);
+    let calls = mock_host::http_calls();
+    mock_host::set_http_call_response("503", b"");
+    filter.on_http_call_response(calls[0].token, 1, 0, 0);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+
+    // The PDP's late answer is ignored
+    let allow = r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#;
+    mock_host::set_http_call_response("200", allow.as_bytes());
+    filter.on_http_call_response(calls[1].token, 1, allow.len(), 0);
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 0);
+}
+
+#[test]
+fn forged_token_leaves_the_principals_limits_alone() {
+    let (token, _) = signed_token(claims("https://idp-a", "service-b"));
+    let rate_limit = RateLimitConfig {
+        requests_per_window: 1,
+        window_ms: 60_000,
+        ..Default::default()
+    };
+    let events_config = SecurityEventsConfig {
+        threshold: 1,
+        ..Default::default()
+    };
+    let mut filter = filter(FilterConfig {
+        rate_limit: Some(rate_limit.clone()),
+        security_events: Some(events_config.clone()),
+        decision_cache: DecisionCacheConfig {
+            ttl_ms: 60_000,
+            deny_ttl_ms: 60_000,
+        },
+        ..unknown_key_filter().config.as_ref().clone()
+    });
+    let now_secs = time::now_secs(&filter);
+    let quota = Quota {
+        limit: 10,
+        remaining: 5,
+        reset: now_secs + 3600,
+    };
+    quota::store(&filter, "alice", &quota, now_secs);
+
+    bearer_request(&mut filter, &token);
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+
+    // The PDP's deny waits for the token, which the fetched keys don't verify
+    let deny = format!(
+        r#"{{"decisions":[{{"decision":"Deny","reason":"not_owner","quota":{{"limit":10,"remaining":0,"reset":{}}}}}]}}"#,
+        now_secs + 3600
+    );
+    mock_host::set_http_call_response("200", deny.as_bytes());
+    filter.on_http_call_response(calls[1].token, 1, deny.len(), 0);
+    assert!(mock_host::local_response().is_none());
+    let point = SigningKey::from_slice(&[9; 32])
+        .unwrap()
+        .verifying_key()
+        .to_encoded_point(false);
+    let body = jwks_body(&Jwk {
+        x: point.x().map(|x| URL_SAFE_NO_PAD.encode(x)),
+        y: point.y().map(|y| URL_SAFE_NO_PAD.encode(y)),
+        ..Default::default()
+    });
+    mock_host::set_http_call_response("200", body.as_bytes());
+    filter.on_http_call_response(calls[0].token, 1, body.len(), 0);
+    assert_eq!(mock_host::local_response().unwrap().status, 401);
+
+    let now_ms = time::now_ms(&filter);
+    let bucket = rate_limit.bucket_id("alice", "doc-1");
+    assert_eq!(
+        ratelimit::acquire(&filter, &rate_limit, &bucket, now_ms),
+        Ok(true)
+    );
+    let remaining = quota::consume(&filter, "alice", now_secs).unwrap();
+    assert_eq!(remaining.map(|quota| quota.remaining), Some(4));
+    assert!(!security_events::is_flagged(
+        &filter,
+        &events_config,
+        "alice",
+        now_ms
+    ));
+    assert!(cache::lookup(&filter, &filter.decision_key(), now_ms).is_none());
+}
+
+#[test]
+fn issuers_audience_is_required() {
+    let (token, jwk) = signed_token(claims("https://idp-b", "service-c"));
+    let mut filter = multi_issuer_filter(jwk);
+
+    bearer_request(&mut filter, &token);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(response.body_str().contains("audience"));
+}
+
+#[test]
+fn unknown_issuer_falls_back_to_default_settings() {
+    let (token, jwk) = signed_token(claims("https://idp-c", "service-b"));
+    let mut filter = multi_issuer_filter(jwk);
+
+    bearer_request(&mut filter, &token);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(mock_host::http_calls().is_empty());
+}
+
+/// Verifies tokens signed with `jwk` under `rules`.
+fn verifying_filter(jwk: Jwk, rules: ValidationRules) -> ServerFilterHttp {
+    let jwks = Jwks { keys: vec![jwk] };
+    let jwt_keys = Rc::new(KeySet::from_jwks(&jwks));
+    let mut filter = filter(FilterConfig {
+        jwt: Some(JwtConfig {
+            rules,
+            jwks,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    filter.jwt_keys = jwt_keys;
+    filter
+}
+
+fn now_secs() -> u64 {
+    mock_host::DEFAULT_TIME_NANOS / 1_000_000_000
+}
+
+#[test]
+fn expiry_within_leeway_is_tolerated() {
+    let (token, jwk) = signed_token(serde_json::json!({ "sub": "alice", "exp": now_secs() - 30 }));
+    let rules = ValidationRules {
+        leeway_secs: 60,
+        ..Default::default()
+    };
+    let mut filter = verifying_filter(jwk.clone(), rules);
+    bearer_request(&mut filter, &token);
+    assert!(mock_host::local_response().is_none());
+
+    let mut filter = verifying_filter(jwk, ValidationRules::default());
+    bearer_request(&mut filter, &token);
+    assert!(mock_host::local_response()
+        .unwrap()
+        .body_str()
+        .contains("expired"));
+}
+
+#[test]
+fn token_older_than_max_age_is_rejected() {
+    let claims =
+        serde_json::json!({ "sub": "alice", "iat": now_secs() - 7200, "exp": now_secs() + 3600 });
+    let (token, jwk) = signed_token(claims);
+    let rules = ValidationRules {
+        leeway_secs: 60,
+        max_token_age_secs: Some(3600),
+        ..Default::default()
+    };
+    let mut filter = verifying_filter(jwk, rules);
+
+    bearer_request(&mut filter, &token);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(response.body_str().contains("maximum accepted age"));
+}
+
+fn grpc_request(filter: &mut ServerFilterHttp) -> Action {
+    let authorization = format!("Bearer {}", token("alice"));
+    mock_host::set_request_headers(&[
+        (":method", "POST"),
+        (":path", "/acme.orders.v1.OrderService/CancelOrder"),
+        ("content-type", "application/grpc"),
+        ("authorization", &authorization),
+    ]);
+    filter.on_http_request_headers(4, false)
+}
+
+#[test]
+fn grpc_request_is_authorized_by_service_and_method() {
+    let mut filter = filter(FilterConfig {
+        grpc: Some(GrpcConfig::default()),
+        ..Default::default()
+    });
+
+    assert_eq!(grpc_request(&mut filter), Action::Pause);
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 1);
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(body["queries"][0]["assetId"], "acme.orders.v1.OrderService");
+    assert_eq!(body["queries"][0]["action"], "CancelOrder");
+}
+
+#[test]
+fn grpc_denial_is_a_grpc_status() {
+    let mut filter = filter(FilterConfig {
+        grpc: Some(GrpcConfig::default()),
+        ..Default::default()
+    });
+    grpc_request(&mut filter);
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Deny","reason":"not_owner"}]}"#,
+    );
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.grpc_status, Some(7));
+    assert_eq!(response.body_str(), "Access denied by policy: not_owner");
+}
+
+fn websocket_request(filter: &mut ServerFilterHttp, asset: &str) -> Action {
+    let authorization = format!("Bearer {}", token("alice"));
+    let path = format!("/stream?asset={}", asset);
+    mock_host::set_request_headers(&[
+        (":method", "GET"),
+        (":path", &path),
+        ("connection", "keep-alive, Upgrade"),
+        ("upgrade", "websocket"),
+        ("authorization", &authorization),
+    ]);
+    filter.on_http_request_headers(6, false)
+}
+
+#[test]
+fn websocket_upgrade_is_authorized_without_waiting_for_body() {
+    let mut config = FilterConfig::default();
+    config
+        .asset_rules
+        .push(serde_json::from_str(r#"{"body_pointer": "/asset"}"#).unwrap());
+    let mut filter = filter(config);
+
+    assert_eq!(websocket_request(&mut filter, "doc-1"), Action::Pause);
+    assert_eq!(mock_host::http_calls().len(), 1);
+    assert_eq!(filter.on_http_request_body(64, false), Action::Continue);
+}
+
+#[test]
+fn websocket_upgrade_is_refused_for_denied_assets() {
+    let mut filter = filter(FilterConfig {
+        upgrades: UpgradeConfig {
+            deny_assets: vec!["report-*".to_string()],
+        },
+        ..Default::default()
+    });
+
+    websocket_request(&mut filter, "report-2024");
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 403);
+    assert!(response.body_str().contains("upgrade_denied"));
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn context_headers_are_sent_to_pdp() {
+    let mut filter = filter(FilterConfig {
+        context_headers: vec!["User-Agent".to_string(), "x-tenant".to_string()],
+        ..Default::default()
+    });
+    let authorization = format!("Bearer {}", token("alice"));
+    mock_host::set_request_headers(&[
+        (":path", "/api?asset=doc-1"),
+        ("authorization", &authorization),
+        ("user-agent", "batch/1.2"),
+    ]);
+
+    filter.on_http_request_headers(3, true);
+
+    let calls = mock_host::http_calls();
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(
+        body["context"],
+        serde_json::json!({ "user-agent": "batch/1.2" })
+    );
+}
+
+#[test]
+fn claims_are_mapped_into_pdp_context() {
+    let mut filter = filter(FilterConfig {
+        claim_attributes: [
+            ("tenant", "/ext/tenant_id"),
+            ("groups", "groups"),
+            ("dept", "/ext/dept"),
+        ]
+        .into_iter()
+        .map(|(name, path)| (name.to_string(), path.to_string()))
+        .collect(),
+        ..Default::default()
+    });
+    let claims = URL_SAFE_NO_PAD
+        .encode(r#"{"sub":"alice","groups":["eng","ops"],"ext":{"tenant_id":"acme"}}"#);
+    let token = format!(
+        "{}.{}.sig",
+        URL_SAFE_NO_PAD.encode(br#"{"alg":"none"}"#),
+        claims
+    );
+
+    bearer_request(&mut filter, &token);
+
+    let calls = mock_host::http_calls();
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(
+        body["context"],
+        serde_json::json!({ "tenant": "acme", "groups": "eng,ops" })
+    );
+}
+
+#[test]
+fn delegated_tokens_send_the_actor_to_pdp() {
+    let config = FilterConfig {
+        principal: PrincipalConfig {
+            delegation: Some(DelegationConfig {
+                allowed_actors: vec!["gateway".to_string(), "batch".to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let delegated = |act: &str| {
+        let claims = URL_SAFE_NO_PAD.encode(format!(r#"{{"sub":"alice","act":{}}}"#, act));
+        format!(
+            "{}.{}.sig",
+            URL_SAFE_NO_PAD.encode(br#"{"alg":"none"}"#),
+            claims
+        )
+    };
+
+    let mut allowed = filter(config.clone());
+    bearer_request(
+        &mut allowed,
+        &delegated(r#"{"sub":"gateway","act":{"sub":"batch"}}"#),
+    );
+    let calls = mock_host::http_calls();
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(
+        body["principal"],
+        serde_json::json!({ "id": "alice", "actor": "gateway" })
+    );
+
+    let mut denied = filter(config);
+    bearer_request(
+        &mut denied,
+        &delegated(r#"{"sub":"gateway","act":{"sub":"mallory"}}"#),
+    );
+    assert!(mock_host::http_calls().is_empty());
+    assert_eq!(
+        mock_host::local_response().expect("local reply").status,
+        403
+    );
+}
+
+#[test]
+fn requests_are_handled_with_their_tenants_settings() {
+    let acme = TenantConfig {
+        pdp_cluster: Some("acme-pdp".to_string()),
+        ..Default::default()
+    };
+    let mut config = FilterConfig {
+        tenancy: Some(TenancyConfig {
+            tenants: [("acme.example.com".to_string(), acme.clone())].into(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let tenant_request = |config: &FilterConfig, authority: &str| {
+        let mut filter = filter(config.clone());
+        let tenant = Tenant::new(acme.apply("acme.example.com", config));
+        filter.tenants = Rc::new([("acme.example.com".to_string(), tenant)].into());
+        let authorization = format!("Bearer {}", token("alice"));
+        mock_host::set_request_headers(&[
+            (":authority", authority),
+            (":path", "/api?asset=doc-1"),
+            ("authorization", &authorization),
+        ]);
+        filter.on_http_request_headers(3, true);
+        filter
+    };
+
+    let mut filter = tenant_request(&config, "ACME.example.com:443");
+    assert_eq!(mock_host::http_calls()[0].upstream, "acme-pdp");
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+    assert_eq!(
+        mock_host::metric("server_filter.tenant.acme.example.com.allowed"),
+        Some(1)
+    );
+
+    tenant_request(&config, "other.example.com");
+    assert!(mock_host::http_calls().is_empty());
+    assert_eq!(
+        mock_host::local_response().expect("local reply").status,
+        403
+    );
+
+    config.tenancy.as_mut().unwrap().require_tenant = false;
+    tenant_request(&config, "other.example.com");
+    assert_eq!(mock_host::http_calls()[0].upstream, "sgnl-pdp-service");
+}
+
+#[test]
+fn tenant_claims_are_bound_to_the_tenants_issuers() {
+    let (_, jwk) = signed_token(serde_json::json!({}));
+    let globex = TenantConfig {
+        jwt: Some(JwtConfig {
+            jwks: Jwks {
+                keys: vec![jwk.clone()],
+            },
+            issuers: vec![TrustedIssuer {
+                rules: ValidationRules {
+                    issuer: Some("https://idp-globex".to_string()),
+                    ..Default::default()
+                },
+                jwks: Jwks { keys: vec![jwk] },
+                remote_jwks: None,
+            }],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let config = FilterConfig {
+        tenancy: Some(TenancyConfig {
+            source: TenantSource::Claim,
+            tenants: [("globex".to_string(), globex.clone())].into(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+    let tenant_request = |iss: &str| {
+        let mut filter = filter(config.clone());
+        let tenant = Tenant::new(globex.apply("globex", &config));
+        filter.tenants = Rc::new([("globex".to_string(), tenant)].into());
+        let (token, _) = signed_token(serde_json::json!({
+            "sub": "alice",
+            "iss": iss,
+            "tenant": "globex",
+            "exp": 4_102_444_800u64,
+        }));
+        bearer_request(&mut filter, &token)
+    };
+
+    assert_eq!(tenant_request("https://idp-globex"), Action::Pause);
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(mock_host::http_calls().len(), 1);
+
+    // Verified with the tenant's keys, but issued by another tenant's idp
+    assert_eq!(tenant_request("https://idp-acme"), Action::Pause);
+    assert_eq!(
+        mock_host::local_response().expect("local reply").status,
+        401
+    );
+    assert!(mock_host::http_calls().is_empty());
+
+    let mut unnamed = config.clone();
+    let tenancy = unnamed.tenancy.as_mut().unwrap();
+    tenancy.tenants.get_mut("globex").unwrap().jwt = None;
+    assert!(unnamed.validate().is_err());
+}
+
+#[test]
+fn connection_attributes_are_sent_to_pdp() {
+    let mut filter = filter(FilterConfig {
+        connection_attributes: true,
+        ..Default::default()
+    });
+    mock_host::set_property(&["source", "address"], b"[2001:db8::7]:53012");
+    mock_host::set_property(&["destination", "address"], b"10.0.0.5:8443");
+    mock_host::set_property(&["connection", "requested_server_name"], b"api.example.com");
+    mock_host::set_property(&["connection", "tls_version"], b"TLSv1.3");
+    mock_host::set_property(
+        &["connection", "uri_san_peer_certificate"],
+        b"spiffe://example.org/ns/a/sa/b",
+    );
+
+    request(&mut filter);
+
+    let calls = mock_host::http_calls();
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(
+        body["connection"],
+        serde_json::json!({
+            "sourceAddress": "2001:db8::7",
+            "destinationAddress": "10.0.0.5:8443",
+            "sni": "api.example.com",
+            "tlsVersion": "TLSv1.3",
+            "peerIdentity": "spiffe://example.org/ns/a/sa/b",
+        })
+    );
+}
+
+#[test]
+fn principal_attributes_from_the_pip_are_sent_to_pdp() {
+    let mut filter = filter(FilterConfig {
+        pip: Some(PipConfig::default()),
+        ..Default::default()
+    });
+    assert_eq!(request(&mut filter), Action::Pause);
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].upstream, "pip-service");
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(body["principal"]["id"], "alice");
+
+    let attributes = br#"{"groups":["eng","admins"],"risk_score":12}"#;
+    mock_host::set_http_call_response("200", attributes);
+    filter.on_http_call_response(calls[0].token, 0, attributes.len(), 0);
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].upstream, "sgnl-pdp-service");
+    let body: serde_json::Value = serde_json::from_slice(&calls[1].body).unwrap();
+    assert_eq!(body["context"]["principal.groups"], "eng,admins");
+    assert_eq!(body["context"]["principal.risk_score"], "12");
+
+    // Cached for the principal's next request
+    let mut next = sibling(&filter, 3);
+    request(&mut next);
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 3);
+    assert_eq!(calls[2].upstream, "sgnl-pdp-service");
+}
+
+#[test]
+fn caller_annotation_is_sent_to_pdp() {
+    let mut filter = filter(FilterConfig {
+        caller_annotation: true,
+        ..Default::default()
+    });
+    let annotation = RequestAnnotation {
+        service_id: "service-a".to_string(),
+        audience: Some("service-b".to_string()),
+        scope: None,
+    };
+    annotation.publish(&filter);
+
+    request(&mut filter);
+
+    let calls = mock_host::http_calls();
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(
+        body["context"],
+        serde_json::json!({ "caller.service_id": "service-a", "caller.audience": "service-b" })
+    );
+}
+
+fn coalescing_filter() -> ServerFilterHttp {
+    filter(FilterConfig {
+        coalescing: Some(CoalescingConfig::default()),
+        ..Default::default()
+    })
+}
+
+/// Another context of the same VM as `leader`.
+fn sibling(leader: &ServerFilterHttp, context_id: u32) -> ServerFilterHttp {
+    ServerFilterHttp {
+        context_id,
+        config: leader.config.clone(),
+        flights: leader.flights.clone(),
+        ..Default::default()
+    }
+}
+
+/// The queries of `request`.
+fn request_queries() -> Vec<Query> {
+    vec![Query {
+        asset_id: "doc-1".to_string(),
+        action: "call".to_string(),
+    }]
+}
+
+fn alice() -> Principal {
+    Principal {
+        id: "alice".to_string(),
+        actor: None,
+    }
+}
+
+#[test]
+fn decision_is_cached_for_the_ttl_the_pdp_reports() {
+    let mut filter = filter(FilterConfig {
+        decision_cache: DecisionCacheConfig {
+            ttl_ms: 60_000,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    request(&mut filter);
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted","ttl_secs":2}]}"#,
+    );
+
+    let key = cache::decision_key(
+        None,
+        &alice(),
+        &request_queries(),
+        &Default::default(),
+        None,
+    );
+    let now_ms = mock_host::DEFAULT_TIME_NANOS / 1_000_000;
+    assert!(cache::lookup(&filter, &key, now_ms + 1_999).is_some());
+    assert!(cache::lookup(&filter, &key, now_ms + 2_000).is_none());
+}
+
+fn admin_request(filter: &mut ServerFilterHttp, method: &str, path: &str, secret: &str) -> Action {
+    mock_host::set_request_headers(&[
+        (":method", method),
+        (":path", path),
+        ("x-admin-secret", secret),
+    ]);
+    filter.on_http_request_headers(3, true)
+}
+
+fn admin_filter() -> ServerFilterHttp {
+    filter(FilterConfig {
+        decision_cache: DecisionCacheConfig {
+            ttl_ms: 60_000,
+            ..Default::default()
+        },
+        admin: Some(AdminConfig {
+            secret: Some("s3cret".to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn admin_flush_empties_the_decision_cache() {
+    let mut filter = admin_filter();
+    request(&mut filter);
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+    let key = cache::decision_key(
+        None,
+        &alice(),
+        &request_queries(),
+        &Default::default(),
+        None,
+    );
+    let now_ms = mock_host::DEFAULT_TIME_NANOS / 1_000_000;
+    assert!(cache::lookup(&filter, &key, now_ms).is_some());
+
+    let mut admin = ServerFilterHttp {
+        context_id: 3,
+        config: filter.config.clone(),
+        ..Default::default()
+    };
+    assert_eq!(
+        admin_request(
+            &mut admin,
+            "DELETE",
+            "/__wasm/admin/cache?cache=decisions",
+            "s3cret"
+        ),
+        Action::Pause
+    );
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body_str(), r#"{"flushed":{"decisions":1}}"#);
+    assert!(cache::lookup(&filter, &key, now_ms).is_none());
+
+    admin_request(&mut admin, "GET", "/__wasm/admin/cache", "s3cret");
+    let stats: serde_json::Value =
+        serde_json::from_slice(&mock_host::local_response().unwrap().body).unwrap();
+    assert_eq!(stats["decision_cache"]["generation"], 1);
+    assert_eq!(stats["decision_cache"]["enabled"], true);
+}
+
+#[test]
+fn status_reports_what_the_filter_runs_with() {
+    let mut filter = admin_filter();
+
+    admin_request(&mut filter, "GET", "/__wasm/status", "s3cret");
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 200);
+    let status: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(status["pdp"]["cluster"], "sgnl-pdp-service");
+    assert_eq!(status["pdp"]["healthy"], serde_json::Value::Null);
+    assert_eq!(status["caches"]["decision_cache"]["enabled"], true);
+    assert_eq!(status["counters"]["allowed"], 0);
+}
+
+#[test]
+fn admin_requires_the_secret() {
+    let mut filter = admin_filter();
+
+    admin_request(&mut filter, "DELETE", "/__wasm/admin/cache", "guess");
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn identical_requests_share_one_pdp_call() {
+    let mut leader = coalescing_filter();
+    let mut follower = sibling(&leader, 3);
+    assert_eq!(request(&mut leader), Action::Pause);
+    assert_eq!(request(&mut follower), Action::Pause);
+    assert_eq!(mock_host::http_calls().len(), 1);
+
+    pdp_response(
+        &mut leader,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 2);
+    assert_eq!(mock_host::with(|host| host.effective_context), Some(2));
+    assert!(leader.flights.borrow().is_empty());
+}
+
+#[test]
+fn request_parked_behind_another_vm_is_resumed_by_tick() {
+    let mut follower = coalescing_filter();
+    let key = coalesce::flight_key(&cache::decision_key(
+        None,
+        &alice(),
+        &request_queries(),
+        &Default::default(),
+        None,
+    ));
+    let now_ms = mock_host::DEFAULT_TIME_NANOS / 1_000_000;
+    assert!(coalesce::try_lead(&follower, &key, now_ms, 10_000));
+
+    assert_eq!(request(&mut follower), Action::Pause);
+    assert!(mock_host::http_calls().is_empty());
+
+    let mut root = ServerFilterRoot {
+        config: follower.config.clone(),
+        flights: follower.flights.clone(),
+        ..Default::default()
+    };
+    root.on_tick();
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 0);
+
+    let outcome = Outcome {
+        decision: "Deny".to_string(),
+        reason: "not_owner".to_string(),
+        obligations: Default::default(),
+        quota: None,
+    };
+    coalesce::finish(&follower, &key, Some(&outcome));
+    root.on_tick();
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 403);
+    assert!(response.body_str().contains("not_owner"));
+    assert!(root.flights.borrow().is_empty());
+}
+
+#[test]
+fn requests_fail_fast_while_pdp_is_unhealthy() {
+    let mut filter = filter(FilterConfig {
+        health_check: Some(HealthCheckConfig {
+            unhealthy_threshold: 1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let mut root = ServerFilterRoot {
+        config: filter.config.clone(),
+        ..Default::default()
+    };
+    root.on_tick();
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].header(":path"), Some("/health"));
+
+    mock_host::set_http_call_response("503", b"");
+    root.on_http_call_response(calls[0].token, 1, 0, 0);
+
+    assert_eq!(request(&mut filter), Action::Pause);
+    assert_eq!(mock_host::http_calls().len(), 1);
+    assert_eq!(
+        mock_host::local_response().expect("local reply").status,
+        403
+    );
+}
+
+#[test]
+fn requests_over_the_concurrency_limit_are_shed() {
+    let mut filter = filter(FilterConfig {
+        concurrency_limit: Some(ConcurrencyLimitConfig {
+            max_in_flight: 1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    assert_eq!(request(&mut filter), Action::Pause);
+    assert_eq!(concurrency::in_flight(&filter), 1);
+
+    let mut second = sibling(&filter, 3);
+    assert_eq!(request(&mut second), Action::Pause);
+    assert_eq!(mock_host::http_calls().len(), 1);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 503);
+    assert!(response
+        .body_str()
+        .contains("Too many authorization requests in flight"));
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+    assert_eq!(concurrency::in_flight(&filter), 0);
+    let mut third = sibling(&filter, 4);
+    assert_eq!(request(&mut third), Action::Pause);
+    assert_eq!(mock_host::http_calls().len(), 2);
+}
+
+#[test]
+fn failed_callout_is_retried_on_the_secondary_cluster() {
+    let mut filter = filter(FilterConfig {
+        failover: Some(FailoverConfig {
+            secondary_clusters: vec!["pdp-secondary".to_string()],
+            unhealthy_threshold: 1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    request(&mut filter);
+    pdp_response(&mut filter, "503", "");
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].upstream, "pdp-secondary");
+    assert!(mock_host::local_response().is_none());
+
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+
+    // The primary is marked down, so the next request goes straight to the secondary
+    let mut next = sibling(&filter, 3);
+    request(&mut next);
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 3);
+    assert_eq!(calls[2].upstream, "pdp-secondary");
+}
+
+#[test]
+fn slow_callout_is_hedged_and_the_first_response_wins() {
+    let mut filter = filter(FilterConfig {
+        hedging: Some(HedgingConfig {
+            threshold_ms: 100,
+            cluster: Some("pdp-hedge".to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let mut root = ServerFilterRoot {
+        config: filter.config.clone(),
+        hedges: filter.hedges.clone(),
+        ..Default::default()
+    };
+    request(&mut filter);
+    root.on_tick();
+    assert_eq!(mock_host::http_calls().len(), 1);
+
+    mock_host::with(|host| host.time_nanos += 150_000_000);
+    root.on_tick();
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].upstream, "pdp-hedge");
+
+    let body = r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#;
+    mock_host::set_http_call_response("200", body.as_bytes());
+    root.on_http_call_response(calls[1].token, 0, body.len(), 0);
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+    assert!(root.hedges.borrow().is_empty());
+
+    // The original call's late answer is ignored
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Deny","reason":"not_owner"}]}"#,
+    );
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(mock_host::with(|host| host.resumed_requests), 1);
+}
+
+fn body_asset_filter() -> ServerFilterHttp {
+    let config: FilterConfig = serde_json::from_value(serde_json::json!({
+        "asset_rules": [{"body_pointer": "/asset"}],
+        "max_request_body_bytes": 32
+    }))
+    .unwrap();
+    filter(config)
+}
+
+fn post_headers(filter: &mut ServerFilterHttp, content_length: Option<&str>) -> Action {
+    let authorization = format!("Bearer {}", token("alice"));
+    let mut headers = vec![
+        (":method", "POST"),
+        (":path", "/api"),
+        ("authorization", authorization.as_str()),
+    ];
+    headers.extend(content_length.map(|length| ("content-length", length)));
+    mock_host::set_request_headers(&headers);
+    filter.on_http_request_headers(headers.len(), false)
+}
+
+#[test]
+fn declared_oversized_body_is_rejected_up_front() {
+    let mut filter = body_asset_filter();
+
+    assert_eq!(post_headers(&mut filter, Some("1048576")), Action::Pause);
+
+    assert_eq!(
+        mock_host::local_response().expect("local reply").status,
+        413
+    );
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn streamed_body_is_rejected_once_over_the_cap() {
+    let mut filter = body_asset_filter();
+    assert_eq!(post_headers(&mut filter, None), Action::Pause);
+
+    mock_host::set_buffer(BufferType::HttpRequestBody, br#"{"asset": "doc-1", "#);
+    assert_eq!(filter.on_http_request_body(20, false), Action::Pause);
+    assert!(mock_host::local_response().is_none());
+    mock_host::set_buffer(BufferType::HttpRequestBody, &[b' '; 40]);
+    assert_eq!(filter.on_http_request_body(40, false), Action::Pause);
+
+    assert_eq!(
+        mock_host::local_response().expect("local reply").status,
+        413
+    );
+    assert!(mock_host::http_calls().is_empty());
+}
+
+fn audit_config(flusher: bool) -> AuditConfig {
+    AuditConfig {
+        queue: Some(AuditQueueConfig {
+            vm_id: "audit-flusher".to_string(),
+            flusher,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn audit_records_are_flushed_by_the_queue_flusher() {
+    let mut filter = filter(FilterConfig {
+        audit: Some(audit_config(false)),
+        ..Default::default()
+    });
+    let mut flusher = ServerFilterRoot {
+        config: Rc::new(FilterConfig {
+            audit: Some(audit_config(true)),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    flusher.on_tick();
+    let mut producer = ServerFilterRoot {
+        config: filter.config.clone(),
+        audit: filter.audit.clone(),
+        audit_queue: filter.audit_queue.clone(),
+        ..Default::default()
+    };
+    producer.on_tick();
+
+    request(&mut filter);
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+    producer.on_tick();
+    assert!(filter.audit.borrow().is_empty());
+    assert_eq!(mock_host::http_calls().len(), 1);
+
+    flusher.on_tick();
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].header(":path"), Some("/audit/events"));
+    let batch: serde_json::Value = serde_json::from_slice(&calls[1].body).unwrap();
+    assert_eq!(batch[0]["request_id"], "req-1");
+    assert_eq!(batch[0]["source"], "pdp");
+}
+
+#[test]
+fn trailers_reach_audit_records_and_obligations() {
+    let mut filter = filter(FilterConfig {
+        audit: Some(AuditConfig {
+            trailers: vec!["x-client-checksum".to_string(), "grpc-status".to_string()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    request(&mut filter);
+    let decision = r#"{"decisions":[{"decision":"Allow","reason":"granted",
+        "obligations":{"responseTrailers":{"x-policy-version":"7"}}}]}"#;
+    pdp_response(&mut filter, "200", decision);
+    assert!(filter.audit.borrow().is_empty());
+
+    mock_host::set_request_trailers(&[("x-client-checksum", "abc123")]);
+    filter.on_http_request_trailers(1);
+    mock_host::set_response_headers(&[(":status", "200"), ("content-type", "application/grpc")]);
+    filter.on_http_response_headers(2, false);
+    mock_host::set_response_trailers(&[("grpc-status", "0")]);
+    filter.on_http_response_trailers(1);
+    filter.on_done();
+
+    assert_eq!(
+        mock_host::response_trailer("x-policy-version").as_deref(),
+        Some("7")
+    );
+    let record = filter.audit.borrow_mut().pop_front().expect("audit record");
+    assert_eq!(record.decision, "Allow");
+    assert_eq!(
+        record.trailers.get("x-client-checksum").map(String::as_str),
+        Some("abc123")
+    );
+    assert_eq!(
+        record.trailers.get("grpc-status").map(String::as_str),
+        Some("0")
+    );
+}
+
+fn signing_config() -> FilterConfig {
+    let signing = serde_json::json!({ "keys": { "svc-a": { "secret": "s3cret" } } });
+    FilterConfig {
+        request_signing: Some(serde_json::from_value(signing).unwrap()),
+        ..Default::default()
+    }
+}
+
+/// Runs the headers of a `POST /api?asset=doc-1` signed over `signed_body`.
+fn signed_request(filter: &mut ServerFilterHttp, signed_body: &[u8], timestamp: u64) -> Action {
+    use hmac::{Hmac, Mac};
+
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"s3cret").unwrap();
+    mac.update(format!("POST\n/api?asset=doc-1\n{}\n", timestamp).as_bytes());
+    mac.update(signed_body);
+    let signature = STANDARD.encode(mac.finalize().into_bytes());
+    let authorization = format!("Bearer {}", token("alice"));
+    mock_host::set_request_headers(&[
+        (":method", "POST"),
+        (":path", "/api?asset=doc-1"),
+        ("authorization", &authorization),
+        ("x-signature-key-id", "svc-a"),
+        ("x-signature-timestamp", &timestamp.to_string()),
+        ("x-signature", &signature),
+    ]);
+    filter.on_http_request_headers(6, false)
+}
+
+#[test]
+fn signed_request_is_verified_over_its_body() {
+    let mut filter = filter(signing_config());
+    let now_secs = mock_host::DEFAULT_TIME_NANOS / 1_000_000_000;
+    assert_eq!(
+        signed_request(&mut filter, br#"{"amount":10}"#, now_secs),
+        Action::Pause
+    );
+    assert!(mock_host::http_calls().is_empty());
+
+    mock_host::set_buffer(BufferType::HttpRequestBody, br#"{"amount":10}"#);
+    assert_eq!(filter.on_http_request_body(13, true), Action::Pause);
+
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(mock_host::http_calls().len(), 1);
+}
+
+#[test]
+fn tampered_body_fails_signature_verification() {
+    let mut filter = filter(signing_config());
+    let now_secs = mock_host::DEFAULT_TIME_NANOS / 1_000_000_000;
+    signed_request(&mut filter, br#"{"amount":10}"#, now_secs);
+
+    mock_host::set_buffer(BufferType::HttpRequestBody, br#"{"amount":99}"#);
+    filter.on_http_request_body(13, true);
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(response.body_str().contains("Signature mismatch"));
+    assert!(mock_host::http_calls().is_empty());
+}
+
+#[test]
+fn stale_signature_is_rejected_up_front() {
+    let mut filter = filter(signing_config());
+    let now_secs = mock_host::DEFAULT_TIME_NANOS / 1_000_000_000;
+    signed_request(&mut filter, b"", now_secs - 600);
+
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(response.body_str().contains("outside the accepted window"));
+}
+
+#[test]
+fn principals_only_draw_from_rate_limit_buckets_they_hold() {
+    mock_host::reset();
+    let ctx = ServerFilterHttp::default();
+    let config = RateLimitConfig {
+        requests_per_window: 1,
+        slots: 1,
+        ..Default::default()
+    };
+    let alice = config.bucket_id("alice", "doc-1");
+    let bob = config.bucket_id("bob", "doc-1");
+
+    assert_eq!(ratelimit::acquire(&ctx, &config, &alice, 0), Ok(true));
+    // Bob's requests go uncounted rather than charged to Alice's bucket
+    assert_eq!(ratelimit::acquire(&ctx, &config, &bob, 0), Ok(false));
+    assert_eq!(ratelimit::acquire(&ctx, &config, &bob, 0), Ok(false));
+    assert_eq!(ratelimit::acquire(&ctx, &config, &alice, 0), Err(1000));
+
+    // Once Alice's bucket has refilled, the slot can pass to Bob
+    assert_eq!(ratelimit::acquire(&ctx, &config, &bob, 1000), Ok(true));
+    assert_eq!(ratelimit::acquire(&ctx, &config, &alice, 1000), Ok(false));
+    assert_eq!(mock_host::with(|host| host.shared_data.len()), 1);
+}
+
+#[test]
+fn a_colliding_principal_cannot_throttle_another() {
+    let mut filter = filter(FilterConfig {
+        rate_limit: Some(RateLimitConfig {
+            requests_per_window: 1,
+            slots: 1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    filter.metrics = Metrics::define("server_filter");
+    let rate_limit = filter.config.rate_limit.clone().unwrap();
+    let noisy = rate_limit.bucket_id("mallory", "doc-1");
+    let now_ms = mock_host::DEFAULT_TIME_NANOS / 1_000_000;
+    assert_eq!(
+        ratelimit::acquire(&filter, &rate_limit, &noisy, now_ms),
+        Ok(true)
+    );
+
+    assert_eq!(request(&mut filter), Action::Pause);
+
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(mock_host::http_calls().len(), 1);
+    assert_eq!(
+        mock_host::metric("server_filter.rate_limit.untracked"),
+        Some(1)
+    );
+}
+
+#[test]
+fn quotas_are_tracked_in_a_fixed_number_of_slots() {
+    mock_host::reset();
+    let ctx = ServerFilterHttp::default();
+    let quota = Quota {
+        limit: 10,
+        remaining: 5,
+        reset: 1_000,
+    };
+    let slot = |principal: &str| slots::key("", principal, slots::DEFAULT_SLOTS);
+    let bob = (0..)
+        .map(|i| format!("user-{}", i))
+        .find(|principal| slot(principal) == slot("alice"))
+        .unwrap();
+    quota::store(&ctx, "alice", &quota, 0);
+
+    // Alice's quota holds the slot until it resets
+    quota::store(&ctx, &bob, &quota, 0);
+    assert_eq!(quota::consume(&ctx, &bob, 0), Ok(None));
+    assert_eq!(
+        quota::consume(&ctx, "alice", 0).map(|q| q.map(|q| q.remaining)),
+        Ok(Some(4))
+    );
+
+    let renewed = Quota {
+        reset: 2_000,
+        ..quota
+    };
+    quota::store(&ctx, &bob, &renewed, 1_000);
+    assert!(quota::consume(&ctx, &bob, 1_000).is_ok_and(|q| q.is_some()));
+    assert_eq!(mock_host::with(|host| host.shared_data.len()), 1);
+}
+
+#[test]
+fn replayed_token_is_rejected() {
+    let config = FilterConfig {
+        replay: Some(Default::default()),
+        ..Default::default()
+    };
+    let claims = URL_SAFE_NO_PAD.encode(r#"{"sub":"alice","jti":"t-1"}"#);
+    let authorization = format!(
+        "Bearer {}.{}.sig",
+        URL_SAFE_NO_PAD.encode(br#"{"alg":"none"}"#),
+        claims
+    );
+    let headers = [
+        (":path", "/api?asset=doc-1"),
+        ("authorization", authorization.as_str()),
+    ];
+
+    let mut first = filter(config);
+    mock_host::set_request_headers(&headers);
+    first.on_http_request_headers(2, true);
+    assert_eq!(mock_host::http_calls().len(), 1);
+
+    let mut replay = ServerFilterHttp {
+        config: first.config.clone(),
+        ..Default::default()
+    };
+    replay.on_http_request_headers(2, true);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(response.body_str().contains("Replayed token"));
+    assert_eq!(mock_host::http_calls().len(), 1);
+}
+
+#[test]
+fn replay_values_share_a_fixed_number_of_slots() {
+    mock_host::reset();
+    let ctx = ServerFilterHttp::default();
+    let config = ReplayConfig {
+        slots: 1,
+        ..Default::default()
+    };
+    let first_use = |nonce, now_secs| replay::first_use(&ctx, &config, "nonce", nonce, now_secs);
+
+    assert!(first_use("n-1", 0));
+    assert!(first_use("n-2", 0));
+    assert!(!first_use("n-1", 1));
+
+    // Values are forgotten once out of the window
+    assert!(first_use("n-1", config.window_secs));
+    assert_eq!(mock_host::with(|host| host.shared_data.len()), 1);
+}
+
+#[test]
+fn replayed_signature_is_rejected() {
+    let mut config = signing_config();
+    config.replay = Some(Default::default());
+    let now_secs = mock_host::DEFAULT_TIME_NANOS / 1_000_000_000;
+    let mut first = filter(config);
+    signed_request(&mut first, b"", now_secs);
+    first.on_http_request_body(0, true);
+    assert!(mock_host::local_response().is_none());
+
+    let mut replay = ServerFilterHttp {
+        config: first.config.clone(),
+        ..Default::default()
+    };
+    signed_request(&mut replay, b"", now_secs);
+    replay.on_http_request_body(0, true);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(response.body_str().contains("Replayed request signature"));
+}
+
+#[test]
+fn revoked_subject_is_rejected() {
+    let mut filter = filter(FilterConfig {
+        revocation: Some(Default::default()),
+        ..Default::default()
+    });
+    let mut root = ServerFilterRoot {
+        config: filter.config.clone(),
+        ..Default::default()
+    };
+    root.on_tick();
+    let calls = mock_host::http_calls();
+    assert_eq!(calls[0].header(":path"), Some("/revocations"));
+    let list = br#"{"jti":["t-9"],"sub":["alice"]}"#;
+    mock_host::set_http_call_response("200", list);
+    root.on_http_call_response(calls[0].token, 1, list.len(), 0);
+
+    assert_eq!(request(&mut filter), Action::Pause);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 401);
+    assert!(response.body_str().contains("revoked"));
+    assert_eq!(mock_host::http_calls().len(), 1);
+}
+
+#[test]
+fn credentials_are_redacted_from_debug_logs() {
+    let mut filter = filter(FilterConfig::default());
+    logging::configure(&LoggingConfig {
+        level: Some(wasm_common::vm::LogLevelName::Debug),
+        ..Default::default()
+    });
+    let token = token("alice");
+    mock_host::set_request_headers(&[
+        (":path", &format!("/api?asset=doc-1&access_token={}", token)),
+        ("authorization", &format!("Bearer {}", token)),
+        ("x-api-key", "key-1"),
+    ]);
+    filter.on_http_request_headers(3, true);
+    let reason = "x".repeat(1000);
+    pdp_response(
+        &mut filter,
+        "200",
+        &format!(
+            r#"{{"decisions":[{{"decision":"Allow","reason":"{}"}}]}}"#,
+            reason
+        ),
+    );
+
+    let logs = mock_host::logs().join("\n");
+    assert!(logs.contains("authorization: [REDACTED]"));
+    assert!(logs.contains("x-api-key: [REDACTED]"));
+    assert!(!logs.contains(&token) && !logs.contains("key-1"));
+    let body_line = logs
+        .lines()
+        .find(|line| line.contains("PDP response body"))
+        .expect("body logged");
+    assert!(body_line.contains("bytes)") && !body_line.contains(&"x".repeat(300)));
+}
+
+#[test]
+fn identity_chain_is_verified_and_sent_to_pdp() {
+    let config = FilterConfig {
+        identity_chain: Some(IdentityChainConfig {
+            keys: [(
+                "k1".to_string(),
+                SigningSecret {
+                    secret: Some("s".to_string()),
+                    secret_env: None,
+                },
+            )]
+            .into(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let chained_request = |filter: &mut ServerFilterHttp, chain: &str| {
+        let authorization = format!("Bearer {}", token("alice"));
+        mock_host::set_request_headers(&[
+            (":path", "/api?asset=doc-1"),
+            ("authorization", &authorization),
+            (identity::HEADER, chain),
+        ]);
+        filter.on_http_request_headers(3, true)
+    };
+
+    let mut accepted = filter(config.clone());
+    let now = time::now_secs(&accepted);
+    let chain = identity::append(None, "bob", "gateway", now, "k1", "s");
+    let chain = identity::append(Some(&chain), "bob", "alice", now, "k1", "s");
+    chained_request(&mut accepted, &chain);
+    let calls = mock_host::http_calls();
+    let body: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(body["context"]["identity.caller"], "bob");
+    assert_eq!(body["context"]["identity.chain"], "gateway,alice");
+
+    // Dropping the first hop breaks the digest the second one carries
+    let mut tampered = filter(config);
+    let last_hop = chain.rsplit(", ").next().unwrap();
+    chained_request(&mut tampered, last_hop);
+    assert!(mock_host::http_calls().is_empty());
+    assert_eq!(mock_host::local_response().unwrap().status, 401);
+}
+
+#[test]
+fn repeated_denies_raise_a_security_event_and_flag_the_principal() {
+    let config = Rc::new(FilterConfig {
+        security_events: Some(SecurityEventsConfig {
+            threshold: 2,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let mut root = ServerFilterRoot {
+        config: config.clone(),
+        ..Default::default()
+    };
+    let events = root.security_events.clone();
+    let http = || ServerFilterHttp {
+        context_id: 2,
+        config: config.clone(),
+        security_events: events.clone(),
+        ..Default::default()
+    };
+    mock_host::reset();
+    let deny = r#"{"decisions":[{"decision":"Deny","reason":"not_owner"}]}"#;
+    for _ in 0..2 {
+        let mut filter = http();
+        request(&mut filter);
+        pdp_response(&mut filter, "200", deny);
+    }
+    root.on_tick();
+
+    let calls = mock_host::http_calls();
+    assert_eq!(calls.len(), 3);
+    assert_eq!(calls[2].upstream, "security-events");
+    let events: serde_json::Value = serde_json::from_slice(&calls[2].body).unwrap();
+    assert_eq!(events[0]["principal"], "alice");
+    assert_eq!(events[0]["denies"], 2);
+
+    let mut flagged = http();
+    request(&mut flagged);
+    assert_eq!(
+        mock_host::request_header("x-risk-signal").as_deref(),
+        Some("repeated_denies")
+    );
+}
+
+#[test]
+fn deny_counts_share_a_fixed_number_of_slots() {
+    mock_host::reset();
+    let ctx = ServerFilterHttp::default();
+    let config = SecurityEventsConfig {
+        threshold: 2,
+        slots: 1,
+        ..Default::default()
+    };
+    let deny = |principal, now_ms| security_events::record_deny(&ctx, &config, principal, now_ms);
+
+    assert_eq!(deny("alice", 0).denies, 1);
+    // Bob's denies go uncounted while alice's holds the only slot
+    assert_eq!(deny("bob", 1_000).denies, 0);
+    assert!(deny("alice", 2_000).event);
+
+    // The slot passes on once alice's window and flag have lapsed
+    let lapsed_ms = 2_000 + config.flag_ms;
+    assert_eq!(deny("bob", lapsed_ms).denies, 1);
+    assert!(!security_events::is_flagged(
+        &ctx, &config, "alice", lapsed_ms
+    ));
+    assert_eq!(mock_host::with(|host| host.shared_data.len()), 1);
+}
+
+#[test]
+fn principals_denied_too_often_are_blocked_for_the_cooldown() {
+    let config = Rc::new(FilterConfig {
+        security_events: Some(SecurityEventsConfig {
+            block: Some(BlockConfig {
+                threshold: 2,
+                cooldown_ms: 60_000,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let http = || ServerFilterHttp {
+        context_id: 2,
+        config: config.clone(),
+        ..Default::default()
+    };
+    mock_host::reset();
+    let deny = r#"{"decisions":[{"decision":"Deny","reason":"not_owner"}]}"#;
+    for _ in 0..2 {
+        let mut filter = http();
+        request(&mut filter);
+        pdp_response(&mut filter, "200", deny);
+    }
+
+    assert_eq!(request(&mut http()), Action::Pause);
+    assert_eq!(mock_host::http_calls().len(), 2);
+    let response = mock_host::local_response().expect("local reply");
+    assert_eq!(response.status, 429);
+    assert_eq!(response.header("retry-after"), Some("60"));
+
+    mock_host::with(|host| host.time_nanos += 60_000_000_000);
+    request(&mut http());
+    assert_eq!(mock_host::http_calls().len(), 3);
+}
+
+#[test]
+fn blocked_principals_keep_their_slot_for_the_cooldown() {
+    mock_host::reset();
+    let ctx = ServerFilterHttp::default();
+    let config = SecurityEventsConfig {
+        slots: 1,
+        block: Some(BlockConfig {
+            threshold: 1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    security_events::record_deny(&ctx, &config, "alice", 0);
+
+    // Alice's window is over, but not her block
+    let now_ms = config.window_ms;
+    assert_eq!(
+        security_events::record_deny(&ctx, &config, "bob", now_ms).denies,
+        0
+    );
+    assert!(security_events::blocked_for_ms(&ctx, &config, "alice", now_ms).is_some());
+}
+
+#[test]
+fn added_latency_covers_the_pdp_wait() {
+    let mut filter = filter(FilterConfig {
+        bypass_paths: vec![PathMatch::Exact("/health".to_string())],
+        ..Default::default()
+    });
+    filter.metrics = Metrics::define("server_filter");
+
+    // Requests answered at once are measured up to the reply
+    mock_host::set_request_headers(&[(":method", "GET"), (":path", "/api")]);
+    let mut unauthenticated = ServerFilterHttp {
+        config: filter.config.clone(),
+        metrics: filter.metrics,
+        ..Default::default()
+    };
+    assert_eq!(
+        unauthenticated.on_http_request_headers(2, true),
+        Action::Pause
+    );
+    mock_host::with(|host| host.time_nanos += 10_000_000);
+    unauthenticated.on_done();
+    assert_eq!(mock_host::metric("server_filter.added_latency_ms"), Some(0));
+
+    assert_eq!(request(&mut filter), Action::Pause);
+    mock_host::with(|host| host.time_nanos += 30_000_000);
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+    // Time spent upstream isn't the filter's
+    mock_host::with(|host| host.time_nanos += 50_000_000);
+    filter.on_done();
+    assert_eq!(
+        mock_host::metric("server_filter.added_latency_ms"),
+        Some(30)
+    );
+
+    // Bypassed requests aren't measured at all
+    mock_host::set_request_headers(&[(":method", "GET"), (":path", "/health")]);
+    let mut bypassed = ServerFilterHttp {
+        config: filter.config.clone(),
+        metrics: filter.metrics,
+        ..Default::default()
+    };
+    assert_eq!(bypassed.on_http_request_headers(2, true), Action::Continue);
+    mock_host::with(|host| host.time_nanos += 10_000_000);
+    bypassed.on_done();
+    assert_eq!(
+        mock_host::metric("server_filter.added_latency_ms"),
+        Some(30)
+    );
+}
+
+#[test]
+fn shadow_mode_lets_denied_requests_through_marked() {
+    let mut filter = filter(FilterConfig {
+        enforcement_mode: EnforcementMode::Shadow,
+        audit: Some(AuditConfig::default()),
+        ..Default::default()
+    });
+    filter.metrics = Metrics::define("server_filter");
+
+    request(&mut filter);
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Deny","reason":"not_owner"}]}"#,
+    );
+
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(
+        mock_host::request_header("X-Shadow-Decision").as_deref(),
+        Some("Deny")
+    );
+    assert_eq!(
+        mock_host::request_header("X-Shadow-Reason").as_deref(),
+        Some("not_owner")
+    );
+    assert_eq!(mock_host::metric("server_filter.shadow.denied"), Some(1));
+    assert_eq!(mock_host::metric("server_filter.denied"), Some(0));
+    let record = filter
+        .audit
+        .borrow()
+        .front()
+        .cloned()
+        .expect("audit record");
+    assert_eq!(record.decision, "Deny");
+    assert!(record.shadow);
+}
+
+#[test]
+fn shadow_mode_lets_withheld_responses_through() {
+    let mut filter = filter(FilterConfig {
+        enforcement_mode: EnforcementMode::Shadow,
+        audit: Some(AuditConfig::default()),
+        ..Default::default()
+    });
+    filter.metrics = Metrics::define("server_filter");
+
+    request(&mut filter);
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted","obligations":{
+            "denyResponseHeaders":{"x-data-classification":["restricted"]},
+            "responseHeaders":{"cache-control":"no-store"}}}]}"#,
+    );
+    mock_host::set_response_headers(&[(":status", "200"), ("x-data-classification", "restricted")]);
+
+    assert_eq!(filter.on_http_response_headers(2, true), Action::Continue);
+    assert!(mock_host::local_response().is_none());
+    assert_eq!(
+        mock_host::response_header("cache-control").as_deref(),
+        Some("no-store")
+    );
+    assert_eq!(mock_host::metric("server_filter.shadow.denied"), Some(1));
+    assert_eq!(mock_host::metric("server_filter.denied"), Some(0));
+    let record = filter.audit.borrow().back().cloned().expect("audit record");
+    assert_eq!(record.decision, "Deny");
+    assert_eq!(record.reason, "response_policy");
+    assert!(record.shadow);
+}
+
+#[test]
+fn forged_shadow_headers_are_dropped() {
+    let mut filter = filter(FilterConfig {
+        enforcement_mode: EnforcementMode::Shadow,
+        ..Default::default()
+    });
+    let authorization = format!("Bearer {}", token("alice"));
+    mock_host::set_request_headers(&[
+        (":method", "GET"),
+        (":path", "/api?asset=doc-1"),
+        ("authorization", &authorization),
+        ("x-shadow-decision", "Allow"),
+        ("x-shadow-reason", "forged"),
+    ]);
+    assert_eq!(filter.on_http_request_headers(5, true), Action::Pause);
+    pdp_response(
+        &mut filter,
+        "200",
+        r#"{"decisions":[{"decision":"Allow","reason":"granted"}]}"#,
+    );
+
+    assert_eq!(mock_host::request_header("x-shadow-decision"), None);
+    assert_eq!(mock_host::request_header("x-shadow-reason"), None);
+}
+
+#[test]
+fn unverified_principals_need_an_explicit_opt_in() {
+    mock_host::reset();
+    let mut root = ServerFilterRoot::default();
+    mock_host::set_buffer(
+        BufferType::PluginConfiguration,
+        br#"{"pdp_timeout_ms": 500}"#,
+    );
+    assert!(!root.on_configure(0));
+
+    mock_host::set_buffer(
+        BufferType::PluginConfiguration,
+        br#"{"principal": {"allow_unverified_principal": true}}"#,
+    );
+    assert!(root.on_configure(0));
+
+    mock_host::set_buffer(
+        BufferType::PluginConfiguration,
+        br#"{"principal": {"source": "header"}}"#,
+    );
+    assert!(root.on_configure(0));
+}
@@ -0,0 +1,38 @@
+use serde::Deserialize;
+
+use crate::local_policy;
+
+/// Handling of protocol upgrades such as WebSocket. Upgrade requests are
+/// authorized like any other, but their bodies carry the upgraded stream, so
+/// the filter never buffers or inspects them.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct UpgradeConfig {
+    /// Assets for which upgrades are refused with 403 before the PDP is
+    /// asked, e.g. `["reports-*"]` for assets that disallow streaming. `*`
+    /// refuses every upgrade and a trailing `*` matches by prefix.
+    pub deny_assets: Vec<String>,
+}
+
+impl UpgradeConfig {
+    pub fn denies(&self, asset_id: &str) -> bool {
+        self.deny_assets
+            .iter()
+            .any(|pattern| local_policy::pattern_matches(Some(pattern), asset_id))
+    }
+}
+
+/// The protocol a request asks to switch to, e.g. `websocket`: the `upgrade`
+/// header of an HTTP/1.1 `Connection: Upgrade` request, or the `:protocol`
+/// of an HTTP/2 extended CONNECT.
+pub fn requested_protocol(header: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let connection = header("connection").unwrap_or_default();
+    let upgrading = connection
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let protocol = match upgrading {
+        true => header("upgrade"),
+        false => header(":protocol"),
+    };
+    protocol.filter(|p| !p.is_empty())
+}
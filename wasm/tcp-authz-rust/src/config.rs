@@ -0,0 +1,57 @@
+use serde::Deserialize;
+use std::time::Duration;
+use wasm_common::logging::LoggingConfig;
+
+/// Plugin configuration for the TCP authorization filter, supplied as JSON
+/// through the Envoy `configuration` field of a network filter.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FilterConfig {
+    pub pdp_cluster: String,
+    pub pdp_path: String,
+    pub pdp_authority: String,
+    pub pdp_timeout_ms: u64,
+    /// Asset the connection is evaluated against. Defaults to the address
+    /// the client connected to.
+    pub asset_id: Option<String>,
+    pub action: String,
+    /// Close connections without an mTLS peer identity instead of using the
+    /// client IP as the principal.
+    pub require_peer_identity: bool,
+    /// What happens to a connection when the PDP can't be consulted.
+    pub failure_mode: FailureMode,
+    pub logging: LoggingConfig,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            pdp_cluster: "sgnl-pdp-service".to_string(),
+            pdp_path: "/access/v2/evaluations".to_string(),
+            pdp_authority: "sgnl-pdp-service:8082".to_string(),
+            pdp_timeout_ms: 5000,
+            asset_id: None,
+            action: "connect".to_string(),
+            require_peer_identity: false,
+            failure_mode: FailureMode::Closed,
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn pdp_timeout(&self) -> Duration {
+        Duration::from_millis(self.pdp_timeout_ms)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Close the connection.
+    #[default]
+    #[serde(rename = "fail_closed")]
+    Closed,
+    /// Let the connection through.
+    #[serde(rename = "fail_open")]
+    Open,
+}
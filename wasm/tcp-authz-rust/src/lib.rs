@@ -0,0 +1,224 @@
+mod config;
+#[cfg(test)]
+mod tests;
+
+use proxy_wasm::traits::*;
+use proxy_wasm::types::*;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use wasm_common::callout::{self, HttpCallout};
+use wasm_common::connection::ConnectionAttributes;
+use wasm_common::logging::{self, LogFields};
+use wasm_common::pdp::{CombineMode, EvaluationRequest, EvaluationResponse, Principal, Query};
+use wasm_common::{log_debug, log_info, log_warn};
+
+use crate::config::{FailureMode, FilterConfig};
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Info);
+    wasm_common::logging::init("tcp_authz");
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(TcpAuthzRoot::default())
+    });
+}}
+
+#[derive(Default)]
+struct TcpAuthzRoot {
+    config: Rc<FilterConfig>,
+}
+
+impl Context for TcpAuthzRoot {}
+
+impl RootContext for TcpAuthzRoot {
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        let raw = self.get_vm_configuration().unwrap_or_default();
+        match wasm_common::vm::configure(&raw) {
+            Ok(_) => true,
+            Err(e) => {
+                log_warn!("Invalid VM configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let raw = self.get_plugin_configuration().unwrap_or_default();
+        match wasm_common::config::parse::<FilterConfig>(&raw) {
+            Ok(config) => {
+                log_info!(
+                    "Configured: pdp_cluster={}, pdp_path={}, timeout={}ms",
+                    config.pdp_cluster,
+                    config.pdp_path,
+                    config.pdp_timeout_ms
+                );
+                logging::configure(&config.logging);
+                self.config = Rc::new(config);
+                true
+            }
+            Err(e) => {
+                log_warn!("Invalid plugin configuration: {}", e);
+                false
+            }
+        }
+    }
+
+    fn create_stream_context(&self, context_id: u32) -> Option<Box<dyn StreamContext>> {
+        Some(Box::new(TcpAuthzStream {
+            context_id,
+            config: self.config.clone(),
+            state: ConnectionState::Pending,
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::StreamContext)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConnectionState {
+    /// Waiting for the PDP; downstream data is held back meanwhile.
+    Pending,
+    Allowed,
+    Denied,
+}
+
+struct TcpAuthzStream {
+    context_id: u32,
+    config: Rc<FilterConfig>,
+    state: ConnectionState,
+}
+
+impl Context for TcpAuthzStream {
+    fn on_http_call_response(
+        &mut self,
+        _token_id: u32,
+        _num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        let status = callout::response_status(self);
+        if !callout::is_success(&status) {
+            log_warn!(fields: self.log_fields(); "PDP returned status {}", status);
+            self.fail();
+            return;
+        }
+
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        log_debug!(fields: self.log_fields(); "PDP response body: {}", logging::body(&body));
+        let decision = serde_json::from_slice::<EvaluationResponse>(&body)
+            .ok()
+            .and_then(|resp| resp.combine(CombineMode::All, 1).cloned());
+        match decision {
+            Some(decision) if decision.decision == "Allow" => {
+                log_info!(fields: self.log_fields(); "Connection allowed: {}", decision.reason);
+                self.allow();
+            }
+            Some(decision) => {
+                log_info!(fields: self.log_fields(); "Connection denied: {}", decision.reason);
+                self.deny();
+            }
+            None => {
+                log_warn!(fields: self.log_fields(); "Unparseable PDP response");
+                self.fail();
+            }
+        }
+    }
+}
+
+impl StreamContext for TcpAuthzStream {
+    fn on_new_connection(&mut self) -> Action {
+        let connection = ConnectionAttributes::read(self);
+        let principal = match (&connection.peer_identity, &connection.source_address) {
+            (Some(identity), _) => identity.clone(),
+            (None, Some(address)) if !self.config.require_peer_identity => address.clone(),
+            _ => {
+                log_info!(fields: self.log_fields(); "Connection without a peer identity closed");
+                self.deny();
+                return Action::Pause;
+            }
+        };
+        let asset_id = match &self.config.asset_id {
+            Some(asset_id) => asset_id.clone(),
+            None => connection.destination_address.clone().unwrap_or_default(),
+        };
+
+        let eval_request = EvaluationRequest {
+            principal: Principal {
+                id: principal,
+                actor: None,
+            },
+            queries: vec![Query {
+                asset_id,
+                action: self.config.action.clone(),
+            }],
+            context: BTreeMap::new(),
+            connection: Some(connection),
+        };
+        match self.dispatch_pdp(&eval_request) {
+            Ok(_) => Action::Pause,
+            Err(e) => {
+                log_warn!(fields: self.log_fields(); "Failed to dispatch PDP call: {}", e);
+                if self.config.failure_mode == FailureMode::Open {
+                    log_warn!(fields: self.log_fields(); "Failing open");
+                    self.state = ConnectionState::Allowed;
+                    return Action::Continue;
+                }
+                self.deny();
+                Action::Pause
+            }
+        }
+    }
+
+    fn on_downstream_data(&mut self, _data_size: usize, _end_of_stream: bool) -> Action {
+        match self.state {
+            ConnectionState::Allowed => Action::Continue,
+            ConnectionState::Pending | ConnectionState::Denied => Action::Pause,
+        }
+    }
+}
+
+impl TcpAuthzStream {
+    fn log_fields(&self) -> LogFields<'static> {
+        LogFields {
+            context_id: Some(self.context_id),
+            ..Default::default()
+        }
+    }
+
+    fn dispatch_pdp(&self, eval_request: &EvaluationRequest) -> Result<u32, String> {
+        let body = serde_json::to_vec(eval_request).map_err(|e| e.to_string())?;
+        let config = &self.config;
+        HttpCallout::post(&config.pdp_cluster, &config.pdp_authority, &config.pdp_path)
+            .json(&body)
+            .timeout(config.pdp_timeout())
+            .dispatch(self)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn allow(&mut self) {
+        let was_pending = self.state == ConnectionState::Pending;
+        self.state = ConnectionState::Allowed;
+        if was_pending {
+            self.resume_downstream();
+        }
+    }
+
+    fn deny(&mut self) {
+        self.state = ConnectionState::Denied;
+        self.close_downstream();
+    }
+
+    /// Applies the failure mode to a connection the PDP couldn't decide.
+    fn fail(&mut self) {
+        match self.config.failure_mode {
+            FailureMode::Open => {
+                log_warn!(fields: self.log_fields(); "Failing open");
+                self.allow();
+            }
+            FailureMode::Closed => self.deny(),
+        }
+    }
+}
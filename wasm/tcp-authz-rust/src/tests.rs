@@ -0,0 +1,76 @@
+//! Connection authorization against the mock host.
+
+use proxy_wasm::traits::{Context, StreamContext};
+use proxy_wasm::types::Action;
+use std::rc::Rc;
+use wasm_common::mock_host;
+
+use crate::config::FilterConfig;
+use crate::{ConnectionState, TcpAuthzStream};
+
+fn filter(config: serde_json::Value) -> TcpAuthzStream {
+    mock_host::reset();
+    mock_host::set_property(&["source", "address"], b"10.0.0.7:51234");
+    mock_host::set_property(&["destination", "address"], b"10.0.1.2:5432");
+    TcpAuthzStream {
+        context_id: 2,
+        config: Rc::new(serde_json::from_value::<FilterConfig>(config).unwrap()),
+        state: ConnectionState::Pending,
+    }
+}
+
+fn pdp_reply(filter: &mut TcpAuthzStream, decision: &str) {
+    let body = serde_json::json!({ "decisions": [{ "decision": decision, "reason": "policy" }] })
+        .to_string();
+    mock_host::set_http_call_response("200", body.as_bytes());
+    filter.on_http_call_response(1, 0, body.len(), 0);
+}
+
+#[test]
+fn peer_identity_is_authorized_against_the_destination() {
+    let mut filter = filter(serde_json::json!({}));
+    mock_host::set_property(
+        &["connection", "uri_san_peer_certificate"],
+        b"spiffe://example.org/ns/db/sa/app",
+    );
+
+    assert_eq!(filter.on_new_connection(), Action::Pause);
+    assert_eq!(filter.on_downstream_data(64, false), Action::Pause);
+
+    let calls = mock_host::http_calls();
+    let request: serde_json::Value = serde_json::from_slice(&calls[0].body).unwrap();
+    assert_eq!(
+        request["principal"]["id"],
+        "spiffe://example.org/ns/db/sa/app"
+    );
+    assert_eq!(request["queries"][0]["assetId"], "10.0.1.2:5432");
+    assert_eq!(request["queries"][0]["action"], "connect");
+
+    pdp_reply(&mut filter, "Allow");
+    assert_eq!(mock_host::with(|host| host.resumed_downstream), 1);
+    assert_eq!(filter.on_downstream_data(64, false), Action::Continue);
+}
+
+#[test]
+fn denied_connection_is_closed() {
+    let mut filter = filter(serde_json::json!({ "asset_id": "orders-db" }));
+    filter.on_new_connection();
+
+    let request: serde_json::Value =
+        serde_json::from_slice(&mock_host::http_calls()[0].body).unwrap();
+    assert_eq!(request["principal"]["id"], "10.0.0.7");
+    assert_eq!(request["queries"][0]["assetId"], "orders-db");
+
+    pdp_reply(&mut filter, "Deny");
+    assert!(mock_host::with(|host| host.closed_downstream));
+    assert_eq!(mock_host::with(|host| host.resumed_downstream), 0);
+}
+
+#[test]
+fn connection_without_peer_identity_is_refused_when_required() {
+    let mut filter = filter(serde_json::json!({ "require_peer_identity": true }));
+
+    assert_eq!(filter.on_new_connection(), Action::Pause);
+    assert!(mock_host::http_calls().is_empty());
+    assert!(mock_host::with(|host| host.closed_downstream));
+}
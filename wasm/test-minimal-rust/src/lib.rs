@@ -1,6 +1,6 @@
+use log::info;
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
-use log::info;
 
 proxy_wasm::main! {{
     proxy_wasm::set_log_level(LogLevel::Info);
@@ -37,4 +37,4 @@ impl HttpContext for MinimalHttp {
         info!("Minimal Rust filter: request received");
         Action::Continue
     }
-}
\ No newline at end of file
+}